@@ -0,0 +1,14 @@
+﻿REM GENRE Electronic
+PERFORMER "DJ Example"
+TITLE "Live Mix"
+FILE "mix.wav" WAVE
+  TRACK 01 AUDIO
+    TITLE "Intro"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE "Second "Track"
+    PERFORMER Guest Artist
+    INDEX 00 01:59:70
+    INDEX 01 02:00:37
+  TRACK 03 AUDIO
+    INDEX 01 125:00:74
@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ravr_wasm::euph_cues::CueSheet;
+
+// Imported cue sheets come from other tools; whatever one parses to must
+// export and import again
+fuzz_target!(|data: &[u8]| {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return;
+    };
+    if let Ok(cues) = CueSheet::parse_cue(text, 44100) {
+        let exported = cues.to_cue("audio.wav");
+        CueSheet::parse_cue(&exported, 44100).expect("exported cue sheet does not parse");
+    }
+});
@@ -0,0 +1,57 @@
+#![no_main]
+
+use std::io::{Cursor, Read};
+use ed25519_dalek::SigningKey;
+use libfuzzer_sys::fuzz_target;
+use ravr_wasm::euph_decoder::{DecodingOptions, EuphContainer, ParseLimits};
+use ravr_wasm::euph_encoder::EuphEncoder;
+
+// Small enough that no input makes a run slow, large enough for real files
+const LIMITS: ParseLimits = ParseLimits {
+    max_chunk_size: 1 << 20,
+    max_chunk_count: 256,
+    max_total_size: 4 << 20,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let public_key = SigningKey::from_bytes(&[1; 32]).verifying_key();
+
+    for lenient in [false, true] {
+        let options = DecodingOptions { lenient, limits: LIMITS };
+
+        // Whole file in memory: decode every chunk, then rewrite the file
+        if let Ok(container) = EuphContainer::parse_with_options(&mut Cursor::new(data), options) {
+            for chunk in container.chunks() {
+                let _ = container.chunk_data_at(chunk.index());
+            }
+            let _ = container.audio_format();
+            let _ = container.decode_audio();
+            let _ = container.cues();
+            let _ = container.lyrics();
+            let _ = container.lyric_line_at(1.5, None);
+            let _ = container.artwork();
+            let _ = container.loudness();
+            let _ = container.peaks();
+            let _ = EuphEncoder::from_container(&container).write(&mut Cursor::new(Vec::new()));
+        }
+
+        // Streaming: read every chunk through the source
+        if let Ok(mut container) = EuphContainer::open_with_options(Cursor::new(data), options) {
+            for index in 0..container.chunk_count() {
+                if let Ok(reader) = container.chunk_reader_at(index) {
+                    let _ = std::io::copy(&mut reader.take(LIMITS.max_chunk_size), &mut std::io::sink());
+                }
+            }
+            let _ = container.read_metadata();
+            let _ = container.read_cues();
+            let _ = container.read_lyrics();
+            let _ = container.read_artwork();
+            let _ = container.read_loudness();
+            let _ = container.read_peaks();
+            let _ = container.read_audio_format();
+            let _ = container.read_audio_range(0.5, 0.25);
+            let _ = container.verify_integrity();
+            let _ = container.verify_signature(&public_key);
+        }
+    }
+});
@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ravr_wasm::euph_lyrics::LyricsTrack;
+
+// LRC files come from lyrics sites and other players; whatever one parses
+// to must export and import again to the same lyrics
+fuzz_target!(|data: &[u8]| {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return;
+    };
+    if let Ok(track) = LyricsTrack::parse_lrc(text) {
+        let exported = track.to_lrc();
+        let imported = LyricsTrack::parse_lrc(&exported).expect("exported lyrics do not parse");
+        assert_eq!(imported.to_lrc(), exported, "exported lyrics change on import");
+    }
+});
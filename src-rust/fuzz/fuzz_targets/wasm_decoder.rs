@@ -0,0 +1,35 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ravr_wasm::{validate_euph_file, EuphDecoder};
+
+// Everything the JS player calls after loading a file; error paths that
+// build a `JsValue` cannot run natively, so decoding goes through `decode_bytes`
+fuzz_target!(|data: &[u8]| {
+    let _ = validate_euph_file(data);
+
+    let mut decoder = EuphDecoder::new();
+    if decoder.decode_bytes(data).is_ok() {
+        let _ = decoder.get_audio_data();
+        let _ = decoder.get_audio_data_with_role("enhanced");
+        let _ = decoder.get_audio_format();
+        let _ = decoder.read_audio_range(0.5, 0.25);
+        let _ = decoder.get_chapters();
+        let _ = decoder.export_cue_sheet("audio.wav");
+        let _ = decoder.get_lyrics_languages();
+        let _ = decoder.get_lyric_line(1.5, None);
+        let _ = decoder.export_lrc(None);
+        let _ = decoder.get_artwork();
+        let _ = decoder.get_artwork_image(0);
+        let _ = decoder.get_loudness();
+        let _ = decoder.get_normalization_gain(true);
+        let _ = decoder.get_peak_resolutions();
+        let _ = decoder.get_peaks(256);
+        let _ = decoder.get_metadata();
+        let _ = decoder.get_metadata_warnings();
+        let _ = decoder.get_title();
+        let _ = decoder.get_tags();
+        let _ = decoder.get_chunk_count();
+        let _ = decoder.is_legacy_layout();
+    }
+});
@@ -0,0 +1,785 @@
+//! `euph`: inspect, verify and build EUPH files from scripts.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use serde_json::{json, Map, Value};
+
+use ravr_wasm::euph_artwork::{Artwork, Picture, PictureRole};
+use ravr_wasm::euph_codec::ChunkCodec;
+use ravr_wasm::euph_decoder::{
+    ChunkData, ChunkType, DecodingOptions, EuphContainer, EuphError, EuphLayout, EuphMetadata,
+    FLAG_AI_COMPRESSED, FLAG_AUDIO_COMPRESSED, FLAG_DSP_COMPRESSED, FLAG_METADATA_COMPRESSED,
+};
+use ravr_wasm::euph_encoder::{EncodingOptions, EuphEncoder, TimestampSource};
+use ravr_wasm::euph_export::ExportFormat;
+use ravr_wasm::euph_lyrics::{Lyrics, LyricsTrack};
+use ravr_wasm::euph_metadata::MetadataWarning;
+use ravr_wasm::euph_peaks;
+use ravr_wasm::euph_signature::SignatureStatus;
+
+const EXIT_CODES: &str = "\
+Exit codes:
+   0  success
+   1  I/O error
+   2  invalid arguments
+   3  not an EUPH file, or an unsupported version or layout
+   4  truncated file or invalid chunk table
+   5  checksum mismatch
+   6  compression error
+   7  missing chunk
+   8  parse limit exceeded
+   9  encryption error, or missing key
+  10  invalid JSON
+  11  reserved chunk type
+  12  signature does not match the file
+  13  file is signed with another key
+  14  file is not signed
+  15  audio payload cannot be decoded
+  16  invalid cue sheet or lyrics file
+  17  invalid artwork, or an image in no supported format";
+
+#[derive(Parser)]
+#[command(name = "euph", version, about = "Inspect, verify and build EUPH files", after_help = EXIT_CODES)]
+struct Cli {
+    /// Print results and errors as JSON
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// Key for encrypted chunks, as KEY_ID:HEX_KEY; may be repeated
+    #[arg(long = "key", global = true, value_name = "KEY_ID:HEX_KEY", value_parser = parse_key)]
+    keys: Vec<(u8, [u8; 32])>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Show the header, chunk table and metadata
+    Info { file: PathBuf },
+
+    /// Check the file and chunk checksums, the integrity hash and the signature
+    Verify {
+        file: PathBuf,
+        /// Hex Ed25519 public key the file must be signed with
+        #[arg(long)]
+        public_key: Option<String>,
+    },
+
+    /// Write the body of a chunk to a file or stdout
+    Extract {
+        file: PathBuf,
+        /// Chunk type: audio, seek, metadata, ai-model, dsp-chain,
+        /// relativistic, cues, lyrics, artwork, loudness, peaks, signature,
+        /// or a four-character code such as ANLY
+        chunk: String,
+        /// Pick the chunk of that type with this role
+        #[arg(long)]
+        role: Option<String>,
+        /// Write the body as stored, without decrypting or decompressing it
+        #[arg(long)]
+        raw: bool,
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Build an EUPH file from audio and JSON descriptions
+    Pack {
+        #[arg(short, long)]
+        output: PathBuf,
+        #[arg(long)]
+        audio: PathBuf,
+        /// Metadata JSON
+        #[arg(long)]
+        meta: Option<PathBuf>,
+        /// DSP chain JSON
+        #[arg(long)]
+        dsp: Option<PathBuf>,
+        /// Relativistic effects JSON
+        #[arg(long)]
+        relativistic: Option<PathBuf>,
+        /// Chapters from a .cue sheet
+        #[arg(long)]
+        cues: Option<PathBuf>,
+        /// Synced lyrics from an LRC or enhanced LRC file, with the language
+        /// from its [la:] tag, replacing lyrics in that language imported
+        /// from the audio file; may be repeated for other languages
+        #[arg(long)]
+        lyrics: Vec<PathBuf>,
+        /// PNG, JPEG, GIF, WebP or BMP picture with its role (front, back,
+        /// artist or other), replacing cover art imported from the audio
+        /// file; may be repeated
+        #[arg(long = "picture", value_name = "ROLE:PATH", value_parser = parse_picture)]
+        pictures: Vec<(PictureRole, PathBuf)>,
+        /// Skip importing metadata, lyrics and cover art from the audio
+        /// file's tags
+        #[arg(long)]
+        no_tags: bool,
+        /// Skip measuring the loudness of the audio for the LOUD chunk
+        #[arg(long)]
+        no_loudness: bool,
+        /// Skip the waveform overviews of the PEAK chunk
+        #[arg(long)]
+        no_peaks: bool,
+        #[arg(long, value_enum, default_value_t = Codec::Zstd)]
+        codec: Codec,
+        /// Compression level, instead of the codec's default; zstd takes
+        /// negative fast levels
+        #[arg(long, allow_negative_numbers = true)]
+        level: Option<i32>,
+        /// Unix timestamp to record instead of the current time
+        #[arg(long)]
+        timestamp: Option<u64>,
+        /// File holding the 32-byte Ed25519 secret key to sign with, raw or hex
+        #[arg(long)]
+        signing_key: Option<PathBuf>,
+    },
+
+    /// Write the audio as a standalone file tagged with the metadata,
+    /// artwork, lyrics and chapters
+    Export {
+        file: PathBuf,
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Output format, by default the one of the output file's extension
+        /// (.wav, .flac, .mka, .ogg or .opus), else the audio's own
+        #[arg(long, value_enum)]
+        format: Option<Export>,
+    },
+
+    /// Replace the metadata of a file, in place when it fits
+    SetMeta { file: PathBuf, meta: PathBuf },
+
+    /// Rewrite a file of either layout in the spec layout
+    Upgrade { input: PathBuf, output: PathBuf },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Codec {
+    None,
+    Gzip,
+    Zstd,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Export {
+    /// The audio's own format
+    Native,
+    Wav,
+    Flac,
+    /// Matroska audio
+    Mka,
+    /// Ogg Vorbis, Opus or FLAC
+    Ogg,
+}
+
+/// A failed command: its exit code, a stable kind for scripts, and the
+/// report gathered before failing, if any.
+struct Failure {
+    code: u8,
+    kind: &'static str,
+    message: String,
+    report: Option<Value>,
+}
+
+impl Failure {
+    fn new(code: u8, kind: &'static str, message: impl Into<String>) -> Self {
+        Self { code, kind, message: message.into(), report: None }
+    }
+
+    fn io(path: &Path, e: std::io::Error) -> Self {
+        Self::new(1, "io", format!("{}: {}", path.display(), e))
+    }
+
+    fn usage(message: impl Into<String>) -> Self {
+        Self::new(2, "usage", message)
+    }
+
+    fn with_report(mut self, report: Value) -> Self {
+        self.report = Some(report);
+        self
+    }
+}
+
+impl From<EuphError> for Failure {
+    fn from(e: EuphError) -> Self {
+        Self::from(&e)
+    }
+}
+
+impl From<&EuphError> for Failure {
+    fn from(e: &EuphError) -> Self {
+        let (code, kind) = match e {
+            EuphError::IoError(_) => (1, "io"),
+            EuphError::InvalidMagic => (3, "invalid_magic"),
+            EuphError::InvalidVersion => (3, "invalid_version"),
+            EuphError::UnsupportedLayout(_) => (3, "unsupported_layout"),
+            EuphError::Truncated { .. } => (4, "truncated"),
+            EuphError::LengthMismatch { .. } => (4, "length_mismatch"),
+            EuphError::InvalidChunkBounds { .. } => (4, "invalid_chunk_bounds"),
+            EuphError::InvalidSeekTable => (4, "invalid_seek_table"),
+            EuphError::InvalidPeaks => (4, "invalid_peaks"),
+            EuphError::ChecksumMismatch { .. } => (5, "checksum_mismatch"),
+            EuphError::CompressionFlagMismatch { .. } => (6, "compression_flag_mismatch"),
+            EuphError::Decompression { .. } => (6, "decompression"),
+            EuphError::UnsupportedCodec(_) => (6, "unsupported_codec"),
+            EuphError::InvalidCompressionLevel(_) => (6, "invalid_compression_level"),
+            EuphError::MissingChunk(_) | EuphError::MissingAudioChunk | EuphError::MissingAiModel => (7, "missing_chunk"),
+            EuphError::ChunkIndexOutOfRange(_) => (7, "chunk_index_out_of_range"),
+            EuphError::ChunkTooLarge { .. } => (8, "chunk_too_large"),
+            EuphError::TooManyChunks { .. } => (8, "too_many_chunks"),
+            EuphError::FileTooLarge { .. } => (8, "file_too_large"),
+            EuphError::UnsupportedCipher(_) => (9, "unsupported_cipher"),
+            EuphError::MissingKey { .. } => (9, "missing_key"),
+            EuphError::Decryption { .. } => (9, "decryption"),
+            EuphError::Encryption { .. } => (9, "encryption"),
+            EuphError::JsonError(_) => (10, "json"),
+            EuphError::ReservedChunkType(_) => (11, "reserved_chunk_type"),
+            EuphError::UnsupportedAudioFormat(_) => (15, "unsupported_audio_format"),
+            EuphError::AudioDecode(_) => (15, "audio_decode"),
+            EuphError::InvalidAudioFormat => (15, "invalid_audio_format"),
+            EuphError::InvalidCueSheet { .. } => (16, "invalid_cue_sheet"),
+            EuphError::InvalidLyrics => (16, "invalid_lyrics"),
+            EuphError::InvalidArtwork => (17, "invalid_artwork"),
+        };
+        Self::new(code, kind, e.to_string())
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let keys: HashMap<u8, [u8; 32]> = cli.keys.iter().copied().collect();
+
+    let result = match &cli.command {
+        Command::Info { file } => info(file, &keys).map(Some),
+        Command::Verify { file, public_key } => verify(file, public_key.as_deref(), &keys).map(Some),
+        Command::Extract { file, chunk, role, raw, output } => {
+            extract(file, chunk, role.as_deref(), *raw, output.as_deref(), &keys)
+        }
+        Command::Pack { output, audio, meta, dsp, relativistic, cues, lyrics, pictures, no_tags, no_loudness, no_peaks, codec, level, timestamp, signing_key } => pack(
+            output,
+            audio,
+            meta.as_deref(),
+            dsp.as_deref(),
+            relativistic.as_deref(),
+            cues.as_deref(),
+            lyrics,
+            pictures,
+            (*no_tags, *no_loudness, *no_peaks),
+            (*codec, *level),
+            *timestamp,
+            signing_key.as_deref(),
+        )
+        .map(Some),
+        Command::Export { file, output, format } => export(file, output, *format, &keys).map(Some),
+        Command::SetMeta { file, meta } => set_meta(file, meta, &keys).map(Some),
+        Command::Upgrade { input, output } => upgrade(input, output).map(Some),
+    };
+
+    match result {
+        Ok(report) => {
+            if let Some(report) = report {
+                print_report(&report, cli.json);
+            }
+            ExitCode::SUCCESS
+        }
+        Err(failure) => {
+            if cli.json {
+                let mut report = match failure.report {
+                    Some(Value::Object(report)) => report,
+                    _ => Map::new(),
+                };
+                report.insert("error".into(), json!({
+                    "kind": failure.kind,
+                    "message": failure.message,
+                    "exit_code": failure.code,
+                }));
+                print_report(&Value::Object(report), true);
+            } else {
+                if let Some(report) = &failure.report {
+                    print_report(report, false);
+                }
+                eprintln!("error: {}", failure.message);
+            }
+            ExitCode::from(failure.code)
+        }
+    }
+}
+
+fn info(path: &Path, keys: &HashMap<u8, [u8; 32]>) -> Result<Value, Failure> {
+    // Lenient, so damaged files can still be inspected
+    let options = DecodingOptions { lenient: true, ..Default::default() };
+    let length = std::fs::metadata(path).map_err(|e| Failure::io(path, e))?.len();
+    let mut container = parse(path, options)?;
+    container.set_key_provider(keys.clone());
+
+    let header_flags: Vec<&str> = [
+        (FLAG_AUDIO_COMPRESSED, "audio_compressed"),
+        (FLAG_METADATA_COMPRESSED, "metadata_compressed"),
+        (FLAG_DSP_COMPRESSED, "dsp_compressed"),
+        (FLAG_AI_COMPRESSED, "ai_compressed"),
+    ]
+    .into_iter()
+    .filter(|(flag, _)| container.flags() & flag != 0)
+    .map(|(_, name)| name)
+    .collect();
+
+    // Metadata that follows no schema is shown as stored
+    let metadata = match container.metadata() {
+        Some(metadata) => json!(metadata),
+        None => container.chunk_data(ChunkType::Metadata).ok()
+            .and_then(|data| serde_json::from_slice::<Value>(&data).ok())
+            .unwrap_or(Value::Null),
+    };
+
+    let chapters = container.cues().ok().flatten().map(|cues| {
+        cues.chapters.iter()
+            .map(|chapter| json!({ "start": cues.seconds(chapter.start), "title": chapter.title }))
+            .collect::<Vec<_>>()
+    });
+
+    let lyrics = container.lyrics().ok().flatten().map(|lyrics| {
+        lyrics.tracks.iter()
+            .map(|track| json!({
+                "language": track.language,
+                "lines": track.lines.len(),
+                "word_timed": track.lines.iter().any(|line| !line.words.is_empty()),
+            }))
+            .collect::<Vec<_>>()
+    });
+
+    let artwork = container.artwork().ok().flatten().map(|artwork| {
+        artwork.pictures.iter()
+            .map(|picture| json!({
+                "role": picture.role.name(),
+                "mime_type": picture.mime_type,
+                "width": picture.width,
+                "height": picture.height,
+                "bytes": picture.data.len(),
+            }))
+            .collect::<Vec<_>>()
+    });
+
+    let peak_resolutions = container.peaks().ok().flatten().map(|peaks| {
+        peaks.levels.iter().map(|level| level.samples_per_bucket).collect::<Vec<_>>()
+    });
+
+    let audio_format = container.audio_format().ok().flatten().map(|format| {
+        let mut value = json!(format);
+        value["duration"] = json!(format.duration_secs());
+        value
+    });
+
+    Ok(json!({
+        "file": path.display().to_string(),
+        "layout": layout_name(container.layout()),
+        "version": format!("{}.{}", container.version().0, container.version().1),
+        "flags": format!("{:#06x}", container.flags()),
+        "header_flags": header_flags,
+        "length": length,
+        "created": container.created(),
+        "modified": container.modified(),
+        "chunks": container.chunks().map(chunk_report).collect::<Vec<_>>(),
+        "metadata": metadata,
+        "metadata_warnings": container.metadata_warnings(),
+        "audio_format": audio_format,
+        "chapters": chapters,
+        "lyrics": lyrics,
+        "artwork": artwork,
+        "loudness": container.loudness().ok().flatten(),
+        "peak_resolutions": peak_resolutions,
+        "problems": container.problems().iter().map(ToString::to_string).collect::<Vec<_>>(),
+    }))
+}
+
+fn verify(path: &Path, public_key: Option<&str>, keys: &HashMap<u8, [u8; 32]>) -> Result<Value, Failure> {
+    let public_key = public_key.map(parse_public_key).transpose()?;
+
+    // Collect every checksum problem rather than stopping at the first
+    let options = DecodingOptions { lenient: true, ..Default::default() };
+    let mut container = parse(path, options)?;
+    container.set_key_provider(keys.clone());
+
+    let integrity = container.verify_integrity()?;
+    let signature = public_key.map(|key| container.verify_signature(&key)).transpose()?;
+
+    let report = json!({
+        "file": path.display().to_string(),
+        "problems": container.problems().iter().map(ToString::to_string).collect::<Vec<_>>(),
+        "integrity": match integrity {
+            Some(true) => "ok",
+            Some(false) => "mismatch",
+            None => "absent",
+        },
+        "signature": signature.map(signature_name),
+    });
+
+    if let Some(problem) = container.problems().first() {
+        return Err(Failure::from(problem).with_report(report));
+    }
+    if integrity == Some(false) {
+        return Err(Failure::new(12, "tampered", "chunks do not match the integrity hash").with_report(report));
+    }
+    match signature {
+        Some(SignatureStatus::Tampered) => {
+            Err(Failure::new(12, "tampered", "signature does not match the file").with_report(report))
+        }
+        Some(SignatureStatus::UnknownKey) => {
+            Err(Failure::new(13, "unknown_key", "file is signed with another key").with_report(report))
+        }
+        Some(SignatureStatus::Unsigned) => Err(Failure::new(14, "unsigned", "file is not signed").with_report(report)),
+        Some(SignatureStatus::Valid) | None => Ok(report),
+    }
+}
+
+fn extract(
+    path: &Path,
+    chunk: &str,
+    role: Option<&str>,
+    raw: bool,
+    output: Option<&Path>,
+    keys: &HashMap<u8, [u8; 32]>,
+) -> Result<Option<Value>, Failure> {
+    let chunk_type = parse_chunk_type(chunk)?;
+    let mut container = parse(path, DecodingOptions::default())?;
+    container.set_key_provider(keys.clone());
+
+    let chunk = match role {
+        Some(role) => container.chunks_with_role(chunk_type, role).next(),
+        None => container.chunk(chunk_type),
+    };
+    let index = chunk.ok_or(EuphError::MissingChunk(chunk_type))?.index();
+    let data = if raw {
+        container.raw_chunk_bytes_at(index).ok_or(EuphError::MissingChunk(chunk_type))?.into()
+    } else if chunk_type == ChunkType::Audio {
+        // The audio file itself, without the format descriptor
+        container.get_audio_data_at(index)?
+    } else {
+        container.chunk_data_at(index)?
+    };
+
+    match output {
+        Some(output) => {
+            std::fs::write(output, &data).map_err(|e| Failure::io(output, e))?;
+            Ok(Some(json!({
+                "file": path.display().to_string(),
+                "chunk": fourcc_name(chunk_type),
+                "role": role,
+                "bytes": data.len(),
+                "output": output.display().to_string(),
+            })))
+        }
+        None => {
+            std::io::stdout().lock().write_all(&data).map_err(|e| Failure::io(Path::new("<stdout>"), e))?;
+            Ok(None)
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn pack(
+    output: &Path,
+    audio: &Path,
+    meta: Option<&Path>,
+    dsp: Option<&Path>,
+    relativistic: Option<&Path>,
+    cues: Option<&Path>,
+    lyrics: &[PathBuf],
+    pictures: &[(PictureRole, PathBuf)],
+    (no_tags, no_loudness, no_peaks): (bool, bool, bool),
+    (codec, level): (Codec, Option<i32>),
+    timestamp: Option<u64>,
+    signing_key: Option<&Path>,
+) -> Result<Value, Failure> {
+    let codec = match codec {
+        Codec::None => ChunkCodec::None,
+        Codec::Gzip => ChunkCodec::Gzip { level: 6 },
+        Codec::Zstd => ChunkCodec::Zstd { level: 3 },
+    };
+    let codec = level.map_or(codec, |level| codec.with_level(level));
+
+    let (metadata, metadata_warnings) = match meta {
+        Some(path) => {
+            let (metadata, warnings) = read_metadata(path)?;
+            (Some(metadata), warnings)
+        }
+        None => (None, Vec::new()),
+    };
+    let mut all_lyrics = Lyrics::default();
+    for path in lyrics {
+        let lrc = std::fs::read_to_string(path).map_err(|e| Failure::io(path, e))?;
+        all_lyrics.set_track(LyricsTrack::parse_lrc(&lrc)?);
+    }
+    let mut artwork = Artwork::default();
+    for (role, path) in pictures {
+        let image = std::fs::read(path).map_err(|e| Failure::io(path, e))?;
+        artwork.set_picture(Picture::from_image(*role, image).ok_or(EuphError::InvalidArtwork)?);
+    }
+    let options = EncodingOptions {
+        codec,
+        timestamp: timestamp.map_or(TimestampSource::default(), TimestampSource::Fixed),
+        compress_audio: codec != ChunkCodec::None,
+        compress_dsp: codec != ChunkCodec::None,
+        dsp_config: dsp.map(read_json).transpose()?,
+        relativistic_effects: relativistic.map(read_json).transpose()?,
+        lyrics: (!all_lyrics.tracks.is_empty()).then_some(all_lyrics),
+        artwork: Some(artwork),
+        import_tags: !no_tags,
+        analyze_loudness: !no_loudness,
+        peak_resolutions: if no_peaks { Vec::new() } else { euph_peaks::DEFAULT_RESOLUTIONS.to_vec() },
+        cue_sheet: cues.map(|path| std::fs::read_to_string(path).map_err(|e| Failure::io(path, e))).transpose()?,
+        ..Default::default()
+    };
+
+    let audio_path = audio.to_str().ok_or_else(|| Failure::usage("audio path is not valid UTF-8"))?;
+    let mut encoder = EuphEncoder::create_from_audio_file(audio_path, metadata, options)
+        .map_err(|e| match e {
+            EuphError::IoError(e) => Failure::io(audio, e),
+            e => e.into(),
+        })?;
+    if let Some(signing_key) = signing_key {
+        encoder = encoder.with_signing_key(read_signing_key(signing_key)?);
+    }
+
+    let mut file = File::create(output).map_err(|e| Failure::io(output, e))?;
+    encoder.write(&mut file)?;
+
+    Ok(json!({
+        "output": output.display().to_string(),
+        "bytes": encoder.get_estimated_size(),
+        "chunks": encoder.chunks().map(|c| fourcc_name(c.chunk_type())).collect::<Vec<_>>(),
+        "signed": signing_key.is_some(),
+        "metadata_warnings": metadata_warnings,
+        "unconverted_tags": encoder.unconverted_tags(),
+    }))
+}
+
+fn export(path: &Path, output: &Path, format: Option<Export>, keys: &HashMap<u8, [u8; 32]>) -> Result<Value, Failure> {
+    let format = format.unwrap_or_else(|| {
+        let extension = output.extension().and_then(|e| e.to_str()).unwrap_or_default().to_ascii_lowercase();
+        match extension.as_str() {
+            "wav" => Export::Wav,
+            "flac" => Export::Flac,
+            "mka" | "mkv" => Export::Mka,
+            "ogg" | "oga" | "opus" => Export::Ogg,
+            _ => Export::Native,
+        }
+    });
+    let format = match format {
+        Export::Native => ExportFormat::Native,
+        Export::Wav => ExportFormat::Wav,
+        Export::Flac => ExportFormat::Flac,
+        Export::Mka => ExportFormat::Matroska,
+        Export::Ogg => ExportFormat::Ogg,
+    };
+
+    let mut container = parse(path, DecodingOptions::default())?;
+    container.set_key_provider(keys.clone());
+    let data = container.export(format)?;
+    std::fs::write(output, &data).map_err(|e| Failure::io(output, e))?;
+
+    Ok(json!({
+        "file": path.display().to_string(),
+        "output": output.display().to_string(),
+        "format": format,
+        "bytes": data.len(),
+    }))
+}
+
+fn set_meta(path: &Path, meta: &Path, keys: &HashMap<u8, [u8; 32]>) -> Result<Value, Failure> {
+    let (metadata, warnings) = read_metadata(meta)?;
+    let file = OpenOptions::new().read(true).write(true).open(path).map_err(|e| Failure::io(path, e))?;
+
+    let mut container = EuphContainer::open(file)?;
+    container.set_key_provider(keys.clone());
+    container.update_metadata(metadata)?;
+
+    Ok(json!({
+        "file": path.display().to_string(),
+        "modified": container.modified(),
+        "metadata_warnings": warnings,
+    }))
+}
+
+fn upgrade(input: &Path, output: &Path) -> Result<Value, Failure> {
+    let mut reader = File::open(input).map_err(|e| Failure::io(input, e))?;
+    let mut writer = File::create(output).map_err(|e| Failure::io(output, e))?;
+    let layout = EuphEncoder::upgrade(&mut reader, &mut writer)?;
+
+    Ok(json!({
+        "input": input.display().to_string(),
+        "output": output.display().to_string(),
+        "from_layout": layout_name(layout),
+    }))
+}
+
+fn parse(path: &Path, options: DecodingOptions) -> Result<EuphContainer, Failure> {
+    let data = std::fs::read(path).map_err(|e| Failure::io(path, e))?;
+    Ok(EuphContainer::parse_with_options(&mut Cursor::new(data), options)?)
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T, Failure> {
+    let data = std::fs::read(path).map_err(|e| Failure::io(path, e))?;
+    serde_json::from_slice(&data).map_err(|e| Failure::from(EuphError::JsonError(e)))
+}
+
+/// Metadata JSON of any schema version, migrated to the current one.
+fn read_metadata(path: &Path) -> Result<(EuphMetadata, Vec<MetadataWarning>), Failure> {
+    let data = std::fs::read(path).map_err(|e| Failure::io(path, e))?;
+    Ok(EuphMetadata::from_json(&data)?)
+}
+
+fn read_signing_key(path: &Path) -> Result<SigningKey, Failure> {
+    let data = std::fs::read(path).map_err(|e| Failure::io(path, e))?;
+    let bytes = match <[u8; 32]>::try_from(data.as_slice()) {
+        Ok(bytes) => bytes,
+        Err(_) => parse_hex_key(String::from_utf8_lossy(&data).trim())
+            .map_err(|_| Failure::usage(format!("{}: expected a 32-byte key, raw or hex", path.display())))?,
+    };
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+fn parse_public_key(hex_key: &str) -> Result<VerifyingKey, Failure> {
+    parse_hex_key(hex_key)
+        .ok()
+        .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+        .ok_or_else(|| Failure::usage("public key must be 32 bytes of hex"))
+}
+
+fn parse_hex_key(hex_key: &str) -> Result<[u8; 32], String> {
+    let bytes = hex::decode(hex_key).map_err(|e| e.to_string())?;
+    <[u8; 32]>::try_from(bytes).map_err(|_| "expected 32 bytes".to_string())
+}
+
+fn parse_key(arg: &str) -> Result<(u8, [u8; 32]), String> {
+    let (key_id, key) = arg.split_once(':').ok_or("expected KEY_ID:HEX_KEY")?;
+    let key_id = key_id.parse().map_err(|_| format!("invalid key id {:?}", key_id))?;
+    Ok((key_id, parse_hex_key(key)?))
+}
+
+fn parse_picture(arg: &str) -> Result<(PictureRole, PathBuf), String> {
+    let (role, path) = arg.split_once(':').ok_or("expected ROLE:PATH")?;
+    let role = PictureRole::from_name(role).ok_or_else(|| format!("unknown picture role {:?}", role))?;
+    Ok((role, PathBuf::from(path)))
+}
+
+fn parse_chunk_type(name: &str) -> Result<ChunkType, Failure> {
+    Ok(match name.to_ascii_lowercase().as_str() {
+        "audio" => ChunkType::Audio,
+        "metadata" | "meta" => ChunkType::Metadata,
+        "ai-model" => ChunkType::AiModel,
+        "dsp-chain" | "dsp" => ChunkType::DspChain,
+        "relativistic" => ChunkType::Relativistic,
+        "signature" => ChunkType::Signature,
+        "cues" => ChunkType::Cues,
+        "lyrics" => ChunkType::Lyrics,
+        "artwork" => ChunkType::Artwork,
+        "loudness" => ChunkType::Loudness,
+        "peaks" => ChunkType::Peaks,
+        "seek" => ChunkType::Seek,
+        _ => {
+            let fourcc = <[u8; 4]>::try_from(name.as_bytes())
+                .map_err(|_| Failure::usage(format!("unknown chunk type {:?}", name)))?;
+            ChunkType::from_fourcc(fourcc)
+        }
+    })
+}
+
+fn chunk_report(chunk: &ChunkData) -> Value {
+    let codec = match chunk.codec() {
+        Ok(ChunkCodec::None) => "none".to_string(),
+        Ok(ChunkCodec::Gzip { level }) => format!("gzip:{}", level),
+        Ok(ChunkCodec::Zstd { level }) => format!("zstd:{}", level),
+        Err(_) => "unknown".to_string(),
+    };
+    let cipher = match chunk.cipher() {
+        Ok(Some((cipher, key_id))) => json!({ "cipher": format!("{:?}", cipher), "key_id": key_id }),
+        Ok(None) => Value::Null,
+        Err(_) => json!("unknown"),
+    };
+
+    json!({
+        "index": chunk.index(),
+        "type": fourcc_name(chunk.chunk_type()),
+        "role": chunk.role(),
+        "offset": chunk.offset(),
+        "size": chunk.size(),
+        "flags": format!("{:#010x}", chunk.flags()),
+        "codec": codec,
+        "encryption": cipher,
+    })
+}
+
+fn fourcc_name(chunk_type: ChunkType) -> String {
+    String::from_utf8_lossy(&chunk_type.fourcc()).into_owned()
+}
+
+fn layout_name(layout: EuphLayout) -> &'static str {
+    match layout {
+        EuphLayout::Legacy => "legacy",
+        EuphLayout::Spec => "spec",
+    }
+}
+
+fn signature_name(status: SignatureStatus) -> &'static str {
+    match status {
+        SignatureStatus::Valid => "valid",
+        SignatureStatus::Tampered => "tampered",
+        SignatureStatus::Unsigned => "unsigned",
+        SignatureStatus::UnknownKey => "unknown_key",
+    }
+}
+
+fn print_report(report: &Value, as_json: bool) {
+    if as_json {
+        println!("{}", serde_json::to_string_pretty(report).unwrap_or_default());
+    } else {
+        print_text(report, 0);
+    }
+}
+
+/// Indented `key: value` lines, with arrays of flat objects on one line each.
+fn print_text(value: &Value, indent: usize) {
+    let pad = " ".repeat(indent);
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                match value {
+                    Value::Object(inner) if !inner.is_empty() => {
+                        println!("{pad}{key}:");
+                        print_text(value, indent + 2);
+                    }
+                    Value::Array(items) if !items.is_empty() => {
+                        println!("{pad}{key}:");
+                        for item in items {
+                            println!("{pad}  - {}", inline_text(item));
+                        }
+                    }
+                    _ => println!("{pad}{key}: {}", inline_text(value)),
+                }
+            }
+        }
+        _ => println!("{pad}{}", inline_text(value)),
+    }
+}
+
+fn inline_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => "-".to_string(),
+        Value::Object(map) => map
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, inline_text(value)))
+            .collect::<Vec<_>>()
+            .join(" "),
+        Value::Array(items) if items.is_empty() => "none".to_string(),
+        Value::Array(items) => items.iter().map(inline_text).collect::<Vec<_>>().join(", "),
+        other => other.to_string(),
+    }
+}
@@ -518,9 +518,9 @@ impl PhaseVocoder {
     pub fn process(&self, input: &[f32], output: &mut [f32]) {
         let len = input.len().min(output.len());
         
-        for i in 0..len {
+        for (i, out) in output.iter_mut().enumerate().take(len) {
             let pos = (i as f32 * self.pitch_shift) as usize;
-            output[i] = if pos < len { input[pos] } else { 0.0 };
+            *out = if pos < len { input[pos] } else { 0.0 };
         }
     }
 }
@@ -564,14 +564,13 @@ impl GranularSynth {
             return;
         }
 
-        let len = output.len();
         let buf_len = self.buffer.len();
 
-        for i in 0..len {
+        for (i, out) in output.iter_mut().enumerate() {
             let grain_pos = (i % self.grain_size) as f32 / self.grain_size as f32;
             let envelope = (grain_pos * PI).sin(); // Hanning window
             let buf_idx = i % buf_len;
-            output[i] = self.buffer[buf_idx] * envelope * self.grain_density;
+            *out = self.buffer[buf_idx] * envelope * self.grain_density;
         }
     }
 }
@@ -0,0 +1,452 @@
+use symphonia::core::meta::{StandardTagKey, StandardVisualKey, Visual};
+
+use crate::euph_audio;
+use crate::euph_decoder::EuphError;
+
+/// Body of a PICT chunk: cover art and other pictures of the release.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Artwork {
+    pub pictures: Vec<Picture>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Picture {
+    pub role: PictureRole,
+    /// e.g. `image/jpeg`.
+    pub mime_type: String,
+    /// Pixels, 0 if unknown.
+    pub width: u32,
+    pub height: u32,
+    pub description: String,
+    /// The image file as is.
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PictureRole {
+    Other,
+    FrontCover,
+    BackCover,
+    Artist,
+}
+
+impl PictureRole {
+    pub fn id(self) -> u8 {
+        match self {
+            PictureRole::Other => 0,
+            PictureRole::FrontCover => 1,
+            PictureRole::BackCover => 2,
+            PictureRole::Artist => 3,
+        }
+    }
+
+    /// Role for an identifier read from a file; ones added by later
+    /// versions read as `Other`.
+    pub fn from_id(id: u8) -> Self {
+        match id {
+            1 => PictureRole::FrontCover,
+            2 => PictureRole::BackCover,
+            3 => PictureRole::Artist,
+            _ => PictureRole::Other,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PictureRole::Other => "other",
+            PictureRole::FrontCover => "front",
+            PictureRole::BackCover => "back",
+            PictureRole::Artist => "artist",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [PictureRole::Other, PictureRole::FrontCover, PictureRole::BackCover, PictureRole::Artist]
+            .into_iter()
+            .find(|role| role.name().eq_ignore_ascii_case(name))
+    }
+
+    fn from_visual_key(key: Option<StandardVisualKey>) -> Self {
+        match key {
+            Some(StandardVisualKey::FrontCover) => PictureRole::FrontCover,
+            Some(StandardVisualKey::BackCover) => PictureRole::BackCover,
+            Some(
+                StandardVisualKey::LeadArtistPerformerSoloist
+                | StandardVisualKey::ArtistPerformer
+                | StandardVisualKey::BandOrchestra,
+            ) => PictureRole::Artist,
+            _ => PictureRole::Other,
+        }
+    }
+}
+
+impl Artwork {
+    /// First picture with `role`.
+    pub fn picture(&self, role: PictureRole) -> Option<&Picture> {
+        self.pictures.iter().find(|picture| picture.role == role)
+    }
+
+    /// Add a picture in place of any with the same role, except that there
+    /// may be any number of `Other` pictures.
+    pub fn set_picture(&mut self, picture: Picture) {
+        let existing = self.pictures.iter_mut()
+            .find(|p| p.role == picture.role && picture.role != PictureRole::Other);
+        match existing {
+            Some(existing) => *existing = picture,
+            None => self.pictures.push(picture),
+        }
+    }
+
+    /// Pictures embedded in the tags of a source audio file: FLAC picture
+    /// blocks, ID3v2 `APIC` frames, Vorbis `METADATA_BLOCK_PICTURE`
+    /// comments and MP4 `covr` atoms, which hold front covers. Linked
+    /// pictures and data that is not an image are skipped.
+    pub fn from_source_file<T>(payload: T) -> Self
+    where
+        T: AsRef<[u8]> + Send + Sync + 'static,
+    {
+        let mut artwork = Self::default();
+        for revision in euph_audio::source_metadata(payload) {
+            for visual in revision.visuals() {
+                if let Some(picture) = Picture::from_visual(visual) {
+                    artwork.set_picture(picture);
+                }
+            }
+        }
+        artwork
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(self.pictures.len() as u32).to_le_bytes());
+        for picture in &self.pictures {
+            let mime_type = truncated(&picture.mime_type, u8::MAX as usize);
+            let description = truncated(&picture.description, u16::MAX as usize);
+            bytes.push(picture.role.id());
+            bytes.push(mime_type.len() as u8);
+            bytes.extend_from_slice(mime_type.as_bytes());
+            bytes.extend_from_slice(&(description.len() as u16).to_le_bytes());
+            bytes.extend_from_slice(description.as_bytes());
+            bytes.extend_from_slice(&picture.width.to_le_bytes());
+            bytes.extend_from_slice(&picture.height.to_le_bytes());
+            bytes.extend_from_slice(&(picture.data.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&picture.data);
+        }
+        bytes
+    }
+
+    pub fn parse(body: &[u8]) -> Result<Self, EuphError> {
+        let mut reader = BodyReader(body);
+        let count = reader.u32()?;
+        // Each picture takes at least 16 bytes, so a count the body cannot
+        // hold is rejected before anything is allocated for it
+        if count as usize > body.len() / 16 {
+            return Err(EuphError::InvalidArtwork);
+        }
+
+        let mut pictures = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let role = PictureRole::from_id(reader.u8()?);
+            let mime_length = reader.u8()? as usize;
+            let mime_type = reader.string(mime_length)?;
+            let description_length = reader.u16()? as usize;
+            let description = reader.string(description_length)?;
+            let width = reader.u32()?;
+            let height = reader.u32()?;
+            let data_length = reader.u32()? as usize;
+            let data = reader.bytes(data_length)?.to_vec();
+            pictures.push(Picture { role, mime_type, width, height, description, data });
+        }
+        if !reader.0.is_empty() {
+            return Err(EuphError::InvalidArtwork);
+        }
+        Ok(Self { pictures })
+    }
+}
+
+impl Picture {
+    /// Picture from a PNG, JPEG, GIF, WebP or BMP file, with its MIME type
+    /// and size read from the image header. `None` for other files.
+    pub fn from_image(role: PictureRole, data: Vec<u8>) -> Option<Self> {
+        let (mime_type, width, height) = image_info(&data)?;
+        Some(Self { role, mime_type: mime_type.to_string(), width, height, description: String::new(), data })
+    }
+
+    /// Picture from a tag, trusting the image header over what the tag says.
+    fn from_visual(visual: &Visual) -> Option<Self> {
+        let role = PictureRole::from_visual_key(visual.usage);
+        let description = visual.tags.iter()
+            .find(|tag| tag.std_key == Some(StandardTagKey::Description))
+            .map(|tag| tag.value.to_string())
+            .unwrap_or_default();
+        let picture = match Self::from_image(role, visual.data.to_vec()) {
+            Some(picture) => picture,
+            // ID3v2 marks a linked picture with the MIME type `-->`
+            None if visual.media_type.starts_with("image/") => {
+                let size = visual.dimensions.unwrap_or_default();
+                Self {
+                    role,
+                    mime_type: visual.media_type.clone(),
+                    width: size.width,
+                    height: size.height,
+                    description: String::new(),
+                    data: visual.data.to_vec(),
+                }
+            }
+            None => return None,
+        };
+        Some(Self { description, ..picture })
+    }
+}
+
+/// Longest prefix of `text` of at most `max` bytes that ends on a character.
+fn truncated(text: &str, max: usize) -> &str {
+    let mut end = text.len().min(max);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// MIME type, width and height of an image file.
+fn image_info(data: &[u8]) -> Option<(&'static str, u32, u32)> {
+    let u16_be = |at: usize| Some(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?) as u32);
+    let u16_le = |at: usize| Some(u16::from_le_bytes(data.get(at..at + 2)?.try_into().ok()?) as u32);
+    let u32_be = |at: usize| Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?));
+    let u32_le = |at: usize| Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?));
+
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        // The IHDR chunk comes first
+        return Some(("image/png", u32_be(16)?, u32_be(20)?));
+    }
+    if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        return Some(("image/gif", u16_le(6)?, u16_le(8)?));
+    }
+    if data.starts_with(b"BM") {
+        // Bottom-up bitmaps have a negative height
+        let height = (u32_le(22)? as i32).unsigned_abs();
+        return Some(("image/bmp", u32_le(18)?, height));
+    }
+    if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        let u24_le = |at: usize| Some(data.get(at..at + 3)?.iter().rev().fold(0, |n, &b| n << 8 | b as u32));
+        return match data.get(12..16)? {
+            b"VP8 " => Some(("image/webp", u16_le(26)? & 0x3FFF, u16_le(28)? & 0x3FFF)),
+            b"VP8L" => {
+                let bits = u32_le(21)?;
+                Some(("image/webp", (bits & 0x3FFF) + 1, (bits >> 14 & 0x3FFF) + 1))
+            }
+            b"VP8X" => Some(("image/webp", u24_le(24)? + 1, u24_le(27)? + 1)),
+            _ => None,
+        };
+    }
+    if data.starts_with(&[0xFF, 0xD8]) {
+        // Walk the segments to the start of frame, which has the size
+        let mut pos = 2;
+        loop {
+            while data.get(pos) == Some(&0xFF) && data.get(pos + 1) == Some(&0xFF) {
+                pos += 1;
+            }
+            if *data.get(pos)? != 0xFF {
+                return None;
+            }
+            let marker = *data.get(pos + 1)?;
+            match marker {
+                0xD0..=0xD9 | 0x01 => pos += 2,
+                0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                    return Some(("image/jpeg", u16_be(pos + 7)?, u16_be(pos + 5)?));
+                }
+                _ => pos += 2 + u16_be(pos + 2)? as usize,
+            }
+        }
+    }
+    None
+}
+
+/// Reads the fields of a PICT chunk body, failing on any that is cut short.
+struct BodyReader<'a>(&'a [u8]);
+
+impl<'a> BodyReader<'a> {
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], EuphError> {
+        if length > self.0.len() {
+            return Err(EuphError::InvalidArtwork);
+        }
+        let (bytes, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, EuphError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, EuphError> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, EuphError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self, length: usize) -> Result<String, EuphError> {
+        String::from_utf8(self.bytes(length)?.to_vec()).map_err(|_| EuphError::InvalidArtwork)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn picture(role: PictureRole, description: &str) -> Picture {
+        Picture {
+            role,
+            mime_type: "image/png".into(),
+            width: 600,
+            height: 400,
+            description: description.into(),
+            data: vec![role.id(); 10],
+        }
+    }
+
+    fn riff_webp(chunk: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut webp = b"RIFF\0\0\0\0WEBP".to_vec();
+        webp.extend_from_slice(chunk);
+        webp.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        webp.extend_from_slice(body);
+        webp
+    }
+
+    #[test]
+    fn body_round_trips() {
+        let artwork = Artwork {
+            pictures: vec![picture(PictureRole::FrontCover, "Cover"), picture(PictureRole::Other, "Booklet, page 2 ✓")],
+        };
+        assert_eq!(Artwork::parse(&artwork.to_bytes()).unwrap(), artwork);
+        assert_eq!(Artwork::parse(&Artwork::default().to_bytes()).unwrap(), Artwork::default());
+    }
+
+    #[test]
+    fn long_texts_are_cut_on_a_character() {
+        let mut long = picture(PictureRole::Artist, &"é".repeat(40_000));
+        long.mime_type = "x".repeat(300);
+        let artwork = Artwork::parse(&Artwork { pictures: vec![long] }.to_bytes()).unwrap();
+        assert_eq!(artwork.pictures[0].mime_type.len(), 255);
+        assert_eq!(artwork.pictures[0].description.len(), 65_534);
+    }
+
+    #[test]
+    fn malformed_bodies_are_rejected() {
+        let body = Artwork { pictures: vec![picture(PictureRole::FrontCover, "Cover")] }.to_bytes();
+        for length in [0, 3, 4, 20, body.len() - 1] {
+            assert!(matches!(Artwork::parse(&body[..length]), Err(EuphError::InvalidArtwork)), "{length} bytes");
+        }
+
+        // More pictures than the body has room for
+        let mut count = body.clone();
+        count[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(Artwork::parse(&count), Err(EuphError::InvalidArtwork)));
+        count[..4].copy_from_slice(&2u32.to_le_bytes());
+        assert!(matches!(Artwork::parse(&count), Err(EuphError::InvalidArtwork)));
+
+        let mut trailing = body.clone();
+        trailing.push(0);
+        assert!(matches!(Artwork::parse(&trailing), Err(EuphError::InvalidArtwork)));
+
+        let mut not_utf8 = body;
+        not_utf8[6] = 0xFF;
+        assert!(matches!(Artwork::parse(&not_utf8), Err(EuphError::InvalidArtwork)));
+    }
+
+    #[test]
+    fn unknown_roles_read_as_other() {
+        let mut body = Artwork { pictures: vec![picture(PictureRole::BackCover, "")] }.to_bytes();
+        body[4] = 9;
+        assert_eq!(Artwork::parse(&body).unwrap().pictures[0].role, PictureRole::Other);
+        assert_eq!(PictureRole::from_name("FRONT"), Some(PictureRole::FrontCover));
+        assert_eq!(PictureRole::from_name("cover"), None);
+    }
+
+    #[test]
+    fn set_picture_replaces_roles_but_keeps_other_pictures() {
+        let mut artwork = Artwork::default();
+        artwork.set_picture(picture(PictureRole::FrontCover, "first"));
+        artwork.set_picture(picture(PictureRole::Other, "one"));
+        artwork.set_picture(picture(PictureRole::FrontCover, "second"));
+        artwork.set_picture(picture(PictureRole::Other, "two"));
+        let descriptions: Vec<_> = artwork.pictures.iter().map(|p| p.description.as_str()).collect();
+        assert_eq!(descriptions, ["second", "one", "two"]);
+        assert_eq!(artwork.picture(PictureRole::FrontCover).unwrap().description, "second");
+        assert!(artwork.picture(PictureRole::Artist).is_none());
+    }
+
+    #[test]
+    fn png_gif_and_bmp_sizes() {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend_from_slice(&[0, 0, 2, 0x58, 0, 0, 1, 0x90, 8, 6, 0, 0, 0]);
+        assert_eq!(image_info(&png), Some(("image/png", 600, 400)));
+
+        let gif = b"GIF89a\x58\x02\x90\x01\x80\0\0";
+        assert_eq!(image_info(gif), Some(("image/gif", 600, 400)));
+
+        let mut bmp = b"BM".to_vec();
+        bmp.extend_from_slice(&[0; 16]);
+        bmp.extend_from_slice(&600i32.to_le_bytes());
+        bmp.extend_from_slice(&400i32.to_le_bytes());
+        assert_eq!(image_info(&bmp), Some(("image/bmp", 600, 400)));
+        // Top-down bitmaps have a negative height
+        bmp[22..26].copy_from_slice(&(-400i32).to_le_bytes());
+        assert_eq!(image_info(&bmp), Some(("image/bmp", 600, 400)));
+    }
+
+    #[test]
+    fn webp_sizes() {
+        // Lossy: frame tag, start code, then 14-bit sizes with scaling bits
+        let mut vp8 = vec![0x30, 0x01, 0x00, 0x9D, 0x01, 0x2A];
+        vp8.extend_from_slice(&(600u16 | 0x4000).to_le_bytes());
+        vp8.extend_from_slice(&400u16.to_le_bytes());
+        assert_eq!(image_info(&riff_webp(b"VP8 ", &vp8)), Some(("image/webp", 600, 400)));
+
+        // Lossless: a signature byte, then both sizes less one in 14 bits each
+        let bits: u32 = 599 | 399 << 14;
+        let mut vp8l = vec![0x2F];
+        vp8l.extend_from_slice(&bits.to_le_bytes());
+        assert_eq!(image_info(&riff_webp(b"VP8L", &vp8l)), Some(("image/webp", 600, 400)));
+
+        // Extended: flags, then both sizes less one in 24 bits each
+        let mut vp8x = vec![0x10, 0, 0, 0];
+        vp8x.extend_from_slice(&599u32.to_le_bytes()[..3]);
+        vp8x.extend_from_slice(&70_000u32.to_le_bytes()[..3]);
+        assert_eq!(image_info(&riff_webp(b"VP8X", &vp8x)), Some(("image/webp", 600, 70_001)));
+
+        assert_eq!(image_info(&riff_webp(b"ALPH", &[0; 10])), None);
+    }
+
+    #[test]
+    fn jpeg_size_from_the_start_of_frame() {
+        let mut jpeg = vec![0xFF, 0xD8];
+        // APP0, then a Huffman table whose marker is in the SOF range
+        jpeg.extend_from_slice(&[0xFF, 0xE0, 0, 16]);
+        jpeg.extend_from_slice(b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0");
+        jpeg.extend_from_slice(&[0xFF, 0xC4, 0, 4, 0, 0]);
+        // Fill bytes may come before any marker
+        jpeg.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0xC2, 0, 11, 8]);
+        jpeg.extend_from_slice(&400u16.to_be_bytes());
+        jpeg.extend_from_slice(&600u16.to_be_bytes());
+        jpeg.extend_from_slice(&[1, 1, 0x11, 0]);
+        assert_eq!(image_info(&jpeg), Some(("image/jpeg", 600, 400)));
+
+        // Cut short before the frame header, or with a segment that is not one
+        assert_eq!(image_info(&jpeg[..24]), None);
+        let mut broken = jpeg.clone();
+        broken[20] = 0x00;
+        assert_eq!(image_info(&broken), None);
+    }
+
+    #[test]
+    fn other_files_are_not_pictures() {
+        assert_eq!(image_info(b""), None);
+        assert_eq!(image_info(b"%PDF-1.7"), None);
+        assert!(Picture::from_image(PictureRole::FrontCover, b"GIF89a".to_vec()).is_none());
+        let picture = Picture::from_image(PictureRole::FrontCover, b"GIF87a\x02\0\x03\0".to_vec()).unwrap();
+        assert_eq!((picture.mime_type.as_str(), picture.width, picture.height), ("image/gif", 2, 3));
+    }
+}
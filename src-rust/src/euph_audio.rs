@@ -0,0 +1,996 @@
+use std::borrow::Cow;
+use std::io::Cursor;
+use std::sync::Arc;
+use serde::Serialize;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{CodecParameters, DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, FormatReader};
+use symphonia::core::io::{BufReader, MediaSourceStream};
+use symphonia::core::meta::{MetadataBuilder, MetadataRevision, StandardTagKey, StandardVisualKey, Tag, Value, Visual};
+use symphonia::default::formats::{FlacReader, MpaReader, OggReader, WavReader};
+
+use crate::euph_decoder::{ChunkType, EuphError};
+
+// Descriptor at the start of an AUDIO chunk body, ahead of the payload. The
+// size byte lets readers skip fields added by later versions.
+const AUDIO_FORMAT_MAGIC: &[u8; 4] = b"AFMT";
+const AUDIO_FORMAT_VERSION: u8 = 1;
+const AUDIO_FORMAT_SIZE: usize = 26;
+
+/// Largest descriptor a later version could write.
+pub(crate) const MAX_AUDIO_FORMAT_SIZE: usize = u8::MAX as usize;
+
+const CODEC_ID_UNKNOWN: u8 = 0;
+const CODEC_ID_WAV: u8 = 1;
+const CODEC_ID_FLAC: u8 = 2;
+const CODEC_ID_MP3: u8 = 3;
+const CODEC_ID_VORBIS: u8 = 4;
+const CODEC_ID_OPUS: u8 = 5;
+
+/// Audio encodings the spec allows in an AUDIO chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioCodec {
+    Wav,
+    Flac,
+    Mp3,
+    Vorbis,
+    /// Recognised but not decodable: there is no pure-Rust Opus decoder.
+    Opus,
+}
+
+impl AudioCodec {
+    /// Identify a payload by its leading bytes. FLAC is `Flac` both as a
+    /// native stream and in Ogg.
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WAVE" {
+            Some(AudioCodec::Wav)
+        } else if data.starts_with(b"fLaC") {
+            Some(AudioCodec::Flac)
+        } else if data.starts_with(b"OggS") {
+            // The first page holds the identification header of the codec
+            let first_page = &data[..data.len().min(64)];
+            if first_page.windows(8).any(|w| w == b"OpusHead") {
+                Some(AudioCodec::Opus)
+            } else if first_page.windows(7).any(|w| w == b"\x01vorbis") {
+                Some(AudioCodec::Vorbis)
+            } else if first_page.windows(5).any(|w| w == b"\x7fFLAC") {
+                Some(AudioCodec::Flac)
+            } else {
+                None
+            }
+        } else if data.starts_with(b"ID3") || (data.len() >= 2 && data[0] == 0xFF && data[1] & 0xE0 == 0xE0) {
+            Some(AudioCodec::Mp3)
+        } else {
+            None
+        }
+    }
+
+    /// Codec identifier as stored in the AUDIO format descriptor.
+    pub fn id(self) -> u8 {
+        match self {
+            AudioCodec::Wav => CODEC_ID_WAV,
+            AudioCodec::Flac => CODEC_ID_FLAC,
+            AudioCodec::Mp3 => CODEC_ID_MP3,
+            AudioCodec::Vorbis => CODEC_ID_VORBIS,
+            AudioCodec::Opus => CODEC_ID_OPUS,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            CODEC_ID_WAV => Some(AudioCodec::Wav),
+            CODEC_ID_FLAC => Some(AudioCodec::Flac),
+            CODEC_ID_MP3 => Some(AudioCodec::Mp3),
+            CODEC_ID_VORBIS => Some(AudioCodec::Vorbis),
+            CODEC_ID_OPUS => Some(AudioCodec::Opus),
+            _ => None,
+        }
+    }
+}
+
+/// Stream parameters of an AUDIO chunk, recorded by the encoder so players
+/// need not sniff the payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct AudioFormat {
+    /// `None` for a codec this build does not know.
+    pub codec: Option<AudioCodec>,
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Speaker positions as a `WAVE_FORMAT_EXTENSIBLE` channel mask, 0 if unknown.
+    pub channel_layout: u32,
+    /// Bits per sample of PCM and lossless payloads.
+    pub bits_per_sample: Option<u8>,
+    /// Samples per channel, when the payload records it.
+    pub frames: Option<u64>,
+}
+
+impl AudioFormat {
+    /// Read the stream parameters from a payload's headers, without decoding it.
+    pub fn probe<T>(payload: T) -> Option<Self>
+    where
+        T: AsRef<[u8]> + Send + Sync + 'static,
+    {
+        let (codec, format) = open_format(payload).ok()?;
+        let params = &format.tracks().iter().find(|t| t.codec_params.codec != CODEC_TYPE_NULL)?.codec_params;
+        Some(Self::from_params(codec, params))
+    }
+
+    fn from_params(codec: Option<AudioCodec>, params: &CodecParameters) -> Self {
+        Self {
+            codec,
+            sample_rate: params.sample_rate.unwrap_or(0),
+            channels: params.channels.map_or(0, |c| c.count() as u16),
+            channel_layout: params.channels.map_or(0, |c| c.bits()),
+            bits_per_sample: params.bits_per_sample.map(|bits| bits.min(u8::MAX as u32) as u8),
+            frames: params.n_frames,
+        }
+    }
+
+    pub fn duration_secs(&self) -> Option<f64> {
+        match (self.frames, self.sample_rate) {
+            (Some(frames), rate) if rate > 0 => Some(frames as f64 / rate as f64),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn to_bytes(self) -> [u8; AUDIO_FORMAT_SIZE] {
+        let mut bytes = [0u8; AUDIO_FORMAT_SIZE];
+        bytes[..4].copy_from_slice(AUDIO_FORMAT_MAGIC);
+        bytes[4] = AUDIO_FORMAT_VERSION;
+        bytes[5] = AUDIO_FORMAT_SIZE as u8;
+        bytes[6] = self.codec.map_or(CODEC_ID_UNKNOWN, AudioCodec::id);
+        bytes[7] = self.bits_per_sample.unwrap_or(0);
+        bytes[8..10].copy_from_slice(&self.channels.to_le_bytes());
+        bytes[10..14].copy_from_slice(&self.sample_rate.to_le_bytes());
+        bytes[14..18].copy_from_slice(&self.channel_layout.to_le_bytes());
+        bytes[18..26].copy_from_slice(&self.frames.unwrap_or(0).to_le_bytes());
+        bytes
+    }
+
+    /// Descriptor at the start of a decoded AUDIO chunk body and its length,
+    /// or `None` for chunks written without one.
+    pub fn parse(body: &[u8]) -> Result<Option<(Self, usize)>, EuphError> {
+        if !body.starts_with(AUDIO_FORMAT_MAGIC) {
+            return Ok(None);
+        }
+        let size = *body.get(5).ok_or(EuphError::InvalidAudioFormat)? as usize;
+        let bytes = body.get(..size).filter(|_| size >= AUDIO_FORMAT_SIZE).ok_or(EuphError::InvalidAudioFormat)?;
+
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let frames = u64::from_le_bytes(bytes[18..26].try_into().unwrap());
+        let format = Self {
+            codec: AudioCodec::from_id(bytes[6]),
+            bits_per_sample: Some(bytes[7]).filter(|&bits| bits > 0),
+            channels: u16::from_le_bytes([bytes[8], bytes[9]]),
+            sample_rate: u32_at(10),
+            channel_layout: u32_at(14),
+            frames: Some(frames).filter(|&frames| frames > 0),
+        };
+        Ok(Some((format, size)))
+    }
+}
+
+/// Prefix an AUDIO payload with its format descriptor, unless it already has
+/// one or is in no format this build recognises.
+pub(crate) fn with_audio_format(payload: Vec<u8>) -> Vec<u8> {
+    if payload.starts_with(AUDIO_FORMAT_MAGIC) {
+        return payload;
+    }
+    let payload = SharedPayload::from(payload);
+    match AudioFormat::probe(payload.clone()) {
+        Some(format) => {
+            let mut body = Vec::with_capacity(AUDIO_FORMAT_SIZE + payload.as_ref().len());
+            body.extend_from_slice(&format.to_bytes());
+            body.extend_from_slice(payload.as_ref());
+            body
+        }
+        None => payload.into_vec(),
+    }
+}
+
+/// Decoded AUDIO chunk body without its format descriptor.
+pub(crate) fn strip_audio_format(body: Cow<'_, [u8]>) -> Result<Cow<'_, [u8]>, EuphError> {
+    let Some((_, size)) = AudioFormat::parse(&body)? else {
+        return Ok(body);
+    };
+    Ok(match body {
+        Cow::Borrowed(body) => Cow::Borrowed(&body[size..]),
+        Cow::Owned(mut body) => {
+            body.drain(..size);
+            Cow::Owned(body)
+        }
+    })
+}
+
+/// Decoded AUDIO chunk: interleaved `f32` samples in [-1.0, 1.0].
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedAudio {
+    pub sample_rate: u32,
+    pub channels: u16,
+    samples: Vec<f32>,
+}
+
+impl DecodedAudio {
+    pub fn new(sample_rate: u32, channels: u16, samples: Vec<f32>) -> Self {
+        Self { sample_rate, channels, samples }
+    }
+
+    /// Number of samples per channel.
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels.max(1) as usize
+    }
+
+    pub fn duration_secs(&self) -> f64 {
+        self.frames() as f64 / self.sample_rate.max(1) as f64
+    }
+
+    pub fn interleaved(&self) -> &[f32] {
+        &self.samples
+    }
+
+    pub fn into_interleaved(self) -> Vec<f32> {
+        self.samples
+    }
+
+    /// Samples of one channel.
+    pub fn channel(&self, channel: u16) -> impl Iterator<Item = f32> + '_ {
+        let step = self.channels.max(1) as usize;
+        self.samples.iter().skip(channel as usize).step_by(step).copied()
+    }
+
+    /// One buffer per channel, e.g. for `WasmDspProcessor::process_block_stereo`.
+    pub fn planar(&self) -> Vec<Vec<f32>> {
+        (0..self.channels).map(|c| self.channel(c).collect()).collect()
+    }
+
+    /// Keep `frames` frames starting `skip` frames in.
+    pub(crate) fn slice_frames(mut self, skip: u64, frames: u64) -> Self {
+        let channels = self.channels.max(1) as u64;
+        let start = skip.saturating_mul(channels).min(self.samples.len() as u64) as usize;
+        let end = skip.saturating_add(frames).saturating_mul(channels).min(self.samples.len() as u64) as usize;
+        self.samples.truncate(end);
+        self.samples.drain(..start);
+        self
+    }
+}
+
+/// Payload shared with the readers that open it, which need to own their
+/// data, so it can be probed, decoded and kept without a copy. Readers see
+/// the bytes from `start` on.
+#[derive(Clone)]
+pub(crate) struct SharedPayload {
+    data: Arc<Vec<u8>>,
+    start: usize,
+}
+
+impl SharedPayload {
+    /// The same payload without its first `count` bytes.
+    pub fn skip(&self, count: usize) -> Self {
+        Self { data: Arc::clone(&self.data), start: self.start.saturating_add(count).min(self.data.len()) }
+    }
+
+    /// The bytes, copied only if a reader still shares them.
+    pub fn into_vec(self) -> Vec<u8> {
+        let mut data = Arc::unwrap_or_clone(self.data);
+        data.drain(..self.start);
+        data
+    }
+}
+
+impl From<Vec<u8>> for SharedPayload {
+    fn from(data: Vec<u8>) -> Self {
+        Self { data: Arc::new(data), start: 0 }
+    }
+}
+
+impl AsRef<[u8]> for SharedPayload {
+    fn as_ref(&self) -> &[u8] {
+        &self.data[self.start..]
+    }
+}
+
+/// Decode a WAV, FLAC, MP3 or Ogg Vorbis payload, without a format
+/// descriptor. Fails with `UnsupportedAudioFormat(Some(Opus))` for Opus,
+/// which this crate does not decode, and with `ChunkTooLarge` once the
+/// decoded samples would exceed `max_size` bytes.
+pub fn decode_audio<T>(data: T, max_size: u64) -> Result<DecodedAudio, EuphError>
+where
+    T: AsRef<[u8]> + Send + Sync + 'static,
+{
+    decode_with_format(data, max_size).map(|(audio, _)| audio)
+}
+
+/// `decode_audio`, also giving the stream parameters `AudioFormat::probe`
+/// would, from the same reader.
+pub(crate) fn decode_with_format<T>(data: T, max_size: u64) -> Result<(DecodedAudio, AudioFormat), EuphError>
+where
+    T: AsRef<[u8]> + Send + Sync + 'static,
+{
+    // There is no pure-Rust Opus decoder; Opus payloads are probed, stored
+    // and exported as they are, but never decoded
+    if AudioCodec::detect(data.as_ref()) == Some(AudioCodec::Opus) {
+        return Err(EuphError::UnsupportedAudioFormat(Some(AudioCodec::Opus)));
+    }
+    let (codec, mut format) = open_format(data)?;
+    let unsupported = |e: SymphoniaError| match e {
+        SymphoniaError::Unsupported(_) => EuphError::UnsupportedAudioFormat(codec),
+        e => EuphError::AudioDecode(e),
+    };
+
+    let track = format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or(EuphError::UnsupportedAudioFormat(codec))?;
+    let audio_format = AudioFormat::from_params(codec, &track.codec_params);
+    let track_id = track.id;
+    let mut sample_rate = track.codec_params.sample_rate.unwrap_or(0);
+    let mut channels = track.codec_params.channels.map_or(0, |c| c.count() as u16);
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .map_err(unsupported)?;
+
+    let mut samples = Vec::new();
+    let mut buffer: Option<SampleBuffer<f32>> = None;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(EuphError::AudioDecode(e)),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // A damaged frame is dropped, as players do
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(e) => return Err(EuphError::AudioDecode(e)),
+        };
+        let spec = *decoded.spec();
+        sample_rate = spec.rate;
+        channels = spec.channels.count() as u16;
+
+        if buffer.as_ref().is_none_or(|b| b.capacity() < decoded.capacity()) {
+            buffer = Some(SampleBuffer::new(decoded.capacity() as u64, spec));
+        }
+        let buffer = buffer.as_mut().unwrap();
+        buffer.copy_interleaved_ref(decoded);
+
+        let size = ((samples.len() + buffer.len()) * std::mem::size_of::<f32>()) as u64;
+        if size > max_size {
+            return Err(EuphError::ChunkTooLarge { chunk: ChunkType::Audio, size, limit: max_size });
+        }
+        samples.extend_from_slice(buffer.samples());
+    }
+
+    Ok((DecodedAudio::new(sample_rate, channels, samples), audio_format))
+}
+
+/// Open the container reader for the encoding a payload starts with. The
+/// reader is chosen directly because probing scans the whole payload for a
+/// marker of any format it knows.
+pub(crate) fn open_format<T>(data: T) -> Result<(Option<AudioCodec>, Box<dyn FormatReader>), EuphError>
+where
+    T: AsRef<[u8]> + Send + Sync + 'static,
+{
+    let codec = AudioCodec::detect(data.as_ref());
+    if codec == Some(AudioCodec::Wav) {
+        check_wav_format(data.as_ref()).map_err(EuphError::AudioDecode)?;
+    }
+    let unsupported = |e: SymphoniaError| match e {
+        SymphoniaError::Unsupported(_) => EuphError::UnsupportedAudioFormat(codec),
+        e => EuphError::AudioDecode(e),
+    };
+
+    let ogg = data.as_ref().starts_with(b"OggS");
+    let mut cursor = Cursor::new(data);
+    if codec == Some(AudioCodec::Mp3) {
+        cursor.set_position(id3v2_size(cursor.get_ref().as_ref()));
+    }
+    let source = MediaSourceStream::new(Box::new(cursor), Default::default());
+    let options = FormatOptions { enable_gapless: true, ..Default::default() };
+    let format: Box<dyn FormatReader> = match codec {
+        Some(AudioCodec::Wav) => Box::new(WavReader::try_new(source, &options).map_err(unsupported)?),
+        Some(AudioCodec::Flac) if !ogg => Box::new(FlacReader::try_new(source, &options).map_err(unsupported)?),
+        Some(AudioCodec::Mp3) => Box::new(MpaReader::try_new(source, &options).map_err(unsupported)?),
+        Some(AudioCodec::Flac | AudioCodec::Vorbis | AudioCodec::Opus) => {
+            Box::new(OggReader::try_new(source, &options).map_err(unsupported)?)
+        }
+        None => return Err(EuphError::UnsupportedAudioFormat(None)),
+    };
+    Ok((codec, format))
+}
+
+/// Tags and pictures a source file carries: its ID3v2 tag (at the start of
+/// an MP3, or in the `id3 ` chunk of a WAV), then whatever the container
+/// reader finds, such as FLAC metadata blocks, Vorbis comments, a WAV
+/// `LIST`/`INFO` chunk or the `ilst` atom of an MP4 file, then the ID3v1 tag
+/// at the end of an MP3. Tags that cannot be read are left out.
+pub(crate) fn source_metadata<T>(data: T) -> Vec<MetadataRevision>
+where
+    T: AsRef<[u8]> + Send + Sync + 'static,
+{
+    let payload = data.as_ref();
+    let mut revisions = Vec::new();
+    let id3v2 = match AudioCodec::detect(payload) {
+        Some(AudioCodec::Mp3) => payload.get(..id3v2_size(payload) as usize).filter(|tag| !tag.is_empty()),
+        Some(AudioCodec::Wav) => riff_chunks(payload)
+            .find(|(id, _)| id.eq_ignore_ascii_case(b"id3 "))
+            .and_then(|(_, (start, size))| payload.get(start..start.checked_add(size)?)),
+        _ => None,
+    };
+    if let Some(tag) = id3v2 {
+        let mut builder = MetadataBuilder::new();
+        if symphonia_metadata::id3v2::read_id3v2(&mut BufReader::new(tag), &mut builder).is_ok() {
+            revisions.push(builder.metadata());
+        }
+    }
+
+    let id3v1 = payload.len().checked_sub(128).map(|start| &payload[start..]);
+    let id3v1 = id3v1
+        .filter(|tag| tag.starts_with(b"TAG") && AudioCodec::detect(payload) == Some(AudioCodec::Mp3))
+        .and_then(|tag| {
+            let mut builder = MetadataBuilder::new();
+            symphonia_metadata::id3v1::read_id3v1(&mut BufReader::new(tag), &mut builder).ok()?;
+            Some(builder.metadata())
+        });
+
+    if AudioCodec::detect(payload) == Some(AudioCodec::Wav) {
+        // The WAV reader stops at the `data` chunk, missing the INFO lists
+        // that often follow it
+        let mut builder = MetadataBuilder::new();
+        let lists = riff_chunks(payload)
+            .filter(|(id, _)| id == b"LIST")
+            .filter_map(|(_, (start, size))| payload.get(start..start.checked_add(size)?))
+            .filter(|list| list.starts_with(b"INFO"));
+        for list in lists {
+            for (id, (start, size)) in subchunks(list, 4) {
+                if let Some(value) = list.get(start..start.saturating_add(size)) {
+                    builder.add_tag(symphonia_metadata::riff::parse(id, value));
+                }
+            }
+        }
+        revisions.push(builder.metadata());
+    } else if payload.get(4..8) == Some(b"ftyp") {
+        // MP4 is not an AUDIO payload, so there is no reader for it
+        revisions.extend(mp4_metadata(payload));
+    } else if let Ok((_, mut format)) = open_format(data) {
+        let mut log = format.metadata();
+        while let Some(revision) = log.pop() {
+            revisions.push(revision);
+        }
+        revisions.extend(log.current().cloned());
+    }
+
+    revisions.extend(id3v1);
+    revisions
+}
+
+/// Tags and cover art of an MP4/M4A file, from the iTunes `ilst` atom in
+/// `moov/udta/meta`. Keys are the atom types, or `----:mean:name` for
+/// freeform atoms such as `----:com.apple.iTunes:MusicBrainz Track Id`.
+fn mp4_metadata(payload: &[u8]) -> Option<MetadataRevision> {
+    let moov = mp4_child(payload, b"moov")?;
+    let meta = mp4_child(mp4_child(moov, b"udta")?, b"meta")?;
+    // `meta` has a version and flags ahead of its atoms, except in some
+    // QuickTime files
+    let meta = match meta.get(4..8) {
+        Some(b"hdlr") => meta,
+        _ => meta.get(4..)?,
+    };
+    let ilst = mp4_child(meta, b"ilst")?;
+
+    let mut builder = MetadataBuilder::new();
+    for (id, item) in mp4_atoms(ilst) {
+        // Atom types are Mac OS Roman, whose © is 0xA9 as in Latin-1
+        let mut key: String = id.iter().map(|&b| b as char).collect();
+        if &id == b"----" {
+            let text = |id| mp4_child(item, id).and_then(|atom| atom.get(4..)).map(String::from_utf8_lossy);
+            let (Some(mean), Some(name)) = (text(b"mean"), text(b"name")) else {
+                continue;
+            };
+            key = format!("----:{mean}:{name}");
+        }
+        let std_key = mp4_tag_key(&key);
+        for (_, data) in mp4_atoms(item).filter(|(id, _)| id == b"data") {
+            // A version byte and the well-known type of the value, then a
+            // locale
+            let Some(value) = data.get(8..) else {
+                continue;
+            };
+            let kind = u32::from_be_bytes(data[..4].try_into().unwrap()) & 0xFF_FFFF;
+            let value = match (&id, kind) {
+                (b"covr", _) => {
+                    builder.add_visual(Visual {
+                        media_type: match kind {
+                            13 => "image/jpeg",
+                            14 => "image/png",
+                            27 => "image/bmp",
+                            _ => "",
+                        }
+                        .to_string(),
+                        dimensions: None,
+                        bits_per_pixel: None,
+                        color_mode: None,
+                        usage: Some(StandardVisualKey::FrontCover),
+                        tags: Vec::new(),
+                        data: value.into(),
+                    });
+                    continue;
+                }
+                // Number and total after two bytes of padding
+                (b"trkn" | b"disk", _) if value.len() >= 6 => {
+                    let number = u16::from_be_bytes([value[2], value[3]]);
+                    let total = u16::from_be_bytes([value[4], value[5]]);
+                    Value::from(format!("{number}/{total}"))
+                }
+                // ID3v1 genre number plus one
+                (b"gnre", _) if value.len() == 2 => {
+                    let genre = u16::from_be_bytes([value[0], value[1]]).checked_sub(1);
+                    match genre.and_then(|g| u8::try_from(g).ok()).and_then(symphonia_metadata::id3v1::util::genre_name) {
+                        Some(name) => Value::from(*name),
+                        None => continue,
+                    }
+                }
+                (_, 1) => Value::from(String::from_utf8_lossy(value).as_ref()),
+                (_, 2) => {
+                    let units: Vec<u16> = value.chunks_exact(2).map(|u| u16::from_be_bytes([u[0], u[1]])).collect();
+                    Value::from(String::from_utf16_lossy(&units))
+                }
+                (_, 21) if matches!(value.len(), 1 | 2 | 4 | 8) => {
+                    let mut bytes = [if value[0] & 0x80 != 0 { 0xFF } else { 0 }; 8];
+                    bytes[8 - value.len()..].copy_from_slice(value);
+                    Value::SignedInt(i64::from_be_bytes(bytes))
+                }
+                (_, 22) if matches!(value.len(), 1 | 2 | 4 | 8) => {
+                    let mut bytes = [0; 8];
+                    bytes[8 - value.len()..].copy_from_slice(value);
+                    Value::UnsignedInt(u64::from_be_bytes(bytes))
+                }
+                _ => Value::Binary(value.into()),
+            };
+            builder.add_tag(Tag::new(std_key, &key, value));
+        }
+    }
+    Some(builder.metadata())
+}
+
+fn mp4_tag_key(key: &str) -> Option<StandardTagKey> {
+    use StandardTagKey::*;
+
+    Some(match key {
+        "\u{a9}nam" => TrackTitle,
+        "\u{a9}ART" => Artist,
+        "aART" => AlbumArtist,
+        "\u{a9}alb" => Album,
+        "\u{a9}wrt" => Composer,
+        "\u{a9}day" => Date,
+        "\u{a9}gen" | "gnre" => Genre,
+        "\u{a9}grp" => ContentGroup,
+        "\u{a9}cmt" => Comment,
+        "\u{a9}lyr" => Lyrics,
+        "\u{a9}too" => Encoder,
+        "trkn" => TrackNumber,
+        "disk" => DiscNumber,
+        "tmpo" => Bpm,
+        "cprt" => Copyright,
+        "desc" => Description,
+        "sonm" => SortTrackTitle,
+        "soar" => SortArtist,
+        "soaa" => SortAlbumArtist,
+        "soal" => SortAlbum,
+        "soco" => SortComposer,
+        "----:com.apple.iTunes:ISRC" => IdentIsrc,
+        _ => return None,
+    })
+}
+
+/// Type and body of each MP4 atom in `data`, up to one that runs past its
+/// end.
+fn mp4_atoms(data: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> + '_ {
+    let mut pos = 0usize;
+    std::iter::from_fn(move || {
+        let header = data.get(pos..pos.checked_add(8)?)?;
+        let id: [u8; 4] = header[4..8].try_into().unwrap();
+        let (start, size) = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            // The last atom of a file may run to its end
+            0 => (pos + 8, data.len() - pos),
+            // 64-bit size after the type
+            1 => {
+                let size = data.get(pos + 8..pos + 16)?;
+                (pos + 16, usize::try_from(u64::from_be_bytes(size.try_into().unwrap())).ok()?)
+            }
+            size => (pos + 8, size as usize),
+        };
+        let end = pos.checked_add(size)?;
+        let body = data.get(start..end)?;
+        pos = end;
+        Some((id, body))
+    })
+}
+
+fn mp4_child<'a>(data: &'a [u8], id: &[u8; 4]) -> Option<&'a [u8]> {
+    mp4_atoms(data).find(|(atom, _)| atom == id).map(|(_, body)| body)
+}
+
+/// Reject WAV `fmt ` chunks symphonia would panic on rather than fail with
+/// an error: no channels, or a zero sample rate or block size.
+fn check_wav_format(data: &[u8]) -> Result<(), SymphoniaError> {
+    let Some((_, (start, _))) = riff_chunks(data).find(|(id, _)| id == b"fmt ") else {
+        return Ok(());
+    };
+    let fmt = data.get(start..start + 16).ok_or(SymphoniaError::DecodeError("wav: truncated fmt chunk"))?;
+    let channels = u16::from_le_bytes([fmt[2], fmt[3]]);
+    let sample_rate = u32::from_le_bytes(fmt[4..8].try_into().unwrap());
+    let block_align = u16::from_le_bytes([fmt[12], fmt[13]]);
+    if channels == 0 || sample_rate == 0 || block_align == 0 {
+        return Err(SymphoniaError::DecodeError("wav: invalid fmt chunk"));
+    }
+    Ok(())
+}
+
+/// Type and body range of each chunk of a RIFF file. Bodies may run past
+/// the end of `data`.
+pub(crate) fn riff_chunks(data: &[u8]) -> impl Iterator<Item = ([u8; 4], (usize, usize))> + '_ {
+    subchunks(data, 12)
+}
+
+/// Chunks laid out like those of a RIFF file, from `pos` on.
+pub(crate) fn subchunks(data: &[u8], mut pos: usize) -> impl Iterator<Item = ([u8; 4], (usize, usize))> + '_ {
+    std::iter::from_fn(move || {
+        let header = data.get(pos..pos.checked_add(8)?)?;
+        let id: [u8; 4] = header[..4].try_into().unwrap();
+        let size = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+        let start = pos + 8;
+        // Chunks are padded to an even size
+        pos = start.saturating_add(size).saturating_add(size & 1);
+        Some((id, (start, size)))
+    })
+}
+
+/// Places in a payload where decoding can start, at least `block_size`
+/// bytes apart, with the first sample decoded from each. The first is the
+/// first audio frame, after the headers. Only PCM WAV and native FLAC are
+/// split: their frames decode on their own and start at exact sample
+/// positions.
+pub(crate) struct SplitPoints {
+    pub sample_rate: u32,
+    /// Payload offset and first sample of each point.
+    pub points: Vec<(usize, u64)>,
+    /// End of the last audio frame.
+    pub end: usize,
+}
+
+pub(crate) fn split_points(payload: &SharedPayload, block_size: usize) -> Option<SplitPoints> {
+    match AudioCodec::detect(payload.as_ref())? {
+        AudioCodec::Wav => wav_split_points(payload.as_ref(), block_size),
+        AudioCodec::Flac if payload.as_ref().starts_with(b"fLaC") => flac_split_points(payload, block_size),
+        _ => None,
+    }
+}
+
+fn wav_split_points(payload: &[u8], block_size: usize) -> Option<SplitPoints> {
+    const WAVE_FORMAT_PCM: u16 = 1;
+    const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+    const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+    let (fmt_start, _) = riff_chunks(payload).find(|(id, _)| id == b"fmt ")?.1;
+    let fmt = payload.get(fmt_start..fmt_start + 16)?;
+    let format_tag = u16::from_le_bytes([fmt[0], fmt[1]]);
+    let sample_rate = u32::from_le_bytes(fmt[4..8].try_into().unwrap());
+    let block_align = u16::from_le_bytes([fmt[12], fmt[13]]) as usize;
+    if ![WAVE_FORMAT_PCM, WAVE_FORMAT_IEEE_FLOAT, WAVE_FORMAT_EXTENSIBLE].contains(&format_tag)
+        || sample_rate == 0
+        || block_align == 0
+    {
+        return None;
+    }
+
+    let (data_start, data_size) = riff_chunks(payload).find(|(id, _)| id == b"data")?.1;
+    let data_end = data_start.saturating_add(data_size).min(payload.len());
+    if data_start >= data_end {
+        return None;
+    }
+    let step = (block_size / block_align).max(1) * block_align;
+    let points = (data_start..data_end)
+        .step_by(step)
+        .map(|offset| (offset, ((offset - data_start) / block_align) as u64))
+        .collect();
+    Some(SplitPoints { sample_rate, points, end: data_end })
+}
+
+fn flac_split_points(shared: &SharedPayload, block_size: usize) -> Option<SplitPoints> {
+    let payload = shared.as_ref();
+    // Metadata blocks: a last-block flag and type byte, then a 24-bit length
+    let mut audio_start = 4;
+    loop {
+        let header = payload.get(audio_start..audio_start + 4)?;
+        let length = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        audio_start += 4 + length;
+        if header[0] & 0x80 != 0 {
+            break;
+        }
+    }
+
+    let (_, mut format) = open_format(shared.clone()).ok()?;
+    let sample_rate = format.tracks().first()?.codec_params.sample_rate?;
+
+    // Packets are the frames as stored, one after the other
+    let mut points = Vec::new();
+    let mut offset = audio_start;
+    let mut last_point = None;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(_) => return None,
+        };
+        if !payload.get(offset..)?.starts_with(&packet.data) {
+            return None;
+        }
+        if last_point.is_none_or(|last| offset - last >= block_size) {
+            points.push((offset, packet.ts()));
+            last_point = Some(offset);
+        }
+        offset += packet.data.len();
+    }
+    if points.is_empty() {
+        return None;
+    }
+    Some(SplitPoints { sample_rate, points, end: offset })
+}
+
+/// Length of the ID3v2 tag at the start of an MP3 payload, if any.
+pub(crate) fn id3v2_size(data: &[u8]) -> u64 {
+    match data.get(..10) {
+        Some(header) if header.starts_with(b"ID3") => {
+            let size = header[6..10].iter().fold(0u64, |size, &b| (size << 7) | (b & 0x7F) as u64);
+            let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+            10 + size + footer
+        }
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::euph_export;
+    use crate::euph_flac;
+    use crate::euph_mux::{self, OggWriter};
+
+    /// Bits packed from the least significant end, as Vorbis does.
+    #[derive(Default)]
+    struct Bits {
+        bytes: Vec<u8>,
+        used: usize,
+    }
+
+    impl Bits {
+        fn put(&mut self, value: u64, bits: u32) -> &mut Self {
+            for i in 0..bits {
+                if self.used.is_multiple_of(8) {
+                    self.bytes.push(0);
+                }
+                *self.bytes.last_mut().unwrap() |= ((value >> i & 1) as u8) << (self.used % 8);
+                self.used += 1;
+            }
+            self
+        }
+
+        fn put_bytes(&mut self, bytes: &[u8]) -> &mut Self {
+            for &byte in bytes {
+                self.put(byte as u64, 8);
+            }
+            self
+        }
+    }
+
+    pub(crate) fn tone(sample_rate: u32, channels: u16, frames: usize) -> DecodedAudio {
+        let samples = (0..frames * channels as usize)
+            .map(|i| ((i / channels as usize) as f32 * 0.05).sin() * 0.5)
+            .collect();
+        DecodedAudio::new(sample_rate, channels, samples)
+    }
+
+    /// Ogg Vorbis of `packets` silent short blocks, with one codebook,
+    /// floor, residue, mapping and mode, and every channel unused.
+    pub(crate) fn silent_vorbis(channels: u8, sample_rate: u32, packets: u64) -> Vec<u8> {
+        let mut identification = Bits::default();
+        identification.put(1, 8).put_bytes(b"vorbis").put(0, 32).put(channels as u64, 8).put(sample_rate as u64, 32);
+        // No bitrates, then blocks of 256 and 2048 samples
+        identification.put(0, 32).put(0, 32).put(0, 32).put(8, 4).put(11, 4).put(1, 1);
+
+        let mut comments = Bits::default();
+        comments.put(3, 8).put_bytes(b"vorbis").put(4, 32).put_bytes(b"test").put(0, 32).put(1, 1);
+
+        let mut setup = Bits::default();
+        setup.put(5, 8).put_bytes(b"vorbis");
+        // A codebook of two one-bit entries of one dimension, without lookup
+        setup.put(0, 8).put(0x56_4342, 24).put(1, 16).put(2, 24).put(0, 2).put(0, 5).put(0, 5).put(0, 4);
+        // A time domain placeholder, and floor 1 with no partitions
+        setup.put(0, 6).put(0, 16);
+        setup.put(0, 6).put(1, 16).put(0, 5).put(0, 2).put(8, 4);
+        // Residue 0 that codes nothing
+        setup.put(0, 6).put(0, 16).put(0, 24).put(0, 24).put(0, 24).put(0, 6).put(0, 8).put(0, 3).put(0, 1);
+        // One mapping with one submap, and one short block mode
+        setup.put(0, 6).put(0, 16).put(0, 1).put(0, 1).put(0, 2).put(0, 8).put(0, 8).put(0, 8);
+        setup.put(0, 6).put(0, 1).put(0, 16).put(0, 16).put(0, 8).put(1, 1);
+
+        let mut ogg = OggWriter::new(1);
+        ogg.packet(&identification.bytes, 0);
+        ogg.flush();
+        ogg.packet(&comments.bytes, 0);
+        ogg.packet(&setup.bytes, 0);
+        ogg.flush();
+        // Each block after the first completes 128 samples
+        for packet in 0..packets {
+            ogg.packet(&[0], packet * 128);
+        }
+        ogg.finish()
+    }
+
+    /// MPEG-1 Layer III at 128 kb/s of `frames` silent frames: no main data,
+    /// so every granule decodes to zeros.
+    pub(crate) fn silent_mp3(stereo: bool, frames: usize) -> Vec<u8> {
+        // 144 * 128000 / 44100 bytes a frame, without padding
+        let mut frame = vec![0u8; 417];
+        frame[..4].copy_from_slice(&[0xFF, 0xFB, 0x90, if stereo { 0x00 } else { 0xC0 }]);
+        frame.repeat(frames)
+    }
+
+    pub(crate) fn opus() -> Vec<u8> {
+        let mut head = b"OpusHead\x01\x02".to_vec();
+        head.extend_from_slice(&312u16.to_le_bytes());
+        head.extend_from_slice(&48_000u32.to_le_bytes());
+        head.extend_from_slice(&[0, 0, 0]);
+        let mut ogg = OggWriter::new(1);
+        ogg.packet(&head, 0);
+        ogg.flush();
+        ogg.packet(b"OpusTags\x04\0\0\0test\0\0\0\0", 0);
+        ogg.flush();
+        // A 20 ms CELT frame of silence
+        ogg.packet(&[0xF8, 0xFF, 0xFE], 960);
+        ogg.finish()
+    }
+
+    fn assert_close(decoded: &DecodedAudio, original: &DecodedAudio, tolerance: f32) {
+        assert_eq!((decoded.sample_rate, decoded.channels), (original.sample_rate, original.channels));
+        assert_eq!(decoded.frames(), original.frames());
+        for (a, b) in decoded.interleaved().iter().zip(original.interleaved()) {
+            assert!((a - b).abs() <= tolerance, "{a} != {b}");
+        }
+    }
+
+    #[test]
+    fn detects_codecs() {
+        let wav = euph_export::wav_file(&tone(8000, 1, 10), 16, 0);
+        let flac = euph_flac::encode(&tone(8000, 1, 10), 16);
+        assert_eq!(AudioCodec::detect(&wav), Some(AudioCodec::Wav));
+        assert_eq!(AudioCodec::detect(&flac), Some(AudioCodec::Flac));
+        assert_eq!(AudioCodec::detect(&euph_mux::ogg_flac(flac.clone()).unwrap()), Some(AudioCodec::Flac));
+        assert_eq!(AudioCodec::detect(&silent_vorbis(1, 8000, 2)), Some(AudioCodec::Vorbis));
+        assert_eq!(AudioCodec::detect(&opus()), Some(AudioCodec::Opus));
+        assert_eq!(AudioCodec::detect(&silent_mp3(false, 1)), Some(AudioCodec::Mp3));
+        assert_eq!(AudioCodec::detect(b"ID3\x04\0\0\0\0\0\0"), Some(AudioCodec::Mp3));
+        assert_eq!(AudioCodec::detect(b"OggS\0\x02"), None);
+        assert_eq!(AudioCodec::detect(b"RIFF\0\0\0\0AVI "), None);
+        for codec in [AudioCodec::Wav, AudioCodec::Flac, AudioCodec::Mp3, AudioCodec::Vorbis, AudioCodec::Opus] {
+            assert_eq!(AudioCodec::from_id(codec.id()), Some(codec));
+        }
+        assert_eq!(AudioCodec::from_id(CODEC_ID_UNKNOWN), None);
+    }
+
+    #[test]
+    fn decodes_wav_and_flac() {
+        let audio = tone(22_050, 2, 5000);
+        let wav = euph_export::wav_file(&audio, 16, 0);
+        assert_close(&decode_audio(wav, u64::MAX).unwrap(), &audio, 1.0 / 32_768.0);
+
+        let audio = tone(96_000, 3, 10_000);
+        let flac = euph_flac::encode(&audio, 24);
+        let decoded = decode_audio(flac.clone(), u64::MAX).unwrap();
+        assert_close(&decoded, &audio, 1.0 / 8_388_608.0);
+        assert_eq!(decode_audio(euph_mux::ogg_flac(flac.clone()).unwrap(), u64::MAX).unwrap(), decoded);
+        assert_eq!(decoded.planar()[2], decoded.channel(2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn decodes_mp3() {
+        for (stereo, channels) in [(false, 1), (true, 2)] {
+            let decoded = decode_audio(silent_mp3(stereo, 10), u64::MAX).unwrap();
+            assert_eq!((decoded.sample_rate, decoded.channels), (44_100, channels));
+            assert!(decoded.frames() >= 8 * 1152 && decoded.frames() <= 10 * 1152, "{} frames", decoded.frames());
+            assert!(decoded.interleaved().iter().all(|&sample| sample == 0.0));
+        }
+
+        // An ID3v2 tag ahead of the frames is skipped
+        let mut tagged = b"ID3\x04\0\0\0\0\0\x0aTIT2\0\0\0\0\0\0".to_vec();
+        tagged.extend(silent_mp3(true, 4));
+        assert_eq!(decode_audio(tagged, u64::MAX).unwrap().channels, 2);
+    }
+
+    #[test]
+    fn decodes_vorbis() {
+        let decoded = decode_audio(silent_vorbis(2, 32_000, 11), u64::MAX).unwrap();
+        assert_eq!((decoded.sample_rate, decoded.channels), (32_000, 2));
+        assert_eq!(decoded.frames(), 10 * 128);
+        assert!(decoded.interleaved().iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn opus_is_probed_but_not_decoded() {
+        assert!(matches!(
+            decode_audio(opus(), u64::MAX),
+            Err(EuphError::UnsupportedAudioFormat(Some(AudioCodec::Opus)))
+        ));
+        let format = AudioFormat::probe(opus()).unwrap();
+        assert_eq!((format.codec, format.sample_rate, format.channels), (Some(AudioCodec::Opus), 48_000, 2));
+    }
+
+    #[test]
+    fn decoding_stops_at_the_size_limit() {
+        let audio = tone(8000, 2, 4000);
+        let wav = euph_export::wav_file(&audio, 16, 0);
+        let size = (audio.interleaved().len() * 4) as u64;
+        assert!(decode_audio(wav.clone(), size).is_ok());
+        assert!(matches!(
+            decode_audio(wav, size - 4),
+            Err(EuphError::ChunkTooLarge { chunk: ChunkType::Audio, limit, .. }) if limit == size - 4
+        ));
+
+        let flac = euph_flac::encode(&audio, 16);
+        assert!(matches!(decode_audio(flac, 1000), Err(EuphError::ChunkTooLarge { .. })));
+    }
+
+    #[test]
+    fn shared_payloads_are_not_copied() {
+        let wav = euph_export::wav_file(&tone(8000, 1, 100), 16, 0);
+        let address = wav.as_ptr();
+        let payload = SharedPayload::from(wav);
+        assert!(AudioFormat::probe(payload.clone()).is_some());
+        assert!(split_points(&payload, 64).is_some());
+        assert!(source_metadata(payload.clone()).iter().all(|revision| revision.tags().is_empty()));
+        assert_eq!(payload.skip(8).as_ref(), &payload.as_ref()[8..]);
+        assert_eq!(payload.skip(usize::MAX).as_ref(), b"");
+        let wav = payload.into_vec();
+        assert_eq!(wav.as_ptr(), address);
+
+        let payload = SharedPayload::from(b"skipped payload".to_vec()).skip(8);
+        let kept = payload.clone();
+        assert_eq!(payload.into_vec(), b"payload");
+        assert_eq!(kept.as_ref(), b"payload");
+    }
+
+    #[test]
+    fn unknown_and_broken_payloads() {
+        assert!(matches!(decode_audio(b"not audio".to_vec(), u64::MAX), Err(EuphError::UnsupportedAudioFormat(None))));
+        let mut wav = euph_export::wav_file(&tone(8000, 1, 10), 16, 0);
+        // A fmt chunk with no channels
+        wav[22..24].copy_from_slice(&0u16.to_le_bytes());
+        assert!(matches!(decode_audio(wav.clone(), u64::MAX), Err(EuphError::AudioDecode(_))));
+        assert_eq!(AudioFormat::probe(wav), None);
+    }
+
+    #[test]
+    fn probes_stream_parameters() {
+        let wav = euph_export::wav_file(&tone(48_000, 6, 4800), 24, 0x3F);
+        let format = AudioFormat::probe(wav).unwrap();
+        assert_eq!(format.codec, Some(AudioCodec::Wav));
+        assert_eq!((format.sample_rate, format.channels, format.channel_layout), (48_000, 6, 0x3F));
+        assert_eq!((format.bits_per_sample, format.frames), (Some(24), Some(4800)));
+        assert_eq!(format.duration_secs(), Some(0.1));
+
+        let format = AudioFormat::probe(euph_flac::encode(&tone(44_100, 2, 441), 16)).unwrap();
+        assert_eq!((format.codec, format.sample_rate, format.channels), (Some(AudioCodec::Flac), 44_100, 2));
+        assert_eq!((format.bits_per_sample, format.frames), (Some(16), Some(441)));
+
+        let format = AudioFormat::probe(silent_vorbis(1, 32_000, 3)).unwrap();
+        assert_eq!((format.codec, format.sample_rate, format.channels), (Some(AudioCodec::Vorbis), 32_000, 1));
+        assert_eq!(AudioFormat::probe(silent_mp3(true, 2)).unwrap().sample_rate, 44_100);
+    }
+}
@@ -0,0 +1,304 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::ops::RangeInclusive;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use ruzstd::decoding::{FrameDecoder, StreamingDecoder};
+
+use crate::euph_decoder::{ChunkType, EuphError, CHUNK_FLAG_COMPRESSED};
+
+// Chunk flag bits recording the codec and level a chunk was compressed with.
+// Files that only set `CHUNK_FLAG_COMPRESSED` predate these and are gzip.
+const CODEC_SHIFT: u32 = 8;
+const CODEC_MASK: u32 = 0x0000_0F00;
+const LEVEL_SHIFT: u32 = 16;
+const LEVEL_MASK: u32 = 0x00FF_0000;
+
+const CODEC_ID_NONE: u8 = 0;
+const CODEC_ID_GZIP: u8 = 1;
+const CODEC_ID_ZSTD: u8 = 2;
+
+/// Compression applied to a single chunk body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkCodec {
+    None,
+    Gzip { level: i32 },
+    Zstd { level: i32 },
+}
+
+/// zstd when this build can encode it (the `zstd` feature), gzip otherwise.
+impl Default for ChunkCodec {
+    fn default() -> Self {
+        if cfg!(feature = "zstd") {
+            ChunkCodec::Zstd { level: 3 }
+        } else {
+            ChunkCodec::Gzip { level: 6 }
+        }
+    }
+}
+
+impl ChunkCodec {
+    pub const GZIP_LEVELS: RangeInclusive<i32> = 0..=9;
+    /// zstd's fast levels are negative; the chunk flags hold the level as a
+    /// signed byte, so they stop at -128. 0 is zstd's default level.
+    pub const ZSTD_LEVELS: RangeInclusive<i32> = -128..=22;
+
+    /// Codec identifier as stored in the chunk flags.
+    pub fn id(self) -> u8 {
+        match self {
+            ChunkCodec::None => CODEC_ID_NONE,
+            ChunkCodec::Gzip { .. } => CODEC_ID_GZIP,
+            ChunkCodec::Zstd { .. } => CODEC_ID_ZSTD,
+        }
+    }
+
+    pub fn level(self) -> i32 {
+        match self {
+            ChunkCodec::None => 0,
+            ChunkCodec::Gzip { level } | ChunkCodec::Zstd { level } => level,
+        }
+    }
+
+    /// The same codec at another level; `None` has no level to change.
+    pub fn with_level(self, level: i32) -> Self {
+        match self {
+            ChunkCodec::None => ChunkCodec::None,
+            ChunkCodec::Gzip { .. } => ChunkCodec::Gzip { level },
+            ChunkCodec::Zstd { .. } => ChunkCodec::Zstd { level },
+        }
+    }
+
+    pub fn validate(self) -> Result<(), EuphError> {
+        let valid = match self {
+            ChunkCodec::None => true,
+            ChunkCodec::Gzip { level } => Self::GZIP_LEVELS.contains(&level),
+            ChunkCodec::Zstd { level } => Self::ZSTD_LEVELS.contains(&level),
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(EuphError::InvalidCompressionLevel(self))
+        }
+    }
+
+    /// Chunk flags describing this codec, to be combined with the other
+    /// chunk flags.
+    pub fn chunk_flags(self) -> u32 {
+        if self == ChunkCodec::None {
+            return 0;
+        }
+        let level = self.level() as i8 as u8 as u32;
+        CHUNK_FLAG_COMPRESSED | ((self.id() as u32) << CODEC_SHIFT) | (level << LEVEL_SHIFT)
+    }
+
+    pub fn from_chunk_flags(flags: u32) -> Result<Self, EuphError> {
+        let id = ((flags & CODEC_MASK) >> CODEC_SHIFT) as u8;
+        let level = ((flags & LEVEL_MASK) >> LEVEL_SHIFT) as u8 as i8 as i32;
+        match id {
+            CODEC_ID_NONE if flags & CHUNK_FLAG_COMPRESSED != 0 => Ok(ChunkCodec::Gzip { level }),
+            CODEC_ID_NONE => Ok(ChunkCodec::None),
+            CODEC_ID_GZIP => Ok(ChunkCodec::Gzip { level }),
+            CODEC_ID_ZSTD => Ok(ChunkCodec::Zstd { level }),
+            id => Err(EuphError::UnsupportedCodec(id)),
+        }
+    }
+
+    /// Compress a chunk body. zstd encoding needs the `zstd` feature and fails
+    /// with `UnsupportedCodec` without it.
+    pub fn compress(self, data: Vec<u8>) -> Result<Vec<u8>, EuphError> {
+        self.validate()?;
+        match self {
+            ChunkCodec::None => Ok(data),
+            ChunkCodec::Gzip { level } => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level as u32));
+                encoder.write_all(&data)?;
+                Ok(encoder.finish()?)
+            }
+            #[cfg(feature = "zstd")]
+            ChunkCodec::Zstd { level } => Ok(zstd::stream::encode_all(data.as_slice(), level)?),
+            #[cfg(not(feature = "zstd"))]
+            ChunkCodec::Zstd { .. } => Err(EuphError::UnsupportedCodec(CODEC_ID_ZSTD)),
+        }
+    }
+
+    /// Decompress a whole chunk body, failing with `ChunkTooLarge` rather
+    /// than producing more than `max_size` bytes.
+    pub fn decompress(self, chunk_type: ChunkType, data: &[u8], max_size: u64) -> Result<Vec<u8>, EuphError> {
+        let mut decompressed = Vec::new();
+        self.decoder(data)?
+            .take(max_size.saturating_add(1))
+            .read_to_end(&mut decompressed)
+            .map_err(|source| EuphError::Decompression { chunk: chunk_type, source })?;
+        if decompressed.len() as u64 > max_size {
+            return Err(EuphError::ChunkTooLarge { chunk: chunk_type, size: decompressed.len() as u64, limit: max_size });
+        }
+        Ok(decompressed)
+    }
+
+    /// Wrap a reader of compressed bytes in the matching streaming decoder.
+    /// Concatenated gzip members or zstd frames, as in block-compressed AUDIO
+    /// chunks, decode as one stream.
+    pub fn decoder<'a, R: Read + 'a>(self, reader: R) -> Result<Box<dyn Read + 'a>, EuphError> {
+        Ok(match self {
+            ChunkCodec::None => Box::new(reader),
+            ChunkCodec::Gzip { .. } => Box::new(MultiGzDecoder::new(reader)),
+            ChunkCodec::Zstd { .. } => Box::new(ZstdFrames::new(reader)),
+        })
+    }
+}
+
+/// Pure-Rust zstd decoder that carries on with the next frame while input
+/// remains, so it works in every build whether or not `zstd` is enabled.
+struct ZstdFrames<R: Read> {
+    frame: Option<StreamingDecoder<BufReader<R>, FrameDecoder>>,
+    between: Option<(BufReader<R>, FrameDecoder)>,
+}
+
+impl<R: Read> ZstdFrames<R> {
+    fn new(reader: R) -> Self {
+        ZstdFrames { frame: None, between: Some((BufReader::new(reader), FrameDecoder::new())) }
+    }
+}
+
+impl<R: Read> Read for ZstdFrames<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(frame) = &mut self.frame {
+                let n = frame.read(buf)?;
+                if n > 0 || buf.is_empty() {
+                    return Ok(n);
+                }
+                self.between = self.frame.take().map(StreamingDecoder::into_parts);
+            }
+            let Some((mut source, decoder)) = self.between.take() else {
+                return Ok(0);
+            };
+            if source.fill_buf()?.is_empty() {
+                return Ok(0);
+            }
+            let frame = StreamingDecoder::new_with_decoder(source, decoder).map_err(io::Error::other)?;
+            self.frame = Some(frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "hello " and "euph" as two separate zstd frames, as the zstd CLI writes them.
+    const TWO_ZSTD_FRAMES: [u8; 36] = [
+        0x28, 0xb5, 0x2f, 0xfd, 0x04, 0x58, 0x31, 0x00, 0x00, 0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x20, 0xd2, 0x3b, 0xe1,
+        0xa9, 0x28, 0xb5, 0x2f, 0xfd, 0x04, 0x58, 0x21, 0x00, 0x00, 0x65, 0x75, 0x70, 0x68, 0xf9, 0x82, 0xd0, 0xb7,
+    ];
+
+    fn sample() -> Vec<u8> {
+        (0..10_000u32).flat_map(|i| (i % 251).to_le_bytes()).collect()
+    }
+
+    fn round_trip(codec: ChunkCodec) {
+        let compressed = codec.compress(sample()).unwrap();
+        let flags = codec.chunk_flags();
+        assert_eq!(ChunkCodec::from_chunk_flags(flags).unwrap(), codec);
+        let decoded = ChunkCodec::from_chunk_flags(flags)
+            .unwrap()
+            .decompress(ChunkType::Audio, &compressed, u64::MAX)
+            .unwrap();
+        assert_eq!(decoded, sample());
+    }
+
+    #[test]
+    fn gzip_round_trips_at_every_level() {
+        for level in ChunkCodec::GZIP_LEVELS {
+            round_trip(ChunkCodec::Gzip { level });
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_round_trips() {
+        for level in [-128, -5, 0, 1, 3, 19, 22] {
+            round_trip(ChunkCodec::Zstd { level });
+        }
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    #[test]
+    fn zstd_encoding_needs_the_feature() {
+        assert!(matches!(
+            ChunkCodec::Zstd { level: 3 }.compress(sample()),
+            Err(EuphError::UnsupportedCodec(CODEC_ID_ZSTD))
+        ));
+        assert!(matches!(ChunkCodec::default(), ChunkCodec::Gzip { .. }));
+    }
+
+    #[test]
+    fn none_is_stored_as_is() {
+        assert_eq!(ChunkCodec::None.chunk_flags(), 0);
+        assert_eq!(ChunkCodec::from_chunk_flags(0).unwrap(), ChunkCodec::None);
+        round_trip(ChunkCodec::None);
+    }
+
+    #[test]
+    fn concatenated_zstd_frames_decode_as_one_stream() {
+        let codec = ChunkCodec::Zstd { level: 3 };
+        let decoded = codec.decompress(ChunkType::Audio, &TWO_ZSTD_FRAMES, u64::MAX).unwrap();
+        assert_eq!(decoded, b"hello euph");
+    }
+
+    #[test]
+    fn concatenated_gzip_members_decode_as_one_stream() {
+        let codec = ChunkCodec::Gzip { level: 6 };
+        let mut compressed = codec.compress(b"hello ".to_vec()).unwrap();
+        compressed.extend(codec.compress(b"euph".to_vec()).unwrap());
+        assert_eq!(codec.decompress(ChunkType::Audio, &compressed, u64::MAX).unwrap(), b"hello euph");
+    }
+
+    #[test]
+    fn legacy_compressed_flag_decodes_as_gzip() {
+        let codec = ChunkCodec::from_chunk_flags(CHUNK_FLAG_COMPRESSED).unwrap();
+        assert!(matches!(codec, ChunkCodec::Gzip { .. }));
+        let compressed = ChunkCodec::Gzip { level: 9 }.compress(sample()).unwrap();
+        assert_eq!(codec.decompress(ChunkType::Audio, &compressed, u64::MAX).unwrap(), sample());
+    }
+
+    #[test]
+    fn unknown_codec_id_is_rejected() {
+        assert!(matches!(
+            ChunkCodec::from_chunk_flags(CHUNK_FLAG_COMPRESSED | (7 << CODEC_SHIFT)),
+            Err(EuphError::UnsupportedCodec(7))
+        ));
+    }
+
+    #[test]
+    fn out_of_range_levels_are_rejected() {
+        for codec in [ChunkCodec::Gzip { level: -1 }, ChunkCodec::Gzip { level: 10 }, ChunkCodec::Zstd { level: -129 }, ChunkCodec::Zstd { level: 23 }] {
+            assert!(matches!(codec.compress(sample()), Err(EuphError::InvalidCompressionLevel(c)) if c == codec));
+        }
+    }
+
+    #[test]
+    fn negative_levels_survive_the_flags() {
+        for level in [-128, -5, -1] {
+            let codec = ChunkCodec::Zstd { level };
+            assert!(codec.validate().is_ok());
+            assert_eq!(ChunkCodec::from_chunk_flags(codec.chunk_flags()).unwrap(), codec);
+        }
+    }
+
+    #[test]
+    fn output_over_the_limit_is_too_large() {
+        let codec = ChunkCodec::Gzip { level: 6 };
+        let compressed = codec.compress(sample()).unwrap();
+        let err = codec.decompress(ChunkType::Metadata, &compressed, 100).unwrap_err();
+        assert!(matches!(err, EuphError::ChunkTooLarge { chunk: ChunkType::Metadata, size: 101, limit: 100 }));
+    }
+
+    #[test]
+    fn garbage_fails_to_decompress() {
+        for codec in [ChunkCodec::Gzip { level: 6 }, ChunkCodec::Zstd { level: 3 }] {
+            let err = codec.decompress(ChunkType::Audio, b"not compressed", u64::MAX).unwrap_err();
+            assert!(matches!(err, EuphError::Decompression { chunk: ChunkType::Audio, .. }));
+        }
+    }
+}
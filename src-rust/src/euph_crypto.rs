@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::ChaCha20Poly1305;
+
+use crate::euph_decoder::{ChunkType, EuphError, CHUNK_FLAG_CRC, CHUNK_FLAG_ENCRYPTED};
+
+// Chunk flag bits recording the cipher and key an encrypted chunk uses. The
+// body of an encrypted chunk starts with the nonce, followed by the
+// ciphertext and authentication tag.
+const CIPHER_SHIFT: u32 = 12;
+const CIPHER_MASK: u32 = 0x0000_F000;
+const KEY_ID_SHIFT: u32 = 24;
+const KEY_ID_MASK: u32 = 0xFF00_0000;
+
+const CIPHER_ID_AES_256_GCM: u8 = 1;
+const CIPHER_ID_CHACHA20_POLY1305: u8 = 2;
+
+/// Size of the nonce stored at the start of an encrypted chunk body.
+pub const NONCE_SIZE: usize = 12;
+
+/// Authenticated cipher applied to a single chunk body, after compression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkCipher {
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl ChunkCipher {
+    /// Cipher identifier as stored in the chunk flags.
+    pub fn id(self) -> u8 {
+        match self {
+            ChunkCipher::Aes256Gcm => CIPHER_ID_AES_256_GCM,
+            ChunkCipher::ChaCha20Poly1305 => CIPHER_ID_CHACHA20_POLY1305,
+        }
+    }
+
+    /// Cipher and key id of an encrypted chunk, `None` for a plain one.
+    pub fn from_chunk_flags(flags: u32) -> Result<Option<(Self, u8)>, EuphError> {
+        if flags & CHUNK_FLAG_ENCRYPTED == 0 {
+            return Ok(None);
+        }
+        let key_id = ((flags & KEY_ID_MASK) >> KEY_ID_SHIFT) as u8;
+        match ((flags & CIPHER_MASK) >> CIPHER_SHIFT) as u8 {
+            CIPHER_ID_AES_256_GCM => Ok(Some((ChunkCipher::Aes256Gcm, key_id))),
+            CIPHER_ID_CHACHA20_POLY1305 => Ok(Some((ChunkCipher::ChaCha20Poly1305, key_id))),
+            id => Err(EuphError::UnsupportedCipher(id)),
+        }
+    }
+
+    /// Chunk flags describing this cipher and key, to be combined with the
+    /// other chunk flags.
+    pub fn chunk_flags(self, key_id: u8) -> u32 {
+        CHUNK_FLAG_ENCRYPTED | ((self.id() as u32) << CIPHER_SHIFT) | ((key_id as u32) << KEY_ID_SHIFT)
+    }
+
+    fn seal(self, key: &[u8; 32], nonce: &[u8; NONCE_SIZE], payload: Payload) -> Option<Vec<u8>> {
+        match self {
+            ChunkCipher::Aes256Gcm => Aes256Gcm::new(key.into()).encrypt(nonce.into(), payload).ok(),
+            ChunkCipher::ChaCha20Poly1305 => ChaCha20Poly1305::new(key.into()).encrypt(nonce.into(), payload).ok(),
+        }
+    }
+
+    fn open(self, key: &[u8; 32], nonce: &[u8; NONCE_SIZE], payload: Payload) -> Option<Vec<u8>> {
+        match self {
+            ChunkCipher::Aes256Gcm => Aes256Gcm::new(key.into()).decrypt(nonce.into(), payload).ok(),
+            ChunkCipher::ChaCha20Poly1305 => ChaCha20Poly1305::new(key.into()).decrypt(nonce.into(), payload).ok(),
+        }
+    }
+}
+
+/// Supplies the 256-bit keys encrypted chunks were sealed with, by the key
+/// id recorded in their chunk flags.
+pub trait KeyProvider {
+    fn key(&self, key_id: u8) -> Option<[u8; 32]>;
+}
+
+impl KeyProvider for HashMap<u8, [u8; 32]> {
+    fn key(&self, key_id: u8) -> Option<[u8; 32]> {
+        self.get(&key_id).copied()
+    }
+}
+
+/// Key provider held by a container; never prints the keys it holds.
+#[derive(Default)]
+pub(crate) struct Keys(Option<Box<dyn KeyProvider>>);
+
+impl Keys {
+    pub fn new(provider: Box<dyn KeyProvider>) -> Self {
+        Keys(Some(provider))
+    }
+
+    pub fn key(&self, key_id: u8) -> Option<[u8; 32]> {
+        self.0.as_ref()?.key(key_id)
+    }
+}
+
+impl std::fmt::Debug for Keys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(if self.0.is_some() { "Keys(..)" } else { "Keys(None)" })
+    }
+}
+
+/// Which chunks an encoder encrypts, and with what.
+#[derive(Clone)]
+pub struct ChunkEncryption {
+    pub cipher: ChunkCipher,
+    pub key_id: u8,
+    key: [u8; 32],
+    /// Chunk types to encrypt. METADATA stays readable unless listed here.
+    pub chunk_types: Vec<ChunkType>,
+}
+
+impl std::fmt::Debug for ChunkEncryption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChunkEncryption")
+            .field("cipher", &self.cipher)
+            .field("key_id", &self.key_id)
+            .field("chunk_types", &self.chunk_types)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ChunkEncryption {
+    /// Encrypt AUDIO chunks with `key`, recorded as `key_id`.
+    pub fn new(cipher: ChunkCipher, key_id: u8, key: [u8; 32]) -> Self {
+        Self {
+            cipher,
+            key_id,
+            key,
+            chunk_types: vec![ChunkType::Audio],
+        }
+    }
+
+    pub fn with_chunk_types(mut self, chunk_types: &[ChunkType]) -> Self {
+        self.chunk_types = chunk_types.to_vec();
+        self
+    }
+
+    pub fn applies_to(&self, chunk_type: ChunkType) -> bool {
+        self.chunk_types.contains(&chunk_type)
+    }
+
+    /// Seal a (possibly compressed) chunk body, returning the stored body and
+    /// its chunk flags.
+    pub(crate) fn encrypt(&self, chunk_type: ChunkType, flags: u32, data: &[u8]) -> Result<(Vec<u8>, u32), EuphError> {
+        encrypt(self.cipher, self.key_id, &self.key, chunk_type, flags, data)
+    }
+}
+
+pub(crate) fn encrypt(
+    cipher: ChunkCipher,
+    key_id: u8,
+    key: &[u8; 32],
+    chunk_type: ChunkType,
+    flags: u32,
+    data: &[u8],
+) -> Result<(Vec<u8>, u32), EuphError> {
+    let flags = (flags & !(CIPHER_MASK | KEY_ID_MASK)) | cipher.chunk_flags(key_id);
+    let mut nonce = [0u8; NONCE_SIZE];
+    getrandom::getrandom(&mut nonce).map_err(|e| EuphError::IoError(std::io::Error::other(e.to_string())))?;
+
+    let aad = associated_data(chunk_type, flags);
+    let sealed = cipher
+        .seal(key, &nonce, Payload { msg: data, aad: &aad })
+        .ok_or(EuphError::Encryption { chunk: chunk_type })?;
+
+    let mut body = Vec::with_capacity(NONCE_SIZE + sealed.len());
+    body.extend_from_slice(&nonce);
+    body.extend_from_slice(&sealed);
+    Ok((body, flags))
+}
+
+/// Open an encrypted chunk body with the key `keys` holds for it.
+pub(crate) fn decrypt(chunk_type: ChunkType, flags: u32, body: &[u8], keys: &Keys) -> Result<Vec<u8>, EuphError> {
+    let Some((cipher, key_id)) = ChunkCipher::from_chunk_flags(flags)? else {
+        return Ok(body.to_vec());
+    };
+    let key = keys.key(key_id).ok_or(EuphError::MissingKey { chunk: chunk_type, key_id })?;
+    if body.len() < NONCE_SIZE {
+        return Err(EuphError::Decryption { chunk: chunk_type });
+    }
+
+    let (nonce, sealed) = body.split_at(NONCE_SIZE);
+    let aad = associated_data(chunk_type, flags);
+    cipher
+        .open(&key, nonce.try_into().unwrap(), Payload { msg: sealed, aad: &aad })
+        .ok_or(EuphError::Decryption { chunk: chunk_type })
+}
+
+/// Bind the ciphertext to its chunk type and flags, so it cannot be moved to
+/// another chunk or have its codec changed. The CRC flag only concerns storage.
+fn associated_data(chunk_type: ChunkType, flags: u32) -> [u8; 8] {
+    let mut aad = [0u8; 8];
+    aad[..4].copy_from_slice(&chunk_type.id().to_le_bytes());
+    aad[4..].copy_from_slice(&(flags & !CHUNK_FLAG_CRC).to_le_bytes());
+    aad
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+    use crate::euph_codec::ChunkCodec;
+    use crate::euph_decoder::{EuphContainer, SPEC_HEADER_SIZE};
+    use crate::euph_encoder::EuphEncoder;
+
+    const KEY: [u8; 32] = [0x11; 32];
+    const ANLY: ChunkType = ChunkType::Custom(*b"ANLY");
+
+    fn keys(key_id: u8, key: [u8; 32]) -> Keys {
+        Keys::new(Box::new(HashMap::from([(key_id, key)])))
+    }
+
+    /// A file whose only chunk, ANLY, is encrypted with `KEY` as key id 3.
+    fn encrypted_file() -> Vec<u8> {
+        let encryption = ChunkEncryption::new(ChunkCipher::ChaCha20Poly1305, 3, KEY).with_chunk_types(&[ANLY]);
+        let mut encoder = EuphEncoder::new().with_encryption(encryption);
+        encoder.add_chunk(ANLY, b"secret analysis".to_vec(), ChunkCodec::None).unwrap();
+        let mut file = Cursor::new(Vec::new());
+        encoder.write(&mut file).unwrap();
+        file.into_inner()
+    }
+
+    #[test]
+    fn both_ciphers_round_trip() {
+        for cipher in [ChunkCipher::Aes256Gcm, ChunkCipher::ChaCha20Poly1305] {
+            let (body, flags) = encrypt(cipher, 7, &KEY, ANLY, 0, b"payload").unwrap();
+            assert_eq!(ChunkCipher::from_chunk_flags(flags).unwrap(), Some((cipher, 7)));
+            assert_eq!(decrypt(ANLY, flags, &body, &keys(7, KEY)).unwrap(), b"payload");
+        }
+    }
+
+    #[test]
+    fn wrong_key_fails_to_decrypt() {
+        let (body, flags) = encrypt(ChunkCipher::Aes256Gcm, 7, &KEY, ANLY, 0, b"payload").unwrap();
+        assert!(matches!(decrypt(ANLY, flags, &body, &keys(7, [0x22; 32])), Err(EuphError::Decryption { chunk: ANLY })));
+    }
+
+    #[test]
+    fn missing_key_is_reported_with_its_id() {
+        let (body, flags) = encrypt(ChunkCipher::Aes256Gcm, 7, &KEY, ANLY, 0, b"payload").unwrap();
+        assert!(matches!(decrypt(ANLY, flags, &body, &keys(8, KEY)), Err(EuphError::MissingKey { chunk: ANLY, key_id: 7 })));
+        assert!(matches!(decrypt(ANLY, flags, &body, &Keys::default()), Err(EuphError::MissingKey { key_id: 7, .. })));
+    }
+
+    #[test]
+    fn associated_data_binds_type_and_flags() {
+        let (body, flags) = encrypt(ChunkCipher::ChaCha20Poly1305, 7, &KEY, ANLY, 0, b"payload").unwrap();
+        let keys = keys(7, KEY);
+        // Moved to another chunk type
+        assert!(matches!(decrypt(ChunkType::Metadata, flags, &body, &keys), Err(EuphError::Decryption { .. })));
+        // Codec flags changed
+        let recompressed = flags | ChunkCodec::Gzip { level: 6 }.chunk_flags();
+        assert!(matches!(decrypt(ANLY, recompressed, &body, &keys), Err(EuphError::Decryption { .. })));
+        // The CRC flag only concerns storage
+        assert_eq!(decrypt(ANLY, flags | CHUNK_FLAG_CRC, &body, &keys).unwrap(), b"payload");
+    }
+
+    #[test]
+    fn damaged_ciphertext_fails_to_decrypt() {
+        let (mut body, flags) = encrypt(ChunkCipher::Aes256Gcm, 7, &KEY, ANLY, 0, b"payload").unwrap();
+        let keys = keys(7, KEY);
+        assert!(matches!(decrypt(ANLY, flags, &body[..NONCE_SIZE - 1], &keys), Err(EuphError::Decryption { .. })));
+        *body.last_mut().unwrap() ^= 1;
+        assert!(matches!(decrypt(ANLY, flags, &body, &keys), Err(EuphError::Decryption { .. })));
+    }
+
+    #[test]
+    fn unknown_cipher_id_is_rejected() {
+        let flags = CHUNK_FLAG_ENCRYPTED | (9 << CIPHER_SHIFT);
+        assert!(matches!(ChunkCipher::from_chunk_flags(flags), Err(EuphError::UnsupportedCipher(9))));
+    }
+
+    #[test]
+    fn encrypted_chunk_needs_its_key() {
+        let mut container = EuphContainer::open(Cursor::new(encrypted_file())).unwrap();
+        assert!(matches!(container.read_chunk(ANLY), Err(EuphError::MissingKey { chunk: ANLY, key_id: 3 })));
+
+        container.set_key_provider(HashMap::from([(3, [0x22; 32])]));
+        assert!(matches!(container.read_chunk(ANLY), Err(EuphError::Decryption { chunk: ANLY })));
+
+        container.set_key_provider(HashMap::from([(3, KEY)]));
+        assert_eq!(container.read_chunk(ANLY).unwrap(), b"secret analysis");
+    }
+
+    #[test]
+    fn tampered_table_flags_fail_to_decrypt() {
+        let mut file = encrypted_file();
+        // Flags of the first chunk table entry: set a compression level bit
+        let flags_offset = SPEC_HEADER_SIZE as usize + 20;
+        file[flags_offset + 2] ^= 0x01;
+
+        let mut container = EuphContainer::open(Cursor::new(file)).unwrap();
+        container.set_key_provider(HashMap::from([(3, KEY)]));
+        assert!(matches!(container.read_chunk(ANLY), Err(EuphError::Decryption { chunk: ANLY })));
+    }
+}
@@ -0,0 +1,338 @@
+use std::fmt::Write;
+use serde::{Serialize, Deserialize};
+
+use crate::euph_decoder::EuphError;
+
+/// Cue sheet positions are in CD frames of 1/75 s.
+const CUE_FRAMES_PER_SECOND: u64 = 75;
+
+/// Body of a CUES chunk: chapters, loops and hot cues of the first AUDIO
+/// chunk, with positions in samples per channel at `sample_rate`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CueSheet {
+    pub sample_rate: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// Chapters in playback order; each runs until the next one starts.
+    pub chapters: Vec<Chapter>,
+    pub loops: Vec<CueLoop>,
+    pub hot_cues: Vec<HotCue>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Chapter {
+    pub start: u64,
+    pub title: String,
+    pub performer: Option<String>,
+}
+
+/// Section to repeat, from `start` up to but not including `end`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CueLoop {
+    pub start: u64,
+    pub end: u64,
+    pub name: Option<String>,
+}
+
+/// Position a DJ can jump to from one of the numbered pads.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HotCue {
+    pub slot: u8,
+    pub position: u64,
+    pub name: Option<String>,
+    /// `#RRGGBB`
+    pub color: Option<String>,
+}
+
+impl CueSheet {
+    pub fn new(sample_rate: u32) -> Self {
+        Self { sample_rate, ..Default::default() }
+    }
+
+    /// Chapter playing at `position`.
+    pub fn chapter_at(&self, position: u64) -> Option<&Chapter> {
+        let index = self.chapters.partition_point(|c| c.start <= position);
+        self.chapters.get(index.checked_sub(1)?)
+    }
+
+    pub fn seconds(&self, position: u64) -> f64 {
+        position as f64 / self.sample_rate.max(1) as f64
+    }
+
+    /// Read a single-file `.cue` sheet, taking each track's `INDEX 01` as the
+    /// start of a chapter. `REM LOOP` and `REM CUE` lines, as written by
+    /// `to_cue`, become loops and hot cues. Cue sheet times are whole CD
+    /// frames, so positions are only as precise as 1/75 s.
+    pub fn parse_cue(text: &str, sample_rate: u32) -> Result<Self, EuphError> {
+        if sample_rate == 0 {
+            return Err(EuphError::InvalidCueSheet { line: 0, reason: "sample rate of the audio is unknown" });
+        }
+        let mut sheet = Self::new(sample_rate);
+        let mut files = 0;
+        let mut track: Option<Track> = None;
+
+        for (number, line) in text.lines().enumerate() {
+            let line_number = number + 1;
+            let invalid = |reason| EuphError::InvalidCueSheet { line: line_number, reason };
+            let mut words = Words(line.trim_start_matches('\u{feff}').trim());
+            let Some(command) = words.next() else {
+                continue;
+            };
+
+            match command.to_ascii_uppercase().as_str() {
+                "FILE" => {
+                    files += 1;
+                    if files > 1 {
+                        return Err(invalid("only single-file cue sheets are supported"));
+                    }
+                }
+                "TRACK" => {
+                    if let Some(finished) = track.take() {
+                        sheet.push_chapter(finished).ok_or_else(|| invalid("previous track has no INDEX 01"))?;
+                    }
+                    track = Some(Track::default());
+                }
+                "TITLE" | "PERFORMER" => {
+                    let value = words.rest().ok_or_else(|| invalid("missing value"))?;
+                    let field = match (&mut track, command.eq_ignore_ascii_case("TITLE")) {
+                        (Some(track), true) => &mut track.title,
+                        (Some(track), false) => &mut track.performer,
+                        (None, true) => &mut sheet.title,
+                        (None, false) => &mut sheet.performer,
+                    };
+                    *field = Some(value);
+                }
+                "INDEX" => {
+                    let index = words.next().ok_or_else(|| invalid("missing index number"))?;
+                    let time = words.next().ok_or_else(|| invalid("missing index time"))?;
+                    let position = parse_cue_time(time, sample_rate).ok_or_else(|| invalid("invalid time"))?;
+                    let track = track.as_mut().ok_or_else(|| invalid("INDEX outside a track"))?;
+                    // INDEX 00 starts the pregap, which belongs to the previous chapter
+                    if index.parse::<u8>() == Ok(1) {
+                        track.start = Some(position);
+                    }
+                }
+                "REM" => match words.next().map(str::to_ascii_uppercase).as_deref() {
+                    Some("LOOP") => {
+                        let mut time = || words.next().and_then(|t| parse_cue_time(t, sample_rate));
+                        let (start, end) = time().zip(time()).ok_or_else(|| invalid("invalid loop"))?;
+                        sheet.loops.push(CueLoop { start, end, name: words.rest() });
+                    }
+                    Some("CUE") => {
+                        let slot = words.next().and_then(|s| s.parse().ok()).ok_or_else(|| invalid("invalid hot cue slot"))?;
+                        let position = words.next()
+                            .and_then(|t| parse_cue_time(t, sample_rate))
+                            .ok_or_else(|| invalid("invalid hot cue time"))?;
+                        let color = words.clone().next().filter(|w| w.starts_with('#')).map(str::to_string);
+                        if color.is_some() {
+                            words.next();
+                        }
+                        sheet.hot_cues.push(HotCue { slot, position, name: words.rest(), color });
+                    }
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+        if let Some(finished) = track {
+            let line = text.lines().count();
+            sheet.push_chapter(finished)
+                .ok_or(EuphError::InvalidCueSheet { line, reason: "last track has no INDEX 01" })?;
+        }
+        if sheet.chapters.windows(2).any(|w| w[1].start < w[0].start) {
+            return Err(EuphError::InvalidCueSheet { line: 0, reason: "tracks are out of order" });
+        }
+        Ok(sheet)
+    }
+
+    /// Add a track read from a cue sheet as a chapter, unless it has no start.
+    fn push_chapter(&mut self, track: Track) -> Option<()> {
+        let title = track.title.unwrap_or_else(|| format!("Track {:02}", self.chapters.len() + 1));
+        self.chapters.push(Chapter { start: track.start?, title, performer: track.performer });
+        Some(())
+    }
+
+    /// Write a `.cue` sheet for the audio exported as `file_name`, with one
+    /// track per chapter. Positions are rounded to the nearest CD frame.
+    pub fn to_cue(&self, file_name: &str) -> String {
+        let mut cue = String::new();
+        if let Some(performer) = &self.performer {
+            let _ = writeln!(cue, "PERFORMER {}", quote(performer));
+        }
+        if let Some(title) = &self.title {
+            let _ = writeln!(cue, "TITLE {}", quote(title));
+        }
+        let file_type = if file_name.to_ascii_lowercase().ends_with(".mp3") { "MP3" } else { "WAVE" };
+        let _ = writeln!(cue, "FILE {} {}", quote(file_name), file_type);
+        for cue_loop in &self.loops {
+            let _ = write!(cue, "REM LOOP {} {}", self.cue_time(cue_loop.start), self.cue_time(cue_loop.end));
+            if let Some(name) = &cue_loop.name {
+                let _ = write!(cue, " {}", quote(name));
+            }
+            cue.push('\n');
+        }
+        for hot_cue in &self.hot_cues {
+            let _ = write!(cue, "REM CUE {} {}", hot_cue.slot, self.cue_time(hot_cue.position));
+            for value in hot_cue.color.iter().chain(&hot_cue.name) {
+                let _ = write!(cue, " {}", quote(value));
+            }
+            cue.push('\n');
+        }
+        for (number, chapter) in self.chapters.iter().enumerate() {
+            let _ = writeln!(cue, "  TRACK {:02} AUDIO", number + 1);
+            let _ = writeln!(cue, "    TITLE {}", quote(&chapter.title));
+            if let Some(performer) = &chapter.performer {
+                let _ = writeln!(cue, "    PERFORMER {}", quote(performer));
+            }
+            let _ = writeln!(cue, "    INDEX 01 {}", self.cue_time(chapter.start));
+        }
+        cue
+    }
+
+    fn cue_time(&self, position: u64) -> String {
+        let rate = self.sample_rate.max(1) as u128;
+        let frames = (position as u128 * CUE_FRAMES_PER_SECOND as u128 + rate / 2) / rate;
+        let frames = frames.min(u64::MAX as u128) as u64;
+        let seconds = frames / CUE_FRAMES_PER_SECOND;
+        format!("{:02}:{:02}:{:02}", seconds / 60, seconds % 60, frames % CUE_FRAMES_PER_SECOND)
+    }
+}
+
+/// Cue sheet track being read.
+#[derive(Default)]
+struct Track {
+    title: Option<String>,
+    performer: Option<String>,
+    start: Option<u64>,
+}
+
+/// `MM:SS:FF` as a sample position.
+fn parse_cue_time(time: &str, sample_rate: u32) -> Option<u64> {
+    let mut parts = time.split(':').map(str::parse::<u64>);
+    let (minutes, seconds, frames) = (parts.next()?.ok()?, parts.next()?.ok()?, parts.next()?.ok()?);
+    if parts.next().is_some() || seconds >= 60 || frames >= CUE_FRAMES_PER_SECOND {
+        return None;
+    }
+    let frames = minutes.checked_mul(60)?.checked_add(seconds)?.checked_mul(CUE_FRAMES_PER_SECOND)?.checked_add(frames)?;
+    u64::try_from(frames as u128 * sample_rate as u128 / CUE_FRAMES_PER_SECOND as u128).ok()
+}
+
+/// Quote a value; cue sheets have no escape for a double quote inside one.
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "'"))
+}
+
+/// Words of a cue sheet line, where a double-quoted string is one word.
+#[derive(Clone)]
+struct Words<'a>(&'a str);
+
+impl<'a> Words<'a> {
+    /// Everything left on the line, unquoted, or `None` if nothing is.
+    fn rest(&mut self) -> Option<String> {
+        let rest = std::mem::take(&mut self.0).trim();
+        let rest = rest.strip_prefix('"').map_or(rest, |quoted| quoted.strip_suffix('"').unwrap_or(quoted));
+        (!rest.is_empty()).then(|| rest.to_string())
+    }
+}
+
+impl<'a> Iterator for Words<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        let line = self.0.trim_start();
+        if line.is_empty() {
+            return None;
+        }
+        let (word, rest) = match line.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+            None => line.split_once(char::is_whitespace).unwrap_or((line, "")),
+        };
+        self.0 = rest;
+        Some(word)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHEET: &str = "\u{feff}REM GENRE Electronic
+PERFORMER \"Various\"
+TITLE \"Night Drive\"
+FILE \"mix.wav\" WAVE
+  TRACK 01 AUDIO
+    TITLE \"Intro\"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE \"Second\"
+    PERFORMER \"Guest\"
+    INDEX 00 01:58:00
+    INDEX 01 02:00:30
+  track 03 audio
+    INDEX 01 04:10:74
+";
+
+    #[test]
+    fn parses_chapters_and_sheet_fields() {
+        let sheet = CueSheet::parse_cue(SHEET, 48_000).unwrap();
+        assert_eq!(sheet.title.as_deref(), Some("Night Drive"));
+        assert_eq!(sheet.performer.as_deref(), Some("Various"));
+        let starts: Vec<u64> = sheet.chapters.iter().map(|c| c.start).collect();
+        // 02:00:30 is 120.4 s; the pregap from INDEX 00 stays with track 1
+        assert_eq!(starts, [0, 120 * 48_000 + 30 * 640, 250 * 48_000 + 74 * 640]);
+        assert_eq!(sheet.chapters[1].performer.as_deref(), Some("Guest"));
+        // Tracks without a title are numbered
+        assert_eq!(sheet.chapters[2].title, "Track 03");
+        assert_eq!(sheet.chapter_at(130 * 48_000).unwrap().title, "Second");
+        assert!(CueSheet::new(48_000).chapter_at(0).is_none());
+    }
+
+    #[test]
+    fn export_round_trips_on_cd_frames() {
+        // Positions on whole CD frames, 588 samples at 44.1 kHz
+        let mut sheet = CueSheet::parse_cue(SHEET, 44_100).unwrap();
+        sheet.loops.push(CueLoop { start: 44_100, end: 88_200, name: Some("Build".to_string()) });
+        sheet.hot_cues.push(HotCue { slot: 2, position: 37 * 588, name: Some("Drop".to_string()), color: Some("#FF8800".to_string()) });
+        sheet.hot_cues.push(HotCue { slot: 3, position: 0, name: None, color: None });
+
+        let cue = sheet.to_cue("mix.wav");
+        assert!(cue.contains("FILE \"mix.wav\" WAVE\n"));
+        assert!(cue.contains("REM LOOP 00:01:00 00:02:00 \"Build\"\n"));
+        assert!(cue.contains("REM CUE 2 00:00:37 \"#FF8800\" \"Drop\"\n"));
+        assert_eq!(CueSheet::parse_cue(&cue, 44_100).unwrap(), sheet);
+        assert!(sheet.to_cue("mix.MP3").contains("\"mix.MP3\" MP3"));
+    }
+
+    #[test]
+    fn export_rounds_to_the_nearest_cd_frame() {
+        let mut sheet = CueSheet::new(44_100);
+        sheet.chapters.push(Chapter { start: 44_100 + 300, title: "A \"quoted\" title".to_string(), performer: None });
+        let cue = sheet.to_cue("a.wav");
+        assert!(cue.contains("INDEX 01 00:01:01\n"));
+        assert!(cue.contains("TITLE \"A 'quoted' title\""));
+    }
+
+    #[test]
+    fn invalid_sheets_name_the_line() {
+        let line = |text: &str| match CueSheet::parse_cue(text, 44_100) {
+            Err(EuphError::InvalidCueSheet { line, .. }) => line,
+            other => panic!("{other:?}"),
+        };
+        assert_eq!(line("FILE \"a.wav\" WAVE\nFILE \"b.wav\" WAVE\n"), 2);
+        assert_eq!(line("TRACK 01 AUDIO\nINDEX 01 00:61:00\n"), 2);
+        assert_eq!(line("INDEX 01 00:00:00\n"), 1);
+        assert_eq!(line("TRACK 01 AUDIO\nTRACK 02 AUDIO\nINDEX 01 00:00:00\n"), 2);
+        assert_eq!(line("TRACK 01 AUDIO\nTITLE \"x\"\n"), 2);
+        assert_eq!(line("REM LOOP 00:01:00\n"), 1);
+        assert_eq!(line("TRACK 01 AUDIO\nINDEX 01 00:02:00\nTRACK 02 AUDIO\nINDEX 01 00:01:00\n"), 0);
+        assert!(matches!(CueSheet::parse_cue(SHEET, 0), Err(EuphError::InvalidCueSheet { line: 0, .. })));
+    }
+
+    #[test]
+    fn cue_time_bounds() {
+        assert_eq!(parse_cue_time("00:00:75", 44_100), None);
+        assert_eq!(parse_cue_time("00:60:00", 44_100), None);
+        assert_eq!(parse_cue_time("00:00", 44_100), None);
+        assert_eq!(parse_cue_time("999:59:74", 75), Some(999 * 60 * 75 + 59 * 75 + 74));
+    }
+}
@@ -1,38 +1,85 @@
-use std::io::{Read, Seek, SeekFrom};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 use serde::{Serialize, Deserialize};
 use crc32fast::Hasher;
 
+use crate::euph_artwork::Artwork;
+use crate::euph_audio::{self, AudioCodec, AudioFormat, DecodedAudio};
+use crate::euph_cues::CueSheet;
+use crate::euph_codec::ChunkCodec;
+use crate::euph_crypto::{self, ChunkCipher, KeyProvider, Keys};
+use crate::euph_encoder::{canonical_json, EuphEncoder, SignatureData, TimestampSource};
+use crate::euph_export::{self, ExportFormat, ExportTags};
+use crate::euph_loudness::Loudness;
+use crate::euph_lyrics::{Lyrics, LyricLine};
+use crate::euph_metadata::MetadataWarning;
+pub use crate::euph_metadata::{EuphMetadata, SpatialProfile};
+use crate::euph_peaks::Peaks;
+use crate::euph_seek::{self, SeekTable};
+use crate::euph_signature::{self, IntegrityHasher, SignatureStatus};
+
 const EUPH_MAGIC: &[u8; 4] = b"EUPH";
 const VERSION_MAJOR: u8 = 1;
-const VERSION_MINOR: u8 = 0;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct EuphMetadata {
-    pub genre: String,
-    pub subgenre: Vec<String>,
-    pub mood: Vec<String>,
-    pub tempo: f32,
-    pub key: String,
-    pub time_signature: String,
-    pub energy: f32,
-    pub valence: f32,
-    pub spatial_profile: SpatialProfile,
-}
+// Header compression flags
+pub const FLAG_AUDIO_COMPRESSED: u16 = 0x0001;
+pub const FLAG_METADATA_COMPRESSED: u16 = 0x0002;
+pub const FLAG_DSP_COMPRESSED: u16 = 0x0004;
+pub const FLAG_AI_COMPRESSED: u16 = 0x0008;
+
+// Chunk table flags
+pub const CHUNK_FLAG_COMPRESSED: u32 = 0x0001;
+/// A CRC32 of the chunk body is stored in the 4 bytes following it.
+pub const CHUNK_FLAG_CRC: u32 = 0x0002;
+/// The chunk body is sealed with the cipher and key named in the chunk flags.
+pub const CHUNK_FLAG_ENCRYPTED: u32 = 0x0004;
+
+// Well-known chunk roles
+pub const ROLE_ORIGINAL: &str = "original";
+pub const ROLE_ENHANCED: &str = "enhanced";
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct SpatialProfile {
-    pub width: f32,
-    pub depth: f32,
-    pub height: f32,
+/// Size of the fixed header of the spec layout: magic, version, flags, length,
+/// CRC, created/modified timestamps and chunk count.
+pub(crate) const SPEC_HEADER_SIZE: u64 = 40;
+/// Size of one chunk table entry: type, offset, size and flags.
+pub(crate) const CHUNK_TABLE_ENTRY_SIZE: u64 = 24;
+/// Offset of the first byte covered by the file CRC, right after the CRC field.
+pub(crate) const FILE_CRC_START: u64 = 20;
+/// Size of the legacy header: magic, version and chunk count.
+const LEGACY_HEADER_SIZE: u64 = 10;
+
+/// On-disk layout an EUPH file was written with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EuphLayout {
+    /// Magic, version, chunk count and inline `type/size/data` chunks, as
+    /// written by the wasm `EuphEncoder::encode`.
+    Legacy,
+    /// Header, timestamps and chunk table as described in `docs/euph-format-spec.md`.
+    Spec,
 }
 
+/// Parsed header and chunk table of an EUPH file, backed by `source`.
+///
+/// `EuphContainer::parse` loads the whole file into memory, so chunk bodies
+/// are available through `chunk_data`. `EuphContainer::open` only reads the
+/// header and chunk table, and chunk bodies are streamed from the source on
+/// demand through `chunk_reader`. Both decompress chunks with the codec
+/// recorded in their flags; the `raw_*` accessors return chunk bodies as stored.
 #[derive(Debug)]
-pub struct EuphContainer {
+pub struct EuphContainer<S = Cursor<Vec<u8>>> {
+    source: S,
+    layout: EuphLayout,
     version: (u8, u8),
     flags: u16,
-    chunks: HashMap<ChunkType, ChunkData>,
+    created: u64,
+    modified: u64,
+    chunks: Vec<ChunkData>,
+    roles_chunk: Option<ChunkData>,
     metadata: Option<EuphMetadata>,
+    metadata_warnings: Vec<MetadataWarning>,
+    problems: Vec<EuphError>,
+    keys: Keys,
+    limits: ParseLimits,
 }
 
 #[derive(Debug, Hash, Eq, PartialEq, Clone, Copy)]
@@ -43,18 +90,523 @@ pub enum ChunkType {
     DspChain,
     Relativistic,
     Signature,
+    /// Seek table of the AUDIO chunk with the same role, mapping sample
+    /// positions to independently compressed blocks. The encoder writes it
+    /// along with the AUDIO chunk.
+    Seek,
+    /// Chapters, loops and hot cues of the audio, as a JSON `CueSheet`.
+    Cues,
+    /// Time-synced lyrics, one set per language, as JSON `Lyrics`.
+    Lyrics,
+    /// Cover art and other pictures, see `Artwork::to_bytes`.
+    Artwork,
+    /// Loudness of the audio and its ReplayGain gains, as JSON `Loudness`.
+    Loudness,
+    /// Waveform overviews of the audio, see `Peaks::to_bytes`.
+    Peaks,
+    /// Roles of the other chunks, keyed by their position in the chunk table.
+    /// The container reads it while opening the file and does not list it
+    /// among its chunks; the encoder writes it when any chunk has a role.
+    Roles,
+    /// Any other chunk type, identified by its four-character code (for
+    /// example `*b"ANLY"`). Build these with `ChunkType::from_fourcc` so a
+    /// code that names a built-in type maps to that type.
+    Custom([u8; 4]),
+}
+
+/// The four-character code, with bytes outside printable ASCII escaped.
+impl std::fmt::Display for ChunkType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.fourcc().escape_ascii())
+    }
+}
+
+impl ChunkType {
+    /// Chunk type identifier as stored in the chunk table.
+    pub fn id(self) -> u32 {
+        match self {
+            ChunkType::Audio => 0x41554449,
+            ChunkType::Metadata => 0x4D455441,
+            ChunkType::AiModel => 0x41494D4F,
+            ChunkType::DspChain => 0x44535043,
+            ChunkType::Relativistic => 0x52454C41,
+            ChunkType::Signature => 0x5349474E,
+            ChunkType::Seek => 0x5345454B,
+            ChunkType::Cues => 0x43554553,
+            ChunkType::Lyrics => 0x4C595243,
+            ChunkType::Artwork => 0x50494354,
+            ChunkType::Loudness => 0x4C4F5544,
+            ChunkType::Peaks => 0x5045414B,
+            ChunkType::Roles => 0x524F4C45,
+            ChunkType::Custom(fourcc) => u32::from_be_bytes(fourcc),
+        }
+    }
+
+    pub fn from_id(id: u32) -> Self {
+        match id {
+            0x41554449 => ChunkType::Audio,
+            0x4D455441 => ChunkType::Metadata,
+            0x41494D4F => ChunkType::AiModel,
+            0x44535043 => ChunkType::DspChain,
+            0x52454C41 => ChunkType::Relativistic,
+            0x5349474E => ChunkType::Signature,
+            0x5345454B => ChunkType::Seek,
+            0x43554553 => ChunkType::Cues,
+            0x4C595243 => ChunkType::Lyrics,
+            0x50494354 => ChunkType::Artwork,
+            0x4C4F5544 => ChunkType::Loudness,
+            0x5045414B => ChunkType::Peaks,
+            0x524F4C45 => ChunkType::Roles,
+            id => ChunkType::Custom(id.to_be_bytes()),
+        }
+    }
+
+    /// Four-character code of the type, e.g. `*b"AUDI"` for `Audio`.
+    pub fn fourcc(self) -> [u8; 4] {
+        self.id().to_be_bytes()
+    }
+
+    pub fn from_fourcc(fourcc: [u8; 4]) -> Self {
+        Self::from_id(u32::from_be_bytes(fourcc))
+    }
+
+    /// Header flag announcing that chunks of this type are compressed.
+    pub fn compression_flag(self) -> Option<u16> {
+        match self {
+            ChunkType::Audio => Some(FLAG_AUDIO_COMPRESSED),
+            ChunkType::Metadata => Some(FLAG_METADATA_COMPRESSED),
+            ChunkType::DspChain => Some(FLAG_DSP_COMPRESSED),
+            ChunkType::AiModel => Some(FLAG_AI_COMPRESSED),
+            ChunkType::Relativistic
+            | ChunkType::Signature
+            | ChunkType::Seek
+            | ChunkType::Cues
+            | ChunkType::Lyrics
+            | ChunkType::Artwork
+            | ChunkType::Loudness
+            | ChunkType::Peaks
+            | ChunkType::Roles
+            | ChunkType::Custom(_) => None,
+        }
+    }
 }
 
+/// Chunk table entry; the body lives in the container's source.
 #[derive(Debug)]
 pub struct ChunkData {
+    chunk_type: ChunkType,
+    role: Option<String>,
+    index: usize,
+    /// Position in the chunk table, which differs from `index` when entries
+    /// were skipped in lenient mode or hidden like the ROLES chunk.
+    table_index: u32,
     offset: u64,
     size: u64,
     flags: u32,
-    data: Vec<u8>,
+}
+
+impl ChunkData {
+    pub fn chunk_type(&self) -> ChunkType {
+        self.chunk_type
+    }
+
+    /// Label telling this chunk apart from others of the same type, such as
+    /// `ROLE_ENHANCED` or the name of a stem.
+    pub fn role(&self) -> Option<&str> {
+        self.role.as_deref()
+    }
+
+    /// Position of the chunk in `EuphContainer::chunks`, for the `*_at` accessors.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Offset of the chunk body from the start of the file.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.flags & CHUNK_FLAG_COMPRESSED != 0
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & CHUNK_FLAG_ENCRYPTED != 0
+    }
+
+    /// Cipher and key id the chunk body was sealed with, from the chunk flags.
+    pub fn cipher(&self) -> Result<Option<(ChunkCipher, u8)>, EuphError> {
+        ChunkCipher::from_chunk_flags(self.flags)
+    }
+
+    /// Codec the chunk body was compressed with, from the chunk flags.
+    pub fn codec(&self) -> Result<ChunkCodec, EuphError> {
+        ChunkCodec::from_chunk_flags(self.flags)
+    }
+
+    fn has_crc(&self) -> bool {
+        self.flags & CHUNK_FLAG_CRC != 0
+    }
+
+    /// Offset just past the body and its CRC, if any, or `None` if the
+    /// table entry points past the addressable range.
+    fn checked_end(&self) -> Option<u64> {
+        let crc_len = if self.has_crc() { 4 } else { 0 };
+        self.offset.checked_add(self.size)?.checked_add(crc_len)
+    }
+
+    /// `checked_end` for entries that have already been validated.
+    fn end(&self) -> u64 {
+        self.checked_end().unwrap_or(u64::MAX)
+    }
+
+    fn range(&self) -> Option<std::ops::Range<usize>> {
+        let start = usize::try_from(self.offset).ok()?;
+        Some(start..start.checked_add(usize::try_from(self.size).ok()?)?)
+    }
+}
+
+/// Entry of the ROLES chunk body, a JSON array naming the role of the chunk
+/// at `index` in the chunk table.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ChunkRole {
+    pub index: u32,
+    pub role: String,
+}
+
+/// Options controlling how strictly `EuphContainer` treats damaged files.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DecodingOptions {
+    /// Record checksum, length and truncation problems in
+    /// `EuphContainer::problems` and keep whatever could be loaded, instead of
+    /// failing on the first one.
+    pub lenient: bool,
+    pub limits: ParseLimits,
+}
+
+/// Upper bounds on what a file can make the decoder allocate, so malformed or
+/// hostile input fails with an error instead of exhausting memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseLimits {
+    /// Largest chunk body, both as stored and once decompressed.
+    pub max_chunk_size: u64,
+    /// Most entries a chunk table may list.
+    pub max_chunk_count: u32,
+    /// Largest file `parse` loads into memory, and largest sum of the chunk
+    /// sizes in a chunk table.
+    pub max_total_size: u64,
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        Self {
+            max_chunk_size: 1 << 30,
+            max_chunk_count: 4096,
+            max_total_size: 4 << 30,
+        }
+    }
+}
+
+impl ParseLimits {
+    pub const UNLIMITED: ParseLimits = ParseLimits {
+        max_chunk_size: u64::MAX,
+        max_chunk_count: u32::MAX,
+        max_total_size: u64::MAX,
+    };
+
+    fn check_chunk_count(&self, count: u32) -> Result<(), EuphError> {
+        if count > self.max_chunk_count {
+            return Err(EuphError::TooManyChunks { count, limit: self.max_chunk_count });
+        }
+        Ok(())
+    }
+}
+
+/// Collects recoverable problems in lenient mode, or fails on the first one.
+struct ProblemLog {
+    lenient: bool,
+    problems: Vec<EuphError>,
+}
+
+impl ProblemLog {
+    fn report(&mut self, problem: EuphError) -> Result<(), EuphError> {
+        if self.lenient {
+            self.problems.push(problem);
+            Ok(())
+        } else {
+            Err(problem)
+        }
+    }
+}
+
+/// Streams one chunk body from a container's source, checking the chunk CRC
+/// once the end of the body is reached.
+pub struct ChunkReader<'a, R> {
+    inner: std::io::Take<&'a mut R>,
+    hasher: Hasher,
+    expected_crc: Option<u32>,
+}
+
+impl<R: Read> Read for ChunkReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            self.hasher.update(&buf[..n]);
+        } else if !buf.is_empty() {
+            if let Some(expected) = self.expected_crc.take() {
+                if self.hasher.clone().finalize() != expected {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "EUPH chunk checksum mismatch"));
+                }
+            }
+        }
+        Ok(n)
+    }
 }
 
 impl EuphContainer {
+    /// Parse an EUPH file written in either the legacy or the spec layout,
+    /// failing on any checksum or length mismatch.
     pub fn parse<R: Read + Seek>(reader: &mut R) -> Result<Self, EuphError> {
+        Self::parse_with_options(reader, DecodingOptions::default())
+    }
+
+    /// Load the whole file into memory and verify every checksum in it.
+    pub fn parse_with_options<R: Read + Seek>(reader: &mut R, options: DecodingOptions) -> Result<Self, EuphError> {
+        let stream_len = reader.seek(SeekFrom::End(0))?;
+        let limit = options.limits.max_total_size;
+        if stream_len > limit {
+            return Err(EuphError::FileTooLarge { size: stream_len, limit });
+        }
+
+        let mut data = Vec::new();
+        reader.seek(SeekFrom::Start(0))?;
+        reader.take(limit.saturating_add(1)).read_to_end(&mut data)?;
+        if data.len() as u64 > limit {
+            return Err(EuphError::FileTooLarge { size: data.len() as u64, limit });
+        }
+
+        let mut container = Self::open_with_options(Cursor::new(data), options)?;
+        let mut log = ProblemLog {
+            lenient: options.lenient,
+            problems: std::mem::take(&mut container.problems),
+        };
+        container.verify_checksums(&mut log)?;
+        // Encrypted metadata waits for a key provider and `read_metadata`
+        (container.metadata, container.metadata_warnings) = match container.chunk_data(ChunkType::Metadata) {
+            Ok(data) => parse_metadata(&data),
+            Err(EuphError::MissingChunk(_) | EuphError::MissingKey { .. }) => (None, Vec::new()),
+            Err(e) => return Err(e),
+        };
+        container.problems = log.problems;
+        Ok(container)
+    }
+}
+
+impl<T: AsRef<[u8]>> EuphContainer<Cursor<T>> {
+    /// Body of the first chunk of a type as stored, borrowed from the
+    /// in-memory or mapped source.
+    pub fn raw_chunk_bytes(&self, chunk_type: ChunkType) -> Option<&[u8]> {
+        self.raw_chunk_bytes_at(self.chunk(chunk_type)?.index)
+    }
+
+    pub fn raw_chunk_bytes_at(&self, index: usize) -> Option<&[u8]> {
+        let chunk = self.chunks.get(index)?;
+        self.source.get_ref().as_ref().get(chunk.range()?)
+    }
+
+    /// Body of the first chunk of a type, decompressed if it is stored compressed.
+    pub fn chunk_data(&self, chunk_type: ChunkType) -> Result<Cow<'_, [u8]>, EuphError> {
+        let chunk = self.chunk(chunk_type).ok_or(EuphError::MissingChunk(chunk_type))?;
+        self.chunk_data_at(chunk.index)
+    }
+
+    pub fn chunk_data_at(&self, index: usize) -> Result<Cow<'_, [u8]>, EuphError> {
+        let chunk = self.chunks.get(index).ok_or(EuphError::ChunkIndexOutOfRange(index))?;
+        let raw = self.raw_chunk_bytes_at(index).ok_or(EuphError::MissingChunk(chunk.chunk_type))?;
+        self.decode_chunk(chunk, raw)
+    }
+
+    /// Embedded audio file, decompressed if it is stored compressed and
+    /// without the format descriptor.
+    pub fn get_audio_data(&self) -> Result<Cow<'_, [u8]>, EuphError> {
+        let chunk = self.chunk(ChunkType::Audio).ok_or(EuphError::MissingAudioChunk)?;
+        self.get_audio_data_at(chunk.index)
+    }
+
+    pub fn get_audio_data_at(&self, index: usize) -> Result<Cow<'_, [u8]>, EuphError> {
+        euph_audio::strip_audio_format(self.chunk_data_at(index)?)
+    }
+
+    /// Format descriptor of the first AUDIO chunk, `None` for files written
+    /// without one. Only the start of the chunk is decompressed.
+    pub fn audio_format(&self) -> Result<Option<AudioFormat>, EuphError> {
+        let chunk = self.chunk(ChunkType::Audio).ok_or(EuphError::MissingAudioChunk)?;
+        let raw = self.raw_chunk_bytes_at(chunk.index).ok_or(EuphError::MissingAudioChunk)?;
+        let reader = self.body_reader(chunk, raw)?;
+        read_audio_format(reader)
+    }
+
+    /// Chapters, loops and hot cues, `None` for files without a CUES chunk.
+    pub fn cues(&self) -> Result<Option<CueSheet>, EuphError> {
+        match self.chunk_data(ChunkType::Cues) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(EuphError::MissingChunk(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Synced lyrics, `None` for files without a LYRC chunk.
+    pub fn lyrics(&self) -> Result<Option<Lyrics>, EuphError> {
+        match self.chunk_data(ChunkType::Lyrics) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(EuphError::MissingChunk(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Pictures of the PICT chunk, `None` for files without one.
+    pub fn artwork(&self) -> Result<Option<Artwork>, EuphError> {
+        match self.chunk_data(ChunkType::Artwork) {
+            Ok(data) => Ok(Some(Artwork::parse(&data)?)),
+            Err(EuphError::MissingChunk(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Loudness measured by the encoder, `None` for files without a LOUD
+    /// chunk.
+    pub fn loudness(&self) -> Result<Option<Loudness>, EuphError> {
+        match self.chunk_data(ChunkType::Loudness) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(EuphError::MissingChunk(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Waveform overviews of the audio, `None` for files without a PEAK
+    /// chunk.
+    pub fn peaks(&self) -> Result<Option<Peaks>, EuphError> {
+        match self.chunk_data(ChunkType::Peaks) {
+            Ok(data) => Ok(Some(Peaks::parse(&data)?)),
+            Err(EuphError::MissingChunk(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Lyric line being sung `time` seconds into playback, in `language` or
+    /// the first language when `None`. Parses the LYRC chunk on every call;
+    /// players polling it should keep the result of `lyrics` instead.
+    pub fn lyric_line_at(&self, time: f64, language: Option<&str>) -> Result<Option<LyricLine>, EuphError> {
+        let Some(lyrics) = self.lyrics()? else {
+            return Ok(None);
+        };
+        Ok(lyrics.line_at(language, (time * 1000.0) as u64).cloned())
+    }
+
+    /// Embedded audio written as `format` with the metadata, artwork,
+    /// lyrics and chapters of this file as its tags. Audio that has to be
+    /// transcoded is decoded within the chunk size limit.
+    pub fn export(&self, format: ExportFormat) -> Result<Vec<u8>, EuphError> {
+        let tags = ExportTags::from_container(self)?;
+        euph_export::export_audio(self.get_audio_data()?.into_owned(), &tags, format, self.limits.max_chunk_size)
+    }
+
+    pub fn get_raw_audio_data(&self) -> Option<&[u8]> {
+        self.raw_chunk_bytes(ChunkType::Audio)
+    }
+
+    /// Embedded audio decoded to PCM.
+    pub fn decode_audio(&self) -> Result<DecodedAudio, EuphError> {
+        let chunk = self.chunk(ChunkType::Audio).ok_or(EuphError::MissingAudioChunk)?;
+        self.decode_audio_at(chunk.index)
+    }
+
+    /// AUDIO chunk at a chunk table position decoded to PCM, e.g. one found
+    /// with `chunks_with_role`.
+    pub fn decode_audio_at(&self, index: usize) -> Result<DecodedAudio, EuphError> {
+        let data = self.get_audio_data_at(index)?.into_owned();
+        euph_audio::decode_audio(data, self.limits.max_chunk_size)
+    }
+
+    pub fn get_ai_enhanced_audio(&self) -> Result<DecodedAudio, EuphError> {
+        let audio = self.decode_audio()?;
+        let ai_model = self.chunk_data(ChunkType::AiModel).map_err(|e| match e {
+            EuphError::MissingChunk(_) => EuphError::MissingAiModel,
+            e => e,
+        })?;
+        
+        // Apply AI enhancement
+        let enhanced = self.apply_ai_enhancement(audio, &ai_model)?;
+        Ok(enhanced)
+    }
+
+    fn apply_ai_enhancement(&self, audio: DecodedAudio, _model_data: &[u8]) -> Result<DecodedAudio, EuphError> {
+        // This would integrate with ONNX runtime or custom AI inference
+        // For now, passing the decoded audio through unchanged
+        Ok(audio)
+    }
+
+    /// Check the per-chunk CRCs and, for the spec layout, the whole-file CRC.
+    fn verify_checksums(&self, log: &mut ProblemLog) -> Result<(), EuphError> {
+        let data = self.source.get_ref().as_ref();
+
+        for chunk in &self.chunks {
+            if !chunk.has_crc() {
+                continue;
+            }
+            let crc_start = (chunk.offset + chunk.size) as usize;
+            let stored = u32::from_le_bytes(data[crc_start..crc_start + 4].try_into().unwrap());
+
+            let body = chunk.range().and_then(|range| data.get(range)).unwrap_or_default();
+            if crc32fast::hash(body) != stored {
+                log.report(EuphError::ChecksumMismatch { chunk: Some(chunk.chunk_type) })?;
+            }
+        }
+
+        if self.layout == EuphLayout::Spec {
+            let expected = u32::from_le_bytes(data[16..20].try_into().unwrap());
+            if crc32fast::hash(&data[FILE_CRC_START as usize..]) != expected {
+                log.report(EuphError::ChecksumMismatch { chunk: None })?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+impl EuphContainer<Cursor<memmap2::Mmap>> {
+    /// Open a file by mapping it into memory, so chunk bodies can be borrowed
+    /// through `raw_chunk_bytes` without copying. Like `open`, this reads only the
+    /// header and chunk table and does not verify checksums.
+    pub fn open_mmap<P: AsRef<std::path::Path>>(path: P, options: DecodingOptions) -> Result<Self, EuphError> {
+        let file = std::fs::File::open(path)?;
+        // SAFETY: the map is read-only; as with any mapped file, another
+        // process truncating it while mapped is outside our control.
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        Self::open_with_options(Cursor::new(map), options)
+    }
+}
+
+impl<R: Read + Seek> EuphContainer<R> {
+    /// Read only the header and chunk table of an EUPH file, leaving chunk
+    /// bodies in `reader` to be fetched with `chunk_reader`.
+    pub fn open(reader: R) -> Result<Self, EuphError> {
+        Self::open_with_options(reader, DecodingOptions::default())
+    }
+
+    pub fn open_with_options(mut reader: R, options: DecodingOptions) -> Result<Self, EuphError> {
+        let stream_len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+
         // Read and verify magic
         let mut magic = [0u8; 4];
         reader.read_exact(&mut magic)?;
@@ -65,62 +617,126 @@ impl EuphContainer {
         // Read version
         let mut version = [0u8; 2];
         reader.read_exact(&mut version)?;
-        
+        if version[0] != VERSION_MAJOR {
+            return Err(EuphError::InvalidVersion);
+        }
+
+        let mut log = ProblemLog {
+            lenient: options.lenient,
+            problems: Vec::new(),
+        };
+
+        let mut container = EuphContainer {
+            source: reader,
+            layout: EuphLayout::Spec,
+            version: (version[0], version[1]),
+            flags: 0,
+            created: 0,
+            modified: 0,
+            chunks: Vec::new(),
+            roles_chunk: None,
+            metadata: None,
+            metadata_warnings: Vec::new(),
+            problems: Vec::new(),
+            keys: Keys::default(),
+            limits: options.limits,
+        };
+
+        container.layout = Self::detect_layout(&mut container.source, stream_len)?;
+        match container.layout {
+            EuphLayout::Legacy => container.read_legacy_chunks(stream_len, &mut log)?,
+            EuphLayout::Spec => container.read_spec_header(stream_len, &mut log)?,
+        }
+        container.problems = log.problems;
+        Ok(container)
+    }
+
+    /// Both layouts share magic and version, so tell them apart by what
+    /// follows: the legacy layout has the first chunk's ASCII type at offset
+    /// 10, where the spec layout has the middle bytes of the file length.
+    /// Only a legacy file without chunks ends before that type; any other
+    /// stream too short to show it, or a spec header cut short, is truncated.
+    fn detect_layout(reader: &mut R, stream_len: u64) -> Result<EuphLayout, EuphError> {
+        let probe_end = LEGACY_HEADER_SIZE + 4;
+        if stream_len < probe_end {
+            let mut count = [0u8; 4];
+            let empty_legacy = stream_len == LEGACY_HEADER_SIZE
+                && reader.read_exact(&mut count).is_ok()
+                && count == [0; 4];
+            reader.seek(SeekFrom::Start(6))?;
+            return match empty_legacy {
+                true => Ok(EuphLayout::Legacy),
+                false => Err(EuphError::Truncated { expected: probe_end, actual: stream_len }),
+            };
+        }
+
+        let mut probe = [0u8; 4];
+        reader.seek(SeekFrom::Start(LEGACY_HEADER_SIZE))?;
+        reader.read_exact(&mut probe)?;
+        reader.seek(SeekFrom::Start(6))?;
+
+        let is_type_tag = probe[0].is_ascii_uppercase()
+            && probe[1..].iter().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || *b == b'_' || *b == b' ');
+        if is_type_tag {
+            return Ok(EuphLayout::Legacy);
+        }
+        if stream_len < SPEC_HEADER_SIZE {
+            return Err(EuphError::Truncated { expected: SPEC_HEADER_SIZE, actual: stream_len });
+        }
+        Ok(EuphLayout::Spec)
+    }
+
+    fn read_spec_header(&mut self, stream_len: u64, log: &mut ProblemLog) -> Result<(), EuphError> {
+        let reader = &mut self.source;
+
         // Read flags
         let mut flags_bytes = [0u8; 2];
         reader.read_exact(&mut flags_bytes)?;
-        let flags = u16::from_le_bytes(flags_bytes);
+        self.flags = u16::from_le_bytes(flags_bytes);
 
         // Read total length
         let mut length_bytes = [0u8; 8];
         reader.read_exact(&mut length_bytes)?;
         let total_length = u64::from_le_bytes(length_bytes);
+        if stream_len < total_length {
+            log.report(EuphError::Truncated { expected: total_length, actual: stream_len })?;
+        } else if stream_len > total_length {
+            log.report(EuphError::LengthMismatch { expected: total_length, actual: stream_len })?;
+        }
 
-        // Read and verify CRC32
-        let mut crc_bytes = [0u8; 4];
-        reader.read_exact(&mut crc_bytes)?;
-        let expected_crc = u32::from_le_bytes(crc_bytes);
+        // Skip the CRC32, which covers the whole file and is only verified
+        // when the file is loaded with `parse`
+        reader.seek(SeekFrom::Current(4))?;
 
-        // Read chunks
-        let chunks = Self::read_chunks(reader)?;
-        
-        // Parse metadata if present
-        let metadata = if let Some(meta_chunk) = chunks.get(&ChunkType::Metadata) {
-            Some(serde_json::from_slice(&meta_chunk.data)?)
-        } else {
-            None
-        };
+        // Read timestamps
+        let mut timestamp_bytes = [0u8; 8];
+        reader.read_exact(&mut timestamp_bytes)?;
+        self.created = u64::from_le_bytes(timestamp_bytes);
+        reader.read_exact(&mut timestamp_bytes)?;
+        self.modified = u64::from_le_bytes(timestamp_bytes);
 
-        Ok(EuphContainer {
-            version: (version[0], version[1]),
-            flags,
-            chunks,
-            metadata,
-        })
+        self.read_chunk_table(stream_len, log)
     }
 
-    fn read_chunks<R: Read + Seek>(reader: &mut R) -> Result<HashMap<ChunkType, ChunkData>, EuphError> {
-        let mut chunks = HashMap::new();
-        
+    fn read_chunk_table(&mut self, stream_len: u64, log: &mut ProblemLog) -> Result<(), EuphError> {
+        let reader = &mut self.source;
+
         // Read chunk count
         let mut chunk_count_bytes = [0u8; 4];
         reader.read_exact(&mut chunk_count_bytes)?;
         let chunk_count = u32::from_le_bytes(chunk_count_bytes);
+        self.limits.check_chunk_count(chunk_count)?;
+
+        let table_end = SPEC_HEADER_SIZE + chunk_count as u64 * CHUNK_TABLE_ENTRY_SIZE;
+        if table_end > stream_len {
+            return Err(EuphError::Truncated { expected: table_end, actual: stream_len });
+        }
 
-        for _ in 0..chunk_count {
+        let mut total_size = 0u64;
+        for table_index in 0..chunk_count {
             // Read chunk header
             let mut type_bytes = [0u8; 4];
             reader.read_exact(&mut type_bytes)?;
-            
-            let chunk_type = match u32::from_le_bytes(type_bytes) {
-                0x41554449 => ChunkType::Audio,
-                0x4D455441 => ChunkType::Metadata,
-                0x41494D4F => ChunkType::AiModel,
-                0x44535043 => ChunkType::DspChain,
-                0x52454C41 => ChunkType::Relativistic,
-                0x5349474E => ChunkType::Signature,
-                _ => continue,
-            };
 
             let mut offset_bytes = [0u8; 8];
             reader.read_exact(&mut offset_bytes)?;
@@ -134,41 +750,747 @@ impl EuphContainer {
             reader.read_exact(&mut flags_bytes)?;
             let flags = u32::from_le_bytes(flags_bytes);
 
-            // Read chunk data
-            let current_pos = reader.stream_position()?;
+            let chunk_type = ChunkType::from_id(u32::from_le_bytes(type_bytes));
+
+            let chunk = ChunkData { chunk_type, role: None, index: self.chunks.len(), table_index, offset, size, flags };
+            let Some(end) = chunk.checked_end().filter(|_| offset >= table_end) else {
+                log.report(EuphError::InvalidChunkBounds { chunk: chunk_type })?;
+                continue;
+            };
+            if size > self.limits.max_chunk_size {
+                log.report(EuphError::ChunkTooLarge { chunk: chunk_type, size, limit: self.limits.max_chunk_size })?;
+                continue;
+            }
+            if end > stream_len {
+                log.report(EuphError::Truncated { expected: end, actual: stream_len })?;
+                continue;
+            }
+
+            total_size = total_size.saturating_add(size);
+            if total_size > self.limits.max_total_size {
+                return Err(EuphError::FileTooLarge { size: total_size, limit: self.limits.max_total_size });
+            }
+
+            if chunk_type == ChunkType::Roles {
+                self.roles_chunk = Some(chunk);
+            } else {
+                self.chunks.push(chunk);
+            }
+        }
+
+        // The chunk flags are what decoding follows; the header flag only has
+        // to agree with them, and is set when any chunk of its type is compressed
+        for chunk_type in [ChunkType::Audio, ChunkType::Metadata, ChunkType::DspChain, ChunkType::AiModel] {
+            let Some(header_flag) = chunk_type.compression_flag() else { continue };
+            let mut chunks = self.chunks_of_type(chunk_type).peekable();
+            if chunks.peek().is_none() {
+                continue;
+            }
+            let header_compressed = self.flags & header_flag != 0;
+            let chunk_compressed = chunks.any(ChunkData::is_compressed);
+            if header_compressed != chunk_compressed {
+                log.report(EuphError::CompressionFlagMismatch { chunk: chunk_type, header_compressed, chunk_compressed })?;
+            }
+        }
+
+        if self.roles_chunk.is_some() {
+            self.read_roles(log)?;
+        }
+
+        Ok(())
+    }
+
+    /// Load the ROLES chunk and attach each role to the chunk it names.
+    fn read_roles(&mut self, log: &mut ProblemLog) -> Result<(), EuphError> {
+        let Some(roles_chunk) = &self.roles_chunk else { return Ok(()) };
+        let mut raw = vec![0u8; roles_chunk.size as usize];
+        self.source.seek(SeekFrom::Start(roles_chunk.offset))?;
+        self.source.read_exact(&mut raw)?;
+
+        if roles_chunk.has_crc() {
+            let mut crc_bytes = [0u8; 4];
+            self.source.read_exact(&mut crc_bytes)?;
+            if crc32fast::hash(&raw) != u32::from_le_bytes(crc_bytes) {
+                return log.report(EuphError::ChecksumMismatch { chunk: Some(ChunkType::Roles) });
+            }
+        }
+
+        let data = self.decode_chunk(roles_chunk, &raw)?;
+        let roles: Vec<ChunkRole> = match serde_json::from_slice(&data) {
+            Ok(roles) => roles,
+            Err(e) => return log.report(EuphError::JsonError(e)),
+        };
+        // Roles refer to table positions, which entries skipped in lenient
+        // mode must not shift
+        for ChunkRole { index, role } in roles {
+            if let Ok(position) = self.chunks.binary_search_by_key(&index, |chunk| chunk.table_index) {
+                self.chunks[position].role = Some(role);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_legacy_chunks(&mut self, stream_len: u64, log: &mut ProblemLog) -> Result<(), EuphError> {
+        let reader = &mut self.source;
+
+        let mut chunk_count_bytes = [0u8; 4];
+        reader.read_exact(&mut chunk_count_bytes)?;
+        let chunk_count = u32::from_le_bytes(chunk_count_bytes);
+        self.limits.check_chunk_count(chunk_count)?;
+
+        let mut offset = LEGACY_HEADER_SIZE;
+        for table_index in 0..chunk_count {
+            // Legacy chunks are stored back to back, so nothing after a
+            // truncated one can be located
+            if offset + 8 > stream_len {
+                log.report(EuphError::Truncated { expected: offset + 8, actual: stream_len })?;
+                break;
+            }
+
+            // Legacy chunk types are the chunk name truncated to its first
+            // four ASCII characters, i.e. the four-character code
             reader.seek(SeekFrom::Start(offset))?;
-            let mut data = vec![0u8; size as usize];
-            reader.read_exact(&mut data)?;
-            reader.seek(SeekFrom::Start(current_pos))?;
+            let mut type_bytes = [0u8; 4];
+            reader.read_exact(&mut type_bytes)?;
+
+            let mut size_bytes = [0u8; 4];
+            reader.read_exact(&mut size_bytes)?;
+            let size = u32::from_le_bytes(size_bytes) as u64;
 
-            chunks.insert(chunk_type, ChunkData {
-                offset,
+            let chunk = ChunkData {
+                chunk_type: ChunkType::from_fourcc(type_bytes),
+                role: None,
+                index: self.chunks.len(),
+                table_index,
+                offset: offset + 8,
                 size,
-                flags,
-                data,
+                flags: 0,
+            };
+            if size > self.limits.max_chunk_size {
+                log.report(EuphError::ChunkTooLarge { chunk: chunk.chunk_type, size, limit: self.limits.max_chunk_size })?;
+                break;
+            }
+            if chunk.end() > stream_len {
+                log.report(EuphError::Truncated { expected: chunk.end(), actual: stream_len })?;
+                break;
+            }
+            offset = chunk.end();
+
+            self.chunks.push(chunk);
+        }
+
+        Ok(())
+    }
+
+    fn index_of(&self, chunk_type: ChunkType) -> Result<usize, EuphError> {
+        self.chunk(chunk_type).map(ChunkData::index).ok_or(EuphError::MissingChunk(chunk_type))
+    }
+
+    /// Stream the body of the first chunk of a type as stored. The chunk CRC,
+    /// when present, is checked once the reader reaches the end of the body
+    /// and a mismatch is reported as an `InvalidData` I/O error.
+    pub fn raw_chunk_reader(&mut self, chunk_type: ChunkType) -> Result<ChunkReader<'_, R>, EuphError> {
+        let index = self.index_of(chunk_type)?;
+        self.raw_chunk_reader_at(index)
+    }
+
+    pub fn raw_chunk_reader_at(&mut self, index: usize) -> Result<ChunkReader<'_, R>, EuphError> {
+        let chunk = self.chunks.get(index).ok_or(EuphError::ChunkIndexOutOfRange(index))?;
+        let (offset, size) = (chunk.offset, chunk.size);
+
+        let expected_crc = if chunk.has_crc() {
+            let mut crc_bytes = [0u8; 4];
+            self.source.seek(SeekFrom::Start(offset + size))?;
+            self.source.read_exact(&mut crc_bytes)?;
+            Some(u32::from_le_bytes(crc_bytes))
+        } else {
+            None
+        };
+
+        self.source.seek(SeekFrom::Start(offset))?;
+        Ok(ChunkReader {
+            inner: (&mut self.source).take(size),
+            hasher: Hasher::new(),
+            expected_crc,
+        })
+    }
+
+    /// Stream the body of the first chunk of a type from the source,
+    /// decompressing it on the fly if it is stored compressed.
+    pub fn chunk_reader(&mut self, chunk_type: ChunkType) -> Result<Box<dyn Read + '_>, EuphError> {
+        let index = self.index_of(chunk_type)?;
+        self.chunk_reader_at(index)
+    }
+
+    pub fn chunk_reader_at(&mut self, index: usize) -> Result<Box<dyn Read + '_>, EuphError> {
+        let chunk = self.chunks.get(index).ok_or(EuphError::ChunkIndexOutOfRange(index))?;
+        let codec = chunk.codec()?;
+        if chunk.is_encrypted() {
+            // The authentication tag covers the whole body, so nothing can be
+            // handed out before all of it has been read
+            let (chunk_type, flags) = (chunk.chunk_type, chunk.flags);
+            let raw = self.read_raw_chunk_at(index)?;
+            let data = euph_crypto::decrypt(chunk_type, flags, &raw, &self.keys)?;
+            return codec.decoder(Cursor::new(data));
+        }
+        codec.decoder(self.raw_chunk_reader_at(index)?)
+    }
+
+    /// Read the whole body of the first chunk of a type as stored, checking its CRC.
+    pub fn read_raw_chunk(&mut self, chunk_type: ChunkType) -> Result<Vec<u8>, EuphError> {
+        let index = self.index_of(chunk_type)?;
+        self.read_raw_chunk_at(index)
+    }
+
+    pub fn read_raw_chunk_at(&mut self, index: usize) -> Result<Vec<u8>, EuphError> {
+        let mut data = Vec::new();
+        let mut reader = self.raw_chunk_reader_at(index)?;
+        if let Err(e) = reader.read_to_end(&mut data) {
+            return Err(match e.kind() {
+                std::io::ErrorKind::InvalidData => EuphError::ChecksumMismatch { chunk: Some(self.chunks[index].chunk_type) },
+                _ => EuphError::IoError(e),
             });
         }
+        Ok(data)
+    }
 
-        Ok(chunks)
+    /// Read the whole body of the first chunk of a type, checking its CRC and
+    /// decompressing it if it is stored compressed.
+    pub fn read_chunk(&mut self, chunk_type: ChunkType) -> Result<Vec<u8>, EuphError> {
+        let index = self.index_of(chunk_type)?;
+        self.read_chunk_at(index)
     }
 
-    pub fn get_audio_data(&self) -> Option<&[u8]> {
-        self.chunks.get(&ChunkType::Audio).map(|chunk| chunk.data.as_slice())
+    pub fn read_chunk_at(&mut self, index: usize) -> Result<Vec<u8>, EuphError> {
+        let raw = self.read_raw_chunk_at(index)?;
+        let chunk = &self.chunks[index];
+        Ok(self.decode_chunk(chunk, &raw)?.into_owned())
     }
 
-    pub fn get_ai_enhanced_audio(&self) -> Result<Vec<f32>, EuphError> {
-        let audio_data = self.get_audio_data().ok_or(EuphError::MissingAudioChunk)?;
-        let ai_model = self.chunks.get(&ChunkType::AiModel).ok_or(EuphError::MissingAiModel)?;
-        
-        // Apply AI enhancement
-        let enhanced = self.apply_ai_enhancement(audio_data, &ai_model.data)?;
-        Ok(enhanced)
+    /// Check the SIGNATURE chunk against the other chunks and `public_key`.
+    /// Editing any chunk after signing, including in place, makes the file
+    /// read as tampered until it is signed again.
+    pub fn verify_signature(&mut self, public_key: &ed25519_dalek::VerifyingKey) -> Result<SignatureStatus, EuphError> {
+        let Some(signature) = self.read_signature()? else {
+            return Ok(SignatureStatus::Unsigned);
+        };
+        let hash = self.integrity_hash()?;
+        Ok(euph_signature::verify(&signature, hash, public_key))
     }
 
-    fn apply_ai_enhancement(&self, audio: &[u8], model_data: &[u8]) -> Result<Vec<f32>, EuphError> {
-        // This would integrate with ONNX runtime or custom AI inference
-        // For now, returning placeholder
-        Ok(vec![0.0f32; 44100 * 2]) // 1 second stereo placeholder
+    /// Whether the chunks match the integrity hash in the SIGNATURE chunk,
+    /// signed or not. Files without one have nothing to check against.
+    pub fn verify_integrity(&mut self) -> Result<Option<bool>, EuphError> {
+        let Some(signature) = self.read_signature()? else {
+            return Ok(None);
+        };
+        let hash = self.integrity_hash()?;
+        Ok(Some(signature.integrity_hash.eq_ignore_ascii_case(&hex::encode(hash))))
+    }
+
+    fn read_signature(&mut self) -> Result<Option<SignatureData>, EuphError> {
+        match self.read_chunk(ChunkType::Signature) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(EuphError::MissingChunk(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Hash the chunks the SIGNATURE chunk covers, in chunk table order.
+    fn integrity_hash(&mut self) -> Result<[u8; 32], EuphError> {
+        let mut entries: Vec<(u32, ChunkType, u32, u64, u64)> = self.chunks.iter()
+            .chain(&self.roles_chunk)
+            .filter(|chunk| chunk.chunk_type != ChunkType::Signature)
+            .map(|chunk| (chunk.table_index, chunk.chunk_type, chunk.flags, chunk.offset, chunk.size))
+            .collect();
+        entries.sort_by_key(|entry| entry.0);
+
+        let mut hasher = IntegrityHasher::default();
+        for (_, chunk_type, flags, offset, size) in entries {
+            hasher.start_chunk(chunk_type, flags, size);
+            self.source.seek(SeekFrom::Start(offset))?;
+            std::io::copy(&mut (&mut self.source).take(size), &mut hasher)?;
+        }
+        Ok(hasher.finalize())
+    }
+
+    /// Load and cache the METADATA chunk of an opened file.
+    pub fn read_metadata(&mut self) -> Result<Option<&EuphMetadata>, EuphError> {
+        if self.metadata.is_none() && self.chunk(ChunkType::Metadata).is_some() {
+            let data = self.read_chunk(ChunkType::Metadata)?;
+            (self.metadata, self.metadata_warnings) = parse_metadata(&data);
+        }
+        Ok(self.metadata.as_ref())
+    }
+
+    /// Read the CUES chunk of an opened file, `None` if it has none.
+    pub fn read_cues(&mut self) -> Result<Option<CueSheet>, EuphError> {
+        match self.read_chunk(ChunkType::Cues) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(EuphError::MissingChunk(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Read the LYRC chunk of an opened file, `None` if it has none.
+    pub fn read_lyrics(&mut self) -> Result<Option<Lyrics>, EuphError> {
+        match self.read_chunk(ChunkType::Lyrics) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(EuphError::MissingChunk(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Read the PICT chunk of an opened file, `None` if it has none.
+    pub fn read_artwork(&mut self) -> Result<Option<Artwork>, EuphError> {
+        match self.read_chunk(ChunkType::Artwork) {
+            Ok(data) => Ok(Some(Artwork::parse(&data)?)),
+            Err(EuphError::MissingChunk(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Read the LOUD chunk of an opened file, `None` if it has none.
+    pub fn read_loudness(&mut self) -> Result<Option<Loudness>, EuphError> {
+        match self.read_chunk(ChunkType::Loudness) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(EuphError::MissingChunk(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Read the PEAK chunk of an opened file, `None` if it has none.
+    pub fn read_peaks(&mut self) -> Result<Option<Peaks>, EuphError> {
+        match self.read_chunk(ChunkType::Peaks) {
+            Ok(data) => Ok(Some(Peaks::parse(&data)?)),
+            Err(EuphError::MissingChunk(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Read the format descriptor of the first AUDIO chunk, `None` for files
+    /// written without one. Only the start of the chunk is read.
+    pub fn read_audio_format(&mut self) -> Result<Option<AudioFormat>, EuphError> {
+        let index = self.index_of(ChunkType::Audio).map_err(|_| EuphError::MissingAudioChunk)?;
+        let reader = self.chunk_reader_at(index)?;
+        read_audio_format(reader)
+    }
+
+    /// Decode `duration` seconds of the first AUDIO chunk from `start_time`.
+    /// When the chunk has a SEEK chunk, only the blocks holding those samples
+    /// are read and decompressed, without checking the chunk CRC; otherwise
+    /// the whole chunk is decoded. Reset any DSP state, such as with
+    /// `WasmDspProcessor::reset`, before processing audio from a new position.
+    pub fn read_audio_range(&mut self, start_time: f64, duration: f64) -> Result<DecodedAudio, EuphError> {
+        let index = self.index_of(ChunkType::Audio).map_err(|_| EuphError::MissingAudioChunk)?;
+        self.read_audio_range_at(index, start_time, duration)
+    }
+
+    pub fn read_audio_range_at(&mut self, index: usize, start_time: f64, duration: f64) -> Result<DecodedAudio, EuphError> {
+        let chunk = self.chunks.get(index).ok_or(EuphError::ChunkIndexOutOfRange(index))?;
+        let table = match self.seek_chunk_for(chunk).map(ChunkData::index) {
+            Some(seek_index) => Some(serde_json::from_slice::<SeekTable>(&self.read_chunk_at(seek_index)?)?),
+            None => None,
+        };
+
+        let Some(table) = table.filter(|table| table.blocks.len() > 1 && table.sample_rate > 0) else {
+            let body = self.read_chunk_at(index)?;
+            let payload = euph_audio::strip_audio_format(Cow::Owned(body))?.into_owned();
+            let audio = euph_audio::decode_audio(payload, self.limits.max_chunk_size)?;
+            let frames = euph_seek::frame_range(start_time, duration, audio.sample_rate);
+            return Ok(audio.slice_frames(frames.start, frames.end - frames.start));
+        };
+
+        let frames = euph_seek::frame_range(start_time, duration, table.sample_rate);
+        let blocks = table.block_range(frames.start, frames.end);
+        let first_frame = table.blocks[blocks.start].frame;
+        let body = self.read_audio_blocks(index, &table, blocks)?;
+        let payload = euph_audio::strip_audio_format(Cow::Owned(body))?.into_owned();
+        let audio = euph_audio::decode_audio(payload, self.limits.max_chunk_size)?;
+        Ok(audio.slice_frames(frames.start.saturating_sub(first_frame), frames.end - frames.start))
+    }
+
+    /// Decompressed header block of an AUDIO chunk followed by `blocks`.
+    fn read_audio_blocks(&mut self, index: usize, table: &SeekTable, blocks: std::ops::Range<usize>) -> Result<Vec<u8>, EuphError> {
+        let chunk = &self.chunks[index];
+        let (chunk_type, offset, codec) = (chunk.chunk_type, chunk.offset, chunk.codec()?);
+        // Blocks of an encrypted chunk are only at hand once all of it is decrypted
+        let decrypted = if chunk.is_encrypted() {
+            let flags = chunk.flags;
+            let raw = self.read_raw_chunk_at(index)?;
+            Some(euph_crypto::decrypt(chunk_type, flags, &raw, &self.keys)?)
+        } else {
+            None
+        };
+        let stored_len = decrypted.as_ref().map_or(self.chunks[index].size, |data| data.len() as u64);
+
+        let mut body = Vec::new();
+        let selected = table.blocks.first().into_iter().chain(table.blocks.get(blocks).into_iter().flatten());
+        for block in selected {
+            let end = block.stored_offset
+                .checked_add(block.stored_size)
+                .filter(|&end| end <= stored_len)
+                .ok_or(EuphError::InvalidSeekTable)?;
+            let stored = match &decrypted {
+                Some(data) => Cow::Borrowed(&data[block.stored_offset as usize..end as usize]),
+                None => {
+                    let mut stored = vec![0u8; block.stored_size as usize];
+                    self.source.seek(SeekFrom::Start(offset + block.stored_offset))?;
+                    self.source.read_exact(&mut stored)?;
+                    Cow::Owned(stored)
+                }
+            };
+            let limit = self.limits.max_chunk_size.saturating_sub(body.len() as u64);
+            body.extend_from_slice(&codec.decompress(chunk_type, &stored, limit)?);
+        }
+        Ok(body)
+    }
+}
+
+impl<R: Read + Write + Seek> EuphContainer<R> {
+    /// Replace the METADATA chunk, keeping its codec, or add one stored
+    /// uncompressed when the file has none. See `replace_chunk_at`.
+    pub fn update_metadata(&mut self, metadata: EuphMetadata) -> Result<(), EuphError> {
+        let body = canonical_json(&metadata)?;
+        match self.chunk(ChunkType::Metadata) {
+            Some(chunk) => {
+                let (index, codec) = (chunk.index, chunk.codec()?);
+                self.replace_chunk_at(index, body, codec)?;
+            }
+            None => {
+                if self.layout != EuphLayout::Spec {
+                    return Err(EuphError::UnsupportedLayout(self.layout));
+                }
+                self.rewrite(Vec::new(), Some((ChunkType::Metadata, body, 0)))?;
+                self.modified = TimestampSource::default().resolve();
+                self.write_header()?;
+            }
+        }
+        self.metadata_warnings = metadata.validate();
+        self.metadata = Some(metadata);
+        Ok(())
+    }
+
+    /// Replace the body of the first chunk of a type. See `replace_chunk_at`.
+    pub fn replace_chunk(&mut self, chunk_type: ChunkType, data: Vec<u8>, codec: ChunkCodec) -> Result<(), EuphError> {
+        let index = self.index_of(chunk_type)?;
+        self.replace_chunk_at(index, data, codec)
+    }
+
+    /// Replace the body of a chunk. When the new body fits in the space
+    /// before the next chunk, including any padding the encoder reserved, it
+    /// overwrites the old one and only that body, its chunk table entry, the
+    /// modified timestamp and the header are rewritten.
+    ///
+    /// Otherwise the whole file is written again in canonical chunk order, so
+    /// edits never leave dead space behind. The source cannot be truncated,
+    /// so the rewritten file keeps at least its old length: the space left
+    /// over becomes padding after the METADATA chunk, for later edits to
+    /// grow into, or zeros at the end of a file without one.
+    ///
+    /// An encrypted chunk stays encrypted with the same cipher and key id,
+    /// which the key provider must supply.
+    ///
+    /// Replacing an AUDIO chunk also rewrites its SEEK chunk, which cannot be
+    /// replaced on its own.
+    ///
+    /// Only spec layout files have a chunk table to update; upgrade legacy
+    /// files first.
+    pub fn replace_chunk_at(&mut self, index: usize, data: Vec<u8>, codec: ChunkCodec) -> Result<(), EuphError> {
+        if self.layout != EuphLayout::Spec {
+            return Err(EuphError::UnsupportedLayout(self.layout));
+        }
+        let chunk = self.chunks.get(index).ok_or(EuphError::ChunkIndexOutOfRange(index))?;
+
+        let mut bodies = Vec::new();
+        match chunk.chunk_type {
+            ChunkType::Seek => return Err(EuphError::ReservedChunkType(ChunkType::Seek)),
+            ChunkType::Audio => {
+                let seek = self.seek_chunk_for(chunk).map(|seek| (seek.index, seek.codec()));
+                let (body, table) = euph_seek::compress_audio(data, codec)?;
+                bodies.push(self.seal_chunk_body(index, body, codec)?);
+                // Audio that cannot be split leaves a SEEK chunk without blocks
+                if let Some((seek_index, seek_codec)) = seek {
+                    let seek_codec = seek_codec?;
+                    let table = seek_codec.compress(canonical_json(&table.unwrap_or_default())?)?;
+                    bodies.push(self.seal_chunk_body(seek_index, table, seek_codec)?);
+                }
+            }
+            _ => {
+                let body = codec.compress(data)?;
+                bodies.push(self.seal_chunk_body(index, body, codec)?);
+            }
+        }
+
+        if bodies.iter().all(|(index, body, flags)| self.fits_in_place(*index, body, *flags)) {
+            for (index, body, flags) in bodies {
+                self.write_chunk_body(index, body, flags)?;
+            }
+        } else {
+            self.rewrite(bodies, None)?;
+        }
+
+        self.modified = TimestampSource::default().resolve();
+        self.write_header()
+    }
+
+    /// Encrypt the compressed body of a chunk again if it was encrypted,
+    /// returning it with the chunk flags to store.
+    fn seal_chunk_body(&self, index: usize, body: Vec<u8>, codec: ChunkCodec) -> Result<(usize, Vec<u8>, u32), EuphError> {
+        let chunk = &self.chunks[index];
+        let flags = codec.chunk_flags() | (chunk.flags & CHUNK_FLAG_CRC);
+        match chunk.cipher()? {
+            Some((cipher, key_id)) => {
+                let key = self.keys.key(key_id).ok_or(EuphError::MissingKey { chunk: chunk.chunk_type, key_id })?;
+                let (body, flags) = euph_crypto::encrypt(cipher, key_id, &key, chunk.chunk_type, flags, &body)?;
+                Ok((index, body, flags))
+            }
+            None => Ok((index, body, flags)),
+        }
+    }
+
+    /// Whether a sealed body, with its CRC, fits before the next chunk.
+    fn fits_in_place(&self, index: usize, body: &[u8], flags: u32) -> bool {
+        let start = self.chunks[index].offset;
+        let needed = body.len() as u64 + if flags & CHUNK_FLAG_CRC != 0 { 4 } else { 0 };
+        start.saturating_add(needed) <= self.next_chunk_offset(start).unwrap_or(u64::MAX)
+    }
+
+    /// Overwrite the body of a chunk with a sealed body that fits in place,
+    /// and update its chunk table entry and the header flags.
+    fn write_chunk_body(&mut self, index: usize, body: Vec<u8>, flags: u32) -> Result<(), EuphError> {
+        let chunk = &self.chunks[index];
+        let crc = (flags & CHUNK_FLAG_CRC != 0).then(|| crc32fast::hash(&body));
+        let (offset, old_end) = (chunk.offset, chunk.end());
+
+        // Write the new body, then clear whatever is left of the old one so
+        // stale bytes do not linger in the padding
+        self.source.seek(SeekFrom::Start(offset))?;
+        self.source.write_all(&body)?;
+        if let Some(crc) = crc {
+            self.source.write_all(&crc.to_le_bytes())?;
+        }
+        let clear_start = self.source.stream_position()?;
+        if clear_start < old_end {
+            std::io::copy(&mut std::io::repeat(0).take(old_end - clear_start), &mut self.source)?;
+        }
+
+        let chunk = &mut self.chunks[index];
+        chunk.size = body.len() as u64;
+        chunk.flags = flags;
+        let (chunk_type, table_index) = (chunk.chunk_type, chunk.table_index);
+
+        // Chunk table entry, past its type and offset fields
+        self.source.seek(SeekFrom::Start(SPEC_HEADER_SIZE + table_index as u64 * CHUNK_TABLE_ENTRY_SIZE + 12))?;
+        self.source.write_all(&(body.len() as u64).to_le_bytes())?;
+        self.source.write_all(&flags.to_le_bytes())?;
+
+        if let Some(header_flag) = chunk_type.compression_flag() {
+            if self.chunks_of_type(chunk_type).any(ChunkData::is_compressed) {
+                self.flags |= header_flag;
+            } else {
+                self.flags &= !header_flag;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the whole file again with the sealed `bodies` in place of the
+    /// stored bodies of their chunks and the chunk `added`, if any, and read
+    /// the new chunk table back. The file is built in memory, keeps its
+    /// created timestamp and is never shorter than before.
+    fn rewrite(&mut self, mut bodies: Vec<(usize, Vec<u8>, u32)>, added: Option<(ChunkType, Vec<u8>, u32)>) -> Result<(), EuphError> {
+        let old_len = self.source.seek(SeekFrom::End(0))?;
+        let mut chunks = Vec::with_capacity(self.chunks.len() + 1);
+        for index in 0..self.chunks.len() {
+            let (data, flags) = match bodies.iter().position(|(body_index, ..)| *body_index == index) {
+                Some(position) => {
+                    let (_, data, flags) = bodies.swap_remove(position);
+                    (data, flags)
+                }
+                None => (self.read_raw_chunk_at(index)?, self.chunks[index].flags),
+            };
+            let chunk = &self.chunks[index];
+            chunks.push((chunk.chunk_type, chunk.role.clone(), data, flags));
+        }
+        chunks.extend(added.map(|(chunk_type, data, flags)| (chunk_type, None, data, flags)));
+
+        let checksums = self.chunks.iter().any(|chunk| chunk.flags & CHUNK_FLAG_CRC != 0);
+        let encoder = EuphEncoder::from_stored_chunks(self.flags, chunks)
+            .with_chunk_checksums(checksums)
+            .with_timestamp(TimestampSource::Fixed(self.created));
+        let slack = (old_len as usize).saturating_sub(encoder.get_estimated_size());
+        let mut file = Cursor::new(Vec::new());
+        encoder.with_metadata_padding(slack).write(&mut file)?;
+        let mut file = file.into_inner();
+        if (file.len() as u64) < old_len {
+            file.resize(old_len as usize, 0);
+            file[8..16].copy_from_slice(&old_len.to_le_bytes());
+        }
+        self.source.seek(SeekFrom::Start(0))?;
+        self.source.write_all(&file)?;
+
+        self.chunks.clear();
+        self.roles_chunk = None;
+        self.source.seek(SeekFrom::Start(6))?;
+        let mut log = ProblemLog { lenient: false, problems: Vec::new() };
+        self.read_spec_header(file.len() as u64, &mut log)
+    }
+
+    /// Offset of the chunk body that follows `offset` in the file, if any.
+    fn next_chunk_offset(&self, offset: u64) -> Option<u64> {
+        self.chunks.iter()
+            .chain(&self.roles_chunk)
+            .map(|chunk| chunk.offset)
+            .filter(|&start| start > offset)
+            .min()
+    }
+
+    /// Rewrite the flags, length, modified timestamp and file CRC.
+    fn write_header(&mut self) -> Result<(), EuphError> {
+        let stream_len = self.source.seek(SeekFrom::End(0))?;
+        self.source.seek(SeekFrom::Start(6))?;
+        self.source.write_all(&self.flags.to_le_bytes())?;
+        self.source.write_all(&stream_len.to_le_bytes())?;
+        self.source.seek(SeekFrom::Start(28))?;
+        self.source.write_all(&self.modified.to_le_bytes())?;
+
+        let mut hasher = Hasher::new();
+        let mut buffer = [0u8; 64 * 1024];
+        self.source.seek(SeekFrom::Start(FILE_CRC_START))?;
+        loop {
+            let n = self.source.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]);
+        }
+        self.source.seek(SeekFrom::Start(16))?;
+        self.source.write_all(&hasher.finalize().to_le_bytes())?;
+        self.source.flush()?;
+        Ok(())
+    }
+}
+
+impl<S> EuphContainer<S> {
+    pub fn layout(&self) -> EuphLayout {
+        self.layout
+    }
+
+    pub fn version(&self) -> (u8, u8) {
+        self.version
+    }
+
+    pub fn flags(&self) -> u16 {
+        self.flags
+    }
+
+    /// Creation time as a Unix timestamp, `0` for legacy files.
+    pub fn created(&self) -> u64 {
+        self.created
+    }
+
+    /// Modification time as a Unix timestamp, `0` for legacy files.
+    pub fn modified(&self) -> u64 {
+        self.modified
+    }
+
+    /// Parsed metadata; for containers from `open` this is only filled in
+    /// once `read_metadata` has been called.
+    pub fn metadata(&self) -> Option<&EuphMetadata> {
+        self.metadata.as_ref()
+    }
+
+    /// What was migrated, dropped or out of range when `metadata` was read.
+    pub fn metadata_warnings(&self) -> &[MetadataWarning] {
+        &self.metadata_warnings
+    }
+
+    /// Keys for decrypting encrypted chunks. Without one, reading an encrypted
+    /// chunk fails with `EuphError::MissingKey`.
+    pub fn set_key_provider<K: KeyProvider + 'static>(&mut self, provider: K) {
+        self.keys = Keys::new(Box::new(provider));
+    }
+
+    /// Problems recorded while parsing in lenient mode.
+    pub fn problems(&self) -> &[EuphError] {
+        &self.problems
+    }
+
+    /// First chunk of a type, in chunk table order.
+    pub fn chunk(&self, chunk_type: ChunkType) -> Option<&ChunkData> {
+        self.chunks_of_type(chunk_type).next()
+    }
+
+    /// Chunks in chunk table order.
+    pub fn chunks(&self) -> impl Iterator<Item = &ChunkData> {
+        self.chunks.iter()
+    }
+
+    pub fn chunks_of_type(&self, chunk_type: ChunkType) -> impl Iterator<Item = &ChunkData> {
+        self.chunks.iter().filter(move |chunk| chunk.chunk_type == chunk_type)
+    }
+
+    /// Chunks of a type labelled with `role`, e.g. every enhanced AUDIO chunk.
+    pub fn chunks_with_role<'a>(&'a self, chunk_type: ChunkType, role: &'a str) -> impl Iterator<Item = &'a ChunkData> {
+        self.chunks_of_type(chunk_type).filter(move |chunk| chunk.role() == Some(role))
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn into_inner(self) -> S {
+        self.source
+    }
+
+    /// SEEK chunk of an AUDIO chunk: the one with the same role, at the same
+    /// position among the chunks with that role.
+    fn seek_chunk_for(&self, audio: &ChunkData) -> Option<&ChunkData> {
+        let with_role = |chunk_type| self.chunks_of_type(chunk_type).filter(move |c| c.role == audio.role);
+        let position = with_role(ChunkType::Audio).position(|c| c.index == audio.index)?;
+        with_role(ChunkType::Seek).nth(position)
+    }
+
+    /// Stream a decrypted and decompressed chunk body from its stored bytes.
+    fn body_reader<'a>(&self, chunk: &ChunkData, raw: &'a [u8]) -> Result<Box<dyn Read + 'a>, EuphError> {
+        if chunk.is_encrypted() {
+            let data = euph_crypto::decrypt(chunk.chunk_type, chunk.flags, raw, &self.keys)?;
+            return chunk.codec()?.decoder(Cursor::new(data));
+        }
+        chunk.codec()?.decoder(raw)
+    }
+
+    /// Decrypt and decompress a chunk body with the cipher and codec recorded
+    /// in its chunk flags.
+    fn decode_chunk<'a>(&self, chunk: &ChunkData, raw: &'a [u8]) -> Result<Cow<'a, [u8]>, EuphError> {
+        let raw = if chunk.is_encrypted() {
+            Cow::Owned(euph_crypto::decrypt(chunk.chunk_type, chunk.flags, raw, &self.keys)?)
+        } else {
+            Cow::Borrowed(raw)
+        };
+        match chunk.codec()? {
+            ChunkCodec::None => Ok(raw),
+            codec => Ok(Cow::Owned(codec.decompress(chunk.chunk_type, &raw, self.limits.max_chunk_size)?)),
+        }
+    }
+}
+
+
+fn read_audio_format(reader: impl Read) -> Result<Option<AudioFormat>, EuphError> {
+    let mut head = Vec::new();
+    reader.take(euph_audio::MAX_AUDIO_FORMAT_SIZE as u64).read_to_end(&mut head)?;
+    Ok(AudioFormat::parse(&head)?.map(|(format, _)| format))
+}
+
+/// Legacy files carry whatever JSON the caller handed to the wasm encoder,
+/// and upgraded files keep it, so a METADATA chunk that is not a JSON object
+/// is left to the raw chunk accessors instead of failing the parse.
+fn parse_metadata(data: &[u8]) -> (Option<EuphMetadata>, Vec<MetadataWarning>) {
+    match EuphMetadata::from_json(data) {
+        Ok((metadata, warnings)) => (Some(metadata), warnings),
+        Err(_) => (None, Vec::new()),
     }
 }
 
@@ -178,10 +1500,158 @@ pub enum EuphError {
     InvalidVersion,
     MissingAudioChunk,
     MissingAiModel,
+    MissingChunk(ChunkType),
+    /// No chunk at this position in `EuphContainer::chunks`.
+    ChunkIndexOutOfRange(usize),
+    /// The chunk type is written by the encoder itself and cannot be added.
+    ReservedChunkType(ChunkType),
+    /// The operation is not available for files in this layout.
+    UnsupportedLayout(EuphLayout),
+    /// The file ends before the length recorded in its header or chunk table.
+    Truncated { expected: u64, actual: u64 },
+    /// The file is longer than the length recorded in its header.
+    LengthMismatch { expected: u64, actual: u64 },
+    /// A chunk CRC, or the whole-file CRC when `chunk` is `None`, does not match.
+    ChecksumMismatch { chunk: Option<ChunkType> },
+    /// The header compression flag for a chunk type disagrees with the
+    /// compression flag in that chunk's table entry.
+    CompressionFlagMismatch { chunk: ChunkType, header_compressed: bool, chunk_compressed: bool },
+    /// A chunk flagged as compressed could not be decompressed.
+    Decompression { chunk: ChunkType, source: std::io::Error },
+    /// The chunk flags name a compression codec this build does not know.
+    UnsupportedCodec(u8),
+    /// A chunk is larger than `ParseLimits::max_chunk_size`, as stored or,
+    /// with `size` counting only what was decompressed before giving up,
+    /// once decompressed.
+    ChunkTooLarge { chunk: ChunkType, size: u64, limit: u64 },
+    /// The chunk table lists more chunks than `ParseLimits::max_chunk_count`.
+    TooManyChunks { count: u32, limit: u32 },
+    /// The file, or the sum of its chunk sizes, is larger than
+    /// `ParseLimits::max_total_size`.
+    FileTooLarge { size: u64, limit: u64 },
+    /// A chunk table entry points into the header or chunk table, or its end
+    /// does not fit in 64 bits.
+    InvalidChunkBounds { chunk: ChunkType },
+    /// The chunk flags name a cipher this build does not know.
+    UnsupportedCipher(u8),
+    /// An encrypted chunk was read without a key for its key id.
+    MissingKey { chunk: ChunkType, key_id: u8 },
+    /// An encrypted chunk failed authentication: wrong key, or damaged or
+    /// tampered ciphertext.
+    Decryption { chunk: ChunkType },
+    /// A chunk could not be sealed with the configured cipher.
+    Encryption { chunk: ChunkType },
+    /// The codec level is outside the range the codec supports.
+    InvalidCompressionLevel(ChunkCodec),
+    /// The AUDIO payload is in an encoding this build cannot decode, or none
+    /// it recognises when `None`.
+    UnsupportedAudioFormat(Option<AudioCodec>),
+    /// The AUDIO payload is damaged beyond what the decoder can skip.
+    AudioDecode(symphonia::core::errors::Error),
+    /// An AUDIO chunk starts with a format descriptor that is cut short or
+    /// smaller than the first version of it.
+    InvalidAudioFormat,
+    /// A SEEK chunk lists a block outside its AUDIO chunk.
+    InvalidSeekTable,
+    /// A `.cue` sheet could not be imported; `line` is 1-based, or 0 when
+    /// the problem is not with one line.
+    InvalidCueSheet { line: usize, reason: &'static str },
+    /// An LRC file has no time-tagged lines.
+    InvalidLyrics,
+    /// A PICT chunk body is cut short or holds more than its pictures, or
+    /// an image file is in no format the encoder recognises.
+    InvalidArtwork,
+    /// A PEAK chunk body is cut short or holds more than its levels.
+    InvalidPeaks,
     IoError(std::io::Error),
     JsonError(serde_json::Error),
 }
 
+impl std::fmt::Display for EuphError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EuphError::InvalidMagic => write!(f, "not an EUPH file"),
+            EuphError::InvalidVersion => write!(f, "unsupported EUPH major version"),
+            EuphError::MissingAudioChunk => write!(f, "no {} chunk", ChunkType::Audio),
+            EuphError::MissingAiModel => write!(f, "no {} chunk", ChunkType::AiModel),
+            EuphError::MissingChunk(chunk) => write!(f, "no {} chunk", chunk),
+            EuphError::ChunkIndexOutOfRange(index) => write!(f, "no chunk at index {}", index),
+            EuphError::ReservedChunkType(chunk) => write!(f, "{} chunks are written by the encoder and cannot be added", chunk),
+            EuphError::UnsupportedLayout(layout) => {
+                let layout = match layout {
+                    EuphLayout::Legacy => "legacy",
+                    EuphLayout::Spec => "spec",
+                };
+                write!(f, "not supported for {} layout files", layout)
+            }
+            EuphError::Truncated { expected, actual } => {
+                write!(f, "file is truncated: {} bytes where {} were expected", actual, expected)
+            }
+            EuphError::LengthMismatch { expected, actual } => {
+                write!(f, "file is {} bytes but its header records {}", actual, expected)
+            }
+            EuphError::ChecksumMismatch { chunk: Some(chunk) } => write!(f, "{} chunk checksum mismatch", chunk),
+            EuphError::ChecksumMismatch { chunk: None } => write!(f, "file checksum mismatch"),
+            EuphError::CompressionFlagMismatch { chunk, header_compressed, chunk_compressed } => {
+                let state = |compressed: &bool| if *compressed { "compressed" } else { "uncompressed" };
+                write!(
+                    f,
+                    "header flags {} chunks as {} but the chunk table as {}",
+                    chunk,
+                    state(header_compressed),
+                    state(chunk_compressed)
+                )
+            }
+            EuphError::Decompression { chunk, source } => write!(f, "{} chunk could not be decompressed: {}", chunk, source),
+            EuphError::UnsupportedCodec(id) => write!(f, "unsupported compression codec {}", id),
+            EuphError::ChunkTooLarge { chunk, size, limit } => {
+                write!(f, "{} chunk is {} bytes, over the limit of {}", chunk, size, limit)
+            }
+            EuphError::TooManyChunks { count, limit } => {
+                write!(f, "chunk table lists {} chunks, over the limit of {}", count, limit)
+            }
+            EuphError::FileTooLarge { size, limit } => write!(f, "file is {} bytes, over the limit of {}", size, limit),
+            EuphError::InvalidChunkBounds { chunk } => write!(f, "{} chunk lies outside the file body", chunk),
+            EuphError::UnsupportedCipher(id) => write!(f, "unsupported cipher {}", id),
+            EuphError::MissingKey { chunk, key_id } => write!(f, "no key {} to decrypt the {} chunk", key_id, chunk),
+            EuphError::Decryption { chunk } => write!(f, "{} chunk failed to decrypt: wrong key or damaged data", chunk),
+            EuphError::Encryption { chunk } => write!(f, "{} chunk could not be encrypted", chunk),
+            EuphError::InvalidCompressionLevel(codec) => {
+                let name = match codec {
+                    ChunkCodec::None => "none",
+                    ChunkCodec::Gzip { .. } => "gzip",
+                    ChunkCodec::Zstd { .. } => "zstd",
+                };
+                write!(f, "compression level {} is out of range for {}", codec.level(), name)
+            }
+            EuphError::UnsupportedAudioFormat(Some(codec)) => write!(f, "cannot decode {:?} audio", codec),
+            EuphError::UnsupportedAudioFormat(None) => write!(f, "audio is in no recognised format"),
+            EuphError::AudioDecode(e) => write!(f, "audio could not be decoded: {}", e),
+            EuphError::InvalidAudioFormat => write!(f, "invalid audio format descriptor"),
+            EuphError::InvalidSeekTable => write!(f, "{} chunk lists a block outside its audio", ChunkType::Seek),
+            EuphError::InvalidCueSheet { line: 0, reason } => write!(f, "invalid cue sheet: {}", reason),
+            EuphError::InvalidCueSheet { line, reason } => write!(f, "invalid cue sheet, line {}: {}", line, reason),
+            EuphError::InvalidLyrics => write!(f, "LRC file has no time-tagged lines"),
+            EuphError::InvalidArtwork => write!(f, "invalid or unrecognised artwork"),
+            EuphError::InvalidPeaks => write!(f, "invalid {} chunk", ChunkType::Peaks),
+            EuphError::IoError(e) => write!(f, "{}", e),
+            EuphError::JsonError(e) => write!(f, "invalid JSON: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for EuphError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EuphError::Decompression { source, .. } => Some(source),
+            EuphError::AudioDecode(e) => Some(e),
+            EuphError::IoError(e) => Some(e),
+            EuphError::JsonError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 impl From<std::io::Error> for EuphError {
     fn from(e: std::io::Error) -> Self {
         EuphError::IoError(e)
@@ -193,3 +1663,491 @@ impl From<serde_json::Error> for EuphError {
         EuphError::JsonError(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::euph_encoder::EuphEncoder;
+
+    /// A spec layout file with a custom chunk and metadata.
+    fn sample_file() -> Vec<u8> {
+        let mut encoder = EuphEncoder::new().with_timestamp(TimestampSource::Fixed(1_700_000_000));
+        encoder.add_chunk(ChunkType::Custom(*b"ANLY"), b"analysis".to_vec(), ChunkCodec::None).unwrap();
+        encoder.set_metadata(EuphMetadata { title: Some("Test".to_string()), ..Default::default() }).unwrap();
+        let mut file = Cursor::new(Vec::new());
+        encoder.write(&mut file).unwrap();
+        file.into_inner()
+    }
+
+    fn parse(data: &[u8]) -> Result<EuphContainer, EuphError> {
+        EuphContainer::parse(&mut Cursor::new(data.to_vec()))
+    }
+
+    #[test]
+    fn truncated_spec_header_is_truncated() {
+        let file = sample_file();
+        for length in [12, 14, 30, 39] {
+            match parse(&file[..length]) {
+                Err(EuphError::Truncated { actual, .. }) => assert_eq!(actual, length as u64),
+                other => panic!("{length} bytes: {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn empty_legacy_file_has_no_chunks() {
+        let container = parse(b"EUPH\x01\x00\x00\x00\x00\x00").unwrap();
+        assert_eq!(container.layout(), EuphLayout::Legacy);
+        assert_eq!(container.chunk_count(), 0);
+    }
+
+    /// A legacy file as the wasm encoder wrote it: METADATA, AUDIO and a
+    /// custom chunk back to back.
+    fn legacy_file() -> Vec<u8> {
+        let chunks: [(&[u8; 4], &[u8]); 3] = [(b"META", br#"{"title":"Legacy"}"#), (b"AUDI", b"audio bytes"), (b"ANLY", b"analysis")];
+        let mut file = b"EUPH\x01\x00".to_vec();
+        file.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+        for (fourcc, body) in chunks {
+            file.extend_from_slice(fourcc);
+            file.extend_from_slice(&(body.len() as u32).to_le_bytes());
+            file.extend_from_slice(body);
+        }
+        file
+    }
+
+    #[test]
+    fn legacy_file_upgrades_to_the_spec_layout() {
+        let legacy = parse(&legacy_file()).unwrap();
+        assert_eq!(legacy.layout(), EuphLayout::Legacy);
+        let types: Vec<_> = legacy.chunks().map(ChunkData::chunk_type).collect();
+        assert_eq!(types, [ChunkType::Metadata, ChunkType::Audio, ChunkType::Custom(*b"ANLY")]);
+        assert_eq!(legacy.metadata().unwrap().title.as_deref(), Some("Legacy"));
+        assert_eq!(&*legacy.chunk_data(ChunkType::Audio).unwrap(), b"audio bytes");
+
+        let mut upgraded = Cursor::new(Vec::new());
+        let layout = EuphEncoder::upgrade(&mut Cursor::new(legacy_file()), &mut upgraded).unwrap();
+        assert_eq!(layout, EuphLayout::Legacy);
+        let upgraded = upgraded.into_inner();
+        assert_eq!(&upgraded[..4], b"EUPH");
+
+        // `parse` checks the new file's length and CRC
+        let spec = parse(&upgraded).unwrap();
+        assert_eq!(spec.layout(), EuphLayout::Spec);
+        assert!(spec.problems().is_empty());
+        assert_eq!(spec.chunk_count(), 3);
+        for chunk in legacy.chunks() {
+            let chunk_type = chunk.chunk_type();
+            assert_eq!(spec.chunk_data(chunk_type).unwrap(), legacy.chunk_data(chunk_type).unwrap(), "{chunk_type}");
+        }
+        assert_eq!(spec.metadata(), legacy.metadata());
+
+        // Upgrading a spec file leaves it in the spec layout
+        let layout = EuphEncoder::upgrade(&mut Cursor::new(upgraded), &mut Cursor::new(Vec::new())).unwrap();
+        assert_eq!(layout, EuphLayout::Spec);
+    }
+
+    #[test]
+    fn damaged_chunk_fails_its_crc() {
+        let mut file = sample_file();
+        let offset = parse(&file).unwrap().chunk(ChunkType::Custom(*b"ANLY")).unwrap().offset() as usize;
+        file[offset] ^= 0xFF;
+
+        match parse(&file) {
+            Err(EuphError::ChecksumMismatch { chunk: Some(ChunkType::Custom(fourcc)) }) => assert_eq!(&fourcc, b"ANLY"),
+            other => panic!("{other:?}"),
+        }
+
+        // Opened files check the CRC when the chunk is read
+        let mut container = EuphContainer::open(Cursor::new(file)).unwrap();
+        assert!(matches!(
+            container.read_chunk(ChunkType::Custom(*b"ANLY")),
+            Err(EuphError::ChecksumMismatch { chunk: Some(ChunkType::Custom(_)) })
+        ));
+        // The other chunks are still readable
+        assert!(container.read_chunk(ChunkType::Metadata).is_ok());
+    }
+
+    #[test]
+    fn damaged_header_fails_the_file_crc() {
+        let mut file = sample_file();
+        // Created timestamp
+        file[FILE_CRC_START as usize] ^= 0x01;
+        assert!(matches!(parse(&file), Err(EuphError::ChecksumMismatch { chunk: None })));
+    }
+
+    fn titled(title: &str) -> EuphMetadata {
+        EuphMetadata { title: Some(title.to_string()), ..Default::default() }
+    }
+
+    /// METADATA, then two custom chunks, so the first custom chunk can only
+    /// grow in place into padding.
+    fn editable_file(metadata_padding: usize) -> Vec<u8> {
+        let mut encoder = EuphEncoder::new()
+            .with_timestamp(TimestampSource::Fixed(1_700_000_000))
+            .with_metadata_padding(metadata_padding);
+        encoder.set_metadata(titled("Test")).unwrap();
+        encoder.add_chunk(ChunkType::Custom(*b"ANLY"), vec![0xA5; 4000], ChunkCodec::None).unwrap();
+        encoder.add_chunk(ChunkType::Custom(*b"ZZZZ"), b"last".to_vec(), ChunkCodec::None).unwrap();
+        let mut file = Cursor::new(Vec::new());
+        encoder.write(&mut file).unwrap();
+        file.into_inner()
+    }
+
+    fn offsets(container: &EuphContainer) -> Vec<u64> {
+        container.chunks().map(ChunkData::offset).collect()
+    }
+
+    #[test]
+    fn edit_that_fits_stays_in_place() {
+        let original = editable_file(256);
+        let mut container = parse(&original).unwrap();
+        let before = offsets(&container);
+
+        container.update_metadata(titled(&"Longer title ".repeat(10))).unwrap();
+        assert_eq!(offsets(&container), before);
+        let file = container.into_inner().into_inner();
+        assert_eq!(file.len(), original.len());
+
+        let reparsed = parse(&file).unwrap();
+        assert_eq!(reparsed.metadata().unwrap().title.as_deref(), Some("Longer title ".repeat(10).as_str()));
+        assert_eq!(offsets(&reparsed), before);
+        assert_eq!(reparsed.created(), 1_700_000_000);
+    }
+
+    #[test]
+    fn edit_that_does_not_fit_rewrites_the_file() {
+        let original = editable_file(64);
+        let mut container = parse(&original).unwrap();
+        let anly = container.chunk(ChunkType::Custom(*b"ANLY")).unwrap().index();
+
+        container.replace_chunk_at(anly, vec![0x5A; 5000], ChunkCodec::None).unwrap();
+        let file = container.into_inner().into_inner();
+        // Grown by the new bytes, less the padding the rewrite no longer keeps
+        assert_eq!(file.len(), original.len() + 1000 - 64);
+
+        let reparsed = parse(&file).unwrap();
+        assert_eq!(reparsed.chunk_data(ChunkType::Custom(*b"ANLY")).unwrap().as_ref(), &[0x5A; 5000][..]);
+        assert_eq!(reparsed.chunk_data(ChunkType::Custom(*b"ZZZZ")).unwrap().as_ref(), b"last");
+        assert_eq!(reparsed.metadata().unwrap().title.as_deref(), Some("Test"));
+        assert_eq!(reparsed.created(), 1_700_000_000);
+    }
+
+    #[test]
+    fn rewrite_turns_dead_space_into_metadata_padding() {
+        let original = editable_file(0);
+        let mut container = parse(&original).unwrap();
+        let anly = container.chunk(ChunkType::Custom(*b"ANLY")).unwrap().index();
+
+        // Shrinking leaves dead space after ANLY; growing METADATA then
+        // cannot fit in place and the rewrite reclaims it
+        container.replace_chunk_at(anly, b"small".to_vec(), ChunkCodec::None).unwrap();
+        container.update_metadata(titled(&"x".repeat(500))).unwrap();
+        let file = container.into_inner().into_inner();
+        assert_eq!(file.len(), original.len());
+
+        let mut container = parse(&file).unwrap();
+        assert_eq!(container.chunk_data(ChunkType::Custom(*b"ANLY")).unwrap().as_ref(), b"small");
+        let before = offsets(&container);
+        // The reclaimed space is padding the next edit grows into
+        container.update_metadata(titled(&"y".repeat(2000))).unwrap();
+        assert_eq!(offsets(&container), before);
+        let file = container.into_inner().into_inner();
+        assert_eq!(file.len(), original.len());
+        assert_eq!(parse(&file).unwrap().metadata().unwrap().title.as_deref(), Some("y".repeat(2000).as_str()));
+    }
+
+    #[test]
+    fn update_metadata_adds_a_missing_chunk() {
+        let mut encoder = EuphEncoder::new();
+        encoder.add_chunk(ChunkType::Custom(*b"ANLY"), b"analysis".to_vec(), ChunkCodec::None).unwrap();
+        let mut file = Cursor::new(Vec::new());
+        encoder.write(&mut file).unwrap();
+
+        let mut container = EuphContainer::open(file).unwrap();
+        container.update_metadata(titled("Added")).unwrap();
+        let file = container.into_inner().into_inner();
+
+        let reparsed = parse(&file).unwrap();
+        assert_eq!(reparsed.metadata().unwrap().title.as_deref(), Some("Added"));
+        assert_eq!(reparsed.chunk_data(ChunkType::Custom(*b"ANLY")).unwrap().as_ref(), b"analysis");
+    }
+
+    fn parse_with(data: &[u8], lenient: bool, limits: ParseLimits) -> Result<EuphContainer, EuphError> {
+        EuphContainer::parse_with_options(&mut Cursor::new(data.to_vec()), DecodingOptions { lenient, limits })
+    }
+
+    /// Start of a chunk table entry.
+    fn entry(table_index: usize) -> usize {
+        SPEC_HEADER_SIZE as usize + table_index * CHUNK_TABLE_ENTRY_SIZE as usize
+    }
+
+    // Table positions in `sample_file`, in canonical order
+    const METADATA_ENTRY: usize = 0;
+    const ANLY_ENTRY: usize = 1;
+
+    #[test]
+    fn default_limits_accept_ordinary_files() {
+        let limits = ParseLimits::default();
+        assert_eq!((limits.max_chunk_size, limits.max_chunk_count, limits.max_total_size), (1 << 30, 4096, 4 << 30));
+        assert!(parse_with(&sample_file(), false, limits).is_ok());
+        assert!(parse_with(&sample_file(), false, ParseLimits::UNLIMITED).is_ok());
+    }
+
+    #[test]
+    fn too_many_chunks() {
+        let limits = ParseLimits { max_chunk_count: 1, ..Default::default() };
+        assert!(matches!(parse_with(&sample_file(), true, limits), Err(EuphError::TooManyChunks { count: 2, limit: 1 })));
+
+        // A count no file could hold fails before the table is read
+        let mut file = sample_file();
+        file[36..40].copy_from_slice(&u32::MAX.to_le_bytes());
+        let open = EuphContainer::open(Cursor::new(file));
+        assert!(matches!(open, Err(EuphError::TooManyChunks { count: u32::MAX, limit: 4096 })));
+    }
+
+    #[test]
+    fn chunk_too_large_as_stored() {
+        let limits = ParseLimits { max_chunk_size: 4, ..Default::default() };
+        assert!(matches!(parse_with(&sample_file(), false, limits), Err(EuphError::ChunkTooLarge { size, limit: 4, .. }) if size > 4));
+
+        // Lenient parsing skips the chunk
+        let container = parse_with(&sample_file(), true, limits).unwrap();
+        assert_eq!(container.chunk_count(), 0);
+        assert!(container.problems().iter().all(|p| matches!(p, EuphError::ChunkTooLarge { .. })));
+    }
+
+    #[test]
+    fn chunk_too_large_once_decompressed() {
+        let mut encoder = EuphEncoder::new();
+        encoder.add_chunk(ChunkType::Custom(*b"ANLY"), vec![0; 100_000], ChunkCodec::Gzip { level: 9 }).unwrap();
+        let mut file = Cursor::new(Vec::new());
+        encoder.write(&mut file).unwrap();
+
+        let limits = ParseLimits { max_chunk_size: 1000, ..Default::default() };
+        let mut container = parse_with(&file.into_inner(), false, limits).unwrap();
+        assert!(matches!(
+            container.read_chunk(ChunkType::Custom(*b"ANLY")),
+            Err(EuphError::ChunkTooLarge { size: 1001, limit: 1000, .. })
+        ));
+    }
+
+    #[test]
+    fn transcoding_exports_decode_within_the_chunk_limit() {
+        let audio = DecodedAudio::new(44_100, 1, vec![0.25; 1000]);
+        let mut encoder = EuphEncoder::new();
+        encoder.add_chunk(ChunkType::Audio, euph_export::wav_file(&audio, 16, 0), ChunkCodec::None).unwrap();
+        let mut file = Cursor::new(Vec::new());
+        encoder.write(&mut file).unwrap();
+
+        // The 2000 bytes of samples are stored, but decode to 4000
+        let limits = ParseLimits { max_chunk_size: 3000, ..Default::default() };
+        let container = parse_with(&file.into_inner(), false, limits).unwrap();
+        assert!(container.export(ExportFormat::Native).unwrap().starts_with(b"RIFF"));
+        assert!(matches!(container.export(ExportFormat::Flac), Err(EuphError::ChunkTooLarge { limit: 3000, .. })));
+    }
+
+    #[test]
+    fn file_too_large() {
+        let file = sample_file();
+        let limits = ParseLimits { max_total_size: 64, ..Default::default() };
+        assert!(matches!(parse_with(&file, true, limits), Err(EuphError::FileTooLarge { limit: 64, .. })));
+
+        // `open` does not load the file, but still bounds the sum of the chunk sizes
+        let options = DecodingOptions { lenient: true, limits: ParseLimits { max_total_size: 10, ..Default::default() } };
+        let open = EuphContainer::open_with_options(Cursor::new(file), options);
+        assert!(matches!(open, Err(EuphError::FileTooLarge { limit: 10, .. })));
+    }
+
+    #[test]
+    fn overflowing_chunk_offset_is_out_of_bounds() {
+        let mut file = sample_file();
+        let offset = entry(ANLY_ENTRY) + 4;
+        file[offset..offset + 8].copy_from_slice(&(u64::MAX - 2).to_le_bytes());
+        let open = EuphContainer::open(Cursor::new(file.clone()));
+        assert!(matches!(open, Err(EuphError::InvalidChunkBounds { chunk: ChunkType::Custom(_) })));
+
+        // Lenient parsing drops the chunk and keeps the rest
+        let options = DecodingOptions { lenient: true, ..Default::default() };
+        let container = EuphContainer::open_with_options(Cursor::new(file), options).unwrap();
+        assert!(container.chunk(ChunkType::Custom(*b"ANLY")).is_none());
+        assert!(container.chunk(ChunkType::Metadata).is_some());
+        assert!(matches!(container.problems(), [EuphError::InvalidChunkBounds { .. }]));
+    }
+
+    #[test]
+    fn chunk_inside_the_header_is_out_of_bounds() {
+        let mut file = sample_file();
+        let offset = entry(METADATA_ENTRY) + 4;
+        file[offset..offset + 8].copy_from_slice(&8u64.to_le_bytes());
+        let open = EuphContainer::open(Cursor::new(file));
+        assert!(matches!(open, Err(EuphError::InvalidChunkBounds { chunk: ChunkType::Metadata })));
+    }
+
+    #[test]
+    fn truncated_file_strict_and_lenient() {
+        let file = sample_file();
+        let cut = &file[..file.len() - 6];
+        assert!(matches!(parse(cut), Err(EuphError::Truncated { actual, .. }) if actual == cut.len() as u64));
+
+        let container = parse_with(cut, true, ParseLimits::default()).unwrap();
+        assert!(container.chunk(ChunkType::Metadata).is_some());
+        assert!(container.chunk(ChunkType::Custom(*b"ANLY")).is_none());
+        let problems = container.problems();
+        assert!(problems.iter().any(|p| matches!(p, EuphError::Truncated { expected, .. } if *expected == file.len() as u64)));
+        assert!(problems.iter().any(|p| matches!(p, EuphError::ChecksumMismatch { chunk: None })));
+    }
+
+    #[test]
+    fn trailing_bytes_strict_and_lenient() {
+        let mut file = sample_file();
+        let length = file.len() as u64;
+        file.extend_from_slice(b"junk");
+        assert!(matches!(parse(&file), Err(EuphError::LengthMismatch { expected, actual }) if expected == length && actual == length + 4));
+
+        let container = parse_with(&file, true, ParseLimits::default()).unwrap();
+        assert_eq!(container.chunk_count(), 2);
+        assert!(container.problems().iter().any(|p| matches!(p, EuphError::LengthMismatch { .. })));
+    }
+
+    #[test]
+    fn damaged_chunk_is_a_problem_in_lenient_mode() {
+        let mut file = sample_file();
+        let offset = parse(&file).unwrap().chunk(ChunkType::Custom(*b"ANLY")).unwrap().offset() as usize;
+        file[offset] ^= 0xFF;
+
+        let container = parse_with(&file, true, ParseLimits::default()).unwrap();
+        assert_eq!(container.metadata().unwrap().title.as_deref(), Some("Test"));
+        assert!(container.problems().iter().any(|p| matches!(p, EuphError::ChecksumMismatch { chunk: Some(ChunkType::Custom(_)) })));
+    }
+
+    #[test]
+    fn truncated_legacy_chunk_strict_and_lenient() {
+        // Count 2, one complete chunk and one cut short
+        let mut file = b"EUPH\x01\x00\x02\x00\x00\x00".to_vec();
+        file.extend_from_slice(b"META\x02\x00\x00\x00{}");
+        file.extend_from_slice(b"AUDI\x10\x00\x00\x00abc");
+        assert!(matches!(parse(&file), Err(EuphError::Truncated { .. })));
+
+        let container = parse_with(&file, true, ParseLimits::default()).unwrap();
+        assert_eq!(container.layout(), EuphLayout::Legacy);
+        assert_eq!(container.chunk_count(), 1);
+        assert!(matches!(container.problems(), [EuphError::Truncated { .. }]));
+    }
+
+    /// A file whose METADATA chunk is stored gzip-compressed.
+    fn compressed_metadata_file() -> Vec<u8> {
+        let mut encoder = EuphEncoder::new().with_timestamp(TimestampSource::Fixed(1_700_000_000));
+        let metadata = EuphMetadata { title: Some("Compressed".to_string()), artist: Some("Gzip".to_string()), ..Default::default() };
+        encoder.add_chunk(ChunkType::Metadata, canonical_json(&metadata).unwrap(), ChunkCodec::Gzip { level: 6 }).unwrap();
+        let mut file = Cursor::new(Vec::new());
+        encoder.write(&mut file).unwrap();
+        file.into_inner()
+    }
+
+    #[test]
+    fn compressed_metadata_is_decoded() {
+        let file = compressed_metadata_file();
+        let container = parse(&file).unwrap();
+        assert_eq!(container.flags() & FLAG_METADATA_COMPRESSED, FLAG_METADATA_COMPRESSED);
+        let chunk = container.chunk(ChunkType::Metadata).unwrap();
+        assert!(chunk.is_compressed());
+        assert!(!file.windows(10).any(|w| w == b"Compressed"));
+        let metadata = container.metadata().unwrap();
+        assert_eq!((metadata.title.as_deref(), metadata.artist.as_deref()), (Some("Compressed"), Some("Gzip")));
+        assert!(container.problems().is_empty());
+    }
+
+    #[test]
+    fn compression_flag_mismatch_strict_and_lenient() {
+        // Header flags sit before the range the file CRC covers
+        let mut cleared = compressed_metadata_file();
+        let flags = u16::from_le_bytes(cleared[6..8].try_into().unwrap()) & !FLAG_METADATA_COMPRESSED;
+        cleared[6..8].copy_from_slice(&flags.to_le_bytes());
+        let mut set = sample_file();
+        let flags = u16::from_le_bytes(set[6..8].try_into().unwrap()) | FLAG_METADATA_COMPRESSED;
+        set[6..8].copy_from_slice(&flags.to_le_bytes());
+
+        for (file, header_compressed) in [(&cleared, false), (&set, true)] {
+            let mismatch = |err: &EuphError| {
+                matches!(err, EuphError::CompressionFlagMismatch { chunk: ChunkType::Metadata, header_compressed: h, chunk_compressed: c }
+                    if *h == header_compressed && *c != header_compressed)
+            };
+            match parse(file) {
+                Err(err) => assert!(mismatch(&err), "{err:?}"),
+                Ok(_) => panic!("strict parse accepted the mismatch"),
+            }
+
+            // The chunk flags decide decoding, so lenient mode still reads it
+            let container = parse_with(file, true, ParseLimits::default()).unwrap();
+            assert!(matches!(container.problems(), [err] if mismatch(err)), "{:?}", container.problems());
+            assert!(container.metadata().unwrap().title.is_some());
+        }
+    }
+
+    /// Table positions and roles listed by the stored ROLES chunk.
+    fn stored_roles(file: &[u8]) -> Option<Vec<(u32, String)>> {
+        let count = u32::from_le_bytes(file[36..40].try_into().unwrap()) as usize;
+        let roles = (0..count).map(entry).find(|&at| file[at..at + 4] == ChunkType::Roles.id().to_le_bytes())?;
+        let offset = u64::from_le_bytes(file[roles + 4..roles + 12].try_into().unwrap()) as usize;
+        let size = u64::from_le_bytes(file[roles + 12..roles + 20].try_into().unwrap()) as usize;
+        let roles: Vec<ChunkRole> = serde_json::from_slice(&file[offset..offset + size]).unwrap();
+        Some(roles.into_iter().map(|ChunkRole { index, role }| (index, role)).collect())
+    }
+
+    #[test]
+    fn roles_label_chunks_of_the_same_type() {
+        let encoder = EuphEncoder::create_enhanced_file(b"original".to_vec(), b"enhanced".to_vec(), b"model".to_vec(), titled("Roles")).unwrap();
+        let mut file = Cursor::new(Vec::new());
+        encoder.write(&mut file).unwrap();
+        let file = file.into_inner();
+
+        let check = |file: &[u8]| {
+            let container = parse(file).unwrap();
+            // The ROLES chunk itself is not listed
+            assert_eq!(container.chunks().filter(|chunk| chunk.chunk_type() == ChunkType::Roles).count(), 0);
+            assert_eq!(container.chunks_of_type(ChunkType::Audio).count(), 2);
+            let mut expected = Vec::new();
+            for (role, body) in [(ROLE_ORIGINAL, &b"original"[..]), (ROLE_ENHANCED, b"enhanced")] {
+                let chunks: Vec<_> = container.chunks_with_role(ChunkType::Audio, role).collect();
+                assert_eq!(chunks.len(), 1, "{role}");
+                assert_eq!(&*container.get_audio_data_at(chunks[0].index()).unwrap(), body);
+                expected.push((chunks[0].table_index, role.to_string()));
+            }
+            assert_eq!(container.chunk(ChunkType::AiModel).unwrap().role(), None);
+            assert_eq!(container.chunks_with_role(ChunkType::AiModel, ROLE_ENHANCED).count(), 0);
+            expected.sort();
+            assert_eq!(stored_roles(file), Some(expected));
+        };
+        check(&file);
+
+        // Rewriting the file carries the roles over to the new table
+        let mut container = EuphContainer::open(Cursor::new(file.clone())).unwrap();
+        container.update_metadata(titled(&"z".repeat(5000))).unwrap();
+        let rewritten = container.into_inner().into_inner();
+        assert_ne!(rewritten.len(), file.len());
+        check(&rewritten);
+    }
+
+    #[test]
+    fn files_without_roles_have_no_roles_chunk() {
+        let file = sample_file();
+        assert_eq!(stored_roles(&file), None);
+        let container = parse(&file).unwrap();
+        assert!(container.chunks().all(|chunk| chunk.role().is_none()));
+        assert_eq!(container.chunks_with_role(ChunkType::Custom(*b"ANLY"), ROLE_ORIGINAL).count(), 0);
+        assert_eq!(container.chunk_count(), 2);
+    }
+
+    #[test]
+    fn errors_display_without_debug_formatting() {
+        let err = EuphError::ChecksumMismatch { chunk: Some(ChunkType::Custom(*b"AN\x01Y")) };
+        assert_eq!(err.to_string(), "AN\\x01Y chunk checksum mismatch");
+        assert_eq!(EuphError::Truncated { expected: 40, actual: 12 }.to_string(), "file is truncated: 12 bytes where 40 were expected");
+        assert_eq!(EuphError::MissingChunk(ChunkType::Metadata).to_string(), "no META chunk");
+
+        let io = std::io::Error::new(std::io::ErrorKind::InvalidData, "bad frame");
+        let err = EuphError::Decompression { chunk: ChunkType::Audio, source: io };
+        assert_eq!(err.to_string(), "AUDI chunk could not be decompressed: bad frame");
+        assert!(std::error::Error::source(&err).is_some());
+    }
+}
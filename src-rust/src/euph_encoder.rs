@@ -1,166 +1,317 @@
-use std::io::{Write, Seek, SeekFrom};
+use std::borrow::Cow;
+use std::io::{Cursor, Read, Write, Seek};
 use std::collections::HashMap;
 use serde::{Serialize, Deserialize};
 use crc32fast::Hasher;
-use flate2::write::GzEncoder;
-use flate2::read::GzDecoder;
-use flate2::Compression;
-use std::io::{Read, Write};
-
-use crate::euph_decoder::{EuphMetadata, ChunkType, EuphError, SpatialProfile};
+use ed25519_dalek::SigningKey;
+
+use crate::euph_artwork::Artwork;
+use crate::euph_audio::{self, AudioFormat, SharedPayload};
+use crate::euph_codec::ChunkCodec;
+use crate::euph_crypto::ChunkEncryption;
+use crate::euph_cues::CueSheet;
+use crate::euph_loudness::Loudness;
+use crate::euph_lyrics::Lyrics;
+use crate::euph_peaks::{self, Peaks};
+use crate::euph_seek;
+use crate::euph_signature::{self, IntegrityHasher};
+use crate::euph_tags::{ImportedTags, UnconvertedTag};
+use crate::euph_decoder::{
+    EuphMetadata, ChunkType, ChunkRole, EuphContainer, EuphError, EuphLayout, ParseLimits,
+    CHUNK_TABLE_ENTRY_SIZE, SPEC_HEADER_SIZE, FILE_CRC_START, CHUNK_FLAG_CRC,
+    CHUNK_FLAG_COMPRESSED, ROLE_ENHANCED, ROLE_ORIGINAL,
+};
 
 const EUPH_MAGIC: &[u8; 4] = b"EUPH";
 const VERSION_MAJOR: u8 = 1;
 const VERSION_MINOR: u8 = 0;
 
-// Compression flags
-const FLAG_AUDIO_COMPRESSED: u16 = 0x0001;
-const FLAG_METADATA_COMPRESSED: u16 = 0x0002;
-const FLAG_DSP_COMPRESSED: u16 = 0x0004;
-const FLAG_AI_COMPRESSED: u16 = 0x0008;
-
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ChunkBuilder {
     chunk_type: ChunkType,
+    role: Option<String>,
     data: Vec<u8>,
     flags: u32,
-    compressed: bool,
+}
+
+impl ChunkBuilder {
+    pub fn chunk_type(&self) -> ChunkType {
+        self.chunk_type
+    }
+
+    pub fn role(&self) -> Option<&str> {
+        self.role.as_deref()
+    }
+
+    /// Chunk body as it will be written, i.e. after compression.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
 }
 
 #[derive(Debug)]
 pub struct EuphEncoder {
     metadata: Option<EuphMetadata>,
-    chunks: HashMap<ChunkType, ChunkBuilder>,
+    chunks: Vec<ChunkBuilder>,
     flags: u16,
-    compression_level: i32,
+    codec: ChunkCodec,
+    chunk_checksums: bool,
+    timestamp: TimestampSource,
+    metadata_padding: usize,
+    signature: Option<SignatureData>,
+    signing_key: Option<SigningKey>,
+    encryption: Option<ChunkEncryption>,
+    unconverted_tags: Vec<UnconvertedTag>,
+}
+
+/// Where the created and modified timestamps of a written file come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimestampSource {
+    /// The current time.
+    Now,
+    /// The `SOURCE_DATE_EPOCH` environment variable when it holds a Unix
+    /// timestamp, the current time otherwise.
+    #[default]
+    SourceDateEpoch,
+    /// A fixed Unix timestamp.
+    Fixed(u64),
+}
+
+impl TimestampSource {
+    pub(crate) fn resolve(self) -> u64 {
+        self.resolve_with(std::env::var("SOURCE_DATE_EPOCH").ok().as_deref())
+    }
+
+    /// `resolve` with `source_date_epoch` as the value of the environment
+    /// variable.
+    fn resolve_with(self, source_date_epoch: Option<&str>) -> u64 {
+        let now = || {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        };
+        match self {
+            TimestampSource::Now => now(),
+            TimestampSource::SourceDateEpoch => source_date_epoch
+                .and_then(|epoch| epoch.trim().parse().ok())
+                .unwrap_or_else(now),
+            TimestampSource::Fixed(timestamp) => timestamp,
+        }
+    }
+}
+
+impl Default for EuphEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl EuphEncoder {
     pub fn new() -> Self {
         Self {
             metadata: None,
-            chunks: HashMap::new(),
+            chunks: Vec::new(),
             flags: 0,
-            compression_level: 3, // Default ZSTD compression level
+            codec: ChunkCodec::default(),
+            chunk_checksums: true,
+            timestamp: TimestampSource::default(),
+            metadata_padding: 0,
+            signature: None,
+            signing_key: None,
+            encryption: None,
+            unconverted_tags: Vec::new(),
         }
     }
 
+    /// Level for the codec used by the `add_*` methods when asked to
+    /// compress; it is checked against the codec's range when a chunk is added.
     pub fn with_compression(mut self, level: i32) -> Self {
-        self.compression_level = level;
+        self.codec = self.codec.with_level(level);
         self
     }
 
-    pub fn set_metadata(&mut self, metadata: EuphMetadata) -> Result<(), EuphError> {
-        let json_data = serde_json::to_vec_pretty(&metadata)?;
-        
-        self.chunks.insert(ChunkType::Metadata, ChunkBuilder {
-            chunk_type: ChunkType::Metadata,
-            data: json_data,
-            flags: 0,
-            compressed: false,
-        });
-        
-        self.metadata = Some(metadata);
-        Ok(())
+    /// Codec used by the `add_*` methods when asked to compress.
+    pub fn with_codec(mut self, codec: ChunkCodec) -> Self {
+        self.codec = codec;
+        self
     }
 
-    pub fn add_audio_data(&mut self, audio_data: Vec<u8>, compress: bool) -> Result<(), EuphError> {
-        let (final_data, is_compressed) = if compress {
-            let mut encoder = GzEncoder::new(Vec::new(), Compression::new(self.compression_level as u32));
-            encoder.write_all(audio_data.as_slice())?;
-            let compressed = encoder.finish()?;
-            self.flags |= FLAG_AUDIO_COMPRESSED;
-            (compressed, true)
-        } else {
-            (audio_data, false)
-        };
+    /// Store a CRC32 after each chunk body so readers can tell which chunk
+    /// is damaged. Enabled by default.
+    pub fn with_chunk_checksums(mut self, enabled: bool) -> Self {
+        self.chunk_checksums = enabled;
+        self
+    }
 
-        self.chunks.insert(ChunkType::Audio, ChunkBuilder {
-            chunk_type: ChunkType::Audio,
-            data: final_data,
-            flags: if is_compressed { 0x01 } else { 0x00 },
-            compressed: is_compressed,
-        });
+    /// Timestamps to record as the file's created and modified times. Pin
+    /// them, together with the inputs, to get byte-identical output.
+    pub fn with_timestamp(mut self, timestamp: TimestampSource) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
 
-        Ok(())
+    /// Zero bytes to reserve after the METADATA chunk, so that
+    /// `EuphContainer::update_metadata` can later grow it in place.
+    pub fn with_metadata_padding(mut self, bytes: usize) -> Self {
+        self.metadata_padding = bytes;
+        self
     }
 
-    pub fn add_ai_model(&mut self, model_data: Vec<u8>, compress: bool) -> Result<(), EuphError> {
-        let (final_data, is_compressed) = if compress {
-            let mut encoder = GzEncoder::new(Vec::new(), Compression::new(self.compression_level as u32));
-            encoder.write_all(model_data.as_slice())?;
-            let compressed = encoder.finish()?;
-            self.flags |= FLAG_AI_COMPRESSED;
-            (compressed, true)
-        } else {
-            (model_data, false)
-        };
+    /// Sign the integrity hash of the written file with `key`. A SIGNATURE
+    /// chunk is written even if `add_signature` was not called.
+    pub fn with_signing_key(mut self, key: SigningKey) -> Self {
+        self.signing_key = Some(key);
+        self
+    }
 
-        self.chunks.insert(ChunkType::AiModel, ChunkBuilder {
-            chunk_type: ChunkType::AiModel,
-            data: final_data,
-            flags: if is_compressed { 0x01 } else { 0x00 },
-            compressed: is_compressed,
-        });
+    /// Encrypt the chunk types `encryption` lists as they are added, after
+    /// compressing them.
+    pub fn with_encryption(mut self, encryption: ChunkEncryption) -> Self {
+        self.encryption = Some(encryption);
+        self
+    }
+
+    fn codec_for(&self, compress: bool) -> ChunkCodec {
+        if compress { self.codec } else { ChunkCodec::None }
+    }
+
+    /// Add a chunk, compressing it with `codec`, replacing any chunk of the
+    /// same type that has no role. Use `ChunkType::Custom` for
+    /// application-defined chunks; readers that do not know them keep them
+    /// as opaque bytes.
+    ///
+    /// PCM WAV and FLAC audio is compressed in blocks and gets a SEEK chunk
+    /// with the same role, so players can decode part of it; see
+    /// `EuphContainer::read_audio_range`.
+    pub fn add_chunk(&mut self, chunk_type: ChunkType, data: Vec<u8>, codec: ChunkCodec) -> Result<(), EuphError> {
+        self.insert_chunk(chunk_type, None, data, codec)
+    }
+
+    /// Add a chunk labelled with `role`, replacing any chunk of the same type
+    /// and role.
+    pub fn add_chunk_with_role(&mut self, chunk_type: ChunkType, role: &str, data: Vec<u8>, codec: ChunkCodec) -> Result<(), EuphError> {
+        self.insert_chunk(chunk_type, Some(role.to_string()), data, codec)
+    }
+
+    fn insert_chunk(&mut self, chunk_type: ChunkType, role: Option<String>, data: Vec<u8>, codec: ChunkCodec) -> Result<(), EuphError> {
+        if matches!(chunk_type, ChunkType::Roles | ChunkType::Seek) {
+            return Err(EuphError::ReservedChunkType(chunk_type));
+        }
+        if chunk_type != ChunkType::Audio {
+            return self.store_chunk(chunk_type, role, codec.compress(data)?, codec.chunk_flags());
+        }
+
+        // The SEEK chunk for this role is rewritten along with the audio, or
+        // dropped when the new audio cannot be split into blocks
+        let (data, seek_table) = euph_seek::compress_audio(data, codec)?;
+        self.store_chunk(ChunkType::Audio, role.clone(), data, codec.chunk_flags())?;
+        match seek_table {
+            Some(table) => self.store_chunk(ChunkType::Seek, role, canonical_json(&table)?, 0),
+            None => {
+                self.chunks.retain(|c| !(c.chunk_type == ChunkType::Seek && c.role == role));
+                Ok(())
+            }
+        }
+    }
+
+    /// Encrypt a compressed body if the encryption covers its type, and store
+    /// it in place of any chunk of the same type and role.
+    fn store_chunk(&mut self, chunk_type: ChunkType, role: Option<String>, mut data: Vec<u8>, mut flags: u32) -> Result<(), EuphError> {
+        if let Some(encryption) = self.encryption.as_ref().filter(|e| e.applies_to(chunk_type)) {
+            (data, flags) = encryption.encrypt(chunk_type, flags, &data)?;
+        }
+        let chunk = ChunkBuilder { chunk_type, role, data, flags };
 
+        match self.chunks.iter_mut().find(|c| c.chunk_type == chunk.chunk_type && c.role == chunk.role) {
+            Some(existing) => *existing = chunk,
+            None => self.chunks.push(chunk),
+        }
         Ok(())
     }
 
-    pub fn add_dsp_chain(&mut self, dsp_config: &DspChainConfig, compress: bool) -> Result<(), EuphError> {
-        let json_data = serde_json::to_vec_pretty(dsp_config)?;
-        
-        let (final_data, is_compressed) = if compress {
-            let mut encoder = GzEncoder::new(Vec::new(), Compression::new(self.compression_level as u32));
-            encoder.write_all(json_data.as_slice())?;
-            let compressed = encoder.finish()?;
-            self.flags |= FLAG_DSP_COMPRESSED;
-            (compressed, true)
-        } else {
-            (json_data, false)
-        };
+    /// Chunks in the order they will be written: grouped by type in
+    /// `canonical_rank` order, and in the order they were first added within
+    /// a type, so the output does not depend on the order types were added in.
+    pub fn chunks(&self) -> impl Iterator<Item = &ChunkBuilder> {
+        let mut chunks: Vec<&ChunkBuilder> = self.chunks.iter().collect();
+        chunks.sort_by_key(|c| canonical_rank(c.chunk_type));
+        chunks.into_iter()
+    }
 
-        self.chunks.insert(ChunkType::DspChain, ChunkBuilder {
-            chunk_type: ChunkType::DspChain,
-            data: final_data,
-            flags: if is_compressed { 0x01 } else { 0x00 },
-            compressed: is_compressed,
-        });
+    /// Chunks of a type labelled with `role`, e.g. every enhanced AUDIO chunk.
+    pub fn chunks_with_role<'a>(&'a self, chunk_type: ChunkType, role: &'a str) -> impl Iterator<Item = &'a ChunkBuilder> {
+        self.chunks.iter().filter(move |c| c.chunk_type == chunk_type && c.role() == Some(role))
+    }
+
+    /// Tags of the source file that `create_from_audio_file` could not
+    /// carry over.
+    pub fn unconverted_tags(&self) -> &[UnconvertedTag] {
+        &self.unconverted_tags
+    }
 
+    pub fn set_metadata(&mut self, metadata: EuphMetadata) -> Result<(), EuphError> {
+        let json_data = canonical_json(&metadata)?;
+        self.add_chunk(ChunkType::Metadata, json_data, ChunkCodec::None)?;
+        self.metadata = Some(metadata);
         Ok(())
     }
 
+    pub fn add_audio_data(&mut self, audio_data: Vec<u8>, compress: bool) -> Result<(), EuphError> {
+        self.add_chunk(ChunkType::Audio, audio_data, self.codec_for(compress))
+    }
+
+    pub fn add_ai_model(&mut self, model_data: Vec<u8>, compress: bool) -> Result<(), EuphError> {
+        self.add_chunk(ChunkType::AiModel, model_data, self.codec_for(compress))
+    }
+
+    pub fn add_dsp_chain(&mut self, dsp_config: &DspChainConfig, compress: bool) -> Result<(), EuphError> {
+        let json_data = canonical_json(dsp_config)?;
+        self.add_chunk(ChunkType::DspChain, json_data, self.codec_for(compress))
+    }
+
     pub fn add_relativistic_effects(&mut self, effects: &RelativisticEffects, compress: bool) -> Result<(), EuphError> {
-        let json_data = serde_json::to_vec_pretty(effects)?;
-        
-        let (final_data, is_compressed) = if compress {
-            let mut encoder = GzEncoder::new(Vec::new(), Compression::new(self.compression_level as u32));
-            encoder.write_all(json_data.as_slice())?;
-            let compressed = encoder.finish()?;
-            (compressed, true)
-        } else {
-            (json_data, false)
-        };
+        let json_data = canonical_json(effects)?;
+        self.add_chunk(ChunkType::Relativistic, json_data, self.codec_for(compress))
+    }
 
-        self.chunks.insert(ChunkType::Relativistic, ChunkBuilder {
-            chunk_type: ChunkType::Relativistic,
-            data: final_data,
-            flags: if is_compressed { 0x01 } else { 0x00 },
-            compressed: is_compressed,
-        });
+    pub fn add_cues(&mut self, cues: &CueSheet, compress: bool) -> Result<(), EuphError> {
+        let json_data = canonical_json(cues)?;
+        self.add_chunk(ChunkType::Cues, json_data, self.codec_for(compress))
+    }
 
-        Ok(())
+    pub fn add_lyrics(&mut self, lyrics: &Lyrics, compress: bool) -> Result<(), EuphError> {
+        let json_data = canonical_json(lyrics)?;
+        self.add_chunk(ChunkType::Lyrics, json_data, self.codec_for(compress))
     }
 
-    pub fn add_signature(&mut self, signature: &SignatureData) -> Result<(), EuphError> {
-        let json_data = serde_json::to_vec_pretty(signature)?;
-        
-        self.chunks.insert(ChunkType::Signature, ChunkBuilder {
-            chunk_type: ChunkType::Signature,
-            data: json_data,
-            flags: 0,
-            compressed: false,
-        });
+    /// Store pictures as the PICT chunk. Images are compressed already, so
+    /// compressing them rarely saves much.
+    pub fn add_artwork(&mut self, artwork: &Artwork, compress: bool) -> Result<(), EuphError> {
+        self.add_chunk(ChunkType::Artwork, artwork.to_bytes(), self.codec_for(compress))
+    }
+
+    /// Store loudness measurements as the LOUD chunk, e.g. the album values
+    /// of `euph_loudness::measure_album` for each track of an album.
+    pub fn add_loudness(&mut self, loudness: &Loudness, compress: bool) -> Result<(), EuphError> {
+        let json_data = canonical_json(loudness)?;
+        self.add_chunk(ChunkType::Loudness, json_data, self.codec_for(compress))
+    }
+
+    /// Store waveform overviews as the PEAK chunk.
+    pub fn add_peaks(&mut self, peaks: &Peaks, compress: bool) -> Result<(), EuphError> {
+        self.add_chunk(ChunkType::Peaks, peaks.to_bytes(), self.codec_for(compress))
+    }
 
+    /// Write a SIGNATURE chunk from `signature`, with its integrity hash (and,
+    /// given a signing key, its digital signature and certificate) filled in
+    /// when the file is written.
+    pub fn add_signature(&mut self, signature: &SignatureData) -> Result<(), EuphError> {
+        self.signature = Some(signature.clone());
         Ok(())
     }
 
@@ -170,37 +321,48 @@ impl EuphEncoder {
         // Write header placeholder (will be updated later)
         buffer.extend_from_slice(EUPH_MAGIC);
         buffer.extend_from_slice(&[VERSION_MAJOR, VERSION_MINOR]);
-        buffer.extend_from_slice(&self.flags.to_le_bytes());
+        buffer.extend_from_slice(&self.header_flags().to_le_bytes());
         buffer.extend_from_slice(&0u64.to_le_bytes()); // Length placeholder
         buffer.extend_from_slice(&0u32.to_le_bytes()); // CRC placeholder
 
         // Write file timestamps
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        buffer.extend_from_slice(&now.to_le_bytes()); // Created
-        buffer.extend_from_slice(&now.to_le_bytes()); // Modified
+        let timestamp = self.timestamp.resolve();
+        buffer.extend_from_slice(&timestamp.to_le_bytes()); // Created
+        buffer.extend_from_slice(&timestamp.to_le_bytes()); // Modified
 
         // Write chunk count
-        let chunk_count = self.chunks.len() as u32;
+        let chunks = self.table_chunks()?;
+        let chunk_count = chunks.len() as u32;
         buffer.extend_from_slice(&chunk_count.to_le_bytes());
 
         // Calculate chunk offsets and write chunk table
-        let mut current_offset = buffer.len() + (self.chunks.len() * 24); // Header + chunk table
+        let mut current_offset = buffer.len() + chunks.len() * CHUNK_TABLE_ENTRY_SIZE as usize; // Header + chunk table
         let mut chunk_table = Vec::new();
         let mut chunk_data = Vec::new();
 
-        for (chunk_type, chunk_builder) in &self.chunks {
+        for chunk_builder in &chunks {
+            let mut flags = chunk_builder.flags & !CHUNK_FLAG_CRC;
+            if self.chunk_checksums {
+                flags |= CHUNK_FLAG_CRC;
+            }
+
             // Write chunk table entry
-            chunk_table.extend_from_slice(&Self::chunk_type_to_u32(*chunk_type).to_le_bytes());
+            chunk_table.extend_from_slice(&chunk_builder.chunk_type.id().to_le_bytes());
             chunk_table.extend_from_slice(&(current_offset as u64).to_le_bytes());
             chunk_table.extend_from_slice(&(chunk_builder.data.len() as u64).to_le_bytes());
-            chunk_table.extend_from_slice(&chunk_builder.flags.to_le_bytes());
+            chunk_table.extend_from_slice(&flags.to_le_bytes());
 
-            // Add chunk data
+            // Add chunk data, followed by its CRC when enabled
             chunk_data.extend_from_slice(&chunk_builder.data);
             current_offset += chunk_builder.data.len();
+            if self.chunk_checksums {
+                chunk_data.extend_from_slice(&self.calculate_crc32(&chunk_builder.data).to_le_bytes());
+                current_offset += 4;
+            }
+
+            let padding = self.padding_after(chunk_builder);
+            chunk_data.resize(chunk_data.len() + padding, 0);
+            current_offset += padding;
         }
 
         // Combine everything
@@ -209,11 +371,11 @@ impl EuphEncoder {
 
         // Calculate and update file length
         let file_length = buffer.len() as u64;
-        buffer[10..18].copy_from_slice(&file_length.to_le_bytes());
+        buffer[8..16].copy_from_slice(&file_length.to_le_bytes());
 
         // Calculate and update CRC32
-        let crc = self.calculate_crc32(&buffer[22..]); // Skip magic, version, flags, length, and CRC fields
-        buffer[18..22].copy_from_slice(&crc.to_le_bytes());
+        let crc = self.calculate_crc32(&buffer[FILE_CRC_START as usize..]); // Skip magic, version, flags, length, and CRC fields
+        buffer[16..20].copy_from_slice(&crc.to_le_bytes());
 
         // Write to output
         writer.write_all(&buffer)?;
@@ -222,15 +384,73 @@ impl EuphEncoder {
         Ok(())
     }
 
-    fn chunk_type_to_u32(chunk_type: ChunkType) -> u32 {
-        match chunk_type {
-            ChunkType::Audio => 0x41554449,
-            ChunkType::Metadata => 0x4D455441,
-            ChunkType::AiModel => 0x41494D4F,
-            ChunkType::DspChain => 0x44535043,
-            ChunkType::Relativistic => 0x52454C41,
-            ChunkType::Signature => 0x5349474E,
+    /// Header flags with the per-type compression flags matching the chunks:
+    /// set when any chunk of the type is compressed.
+    fn header_flags(&self) -> u16 {
+        let mut flags = self.flags;
+        for chunk_builder in &self.chunks {
+            if let Some(flag) = chunk_builder.chunk_type.compression_flag() {
+                flags &= !flag;
+            }
+        }
+        for chunk_builder in &self.chunks {
+            if let Some(flag) = chunk_builder.chunk_type.compression_flag() {
+                if chunk_builder.flags & CHUNK_FLAG_COMPRESSED != 0 {
+                    flags |= flag;
+                }
+            }
         }
+        flags
+    }
+
+    /// Chunks in the order they go in the chunk table, followed by the ROLES
+    /// chunk if any chunk has a role and the SIGNATURE chunk if one is to be
+    /// written, which then replaces any SIGNATURE chunk added as raw bytes.
+    fn table_chunks(&self) -> Result<Vec<Cow<'_, ChunkBuilder>>, EuphError> {
+        let generate_signature = self.signature.is_some() || self.signing_key.is_some();
+        let mut chunks: Vec<Cow<'_, ChunkBuilder>> = self.chunks()
+            .filter(|c| !(generate_signature && c.chunk_type == ChunkType::Signature))
+            .map(Cow::Borrowed)
+            .collect();
+        chunks.extend(self.roles_chunk()?.map(Cow::Owned));
+
+        if generate_signature {
+            let mut hasher = IntegrityHasher::default();
+            for chunk in &chunks {
+                hasher.start_chunk(chunk.chunk_type, chunk.flags, chunk.data.len() as u64);
+                hasher.write_all(&chunk.data)?;
+            }
+
+            let mut signature = self.signature.clone().unwrap_or_default();
+            euph_signature::sign(&mut signature, hasher.finalize(), self.signing_key.as_ref());
+            chunks.push(Cow::Owned(ChunkBuilder {
+                chunk_type: ChunkType::Signature,
+                role: None,
+                data: canonical_json(&signature)?,
+                flags: 0,
+            }));
+        }
+
+        Ok(chunks)
+    }
+
+    /// ROLES chunk naming the role of each labelled chunk by its table
+    /// position, or `None` when no chunk has a role.
+    fn roles_chunk(&self) -> Result<Option<ChunkBuilder>, EuphError> {
+        let roles: Vec<ChunkRole> = self.chunks()
+            .enumerate()
+            .filter_map(|(index, c)| Some(ChunkRole { index: index as u32, role: c.role.clone()? }))
+            .collect();
+        if roles.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(ChunkBuilder {
+            chunk_type: ChunkType::Roles,
+            role: None,
+            data: serde_json::to_vec(&roles)?,
+            flags: 0,
+        }))
     }
 
     fn calculate_crc32(&self, data: &[u8]) -> u32 {
@@ -240,15 +460,53 @@ impl EuphEncoder {
     }
 
     pub fn get_estimated_size(&self) -> usize {
-        let mut size = 50; // Header size
-        size += self.chunks.len() * 24; // Chunk table
+        let chunks = self.table_chunks().unwrap_or_default();
+
+        let mut size = SPEC_HEADER_SIZE as usize;
+        size += chunks.len() * CHUNK_TABLE_ENTRY_SIZE as usize; // Chunk table
         
-        for chunk in self.chunks.values() {
+        for chunk in &chunks {
             size += chunk.data.len();
+            if self.chunk_checksums {
+                size += 4;
+            }
+            size += self.padding_after(chunk);
         }
         
         size
     }
+
+    fn padding_after(&self, chunk: &ChunkBuilder) -> usize {
+        if chunk.chunk_type == ChunkType::Metadata { self.metadata_padding } else { 0 }
+    }
+}
+
+/// Position of a chunk type in the canonical chunk order: built-in types in
+/// spec order, then custom types by identifier, then the ROLES chunk. The
+/// SIGNATURE chunk comes last, after everything its integrity hash covers.
+fn canonical_rank(chunk_type: ChunkType) -> (u8, u32) {
+    match chunk_type {
+        ChunkType::Audio => (0, 0),
+        ChunkType::Seek => (0, 1),
+        ChunkType::Metadata => (1, 0),
+        ChunkType::AiModel => (2, 0),
+        ChunkType::DspChain => (3, 0),
+        ChunkType::Relativistic => (4, 0),
+        ChunkType::Cues => (5, 0),
+        ChunkType::Lyrics => (6, 0),
+        ChunkType::Artwork => (7, 0),
+        ChunkType::Loudness => (8, 0),
+        ChunkType::Peaks => (9, 0),
+        ChunkType::Custom(_) => (10, chunk_type.id()),
+        ChunkType::Roles => (11, 0),
+        ChunkType::Signature => (12, 0),
+    }
+}
+
+/// Serialize a JSON chunk body with object keys sorted, so structures holding
+/// `HashMap`s serialize the same way every time.
+pub(crate) fn canonical_json<T: Serialize>(value: &T) -> Result<Vec<u8>, EuphError> {
+    Ok(serde_json::to_vec_pretty(&serde_json::to_value(value)?)?)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -380,7 +638,7 @@ pub struct GravityEffects {
     pub phase_shift: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct SignatureData {
     pub author: String,
     pub organization: Option<String>,
@@ -392,12 +650,6 @@ pub struct SignatureData {
     pub certificate: Option<String>,
 }
 
-impl From<std::io::Error> for EuphError {
-    fn from(e: std::io::Error) -> Self {
-        EuphError::IoError(e)
-    }
-}
-
 // Utility functions for working with EUPH files
 impl EuphEncoder {
     pub fn create_from_audio_file(
@@ -405,11 +657,60 @@ impl EuphEncoder {
         metadata: Option<EuphMetadata>,
         options: EncodingOptions,
     ) -> Result<Self, EuphError> {
-        let mut encoder = Self::new().with_compression(options.compression_level);
+        let mut encoder = Self::new()
+            .with_codec(options.codec)
+            .with_timestamp(options.timestamp)
+            .with_metadata_padding(options.metadata_padding);
+        if let Some(encryption) = options.encryption {
+            encoder = encoder.with_encryption(encryption);
+        }
 
-        // Read audio file
-        let audio_data = std::fs::read(audio_path)?;
-        encoder.add_audio_data(audio_data, options.compress_audio)?;
+        // Read audio file, shared with the readers that probe, decode and
+        // read tags from it
+        let audio_data = SharedPayload::from(std::fs::read(audio_path)?);
+        let format = AudioFormat::probe(audio_data.clone());
+        // Cue sheet times become sample positions at the audio's rate
+        let cues = match &options.cue_sheet {
+            Some(cue_sheet) => {
+                let sample_rate = format.map_or(0, |format| format.sample_rate);
+                Some(CueSheet::parse_cue(cue_sheet, sample_rate)?)
+            }
+            None => None,
+        };
+        // Loudness and peaks share one decode of the audio; audio this build
+        // cannot decode gets neither
+        let decoded = match options.analyze_loudness || !options.peak_resolutions.is_empty() {
+            true => euph_audio::decode_audio(audio_data.clone(), options.limits.max_chunk_size).ok(),
+            false => None,
+        };
+        let loudness = decoded.as_ref().filter(|_| options.analyze_loudness).map(|audio| {
+            Loudness::measure(audio, format.map_or(0, |format| format.channel_layout))
+        });
+        let peaks = decoded.as_ref()
+            .filter(|_| !options.peak_resolutions.is_empty())
+            .map(|audio| Peaks::measure(audio, &options.peak_resolutions));
+        // Tags of the source file fill in what the caller leaves out
+        let (mut artwork, imported) = match options.import_tags {
+            true => (Artwork::from_source_file(audio_data.clone()), ImportedTags::from_source_file(audio_data.clone())),
+            false => Default::default(),
+        };
+        for picture in options.artwork.into_iter().flat_map(|artwork| artwork.pictures) {
+            artwork.set_picture(picture);
+        }
+        let metadata = match metadata {
+            Some(mut metadata) => {
+                metadata.fill_missing(imported.metadata);
+                Some(metadata)
+            }
+            None => Some(imported.metadata).filter(|metadata| *metadata != EuphMetadata::default()),
+        };
+        let mut lyrics = imported.lyrics;
+        for track in options.lyrics.into_iter().flat_map(|lyrics| lyrics.tracks) {
+            lyrics.set_track(track);
+        }
+        encoder.unconverted_tags = imported.unconverted;
+        // The readers have let go of their shares by now
+        encoder.add_audio_data(audio_data.into_vec(), options.compress_audio)?;
 
         // Add metadata if provided
         if let Some(meta) = metadata {
@@ -426,6 +727,26 @@ impl EuphEncoder {
             encoder.add_relativistic_effects(&relativistic, true)?;
         }
 
+        if let Some(cues) = cues {
+            encoder.add_cues(&cues, true)?;
+        }
+
+        if !lyrics.tracks.is_empty() {
+            encoder.add_lyrics(&lyrics, true)?;
+        }
+
+        if !artwork.pictures.is_empty() {
+            encoder.add_artwork(&artwork, false)?;
+        }
+
+        if let Some(loudness) = loudness {
+            encoder.add_loudness(&loudness, true)?;
+        }
+
+        if let Some(peaks) = peaks {
+            encoder.add_peaks(&peaks, true)?;
+        }
+
         // Add signature
         if let Some(signature) = options.signature {
             encoder.add_signature(&signature)?;
@@ -434,6 +755,40 @@ impl EuphEncoder {
         Ok(encoder)
     }
 
+    /// Build an encoder holding the chunks of an already parsed container,
+    /// copied as stored so compressed chunks are not recompressed.
+    pub fn from_container<T: AsRef<[u8]>>(container: &EuphContainer<Cursor<T>>) -> Self {
+        let chunks = container.chunks().map(|chunk| {
+            let data = container.raw_chunk_bytes_at(chunk.index()).unwrap_or_default();
+            (chunk.chunk_type(), chunk.role().map(str::to_string), data.to_vec(), chunk.flags())
+        });
+        let mut encoder = Self::from_stored_chunks(container.flags(), chunks);
+        encoder.metadata = container.metadata().cloned();
+        encoder
+    }
+
+    /// Build an encoder holding chunk bodies exactly as stored, with their
+    /// chunk flags, under the header flags `flags`.
+    pub(crate) fn from_stored_chunks(
+        flags: u16,
+        chunks: impl IntoIterator<Item = (ChunkType, Option<String>, Vec<u8>, u32)>,
+    ) -> Self {
+        let mut encoder = Self::new();
+        encoder.flags = flags;
+        encoder.chunks = chunks.into_iter()
+            .map(|(chunk_type, role, data, flags)| ChunkBuilder { chunk_type, role, data, flags })
+            .collect();
+        encoder
+    }
+
+    /// Rewrite an EUPH file of either layout in the spec layout, returning
+    /// the layout it was read in.
+    pub fn upgrade<R: Read + Seek, W: Write + Seek>(reader: &mut R, writer: &mut W) -> Result<EuphLayout, EuphError> {
+        let container = EuphContainer::parse(reader)?;
+        Self::from_container(&container).write(writer)?;
+        Ok(container.layout())
+    }
+
     pub fn create_enhanced_file(
         original_audio: Vec<u8>,
         enhanced_audio: Vec<u8>,
@@ -443,7 +798,9 @@ impl EuphEncoder {
         let mut encoder = Self::new().with_compression(6); // Higher compression for enhanced files
 
         // Add original and enhanced audio
-        encoder.add_audio_data(original_audio, true)?;
+        let codec = encoder.codec;
+        encoder.add_chunk_with_role(ChunkType::Audio, ROLE_ORIGINAL, original_audio, codec)?;
+        encoder.add_chunk_with_role(ChunkType::Audio, ROLE_ENHANCED, enhanced_audio, codec)?;
         encoder.add_ai_model(ai_model_data, true)?;
 
         // Set metadata
@@ -468,72 +825,164 @@ impl EuphEncoder {
 
 #[derive(Debug)]
 pub struct EncodingOptions {
-    pub compression_level: i32,
+    /// Codec for the chunks `compress_audio` and `compress_dsp` ask to compress.
+    pub codec: ChunkCodec,
+    /// Created and modified timestamps of the written file.
+    pub timestamp: TimestampSource,
+    /// Zero bytes reserved after the metadata for in-place edits.
+    pub metadata_padding: usize,
+    /// Chunks to encrypt, and the key to encrypt them with.
+    pub encryption: Option<ChunkEncryption>,
     pub compress_audio: bool,
     pub compress_dsp: bool,
     pub dsp_config: Option<DspChainConfig>,
     pub relativistic_effects: Option<RelativisticEffects>,
+    /// Text of a `.cue` sheet to store as the CUES chunk.
+    pub cue_sheet: Option<String>,
+    /// Lyrics to store along with those imported from the audio file's
+    /// tags, replacing imported ones in the same language.
+    pub lyrics: Option<Lyrics>,
+    /// Pictures to store along with those imported from the audio file's
+    /// tags, replacing imported ones with the same role.
+    pub artwork: Option<Artwork>,
+    /// Read metadata, lyrics and pictures from the audio file's tags. Fields
+    /// of the metadata passed to `create_from_audio_file` win over imported
+    /// ones.
+    pub import_tags: bool,
+    /// Decode the audio to measure its loudness for the LOUD chunk. Audio
+    /// this build cannot decode gets no LOUD chunk.
+    pub analyze_loudness: bool,
+    /// Bounds the decode for the LOUD and PEAK chunks: audio that decodes to
+    /// more than `limits.max_chunk_size` bytes of samples gets neither.
+    pub limits: ParseLimits,
+    /// Bucket sizes of the waveform overviews in the PEAK chunk, in samples
+    /// per channel; empty for no PEAK chunk.
+    pub peak_resolutions: Vec<u32>,
     pub signature: Option<SignatureData>,
 }
 
 impl Default for EncodingOptions {
     fn default() -> Self {
         Self {
-            compression_level: 3,
+            codec: ChunkCodec::default(),
+            timestamp: TimestampSource::default(),
+            metadata_padding: 0,
+            encryption: None,
             compress_audio: true,
             compress_dsp: true,
             dsp_config: None,
             relativistic_effects: None,
+            cue_sheet: None,
+            lyrics: None,
+            artwork: None,
+            import_tags: true,
+            analyze_loudness: true,
+            limits: ParseLimits::default(),
+            peak_resolutions: euph_peaks::DEFAULT_RESOLUTIONS.to_vec(),
             signature: None,
         }
     }
 }
 
-// WASM-compatible exports
-#[cfg(feature = "wasm")]
-mod wasm_exports {
+#[cfg(test)]
+mod tests {
     use super::*;
-    use wasm_bindgen::prelude::*;
+    use crate::euph_audio::AudioCodec;
 
-    #[wasm_bindgen]
-    pub struct WasmEuphEncoder {
-        inner: EuphEncoder,
+    fn write(encoder: &EuphEncoder) -> Vec<u8> {
+        let mut file = Cursor::new(Vec::new());
+        encoder.write(&mut file).unwrap();
+        file.into_inner()
     }
 
-    #[wasm_bindgen]
-    impl WasmEuphEncoder {
-        #[wasm_bindgen(constructor)]
-        pub fn new() -> Self {
-            Self {
-                inner: EuphEncoder::new(),
-            }
-        }
+    fn sample_encoder() -> EuphEncoder {
+        let mut encoder = EuphEncoder::new()
+            .with_codec(ChunkCodec::Gzip { level: 6 })
+            .with_signing_key(SigningKey::from_bytes(&[7; 32]));
+        encoder.add_chunk(ChunkType::Custom(*b"ANLY"), vec![0x5A; 4096], encoder.codec_for(true)).unwrap();
+        encoder.set_metadata(EuphMetadata { title: Some("Epoch".to_string()), ..Default::default() }).unwrap();
+        encoder
+    }
 
-        #[wasm_bindgen(js_name = "addAudioData")]
-        pub fn add_audio_data(&mut self, data: &[u8], compress: bool) -> Result<(), JsValue> {
-            self.inner.add_audio_data(data.to_vec(), compress)
-                .map_err(|e| JsValue::from_str(&format!("{:?}", e)))
+    #[test]
+    fn source_date_epoch_is_read_as_a_timestamp() {
+        let source = TimestampSource::SourceDateEpoch;
+        assert_eq!(source.resolve_with(Some(" 1234567890\n")), 1_234_567_890);
+        assert_eq!(source.resolve_with(Some("0")), 0);
+        assert_eq!(TimestampSource::Fixed(42).resolve_with(Some("1234567890")), 42);
+
+        // Anything else is the current time
+        let before = TimestampSource::Now.resolve();
+        for epoch in [None, Some(""), Some("yesterday"), Some("-1"), Some("1.5")] {
+            let resolved = source.resolve_with(epoch);
+            assert!(resolved >= before && resolved <= TimestampSource::Now.resolve(), "{epoch:?}");
         }
+        assert!(TimestampSource::Now.resolve_with(Some("1234567890")) >= before);
+    }
 
-        #[wasm_bindgen(js_name = "setMetadata")]
-        pub fn set_metadata(&mut self, metadata_json: &str) -> Result<(), JsValue> {
-            let metadata: EuphMetadata = serde_json::from_str(metadata_json)
-                .map_err(|e| JsValue::from_str(&format!("{:?}", e)))?;
-            self.inner.set_metadata(metadata)
-                .map_err(|e| JsValue::from_str(&format!("{:?}", e)))
-        }
+    #[test]
+    fn fixed_timestamp_is_recorded() {
+        let encoder = sample_encoder().with_timestamp(TimestampSource::Fixed(42));
+        let file = write(&encoder);
+        assert_eq!(file, write(&encoder));
+        let container = EuphContainer::parse(&mut Cursor::new(file)).unwrap();
+        assert_eq!((container.created(), container.modified()), (42, 42));
+    }
 
-        #[wasm_bindgen(js_name = "encode")]
-        pub fn encode(&self) -> Result<Vec<u8>, JsValue> {
-            let mut buffer = Vec::new();
-            self.inner.write(&mut std::io::Cursor::new(&mut buffer))
-                .map_err(|e| JsValue::from_str(&format!("{:?}", e)))?;
-            Ok(buffer)
-        }
+    #[test]
+    fn audio_format_descriptor_round_trips() {
+        let audio = crate::euph_audio::DecodedAudio::new(44_100, 2, vec![0.5; 8_820]);
+        let wav = crate::euph_export::wav_file(&audio, 16, 0);
+        let flac = crate::euph_flac::encode(&audio, 24);
+        let container = |payload: Vec<u8>, compress| {
+            let mut encoder = EuphEncoder::new().with_codec(ChunkCodec::Gzip { level: 6 });
+            encoder.add_audio_data(payload, compress).unwrap();
+            EuphContainer::parse(&mut Cursor::new(write(&encoder))).unwrap()
+        };
 
-        #[wasm_bindgen(js_name = "getEstimatedSize")]
-        pub fn get_estimated_size(&self) -> usize {
-            self.inner.get_estimated_size()
+        for (payload, compress) in [(&wav, false), (&wav, true), (&flac, false), (&flac, true)] {
+            let container = container(payload.clone(), compress);
+            let format = container.audio_format().unwrap().unwrap();
+            assert_eq!(Some(format), AudioFormat::probe(payload.clone()));
+            assert_eq!((format.sample_rate, format.channels, format.frames), (44_100, 2, Some(4_410)));
+            assert_eq!(&*container.get_audio_data().unwrap(), &payload[..]);
         }
+        let format = container(wav.clone(), true).audio_format().unwrap().unwrap();
+        assert_eq!((format.codec, format.bits_per_sample), (Some(AudioCodec::Wav), Some(16)));
+
+        // A payload that already has a descriptor keeps it, and one in no
+        // known format gets none
+        let container = container(euph_audio::with_audio_format(flac.clone()), true);
+        assert_eq!(container.audio_format().unwrap().unwrap().codec, Some(AudioCodec::Flac));
+        assert_eq!(&*container.get_audio_data().unwrap(), &flac[..]);
+        let unknown = EuphContainer::parse(&mut Cursor::new({
+            let mut encoder = EuphEncoder::new();
+            encoder.add_audio_data(b"no known format".to_vec(), false).unwrap();
+            write(&encoder)
+        }))
+        .unwrap();
+        assert_eq!(unknown.audio_format().unwrap(), None);
+        assert_eq!(&*unknown.get_audio_data().unwrap(), b"no known format");
+    }
+
+    #[test]
+    fn analysis_decode_is_bounded_by_the_limits() {
+        let audio = crate::euph_audio::DecodedAudio::new(8_000, 1, vec![0.25; 8_000]);
+        let path = std::env::temp_dir().join(format!("euph-encoder-test-{}.wav", std::process::id()));
+        std::fs::write(&path, crate::euph_export::wav_file(&audio, 16, 0)).unwrap();
+        let encode = |limits| {
+            let options = EncodingOptions { limits, ..Default::default() };
+            EuphEncoder::create_from_audio_file(path.to_str().unwrap(), None, options).unwrap()
+        };
+        let types = |encoder: &EuphEncoder| encoder.chunks().map(ChunkBuilder::chunk_type).collect::<Vec<_>>();
+
+        let analysed = types(&encode(ParseLimits::default()));
+        // One second of mono f32 samples is 32000 bytes
+        let bounded = types(&encode(ParseLimits { max_chunk_size: 16_000, ..Default::default() }));
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(analysed.contains(&ChunkType::Loudness) && analysed.contains(&ChunkType::Peaks));
+        assert!(bounded.contains(&ChunkType::Audio));
+        assert!(!bounded.contains(&ChunkType::Loudness) && !bounded.contains(&ChunkType::Peaks));
     }
 }
@@ -0,0 +1,959 @@
+use std::io::Cursor;
+
+use serde::Serialize;
+use symphonia::core::errors::Error as SymphoniaError;
+
+use crate::euph_artwork::{Artwork, Picture, PictureRole};
+use crate::euph_audio::{self, AudioCodec, AudioFormat, DecodedAudio, SharedPayload};
+use crate::euph_cues::CueSheet;
+use crate::euph_decoder::{EuphContainer, EuphError};
+use crate::euph_flac;
+use crate::euph_lyrics::Lyrics;
+use crate::euph_metadata::EuphMetadata;
+use crate::euph_mux;
+
+/// Vendor string of Vorbis comments written for files that had none.
+const VENDOR: &str = concat!("ravr-wasm ", env!("CARGO_PKG_VERSION"));
+
+/// File an AUDIO payload is exported as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// The payload's own format, retagged. FLAC in Ogg is written as a
+    /// FLAC file.
+    Native,
+    /// PCM WAV, decoding other payloads.
+    Wav,
+    /// FLAC, encoding other payloads losslessly from their decoded samples.
+    Flac,
+    /// Matroska audio with the payload's packets as they are and native
+    /// chapters, tags and attachments.
+    Matroska,
+    /// Ogg: Vorbis and Opus as they are, FLAC and WAV as Ogg FLAC. MP3 has
+    /// no Ogg mapping.
+    Ogg,
+}
+
+impl ExportFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            ExportFormat::Native => "native",
+            ExportFormat::Wav => "wav",
+            ExportFormat::Flac => "flac",
+            ExportFormat::Matroska => "matroska",
+            ExportFormat::Ogg => "ogg",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [ExportFormat::Native, ExportFormat::Wav, ExportFormat::Flac, ExportFormat::Matroska, ExportFormat::Ogg]
+            .into_iter()
+            .find(|format| format.name().eq_ignore_ascii_case(name))
+    }
+
+    /// File extension for a payload in `codec` exported in this format.
+    pub fn extension(self, codec: AudioCodec) -> &'static str {
+        match (self, codec) {
+            (ExportFormat::Wav, _) | (ExportFormat::Native, AudioCodec::Wav) => "wav",
+            (ExportFormat::Flac, _) | (ExportFormat::Native, AudioCodec::Flac) => "flac",
+            (ExportFormat::Native, AudioCodec::Mp3) => "mp3",
+            (ExportFormat::Native | ExportFormat::Ogg, AudioCodec::Opus) => "opus",
+            (ExportFormat::Native | ExportFormat::Ogg, _) => "ogg",
+            (ExportFormat::Matroska, _) => "mka",
+        }
+    }
+}
+
+/// What an exported file carries besides the audio.
+#[derive(Debug, Clone, Default)]
+pub struct ExportTags {
+    pub metadata: EuphMetadata,
+    pub artwork: Artwork,
+    pub lyrics: Lyrics,
+    /// Chapters become tags of the exported file; loops and hot cues have
+    /// no place in them.
+    pub cues: Option<CueSheet>,
+}
+
+/// A chapter in milliseconds, ending where the next one starts or the
+/// audio ends.
+pub(crate) struct ChapterTime<'a> {
+    pub start: u64,
+    pub end: Option<u64>,
+    pub title: &'a str,
+    pub performer: Option<&'a str>,
+}
+
+impl ExportTags {
+    pub fn from_container<T: AsRef<[u8]>>(container: &EuphContainer<Cursor<T>>) -> Result<Self, EuphError> {
+        Ok(Self {
+            metadata: container.metadata().cloned().unwrap_or_default(),
+            artwork: container.artwork()?.unwrap_or_default(),
+            lyrics: container.lyrics()?.unwrap_or_default(),
+            cues: container.cues()?,
+        })
+    }
+
+    pub(crate) fn chapters(&self, duration: Option<u64>) -> Vec<ChapterTime<'_>> {
+        let Some(cues) = &self.cues else {
+            return Vec::new();
+        };
+        let milliseconds = |position| (cues.seconds(position) * 1000.0).round() as u64;
+        cues.chapters.iter()
+            .enumerate()
+            .map(|(i, chapter)| ChapterTime {
+                start: milliseconds(chapter.start),
+                end: cues.chapters.get(i + 1).map(|next| milliseconds(next.start)).or(duration),
+                title: &chapter.title,
+                performer: chapter.performer.as_deref(),
+            })
+            .collect()
+    }
+}
+
+/// Write `payload`, the file in an AUDIO chunk, as `format` with `tags`.
+/// Tags the source already had are replaced. Fails with
+/// `UnsupportedAudioFormat` for payloads `format` cannot hold or this build
+/// cannot decode when it has to, and with `ChunkTooLarge` when the samples
+/// of a payload that has to be decoded would exceed `max_size` bytes.
+pub fn export_audio(payload: Vec<u8>, tags: &ExportTags, format: ExportFormat, max_size: u64) -> Result<Vec<u8>, EuphError> {
+    let codec = AudioCodec::detect(&payload).ok_or(EuphError::UnsupportedAudioFormat(None))?;
+    // FLAC in Ogg is unwrapped, and written as a FLAC file when native
+    let payload = match codec == AudioCodec::Flac && payload.starts_with(b"OggS") {
+        true => euph_mux::flac_from_ogg(&payload)?,
+        false => payload,
+    };
+    // Shared by the readers that probe and decode it
+    let payload = &SharedPayload::from(payload);
+    match (format, codec) {
+        (ExportFormat::Native, AudioCodec::Mp3) => retag_mp3(payload, tags),
+        (ExportFormat::Native | ExportFormat::Wav, AudioCodec::Wav) => retag_wav(payload, tags),
+        (ExportFormat::Native | ExportFormat::Flac, AudioCodec::Flac) => retag_flac(payload, tags),
+        (ExportFormat::Native | ExportFormat::Ogg, AudioCodec::Vorbis | AudioCodec::Opus) => retag_ogg(payload, codec, tags),
+        (ExportFormat::Wav, _) => {
+            let (audio, format) = decode(payload, max_size)?;
+            retag_wav(&wav_file(&audio, pcm_bits(&format), format.channel_layout).into(), tags)
+        }
+        (ExportFormat::Flac, _) => {
+            let (audio, format) = decode(payload, max_size)?;
+            retag_flac(&euph_flac::encode(&audio, pcm_bits(&format)).into(), tags)
+        }
+        (ExportFormat::Ogg, AudioCodec::Flac) => euph_mux::ogg_flac(retag_flac(payload, tags)?),
+        (ExportFormat::Ogg, AudioCodec::Wav) => {
+            let (audio, format) = decode(payload, max_size)?;
+            euph_mux::ogg_flac(retag_flac(&euph_flac::encode(&audio, pcm_bits(&format)).into(), tags)?)
+        }
+        (ExportFormat::Ogg, AudioCodec::Mp3) => Err(EuphError::UnsupportedAudioFormat(Some(codec))),
+        (ExportFormat::Matroska, _) => euph_mux::matroska(payload, tags),
+    }
+}
+
+fn decode(payload: &SharedPayload, max_size: u64) -> Result<(DecodedAudio, AudioFormat), EuphError> {
+    euph_audio::decode_with_format(payload.clone(), max_size)
+}
+
+/// Depth of transcoded PCM: 24 bits for deeper lossless sources, 16 for
+/// the rest and for lossy ones.
+fn pcm_bits(format: &AudioFormat) -> u8 {
+    match format.bits_per_sample {
+        Some(bits) if bits > 16 => 24,
+        _ => 16,
+    }
+}
+
+fn invalid(reason: &'static str) -> EuphError {
+    EuphError::AudioDecode(SymphoniaError::DecodeError(reason))
+}
+
+/// Text tags of `metadata` under their Vorbis comment names, each with its
+/// values, fields first and free-form tags after.
+pub(crate) fn text_fields(metadata: &EuphMetadata) -> Vec<(String, Vec<String>)> {
+    fn push(fields: &mut Vec<(String, Vec<String>)>, name: &str, values: Vec<String>) {
+        if !values.is_empty() && !fields.iter().any(|(n, _)| n.eq_ignore_ascii_case(name)) {
+            fields.push((name.to_string(), values));
+        }
+    }
+    let text = |value: &Option<String>| value.iter().cloned().collect::<Vec<_>>();
+    let number = |value: Option<u32>| value.iter().map(u32::to_string).collect::<Vec<_>>();
+    let ids = &metadata.musicbrainz;
+
+    let mut fields = Vec::new();
+    push(&mut fields, "TITLE", text(&metadata.title));
+    push(&mut fields, "ARTIST", text(&metadata.artist));
+    push(&mut fields, "ALBUM", text(&metadata.album));
+    push(&mut fields, "ALBUMARTIST", text(&metadata.album_artist));
+    push(&mut fields, "TRACKNUMBER", number(metadata.track_number));
+    push(&mut fields, "TRACKTOTAL", number(metadata.track_total));
+    push(&mut fields, "DISCNUMBER", number(metadata.disc_number));
+    push(&mut fields, "DISCTOTAL", number(metadata.disc_total));
+    push(&mut fields, "DATE", metadata.year.iter().map(u16::to_string).collect());
+    push(&mut fields, "COMPOSER", text(&metadata.composer));
+    push(&mut fields, "ISRC", text(&metadata.isrc));
+    push(&mut fields, "GENRE", metadata.genre.iter().chain(&metadata.subgenre).cloned().collect());
+    push(&mut fields, "MOOD", metadata.mood.clone());
+    push(&mut fields, "BPM", metadata.tempo.iter().map(f32::to_string).collect());
+    push(&mut fields, "INITIALKEY", text(&metadata.key));
+    push(&mut fields, "MUSICBRAINZ_TRACKID", text(&ids.recording_id));
+    push(&mut fields, "MUSICBRAINZ_RELEASETRACKID", text(&ids.track_id));
+    push(&mut fields, "MUSICBRAINZ_ALBUMID", text(&ids.release_id));
+    push(&mut fields, "MUSICBRAINZ_RELEASEGROUPID", text(&ids.release_group_id));
+    push(&mut fields, "MUSICBRAINZ_ARTISTID", text(&ids.artist_id));
+    push(&mut fields, "MUSICBRAINZ_ALBUMARTISTID", text(&ids.album_artist_id));
+    for (name, values) in &metadata.tags {
+        push(&mut fields, &name.to_uppercase(), values.clone());
+    }
+    fields
+}
+
+/// `NAME=value` comments for a Vorbis comment block: the text fields, LRC
+/// lyrics and chapters, as `CHAPTER001=00:00:00.000` and `CHAPTER001NAME`.
+fn vorbis_comments(tags: &ExportTags, duration: Option<u64>) -> Vec<String> {
+    let mut comments: Vec<String> = text_fields(&tags.metadata)
+        .into_iter()
+        .flat_map(|(name, values)| values.into_iter().map(move |value| format!("{name}={value}")))
+        .collect();
+    for track in &tags.lyrics.tracks {
+        comments.push(format!("LYRICS={}", track.to_lrc()));
+    }
+    for (i, chapter) in tags.chapters(duration).iter().enumerate().take(999) {
+        let ms = chapter.start;
+        comments.push(format!(
+            "CHAPTER{:03}={:02}:{:02}:{:02}.{:03}",
+            i + 1, ms / 3_600_000, ms / 60_000 % 60, ms / 1000 % 60, ms % 1000
+        ));
+        comments.push(format!("CHAPTER{:03}NAME={}", i + 1, chapter.title));
+    }
+    comments
+}
+
+/// Body of a Vorbis comment block, as FLAC stores it and Vorbis and Opus
+/// headers hold after their signatures.
+fn vorbis_comment_body(vendor: &str, comments: &[String]) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    body.extend_from_slice(vendor.as_bytes());
+    body.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for comment in comments {
+        body.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        body.extend_from_slice(comment.as_bytes());
+    }
+    body
+}
+
+/// Vendor string of a Vorbis comment body.
+fn vorbis_vendor(body: &[u8]) -> Option<&str> {
+    let length = u32::from_le_bytes(body.get(..4)?.try_into().unwrap()) as usize;
+    std::str::from_utf8(body.get(4..4usize.checked_add(length)?)?).ok()
+}
+
+/// ID3v2 and FLAC picture type of a role.
+fn picture_type(role: PictureRole) -> u8 {
+    match role {
+        PictureRole::Other => 0,
+        PictureRole::FrontCover => 3,
+        PictureRole::BackCover => 4,
+        PictureRole::Artist => 8,
+    }
+}
+
+/// Body of a FLAC `PICTURE` block, also used base64-encoded as a Vorbis
+/// `METADATA_BLOCK_PICTURE` comment.
+fn flac_picture(picture: &Picture) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&(picture_type(picture.role) as u32).to_be_bytes());
+    for text in [&picture.mime_type, &picture.description] {
+        body.extend_from_slice(&(text.len() as u32).to_be_bytes());
+        body.extend_from_slice(text.as_bytes());
+    }
+    body.extend_from_slice(&picture.width.to_be_bytes());
+    body.extend_from_slice(&picture.height.to_be_bytes());
+    body.extend_from_slice(&[0; 8]);
+    body.extend_from_slice(&(picture.data.len() as u32).to_be_bytes());
+    body.extend_from_slice(&picture.data);
+    body
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut text = String::with_capacity(data.len().div_ceil(3) * 4);
+    for group in data.chunks(3) {
+        let bits = group.iter().enumerate().fold(0u32, |bits, (i, &b)| bits | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= group.len() {
+                true => text.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3F) as usize] as char),
+                false => text.push('='),
+            }
+        }
+    }
+    text
+}
+
+/// Length of the audio in milliseconds, from the headers or by reading
+/// through its packets, where chapters need it for the end of the last.
+fn duration_ms(payload: &SharedPayload, tags: &ExportTags) -> Option<u64> {
+    if tags.cues.as_ref().is_none_or(|cues| cues.chapters.is_empty()) {
+        return None;
+    }
+    if let Some(seconds) = AudioFormat::probe(payload.clone()).and_then(|format| format.duration_secs()) {
+        return Some((seconds * 1000.0).round() as u64);
+    }
+    euph_mux::packets(payload).ok()?.duration_ms()
+}
+
+/// ID3v2.4 text frames for the Vorbis comment names that have one.
+const ID3_TEXT_FRAMES: [(&str, &[u8; 4]); 17] = [
+    ("TITLE", b"TIT2"),
+    ("ARTIST", b"TPE1"),
+    ("ALBUM", b"TALB"),
+    ("ALBUMARTIST", b"TPE2"),
+    ("DATE", b"TDRC"),
+    ("COMPOSER", b"TCOM"),
+    ("ISRC", b"TSRC"),
+    ("GENRE", b"TCON"),
+    ("MOOD", b"TMOO"),
+    ("INITIALKEY", b"TKEY"),
+    ("LABEL", b"TPUB"),
+    ("COPYRIGHT", b"TCOP"),
+    ("LYRICIST", b"TEXT"),
+    ("CONDUCTOR", b"TPE3"),
+    ("REMIXER", b"TPE4"),
+    ("ENCODEDBY", b"TENC"),
+    ("LANGUAGE", b"TLAN"),
+];
+
+/// `TXXX` descriptions MusicBrainz Picard writes. The recording id goes in
+/// a `UFID` frame instead.
+const ID3_MUSICBRAINZ: [(&str, &str); 5] = [
+    ("MUSICBRAINZ_RELEASETRACKID", "MusicBrainz Release Track Id"),
+    ("MUSICBRAINZ_ALBUMID", "MusicBrainz Album Id"),
+    ("MUSICBRAINZ_RELEASEGROUPID", "MusicBrainz Release Group Id"),
+    ("MUSICBRAINZ_ARTISTID", "MusicBrainz Artist Id"),
+    ("MUSICBRAINZ_ALBUMARTISTID", "MusicBrainz Album Artist Id"),
+];
+
+/// An ID3v2.4 tag with the text fields, LRC lyrics as `USLT` frames,
+/// pictures as `APIC` frames and chapters as `CHAP` frames listed by a
+/// `CTOC` frame. Text is UTF-8.
+pub(crate) fn id3v2_tag(tags: &ExportTags, duration: Option<u64>) -> Vec<u8> {
+    let fields = text_fields(&tags.metadata);
+    let first = |name: &str| fields.iter().find(|(n, _)| n == name).map(|(_, values)| values[0].as_str());
+    let text = |values: &[String]| {
+        let mut body = vec![3];
+        body.extend_from_slice(values.join("\0").as_bytes());
+        body
+    };
+    // Comments and lyrics: encoding, language, empty description, text
+    let language_text = |language: &str, value: &str| {
+        let mut body = vec![3];
+        body.extend_from_slice(language.as_bytes());
+        body.push(0);
+        body.extend_from_slice(value.as_bytes());
+        body
+    };
+    let txxx = |description: &str, values: &[String]| {
+        let mut body = vec![3];
+        body.extend_from_slice(description.as_bytes());
+        body.push(0);
+        body.extend_from_slice(values.join("\0").as_bytes());
+        body
+    };
+
+    let mut frames = Vec::new();
+    for (name, values) in &fields {
+        match name.as_str() {
+            "TRACKNUMBER" | "DISCNUMBER" => {
+                let (id, total) = match name.as_str() {
+                    "TRACKNUMBER" => (b"TRCK", first("TRACKTOTAL")),
+                    _ => (b"TPOS", first("DISCTOTAL")),
+                };
+                let position = match total {
+                    Some(total) => format!("{}/{}", values[0], total),
+                    None => values[0].clone(),
+                };
+                frames.extend(id3_frame(id, &text(&[position])));
+            }
+            "TRACKTOTAL" if first("TRACKNUMBER").is_some() => {}
+            "DISCTOTAL" if first("DISCNUMBER").is_some() => {}
+            // A whole number of beats per minute
+            "BPM" => {
+                let bpm = values[0].parse::<f32>().map_or(values[0].clone(), |bpm| bpm.round().to_string());
+                frames.extend(id3_frame(b"TBPM", &text(&[bpm])));
+            }
+            "COMMENT" => {
+                for value in values {
+                    frames.extend(id3_frame(b"COMM", &language_text("XXX", value)));
+                }
+            }
+            "LYRICS" => {
+                for value in values {
+                    frames.extend(id3_frame(b"USLT", &language_text("XXX", value)));
+                }
+            }
+            "MUSICBRAINZ_TRACKID" => {
+                let mut body = b"http://musicbrainz.org\0".to_vec();
+                body.extend_from_slice(values[0].as_bytes());
+                frames.extend(id3_frame(b"UFID", &body));
+            }
+            name => match ID3_TEXT_FRAMES.iter().find(|(n, _)| *n == name) {
+                Some((_, id)) => frames.extend(id3_frame(id, &text(values))),
+                None => {
+                    let description = ID3_MUSICBRAINZ.iter().find(|(n, _)| *n == name).map_or(name, |(_, d)| *d);
+                    frames.extend(id3_frame(b"TXXX", &txxx(description, values)));
+                }
+            },
+        }
+    }
+
+    for track in &tags.lyrics.tracks {
+        // ID3v2 languages are three-letter codes
+        let language = track.language.as_deref()
+            .filter(|language| language.len() == 3 && language.bytes().all(|b| b.is_ascii_lowercase()))
+            .unwrap_or("XXX");
+        frames.extend(id3_frame(b"USLT", &language_text(language, &track.to_lrc())));
+    }
+
+    for picture in &tags.artwork.pictures {
+        let mut body = vec![3];
+        body.extend_from_slice(picture.mime_type.as_bytes());
+        body.push(0);
+        body.push(picture_type(picture.role));
+        body.extend_from_slice(picture.description.as_bytes());
+        body.push(0);
+        body.extend_from_slice(&picture.data);
+        frames.extend(id3_frame(b"APIC", &body));
+    }
+
+    // CTOC lists at most 255 chapters
+    let chapters = tags.chapters(duration);
+    let chapters = &chapters[..chapters.len().min(255)];
+    for (i, chapter) in chapters.iter().enumerate() {
+        let mut body = format!("chp{}\0", i + 1).into_bytes();
+        body.extend_from_slice(&(chapter.start.min(u32::MAX as u64) as u32).to_be_bytes());
+        body.extend_from_slice(&(chapter.end.unwrap_or(chapter.start).min(u32::MAX as u64) as u32).to_be_bytes());
+        // Byte offsets are unused
+        body.extend_from_slice(&[0xFF; 8]);
+        body.extend(id3_frame(b"TIT2", &text(&[chapter.title.to_string()])));
+        if let Some(performer) = chapter.performer {
+            body.extend(id3_frame(b"TPE1", &text(&[performer.to_string()])));
+        }
+        frames.extend(id3_frame(b"CHAP", &body));
+    }
+    if !chapters.is_empty() {
+        // Top-level and ordered
+        let mut body = b"toc\0\x03".to_vec();
+        body.push(chapters.len() as u8);
+        for i in 0..chapters.len() {
+            body.extend_from_slice(format!("chp{}\0", i + 1).as_bytes());
+        }
+        frames.extend(id3_frame(b"CTOC", &body));
+    }
+
+    let mut tag = b"ID3\x04\x00\x00".to_vec();
+    tag.extend_from_slice(&synchsafe(frames.len() as u32));
+    tag.extend(frames);
+    tag
+}
+
+fn id3_frame(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut frame = id.to_vec();
+    frame.extend_from_slice(&synchsafe(body.len() as u32));
+    frame.extend_from_slice(&[0, 0]);
+    frame.extend_from_slice(body);
+    frame
+}
+
+fn synchsafe(size: u32) -> [u8; 4] {
+    [(size >> 21) as u8 & 0x7F, (size >> 14) as u8 & 0x7F, (size >> 7) as u8 & 0x7F, size as u8 & 0x7F]
+}
+
+/// Replace the ID3v2 tags at the start of an MP3 and the ID3v1 tag at its
+/// end with a new ID3v2 tag.
+fn retag_mp3(shared: &SharedPayload, tags: &ExportTags) -> Result<Vec<u8>, EuphError> {
+    let mut audio = shared.as_ref();
+    while audio.starts_with(b"ID3") {
+        let size = (euph_audio::id3v2_size(audio) as usize).min(audio.len());
+        audio = &audio[size..];
+    }
+    if audio.len() >= 128 && audio[audio.len() - 128..].starts_with(b"TAG") {
+        audio = &audio[..audio.len() - 128];
+    }
+    let mut mp3 = id3v2_tag(tags, duration_ms(shared, tags));
+    mp3.extend_from_slice(audio);
+    Ok(mp3)
+}
+
+/// RIFF `INFO` ids for the Vorbis comment names that have one.
+const RIFF_INFO: [(&str, &[u8; 4]); 9] = [
+    ("TITLE", b"INAM"),
+    ("ARTIST", b"IART"),
+    ("ALBUM", b"IPRD"),
+    ("DATE", b"ICRD"),
+    ("GENRE", b"IGNR"),
+    ("COMPOSER", b"IMUS"),
+    ("TRACKNUMBER", b"IPRT"),
+    ("COMMENT", b"ICMT"),
+    ("COPYRIGHT", b"ICOP"),
+];
+
+/// Replace the `INFO` and `adtl` lists, cue points and ID3v2 tag of a WAV
+/// file. The main fields go in an `INFO` list, chapters become cue points
+/// labelled in an `adtl` list, and everything is also written as an ID3v2
+/// tag in an `id3 ` chunk, which carries what `INFO` cannot.
+fn retag_wav(shared: &SharedPayload, tags: &ExportTags) -> Result<Vec<u8>, EuphError> {
+    let format = AudioFormat::probe(shared.clone()).ok_or(EuphError::UnsupportedAudioFormat(Some(AudioCodec::Wav)))?;
+    let payload = shared.as_ref();
+    let mut wav = payload[..12].to_vec();
+    for (id, (start, size)) in euph_audio::riff_chunks(payload) {
+        let body = payload.get(start..start.saturating_add(size)).ok_or_else(|| invalid("wav: truncated chunk"))?;
+        let list_type = body.get(..4).filter(|_| id == *b"LIST");
+        let tag_chunk = matches!(&id, b"id3 " | b"ID3 " | b"cue ") || matches!(list_type, Some(b"INFO" | b"adtl"));
+        if !tag_chunk {
+            push_riff_chunk(&mut wav, &id, body);
+        }
+    }
+
+    let fields = text_fields(&tags.metadata);
+    let mut info = b"INFO".to_vec();
+    for (name, id) in RIFF_INFO {
+        if let Some((_, values)) = fields.iter().find(|(n, _)| n == name) {
+            let mut value = values[0].clone().into_bytes();
+            value.push(0);
+            push_riff_chunk(&mut info, id, &value);
+        }
+    }
+    if info.len() > 4 {
+        push_riff_chunk(&mut wav, b"LIST", &info);
+    }
+
+    // Cue points are sample offsets at the WAV's own rate
+    let chapters = tags.cues.as_ref().map_or(&[][..], |cues| &cues.chapters);
+    if let Some(cues) = tags.cues.as_ref().filter(|_| !chapters.is_empty()) {
+        let mut cue = (chapters.len() as u32).to_le_bytes().to_vec();
+        let mut labels = b"adtl".to_vec();
+        for (i, chapter) in chapters.iter().enumerate() {
+            let id = i as u32 + 1;
+            let position = chapter.start as u128 * format.sample_rate as u128 / cues.sample_rate.max(1) as u128;
+            let position = position.min(u32::MAX as u128) as u32;
+            cue.extend_from_slice(&id.to_le_bytes());
+            cue.extend_from_slice(&position.to_le_bytes());
+            cue.extend_from_slice(b"data");
+            cue.extend_from_slice(&[0; 8]);
+            cue.extend_from_slice(&position.to_le_bytes());
+            let mut label = id.to_le_bytes().to_vec();
+            label.extend_from_slice(chapter.title.as_bytes());
+            label.push(0);
+            push_riff_chunk(&mut labels, b"labl", &label);
+        }
+        push_riff_chunk(&mut wav, b"cue ", &cue);
+        push_riff_chunk(&mut wav, b"LIST", &labels);
+    }
+
+    let duration = format.duration_secs().map(|seconds| (seconds * 1000.0).round() as u64);
+    push_riff_chunk(&mut wav, b"id3 ", &id3v2_tag(tags, duration));
+    let size = (wav.len() - 8) as u32;
+    wav[4..8].copy_from_slice(&size.to_le_bytes());
+    Ok(wav)
+}
+
+fn push_riff_chunk(out: &mut Vec<u8>, id: &[u8; 4], body: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(body);
+    if body.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+/// PCM WAV of `audio` at `bits` per sample, `WAVE_FORMAT_EXTENSIBLE` when
+/// it has more than two channels or more than 16 bits per sample.
+pub(crate) fn wav_file(audio: &DecodedAudio, bits: u8, channel_layout: u32) -> Vec<u8> {
+    let bytes_per_sample = bits as usize / 8;
+    let block_align = audio.channels as usize * bytes_per_sample;
+    let extensible = audio.channels > 2 || bits > 16;
+
+    let mut fmt = Vec::new();
+    fmt.extend_from_slice(&(if extensible { 0xFFFEu16 } else { 1 }).to_le_bytes());
+    fmt.extend_from_slice(&audio.channels.to_le_bytes());
+    fmt.extend_from_slice(&audio.sample_rate.to_le_bytes());
+    fmt.extend_from_slice(&((audio.sample_rate as usize * block_align) as u32).to_le_bytes());
+    fmt.extend_from_slice(&(block_align as u16).to_le_bytes());
+    fmt.extend_from_slice(&(bits as u16).to_le_bytes());
+    if extensible {
+        fmt.extend_from_slice(&22u16.to_le_bytes());
+        fmt.extend_from_slice(&(bits as u16).to_le_bytes());
+        fmt.extend_from_slice(&channel_layout.to_le_bytes());
+        // KSDATAFORMAT_SUBTYPE_PCM
+        fmt.extend_from_slice(b"\x01\x00\x00\x00\x00\x00\x10\x00\x80\x00\x00\xaa\x00\x38\x9b\x71");
+    }
+
+    let scale = (1i64 << (bits - 1)) as f64;
+    let (min, max) = (-(1i64 << (bits - 1)), (1i64 << (bits - 1)) - 1);
+    let mut data = Vec::with_capacity(audio.interleaved().len() * bytes_per_sample);
+    for &sample in audio.interleaved() {
+        let value = ((sample as f64 * scale).round() as i64).clamp(min, max);
+        data.extend_from_slice(&value.to_le_bytes()[..bytes_per_sample]);
+    }
+
+    let mut wav = b"RIFF\0\0\0\0WAVE".to_vec();
+    push_riff_chunk(&mut wav, b"fmt ", &fmt);
+    push_riff_chunk(&mut wav, b"data", &data);
+    let size = (wav.len() - 8) as u32;
+    wav[4..8].copy_from_slice(&size.to_le_bytes());
+    wav
+}
+
+const FLAC_PADDING: u8 = 1;
+const FLAC_VORBIS_COMMENT: u8 = 4;
+const FLAC_PICTURE: u8 = 6;
+
+/// Type and body of a FLAC metadata block.
+pub(crate) type FlacBlock<'a> = (u8, &'a [u8]);
+
+/// The metadata blocks of a FLAC file, STREAMINFO first, and where its
+/// frames start.
+pub(crate) fn flac_blocks(flac: &[u8]) -> Result<(Vec<FlacBlock<'_>>, usize), EuphError> {
+    let mut blocks = Vec::new();
+    let mut position = 4;
+    loop {
+        let header = flac.get(position..position + 4).ok_or_else(|| invalid("flac: truncated metadata block"))?;
+        let length = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let body = flac.get(position + 4..position + 4 + length).ok_or_else(|| invalid("flac: truncated metadata block"))?;
+        blocks.push((header[0] & 0x7F, body));
+        position += 4 + length;
+        if header[0] & 0x80 != 0 {
+            return Ok((blocks, position));
+        }
+    }
+}
+
+pub(crate) fn flac_block_header(block_type: u8, length: usize, last: bool) -> [u8; 4] {
+    let length = (length as u32).to_be_bytes();
+    [block_type | if last { 0x80 } else { 0 }, length[1], length[2], length[3]]
+}
+
+/// Replace the Vorbis comment, picture and padding blocks of a FLAC file,
+/// keeping the vendor string of the old comments.
+fn retag_flac(shared: &SharedPayload, tags: &ExportTags) -> Result<Vec<u8>, EuphError> {
+    let payload = shared.as_ref();
+    let (blocks, frames) = flac_blocks(payload)?;
+    let vendor = blocks.iter()
+        .find(|(block_type, _)| *block_type == FLAC_VORBIS_COMMENT)
+        .and_then(|(_, body)| vorbis_vendor(body))
+        .unwrap_or(VENDOR);
+    let mut kept: Vec<(u8, Vec<u8>)> = blocks.iter()
+        .filter(|(block_type, _)| ![FLAC_PADDING, FLAC_VORBIS_COMMENT, FLAC_PICTURE].contains(block_type))
+        .map(|(block_type, body)| (*block_type, body.to_vec()))
+        .collect();
+    let comments = vorbis_comments(tags, duration_ms(shared, tags));
+    kept.push((FLAC_VORBIS_COMMENT, vorbis_comment_body(vendor, &comments)));
+    for picture in &tags.artwork.pictures {
+        kept.push((FLAC_PICTURE, flac_picture(picture)));
+    }
+
+    let mut flac = b"fLaC".to_vec();
+    for (i, (block_type, body)) in kept.iter().enumerate() {
+        flac.extend_from_slice(&flac_block_header(*block_type, body.len(), i == kept.len() - 1));
+        flac.extend_from_slice(body);
+    }
+    flac.extend_from_slice(&payload[frames..]);
+    Ok(flac)
+}
+
+/// Replace the comment header of an Ogg Vorbis or Opus stream, pictures
+/// going in `METADATA_BLOCK_PICTURE` comments.
+fn retag_ogg(shared: &SharedPayload, codec: AudioCodec, tags: &ExportTags) -> Result<Vec<u8>, EuphError> {
+    let payload = shared.as_ref();
+    let (signature, header_count): (&[u8], usize) = match codec {
+        AudioCodec::Opus => (b"OpusTags", 2),
+        _ => (b"\x03vorbis", 3),
+    };
+    let headers = euph_mux::ogg_headers(payload, header_count)?;
+    let vendor = headers.packets[1].strip_prefix(signature).and_then(vorbis_vendor).unwrap_or(VENDOR);
+
+    let mut comments = vorbis_comments(tags, duration_ms(shared, tags));
+    for picture in &tags.artwork.pictures {
+        comments.push(format!("METADATA_BLOCK_PICTURE={}", base64(&flac_picture(picture))));
+    }
+    let mut comment_header = signature.to_vec();
+    comment_header.extend(vorbis_comment_body(vendor, &comments));
+    if codec == AudioCodec::Vorbis {
+        // Framing bit
+        comment_header.push(1);
+    }
+    let mut packets = headers.packets.clone();
+    packets[1] = comment_header;
+    Ok(euph_mux::replace_ogg_headers(payload, &headers, &packets))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::euph_audio::tests::{opus, silent_mp3, silent_vorbis, tone};
+    use crate::euph_cues::Chapter;
+    use crate::euph_lyrics::LyricsTrack;
+
+    const RECORDING_ID: &str = "f1a2b3c4-d5e6-4f70-8192-a3b4c5d6e7f8";
+    const RELEASE_ID: &str = "11111111-2222-4333-8444-555555555555";
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR\0\0\0\x02\0\0\0\x03\x08\x06\0\0\0";
+
+    /// Tags with chapters at 0 and 500 ms.
+    fn export_tags() -> ExportTags {
+        let metadata = EuphMetadata {
+            title: Some("Title".into()),
+            artist: Some("Artist".into()),
+            track_number: Some(3),
+            track_total: Some(12),
+            tempo: Some(120.4),
+            musicbrainz: crate::euph_metadata::MusicBrainzIds {
+                recording_id: Some(RECORDING_ID.into()),
+                release_id: Some(RELEASE_ID.into()),
+                ..Default::default()
+            },
+            tags: [("LABEL".to_string(), vec!["Label".to_string()]), ("rating".to_string(), vec!["5".to_string()])].into(),
+            ..Default::default()
+        };
+        let mut lyrics = Lyrics::default();
+        lyrics.set_track(LyricsTrack::parse_lrc("[la:eng]\n[00:00.25]Hello").unwrap());
+        let cues = CueSheet {
+            sample_rate: 1000,
+            title: None,
+            performer: None,
+            chapters: vec![
+                Chapter { start: 0, title: "Intro".into(), performer: None },
+                Chapter { start: 500, title: "Verse".into(), performer: Some("Guest".into()) },
+            ],
+            loops: Vec::new(),
+            hot_cues: Vec::new(),
+        };
+        ExportTags {
+            metadata,
+            artwork: Artwork { pictures: vec![Picture::from_image(PictureRole::FrontCover, PNG.to_vec()).unwrap()] },
+            lyrics,
+            cues: Some(cues),
+        }
+    }
+
+    fn old_tags() -> ExportTags {
+        let metadata = EuphMetadata { title: Some("Old title".into()), album: Some("Old album".into()), ..Default::default() };
+        ExportTags { metadata, ..Default::default() }
+    }
+
+    /// Tags and the number of pictures symphonia reads from a file.
+    fn read_back(file: Vec<u8>) -> (Vec<(String, String)>, usize) {
+        let revisions = euph_audio::source_metadata(file);
+        let tags = revisions.iter()
+            .flat_map(|revision| revision.tags())
+            .map(|tag| (tag.key.clone(), tag.value.to_string()))
+            .collect();
+        (tags, revisions.iter().map(|revision| revision.visuals().len()).sum())
+    }
+
+    fn has(tags: &[(String, String)], key: &str, value: &str) -> bool {
+        tags.iter().any(|(k, v)| k.eq_ignore_ascii_case(key) && v == value)
+    }
+
+    fn unsynchsafe(bytes: &[u8]) -> usize {
+        bytes.iter().fold(0, |size, &b| (size << 7) | (b & 0x7F) as usize)
+    }
+
+    /// The frames following an ID3v2.4 tag header or a `CHAP` frame header.
+    fn id3_frames(frames: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        let mut found = Vec::new();
+        let mut rest = frames;
+        while rest.len() >= 10 {
+            let size = unsynchsafe(&rest[4..8]);
+            found.push((rest[..4].try_into().unwrap(), &rest[10..10 + size]));
+            rest = &rest[10 + size..];
+        }
+        assert!(rest.is_empty());
+        found
+    }
+
+    fn riff_body<'a>(file: &'a [u8], id: &[u8; 4], list_type: Option<&[u8; 4]>) -> Vec<&'a [u8]> {
+        euph_audio::riff_chunks(file)
+            .filter(|(chunk, _)| chunk == id)
+            .map(|(_, (start, size))| &file[start..start + size])
+            .filter(|body| list_type.is_none_or(|list_type| body.starts_with(list_type)))
+            .collect()
+    }
+
+    #[test]
+    fn base64_matches_rfc_4648() {
+        let vectors = [("", ""), ("f", "Zg=="), ("fo", "Zm8="), ("foo", "Zm9v"), ("foob", "Zm9vYg=="), ("fooba", "Zm9vYmE="), ("foobar", "Zm9vYmFy")];
+        for (data, text) in vectors {
+            assert_eq!(base64(data.as_bytes()), text);
+        }
+        assert_eq!(base64(&[0xFB, 0xFF, 0xBF]), "+/+/");
+    }
+
+    #[test]
+    fn synchsafe_sizes() {
+        assert_eq!(synchsafe(0), [0, 0, 0, 0]);
+        assert_eq!(synchsafe(127), [0, 0, 0, 127]);
+        assert_eq!(synchsafe(128), [0, 0, 1, 0]);
+        assert_eq!(synchsafe(0x0FFF_FFFF), [0x7F; 4]);
+        for size in [0, 1, 127, 128, 300, 16_384, 2_097_152, 0x0FFF_FFFF] {
+            assert_eq!(unsynchsafe(&synchsafe(size)), size as usize);
+        }
+    }
+
+    #[test]
+    fn id3v2_frames() {
+        let mut tags = export_tags();
+        // A picture needing every byte of a synchsafe size
+        tags.artwork.pictures[0].data.resize(20_000, 0);
+        let tag = id3v2_tag(&tags, Some(2000));
+        assert_eq!(&tag[..6], b"ID3\x04\x00\x00");
+        assert_eq!(unsynchsafe(&tag[6..10]), tag.len() - 10);
+        let frames = id3_frames(&tag[10..]);
+        let bodies = |id: &[u8; 4]| frames.iter().filter(|(frame, _)| frame == id).map(|(_, body)| *body).collect::<Vec<_>>();
+
+        assert_eq!(bodies(b"TIT2"), [b"\x03Title"]);
+        assert_eq!(bodies(b"TRCK"), [b"\x033/12"]);
+        assert_eq!(bodies(b"TBPM"), [b"\x03120"]);
+        assert_eq!(bodies(b"TPUB"), [b"\x03Label"]);
+        assert_eq!(bodies(b"UFID"), [format!("http://musicbrainz.org\0{RECORDING_ID}").as_bytes()]);
+        let txxx = bodies(b"TXXX");
+        assert_eq!(txxx.len(), 2);
+        assert!(txxx.contains(&format!("\x03MusicBrainz Album Id\0{RELEASE_ID}").as_bytes()));
+        assert!(txxx.contains(&&b"\x03RATING\x005"[..]));
+        assert!(bodies(b"USLT")[0].starts_with(b"\x03eng\0[la:eng]\n[00:00.25]Hello"));
+        let apic = bodies(b"APIC");
+        assert!(apic[0].starts_with(b"\x03image/png\0\x03\0\x89PNG"));
+        assert_eq!(apic[0].len(), 13 + 20_000);
+
+        // Chapters end where the next starts, the last with the audio
+        let chapters = bodies(b"CHAP");
+        assert_eq!(chapters.len(), 2);
+        assert!(chapters[0].starts_with(b"chp1\0\0\0\0\0\0\0\x01\xf4\xff\xff\xff\xff\xff\xff\xff\xff"));
+        assert!(chapters[1].starts_with(b"chp2\0\0\0\x01\xf4\0\0\x07\xd0"));
+        let subframes = id3_frames(&chapters[1][21..]);
+        assert_eq!(subframes, [(*b"TIT2", &b"\x03Verse"[..]), (*b"TPE1", &b"\x03Guest"[..])]);
+        assert_eq!(bodies(b"CTOC"), [b"toc\0\x03\x02chp1\0chp2\0"]);
+    }
+
+    #[test]
+    fn mp3_exports_replace_id3v2_and_id3v1_tags() {
+        let frames = silent_mp3(true, 4);
+        let old = id3v2_tag(&old_tags(), None);
+        let mut id3v1 = [0u8; 128];
+        id3v1[..3].copy_from_slice(b"TAG");
+        id3v1[3..12].copy_from_slice(b"Old title");
+        let mp3 = [&old[..], &old, &frames, &id3v1].concat();
+
+        let exported = export_audio(mp3, &export_tags(), ExportFormat::Native, u64::MAX).unwrap();
+        let tag_size = euph_audio::id3v2_size(&exported) as usize;
+        assert_eq!(&exported[tag_size..], &frames[..]);
+        let (tags, pictures) = read_back(exported);
+        assert!(has(&tags, "TIT2", "Title") && has(&tags, "TPE1", "Artist"));
+        assert!(!tags.iter().any(|(_, value)| value.starts_with("Old")));
+        assert_eq!(pictures, 1);
+    }
+
+    #[test]
+    fn wav_exports_replace_info_cues_and_id3() {
+        let audio = tone(8000, 1, 8000);
+        let mut wav = wav_file(&audio, 16, 0);
+        let mut info = b"INFO".to_vec();
+        push_riff_chunk(&mut info, b"INAM", b"Old title\0");
+        push_riff_chunk(&mut wav, b"LIST", &info);
+        push_riff_chunk(&mut wav, b"cue ", &[0; 4]);
+        push_riff_chunk(&mut wav, b"id3 ", &id3v2_tag(&old_tags(), None));
+
+        let exported = export_audio(wav.clone(), &export_tags(), ExportFormat::Native, u64::MAX).unwrap();
+        assert_eq!(u32::from_le_bytes(exported[4..8].try_into().unwrap()) as usize, exported.len() - 8);
+        assert_eq!(riff_body(&exported, b"data", None), riff_body(&wav, b"data", None));
+
+        let info = riff_body(&exported, b"LIST", Some(b"INFO"));
+        assert_eq!(info.len(), 1);
+        let fields: Vec<_> = euph_audio::subchunks(info[0], 4).map(|(id, _)| id).collect();
+        assert_eq!(fields[..2], [*b"INAM", *b"IART"]);
+        assert!(info[0].windows(6).any(|w| w == b"Title\0"));
+
+        // Cue points at the WAV's own rate, labelled in order
+        let cue = riff_body(&exported, b"cue ", None);
+        assert_eq!(cue.len(), 1);
+        assert_eq!(&cue[0][..4], &2u32.to_le_bytes());
+        let positions: Vec<_> = cue[0][4..].chunks(24).map(|point| u32::from_le_bytes(point[4..8].try_into().unwrap())).collect();
+        assert_eq!(positions, [0, 4000]);
+        let labels = riff_body(&exported, b"LIST", Some(b"adtl"));
+        assert_eq!(labels.len(), 1);
+        let labels: Vec<_> = euph_audio::subchunks(labels[0], 4)
+            .map(|(id, (start, size))| (id, &labels[0][start..start + size]))
+            .collect();
+        assert_eq!(labels, [(*b"labl", &b"\x01\0\0\0Intro\0"[..]), (*b"labl", &b"\x02\0\0\0Verse\0"[..])]);
+
+        assert_eq!(riff_body(&exported, b"id3 ", None).len(), 1);
+        let (tags, pictures) = read_back(exported.clone());
+        assert!(has(&tags, "TIT2", "Title") && has(&tags, "INAM", "Title\0"));
+        assert!(!tags.iter().any(|(_, value)| value.starts_with("Old")));
+        assert_eq!(pictures, 1);
+        assert_eq!(euph_audio::decode_audio(exported, u64::MAX).unwrap(), euph_audio::decode_audio(wav, u64::MAX).unwrap());
+    }
+
+    #[test]
+    fn flac_exports_read_back_with_tags_and_chapters() {
+        let audio = tone(8000, 2, 8000);
+        let flac = euph_flac::encode(&audio, 16);
+        let first = export_audio(flac, &old_tags(), ExportFormat::Native, u64::MAX).unwrap();
+        let exported = export_audio(first.clone(), &export_tags(), ExportFormat::Native, u64::MAX).unwrap();
+
+        let (tags, pictures) = read_back(exported.clone());
+        for (key, value) in [
+            ("TITLE", "Title"),
+            ("TRACKNUMBER", "3"),
+            ("TRACKTOTAL", "12"),
+            ("MUSICBRAINZ_TRACKID", RECORDING_ID),
+            ("RATING", "5"),
+            ("CHAPTER001", "00:00:00.000"),
+            ("CHAPTER001NAME", "Intro"),
+            ("CHAPTER002", "00:00:00.500"),
+            ("CHAPTER002NAME", "Verse"),
+        ] {
+            assert!(has(&tags, key, value), "{key}={value}");
+        }
+        assert!(tags.iter().any(|(key, value)| key == "LYRICS" && value.contains("[00:00.25]Hello")));
+        assert!(!tags.iter().any(|(_, value)| value.starts_with("Old")));
+        assert_eq!(pictures, 1);
+
+        let (blocks, _) = flac_blocks(&exported).unwrap();
+        let comments = blocks.iter().find(|(block_type, _)| *block_type == FLAC_VORBIS_COMMENT).unwrap();
+        assert_eq!(vorbis_vendor(comments.1), Some(VENDOR));
+        assert_eq!(euph_audio::decode_audio(exported, u64::MAX).unwrap(), euph_audio::decode_audio(first, u64::MAX).unwrap());
+    }
+
+    #[test]
+    fn vorbis_exports_replace_the_comment_header() {
+        let ogg = silent_vorbis(2, 32_000, 11);
+        let exported = export_audio(ogg.clone(), &export_tags(), ExportFormat::Native, u64::MAX).unwrap();
+
+        let comments = &euph_mux::ogg_headers(&exported, 3).unwrap().packets[1];
+        assert!(comments.starts_with(b"\x03vorbis\x04\0\0\0test"));
+        assert_eq!(comments.last(), Some(&1));
+        let (tags, pictures) = read_back(exported.clone());
+        assert!(has(&tags, "TITLE", "Title") && has(&tags, "CHAPTER002NAME", "Verse"));
+        assert_eq!(pictures, 1);
+        assert_eq!(euph_audio::decode_audio(exported, u64::MAX).unwrap(), euph_audio::decode_audio(ogg, u64::MAX).unwrap());
+    }
+
+    #[test]
+    fn opus_exports_replace_the_comment_header() {
+        let ogg = opus();
+        let exported = export_audio(ogg.clone(), &export_tags(), ExportFormat::Native, u64::MAX).unwrap();
+
+        let headers = euph_mux::ogg_headers(&exported, 2).unwrap();
+        assert_eq!(headers.packets[0], euph_mux::ogg_headers(&ogg, 2).unwrap().packets[0]);
+        let comments = &headers.packets[1];
+        assert!(comments.starts_with(b"OpusTags\x04\0\0\0test"));
+        let picture = format!("METADATA_BLOCK_PICTURE={}", base64(&flac_picture(&export_tags().artwork.pictures[0])));
+        assert!(comments.windows(20).any(|w| w == b"CHAPTER002NAME=Verse"));
+        // Without the framing bit Vorbis has
+        assert!(comments.ends_with(picture.as_bytes()));
+
+        let packets = |ogg: Vec<u8>| {
+            let packets = euph_mux::packets(&ogg.into()).unwrap().packets;
+            packets.into_iter().map(|packet| packet.data).collect::<Vec<_>>()
+        };
+        assert_eq!(packets(exported), packets(ogg));
+    }
+}
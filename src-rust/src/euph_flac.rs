@@ -0,0 +1,426 @@
+use crate::euph_audio::DecodedAudio;
+
+/// Samples per channel in each frame but the last.
+const BLOCK_SIZE: usize = 4096;
+
+/// Encode `audio` as a FLAC file with only a STREAMINFO block, at
+/// `bits_per_sample` from 8 to 24; samples are rounded to that depth.
+/// Frames use fixed predictors and partitioned Rice coding, and stereo
+/// frames whichever of the stereo decorrelation modes is smallest. The
+/// MD5 signature is left unset.
+pub fn encode(audio: &DecodedAudio, bits_per_sample: u8) -> Vec<u8> {
+    let bits = bits_per_sample.clamp(8, 24) as u32;
+    let channels = audio.channels.clamp(1, 8) as usize;
+    let scale = (1i64 << (bits - 1)) as f64;
+    let (min, max) = (-(1i64 << (bits - 1)), (1i64 << (bits - 1)) - 1);
+    let samples: Vec<Vec<i64>> = (0..channels)
+        .map(|channel| {
+            audio.channel(channel as u16).map(|s| ((s as f64 * scale).round() as i64).clamp(min, max)).collect()
+        })
+        .collect();
+    let frames = audio.frames();
+
+    let mut frame_sizes = (u32::MAX, 0u32);
+    let mut body = Vec::new();
+    for (number, start) in (0..frames).step_by(BLOCK_SIZE).enumerate() {
+        let block: Vec<&[i64]> = samples.iter().map(|s| &s[start..frames.min(start + BLOCK_SIZE)]).collect();
+        let frame = encode_frame(&block, number as u64, audio.sample_rate, bits);
+        frame_sizes = (frame_sizes.0.min(frame.len() as u32), frame_sizes.1.max(frame.len() as u32));
+        body.extend(frame);
+    }
+
+    let block_size = BLOCK_SIZE.min(frames.max(16)) as u64;
+    let mut streaminfo = BitWriter::default();
+    streaminfo.write(block_size, 16);
+    streaminfo.write(block_size, 16);
+    streaminfo.write(if frames == 0 { 0 } else { frame_sizes.0 as u64 }, 24);
+    streaminfo.write(frame_sizes.1 as u64, 24);
+    streaminfo.write(audio.sample_rate as u64, 20);
+    streaminfo.write(channels as u64 - 1, 3);
+    streaminfo.write(bits as u64 - 1, 5);
+    streaminfo.write(frames as u64, 36);
+    streaminfo.write(0, 64);
+    streaminfo.write(0, 64);
+
+    let mut flac = b"fLaC".to_vec();
+    flac.push(0x80);
+    flac.extend_from_slice(&34u32.to_be_bytes()[1..]);
+    flac.extend(streaminfo.finish());
+    flac.extend(body);
+    flac
+}
+
+fn encode_frame(block: &[&[i64]], number: u64, sample_rate: u32, bits: u32) -> Vec<u8> {
+    let length = block[0].len();
+    let (assignment, subframes) = if block.len() == 2 {
+        // Stereo is tried as left/right, left/side, side/right and
+        // mid/side, the side channel needing a bit more
+        let (left, right) = (block[0], block[1]);
+        let side = left.iter().zip(right).map(|(l, r)| l - r).collect();
+        let mid = left.iter().zip(right).map(|(l, r)| (l + r) >> 1).collect();
+        let mut candidates = [(left.to_vec(), bits), (right.to_vec(), bits), (side, bits + 1), (mid, bits)]
+            .map(|(samples, bits)| Some(Subframe::best(samples, bits)));
+        let size = |i: usize| candidates[i].as_ref().map_or(0, |subframe| subframe.bits);
+        let modes = [(0b0001, [0, 1]), (0b1000, [0, 2]), (0b1001, [2, 1]), (0b1010, [3, 2])];
+        let (assignment, [first, second]) = modes.into_iter().min_by_key(|(_, [a, b])| size(*a) + size(*b)).unwrap();
+        (assignment, vec![candidates[first].take().unwrap(), candidates[second].take().unwrap()])
+    } else {
+        (block.len() as u64 - 1, block.iter().map(|samples| Subframe::best(samples.to_vec(), bits)).collect())
+    };
+
+    let mut frame = BitWriter::default();
+    frame.write(0b1111_1111_1111_1000, 16);
+    frame.write(if length == BLOCK_SIZE { 0b1100 } else { 0b0111 }, 4);
+    let rate_code = match sample_rate {
+        88200 => 0b0001,
+        176400 => 0b0010,
+        192000 => 0b0011,
+        8000 => 0b0100,
+        16000 => 0b0101,
+        22050 => 0b0110,
+        24000 => 0b0111,
+        32000 => 0b1000,
+        44100 => 0b1001,
+        48000 => 0b1010,
+        96000 => 0b1011,
+        _ => 0b0000,
+    };
+    frame.write(rate_code, 4);
+    frame.write(assignment, 4);
+    let size_code = match bits {
+        8 => 0b001,
+        12 => 0b010,
+        16 => 0b100,
+        20 => 0b101,
+        24 => 0b110,
+        _ => 0b000,
+    };
+    frame.write(size_code, 3);
+    frame.write(0, 1);
+    for byte in utf8_number(number) {
+        frame.write(byte as u64, 8);
+    }
+    if length != BLOCK_SIZE {
+        frame.write(length as u64 - 1, 16);
+    }
+    let crc = crc8(frame.bytes());
+    frame.write(crc as u64, 8);
+
+    for subframe in subframes {
+        subframe.write(&mut frame);
+    }
+    let mut bytes = frame.finish();
+    let crc = crc16(&bytes);
+    bytes.extend_from_slice(&crc.to_be_bytes());
+    bytes
+}
+
+/// Frame numbers are coded like UTF-8, extended to 36 bits.
+fn utf8_number(number: u64) -> Vec<u8> {
+    if number < 0x80 {
+        return vec![number as u8];
+    }
+    let mut continuation = Vec::new();
+    let mut rest = number;
+    while rest >= 1 << (6 - continuation.len()) {
+        continuation.push(0x80 | (rest & 0x3F) as u8);
+        rest >>= 6;
+    }
+    let length = continuation.len() + 1;
+    let lead = (0xFF00u16 >> length) as u8 | rest as u8;
+    std::iter::once(lead).chain(continuation.into_iter().rev()).collect()
+}
+
+/// A channel of a frame in the coding that takes the fewest bits.
+struct Subframe {
+    samples: Vec<i64>,
+    sample_bits: u32,
+    coding: Coding,
+    bits: u64,
+}
+
+enum Coding {
+    Constant,
+    Verbatim,
+    /// Predictor order and the Rice parameter of each partition.
+    Fixed { order: usize, partition_order: u32, parameters: Vec<u32>, residual: Vec<i64> },
+}
+
+impl Subframe {
+    fn best(samples: Vec<i64>, sample_bits: u32) -> Self {
+        let header = 8;
+        if samples.iter().all(|&s| s == samples[0]) {
+            return Self { samples, sample_bits, coding: Coding::Constant, bits: header + sample_bits as u64 };
+        }
+        let verbatim_bits = header + samples.len() as u64 * sample_bits as u64;
+        let mut best = Self { samples, sample_bits, coding: Coding::Verbatim, bits: verbatim_bits };
+        for order in 0..=4.min(best.samples.len() - 1) {
+            let residual = fixed_residual(&best.samples, order);
+            let (partition_order, parameters, residual_bits) = rice_partitions(&residual, best.samples.len(), order);
+            let bits = header + (order as u64 * sample_bits as u64) + residual_bits;
+            if bits < best.bits {
+                best.coding = Coding::Fixed { order, partition_order, parameters, residual };
+                best.bits = bits;
+            }
+        }
+        best
+    }
+
+    fn write(&self, out: &mut BitWriter) {
+        match &self.coding {
+            Coding::Constant => {
+                out.write(0, 8);
+                out.write_signed(self.samples[0], self.sample_bits);
+            }
+            Coding::Verbatim => {
+                out.write(0b0000_0010, 8);
+                for &sample in &self.samples {
+                    out.write_signed(sample, self.sample_bits);
+                }
+            }
+            Coding::Fixed { order, partition_order, parameters, residual } => {
+                out.write(0b0001_0000 | (*order as u64) << 1, 8);
+                for &sample in &self.samples[..*order] {
+                    out.write_signed(sample, self.sample_bits);
+                }
+                // Parameters above 14 need the five-bit method
+                let wide = parameters.iter().any(|&k| k > 14);
+                out.write(wide as u64, 2);
+                out.write(*partition_order as u64, 4);
+                let partition_length = self.samples.len() >> partition_order;
+                let mut values = residual.iter();
+                for (i, &k) in parameters.iter().enumerate() {
+                    out.write(k as u64, if wide { 5 } else { 4 });
+                    let count = if i == 0 { partition_length - order } else { partition_length };
+                    for &value in values.by_ref().take(count) {
+                        out.write_rice(zigzag(value), k);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn fixed_residual(samples: &[i64], order: usize) -> Vec<i64> {
+    (order..samples.len())
+        .map(|i| {
+            let s = |back: usize| samples[i - back];
+            match order {
+                0 => s(0),
+                1 => s(0) - s(1),
+                2 => s(0) - 2 * s(1) + s(2),
+                3 => s(0) - 3 * s(1) + 3 * s(2) - s(3),
+                _ => s(0) - 4 * s(1) + 6 * s(2) - 4 * s(3) + s(4),
+            }
+        })
+        .collect()
+}
+
+/// Partition order and Rice parameters taking the fewest bits for
+/// `residual`, and that number of bits. Sizes are estimated from the sum
+/// of each partition, which is exact to within a bit per value.
+fn rice_partitions(residual: &[i64], length: usize, order: usize) -> (u32, Vec<u32>, u64) {
+    // Partitions must divide the block evenly and the first must hold more
+    // than the warm-up samples
+    let max_order = (0..=8u32)
+        .take_while(|&p| length.is_multiple_of(1 << p) && (length >> p) > order)
+        .last()
+        .unwrap_or(0);
+    let finest = 1usize << max_order;
+    let partition_length = length >> max_order;
+    let mut sums = vec![(0u64, 0u64); finest];
+    for (i, &value) in residual.iter().enumerate() {
+        let partition = (i + order) / partition_length;
+        sums[partition].0 += zigzag(value);
+        sums[partition].1 += 1;
+    }
+
+    let mut best: Option<(u32, Vec<u32>, u64)> = None;
+    for partition_order in (0..=max_order).rev() {
+        if partition_order < max_order {
+            sums = sums.chunks(2).map(|pair| (pair[0].0 + pair[1].0, pair[0].1 + pair[1].1)).collect();
+        }
+        let parameters: Vec<(u32, u64)> = sums.iter().map(|&(sum, count)| rice_parameter(sum, count)).collect();
+        let wide = parameters.iter().any(|&(k, _)| k > 14);
+        let bits = 6 + parameters.iter().map(|&(_, bits)| bits + if wide { 5 } else { 4 }).sum::<u64>();
+        if best.as_ref().is_none_or(|(_, _, best_bits)| bits < *best_bits) {
+            best = Some((partition_order, parameters.into_iter().map(|(k, _)| k).collect(), bits));
+        }
+    }
+    best.unwrap()
+}
+
+/// Rice parameter for values summing to `sum`, and their estimated size.
+fn rice_parameter(sum: u64, count: u64) -> (u32, u64) {
+    let cost = |k: u32| count * (k as u64 + 1) + (sum >> k);
+    (0..=30).map(|k| (k, cost(k))).min_by_key(|&(_, bits)| bits).unwrap()
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    pending: u64,
+    pending_bits: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u64, mut bits: u32) {
+        // Fewer than 8 bits are pending between calls, so 32 more fit
+        while bits > 0 {
+            let take = bits.min(32);
+            bits -= take;
+            self.pending = (self.pending << take) | ((value >> bits) & ((1u64 << take) - 1));
+            self.pending_bits += take;
+            while self.pending_bits >= 8 {
+                self.pending_bits -= 8;
+                self.bytes.push((self.pending >> self.pending_bits) as u8);
+            }
+            self.pending &= (1u64 << self.pending_bits) - 1;
+        }
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64 & ((1u64 << bits) - 1), bits);
+    }
+
+    fn write_rice(&mut self, value: u64, k: u32) {
+        let mut quotient = value >> k;
+        while quotient > 32 {
+            self.write(0, 32);
+            quotient -= 32;
+        }
+        self.write(1, quotient as u32 + 1);
+        self.write(value & ((1u64 << k) - 1), k);
+    }
+
+    /// Whole bytes written so far.
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The bytes, the last padded with zero bits.
+    fn finish(mut self) -> Vec<u8> {
+        if self.pending_bits > 0 {
+            self.write(0, 8 - self.pending_bits);
+        }
+        self.bytes
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 })
+    })
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |crc, &byte| {
+        (0..8).fold(crc ^ (byte as u16) << 8, |crc, _| if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::euph_audio::decode_audio;
+
+    /// Noise over the whole range of `bits`-bit samples.
+    fn noise(frames: usize, channels: usize, bits: u32, seed: u32) -> Vec<Vec<i64>> {
+        let mut state = seed;
+        (0..channels)
+            .map(|_| {
+                (0..frames)
+                    .map(|_| {
+                        state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                        (state as i32 as i64) >> (32 - bits)
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Encode integer samples, decode them back and compare.
+    fn assert_round_trip(channels: &[Vec<i64>], bits: u32, sample_rate: u32) {
+        let scale = (1i64 << (bits - 1)) as f32;
+        let frames = channels[0].len();
+        let interleaved = (0..frames).flat_map(|i| channels.iter().map(move |c| c[i] as f32 / scale)).collect();
+        let audio = DecodedAudio::new(sample_rate, channels.len() as u16, interleaved);
+
+        let flac = encode(&audio, bits as u8);
+        let decoded = decode_audio(flac, u64::MAX).unwrap();
+        assert_eq!((decoded.sample_rate, decoded.channels, decoded.frames()), (sample_rate, channels.len() as u16, frames));
+        for (channel, expected) in channels.iter().enumerate() {
+            let samples: Vec<i64> = decoded.channel(channel as u16).map(|s| (s * scale).round() as i64).collect();
+            assert_eq!(&samples, expected, "{bits}-bit channel {channel}");
+        }
+    }
+
+    #[test]
+    fn noise_round_trips_at_each_depth() {
+        for bits in [8, 16, 20, 24] {
+            assert_round_trip(&noise(10_000, 1, bits, bits), bits, 44_100);
+            assert_round_trip(&noise(10_000, 2, bits, bits + 1), bits, 48_000);
+        }
+    }
+
+    #[test]
+    fn smooth_signals_use_predictors() {
+        let sine: Vec<i64> = (0..20_000).map(|i| ((i as f64 * 0.01).sin() * 20_000.0) as i64).collect();
+        let channels = [sine.clone(), sine.iter().map(|s| s / 2).collect()];
+        assert_round_trip(&channels, 16, 44_100);
+        // Far below the two bytes per sample of verbatim subframes
+        let audio = DecodedAudio::new(44_100, 1, sine.iter().map(|&s| s as f32 / 32_768.0).collect());
+        assert!(encode(&audio, 16).len() < sine.len());
+    }
+
+    #[test]
+    fn extreme_values_round_trip() {
+        for bits in [16, 24] {
+            let (min, max) = (-(1i64 << (bits - 1)), (1i64 << (bits - 1)) - 1);
+            // The side channel of opposite extremes needs a bit more than the samples
+            let left: Vec<i64> = (0..5000).map(|i| if i % 2 == 0 { max } else { min }).collect();
+            let right: Vec<i64> = left.iter().map(|&s| if s == max { min } else { max }).collect();
+            assert_round_trip(&[left.clone(), right], bits, 44_100);
+            assert_round_trip(&[vec![min; 5000], vec![max; 5000]], bits, 44_100);
+            assert_round_trip(&[left], bits, 44_100);
+        }
+    }
+
+    #[test]
+    fn samples_beyond_full_scale_are_clamped() {
+        let audio = DecodedAudio::new(44_100, 1, vec![1.5, -1.5, 1.0, -1.0, 0.0]);
+        let decoded = decode_audio(encode(&audio, 16), u64::MAX).unwrap();
+        let samples: Vec<i64> = decoded.channel(0).map(|s| (s * 32_768.0).round() as i64).collect();
+        assert_eq!(samples, [32_767, -32_768, 32_767, -32_768, 0]);
+    }
+
+    #[test]
+    fn short_blocks_round_trip() {
+        // Whole files shorter than the 16-sample minimum block, and a last
+        // block of a few samples after full ones
+        for frames in [1, 2, 5, 15] {
+            assert_round_trip(&noise(frames, 1, 16, frames as u32), 16, 44_100);
+            assert_round_trip(&noise(frames, 2, 24, frames as u32), 24, 44_100);
+        }
+        assert_round_trip(&noise(BLOCK_SIZE * 2 + 3, 2, 16, 7), 16, 44_100);
+    }
+
+    #[test]
+    fn rates_without_a_frame_code_use_streaminfo() {
+        assert_round_trip(&noise(3000, 2, 16, 3), 16, 11_025);
+    }
+
+    #[test]
+    fn frame_numbers_are_coded_like_utf8() {
+        assert_eq!(utf8_number(0x7F), [0x7F]);
+        assert_eq!(utf8_number(0x80), [0xC2, 0x80]);
+        assert_eq!(utf8_number(0x7FF), [0xDF, 0xBF]);
+        assert_eq!(utf8_number(0x800), [0xE0, 0xA0, 0x80]);
+        assert_eq!(utf8_number(0xFFFF), [0xEF, 0xBF, 0xBF]);
+        assert_eq!(utf8_number(0x10000), [0xF0, 0x90, 0x80, 0x80]);
+    }
+}
@@ -0,0 +1,483 @@
+use serde::{Serialize, Deserialize};
+
+use crate::euph_audio::{self, DecodedAudio};
+use crate::euph_decoder::EuphError;
+
+/// Loudness ReplayGain 2.0 normalizes to, in LUFS.
+pub const REPLAYGAIN_REFERENCE: f64 = -18.0;
+
+/// Body of a LOUD chunk: loudness of the first AUDIO chunk per ITU-R
+/// BS.1770-4 and EBU R128, and the gains that bring it to `reference`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS, `None` for silence or audio shorter
+    /// than one 400 ms block.
+    pub integrated: Option<f64>,
+    /// Loudness range in LU (EBU Tech 3342).
+    pub range: f64,
+    /// Largest sample, 1.0 being full scale.
+    pub sample_peak: f64,
+    /// Largest value between samples, estimated by 4x oversampling.
+    pub true_peak: f64,
+    /// Target loudness of the gains, in LUFS.
+    pub reference: f64,
+    /// Gain in dB that brings the track to `reference`.
+    pub track_gain: Option<f64>,
+    /// Gain in dB that brings the album the track belongs to to
+    /// `reference`, applied alike to every track so their balance is kept.
+    pub album_gain: Option<f64>,
+    /// Largest true peak of any track of the album.
+    pub album_peak: Option<f64>,
+}
+
+impl Loudness {
+    /// Measure a decoded track. `channel_layout` is a
+    /// `WAVE_FORMAT_EXTENSIBLE` channel mask, 0 if unknown.
+    pub fn measure(audio: &DecodedAudio, channel_layout: u32) -> Self {
+        let mut meter = LoudnessMeter::new(audio.sample_rate, audio.channels, channel_layout);
+        meter.add_samples(audio.interleaved());
+        meter.loudness()
+    }
+
+    /// Decode a WAV, FLAC, MP3 or Ogg Vorbis file and measure it, failing
+    /// with `ChunkTooLarge` once the decoded samples would exceed `max_size`
+    /// bytes, such as `ParseLimits::max_chunk_size`.
+    pub fn from_source_file<T>(payload: T, max_size: u64) -> Result<Self, EuphError>
+    where
+        T: AsRef<[u8]> + Send + Sync + 'static,
+    {
+        let (audio, format) = euph_audio::decode_with_format(payload, max_size)?;
+        Ok(Self::measure(&audio, format.channel_layout))
+    }
+
+    /// Linear factor to scale samples by for track or album normalization,
+    /// lowered so the true peak stays within full scale. Album mode falls
+    /// back to the track values for files without album values, and
+    /// silence is left as it is.
+    pub fn scale(&self, album: bool) -> f32 {
+        let (gain, peak) = match (album, self.album_gain, self.album_peak) {
+            (true, Some(gain), peak) => (gain, peak.unwrap_or(self.true_peak)),
+            _ => match self.track_gain {
+                Some(gain) => (gain, self.true_peak),
+                None => return 1.0,
+            },
+        };
+        let scale = 10f64.powf(gain / 20.0);
+        let limit = if peak > 0.0 { 1.0 / peak } else { scale };
+        scale.min(limit) as f32
+    }
+}
+
+/// Measure tracks as an album: each keeps its own track values, and all get
+/// the gain and peak of the album, whose loudness is gated over the blocks
+/// of every track together.
+pub fn measure_album(meters: &[LoudnessMeter]) -> Vec<Loudness> {
+    let blocks: Vec<f64> = meters.iter().flat_map(LoudnessMeter::momentary_blocks).collect();
+    let album_gain = gated_loudness(&blocks).map(|loudness| REPLAYGAIN_REFERENCE - loudness);
+    let album_peak = meters.iter().map(|meter| meter.true_peak).fold(0.0, f64::max);
+    meters.iter()
+        .map(|meter| Loudness { album_gain, album_peak: Some(album_peak), ..meter.loudness() })
+        .collect()
+}
+
+// K-weighting filters of BS.1770, as analogue prototypes so they can be
+// matched at any sample rate
+const SHELF_FREQUENCY: f64 = 1681.974450955533;
+const SHELF_GAIN_DB: f64 = 3.999843853973347;
+const SHELF_Q: f64 = 0.7071752369554196;
+const HIGH_PASS_FREQUENCY: f64 = 38.13547087602444;
+const HIGH_PASS_Q: f64 = 0.5003270373238773;
+
+/// 48-tap interpolation filter of BS.1770-4 Annex 2, as its four phases.
+const TRUE_PEAK_PHASES: [[f64; 12]; 4] = [
+    [
+        0.0017089843750, 0.0109863281250, -0.0196533203125, 0.0332031250000, -0.0594482421875, 0.1373291015625,
+        0.9721679687500, -0.1022949218750, 0.0476074218750, -0.0266113281250, 0.0148925781250, -0.0083007812500,
+    ],
+    [
+        -0.0291748046875, 0.0292968750000, -0.0517578125000, 0.0891113281250, -0.1665039062500, 0.4650878906250,
+        0.7797851562500, -0.2003173828125, 0.1015625000000, -0.0582275390625, 0.0330810546875, -0.0189208984375,
+    ],
+    [
+        -0.0189208984375, 0.0330810546875, -0.0582275390625, 0.1015625000000, -0.2003173828125, 0.7797851562500,
+        0.4650878906250, -0.1665039062500, 0.0891113281250, -0.0517578125000, 0.0292968750000, -0.0291748046875,
+    ],
+    [
+        -0.0083007812500, 0.0148925781250, -0.0266113281250, 0.0476074218750, -0.1022949218750, 0.9721679687500,
+        0.1373291015625, -0.0594482421875, 0.0332031250000, -0.0196533203125, 0.0109863281250, 0.0017089843750,
+    ],
+];
+
+/// Steps of 100 ms per 400 ms momentary block and per 3 s short-term block.
+const MOMENTARY_STEPS: usize = 4;
+const SHORT_TERM_STEPS: usize = 30;
+
+/// Running BS.1770 measurement of one track, fed interleaved samples.
+#[derive(Debug, Clone)]
+pub struct LoudnessMeter {
+    channels: Vec<ChannelState>,
+    /// Samples per channel in a 100 ms step.
+    step_length: usize,
+    step_position: usize,
+    step_energy: f64,
+    /// Weighted sum of squared K-weighted samples of each finished step.
+    steps: Vec<f64>,
+    sample_peak: f64,
+    true_peak: f64,
+}
+
+#[derive(Debug, Clone)]
+struct ChannelState {
+    weight: f64,
+    shelf: Biquad,
+    high_pass: Biquad,
+    /// Last samples, newest first, for the true peak filter.
+    history: [f64; 12],
+}
+
+impl LoudnessMeter {
+    pub fn new(sample_rate: u32, channels: u16, channel_layout: u32) -> Self {
+        let sample_rate = sample_rate.max(1) as f64;
+        let shelf = Biquad::high_shelf(sample_rate);
+        let high_pass = Biquad::high_pass(sample_rate);
+        Self {
+            channels: channel_weights(channels, channel_layout)
+                .into_iter()
+                .map(|weight| ChannelState { weight, shelf, high_pass, history: [0.0; 12] })
+                .collect(),
+            step_length: ((sample_rate / 10.0).round() as usize).max(1),
+            step_position: 0,
+            step_energy: 0.0,
+            steps: Vec::new(),
+            sample_peak: 0.0,
+            true_peak: 0.0,
+        }
+    }
+
+    pub fn add_samples(&mut self, interleaved: &[f32]) {
+        if self.channels.is_empty() {
+            return;
+        }
+        for frame in interleaved.chunks_exact(self.channels.len()) {
+            for (channel, &sample) in self.channels.iter_mut().zip(frame) {
+                let sample = sample as f64;
+                self.sample_peak = self.sample_peak.max(sample.abs());
+                // Interpolation can only add peaks, never hide a sample
+                self.true_peak = self.true_peak.max(sample.abs());
+
+                channel.history.copy_within(..11, 1);
+                channel.history[0] = sample;
+                for phase in &TRUE_PEAK_PHASES {
+                    let value: f64 = phase.iter().zip(&channel.history).map(|(c, x)| c * x).sum();
+                    self.true_peak = self.true_peak.max(value.abs());
+                }
+
+                let weighted = channel.high_pass.process(channel.shelf.process(sample));
+                self.step_energy += channel.weight * weighted * weighted;
+            }
+
+            self.step_position += 1;
+            if self.step_position == self.step_length {
+                self.steps.push(self.step_energy);
+                self.step_position = 0;
+                self.step_energy = 0.0;
+            }
+        }
+    }
+
+    /// Track values measured so far, without album values.
+    pub fn loudness(&self) -> Loudness {
+        let integrated = gated_loudness(&self.momentary_blocks().collect::<Vec<_>>());
+        Loudness {
+            integrated,
+            range: self.loudness_range(),
+            sample_peak: self.sample_peak,
+            true_peak: self.true_peak,
+            reference: REPLAYGAIN_REFERENCE,
+            track_gain: integrated.map(|loudness| REPLAYGAIN_REFERENCE - loudness),
+            album_gain: None,
+            album_peak: None,
+        }
+    }
+
+    /// Mean energy of each 400 ms block, the blocks overlapping by 75%.
+    fn momentary_blocks(&self) -> impl Iterator<Item = f64> + '_ {
+        self.blocks(MOMENTARY_STEPS)
+    }
+
+    fn blocks(&self, steps: usize) -> impl Iterator<Item = f64> + '_ {
+        let length = (steps * self.step_length) as f64;
+        self.steps.windows(steps).map(move |window| window.iter().sum::<f64>() / length)
+    }
+
+    /// Spread between the 10th and 95th percentile of the short-term
+    /// loudness, gated 20 LU below the mean.
+    fn loudness_range(&self) -> f64 {
+        let blocks: Vec<f64> = self.blocks(SHORT_TERM_STEPS).collect();
+        let Some(gate) = gated_mean(&blocks).map(|mean| energy_to_lufs(mean) - 20.0) else {
+            return 0.0;
+        };
+        let mut loudness: Vec<f64> = blocks.iter()
+            .map(|&energy| energy_to_lufs(energy))
+            .filter(|&lufs| lufs > ABSOLUTE_GATE && lufs > gate)
+            .collect();
+        if loudness.is_empty() {
+            return 0.0;
+        }
+        loudness.sort_by(f64::total_cmp);
+        let percentile = |p: f64| loudness[((loudness.len() - 1) as f64 * p).round() as usize];
+        percentile(0.95) - percentile(0.10)
+    }
+}
+
+/// Blocks quieter than this do not count, in LUFS.
+const ABSOLUTE_GATE: f64 = -70.0;
+
+fn energy_to_lufs(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+/// Mean energy of the blocks above the absolute gate.
+fn gated_mean(blocks: &[f64]) -> Option<f64> {
+    mean(blocks.iter().copied().filter(|&energy| energy_to_lufs(energy) > ABSOLUTE_GATE))
+}
+
+/// Loudness of the blocks above the absolute gate and 10 LU below their
+/// mean, in LUFS.
+fn gated_loudness(blocks: &[f64]) -> Option<f64> {
+    let gate = energy_to_lufs(gated_mean(blocks)?) - 10.0;
+    let energy = mean(blocks.iter().copied().filter(|&energy| {
+        let lufs = energy_to_lufs(energy);
+        lufs > ABSOLUTE_GATE && lufs > gate
+    }))?;
+    Some(energy_to_lufs(energy))
+}
+
+fn mean(values: impl Iterator<Item = f64>) -> Option<f64> {
+    let (sum, count) = values.fold((0.0, 0usize), |(sum, count), value| (sum + value, count + 1));
+    (count > 0).then(|| sum / count as f64)
+}
+
+/// BS.1770 channel weights: 0 for the LFE channel, 1.41 for surround
+/// channels and 1 for the rest. Without a channel mask, six channels are
+/// taken to be 5.1 in WAV order.
+fn channel_weights(channels: u16, channel_layout: u32) -> Vec<f64> {
+    const LOW_FREQUENCY: u32 = 0x8;
+    const SURROUND: u32 = 0x10 | 0x20 | 0x100 | 0x200 | 0x400;
+
+    let layout = match (channel_layout, channels) {
+        (0, 6) => 0x3F,
+        (layout, _) => layout,
+    };
+    // Speakers take the channels in order of their mask bit
+    let mut speakers = (0..32).map(|bit| 1u32 << bit).filter(|speaker| layout & speaker != 0);
+    (0..channels)
+        .map(|_| match speakers.next() {
+            Some(LOW_FREQUENCY) => 0.0,
+            Some(speaker) if speaker & SURROUND != 0 => 1.41,
+            _ => 1.0,
+        })
+        .collect()
+}
+
+/// Second-order filter in transposed direct form II.
+#[derive(Debug, Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    /// Stage 1 of K-weighting, modelling the acoustic effect of the head.
+    fn high_shelf(sample_rate: f64) -> Self {
+        let k = (std::f64::consts::PI * SHELF_FREQUENCY / sample_rate).tan();
+        let vh = 10f64.powf(SHELF_GAIN_DB / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / SHELF_Q + k * k;
+        Self {
+            b: [(vh + vb * k / SHELF_Q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / SHELF_Q + k * k) / a0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / SHELF_Q + k * k) / a0],
+            state: [0.0; 2],
+        }
+    }
+
+    /// Stage 2 of K-weighting, the RLB high-pass.
+    fn high_pass(sample_rate: f64) -> Self {
+        let k = (std::f64::consts::PI * HIGH_PASS_FREQUENCY / sample_rate).tan();
+        let a0 = 1.0 + k / HIGH_PASS_Q + k * k;
+        Self {
+            b: [1.0, -2.0, 1.0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / HIGH_PASS_Q + k * k) / a0],
+            state: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.state[0];
+        self.state[0] = self.b[1] * x - self.a[0] * y + self.state[1];
+        self.state[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+    use crate::euph_decoder::{ChunkType, EuphContainer};
+    use crate::euph_encoder::EuphEncoder;
+    use crate::euph_export;
+
+    const RATE: u32 = 48_000;
+
+    /// Stereo sine of `frequency` Hz, in sections of seconds at a peak level
+    /// in dBFS, as the EBU test signals are made.
+    fn sine(frequency: f64, sections: &[(f64, f64)]) -> DecodedAudio {
+        let mut samples = Vec::new();
+        let mut n = 0u64;
+        for &(seconds, dbfs) in sections {
+            let amplitude = 10f64.powf(dbfs / 20.0);
+            for _ in 0..(seconds * RATE as f64).round() as u64 {
+                let sample = (amplitude * (2.0 * std::f64::consts::PI * frequency * n as f64 / RATE as f64).sin()) as f32;
+                samples.extend([sample, sample]);
+                n += 1;
+            }
+        }
+        DecodedAudio::new(RATE, 2, samples)
+    }
+
+    fn assert_near(value: f64, expected: f64, tolerance: f64) {
+        assert!((value - expected).abs() <= tolerance, "{value} is not {expected} ± {tolerance}");
+    }
+
+    // Cases of EBU Tech 3341, whose integrated loudness must be within
+    // 0.1 LU, mostly shortened with the sections kept in proportion
+
+    #[test]
+    fn sine_at_minus_23_dbfs_measures_minus_23_lufs() {
+        let loudness = Loudness::measure(&sine(1000.0, &[(5.0, -23.0)]), 0);
+        assert_near(loudness.integrated.unwrap(), -23.0, 0.1);
+        assert_near(loudness.track_gain.unwrap(), 5.0, 0.1);
+        assert_near(loudness.sample_peak, 10f64.powf(-23.0 / 20.0), 1e-4);
+        assert_eq!(loudness.reference, REPLAYGAIN_REFERENCE);
+        assert!(loudness.range < 0.1);
+    }
+
+    #[test]
+    fn sine_at_minus_33_dbfs_measures_minus_33_lufs() {
+        let loudness = Loudness::measure(&sine(1000.0, &[(5.0, -33.0)]), 0);
+        assert_near(loudness.integrated.unwrap(), -33.0, 0.1);
+    }
+
+    #[test]
+    fn relative_gate_leaves_out_quiet_sections() {
+        let loudness = Loudness::measure(&sine(1000.0, &[(2.0, -36.0), (12.0, -23.0), (2.0, -36.0)]), 0);
+        assert_near(loudness.integrated.unwrap(), -23.0, 0.1);
+    }
+
+    #[test]
+    fn absolute_gate_leaves_out_near_silence() {
+        // At full length: blocks straddling the silence weigh more in shorter sections
+        let sections = [(10.0, -72.0), (10.0, -26.0), (10.0, -20.0), (10.0, -26.0), (10.0, -72.0)];
+        let loudness = Loudness::measure(&sine(1000.0, &sections), 0);
+        assert_near(loudness.integrated.unwrap(), -23.0, 0.1);
+    }
+
+    #[test]
+    fn silence_has_no_loudness() {
+        let loudness = Loudness::measure(&DecodedAudio::new(RATE, 2, vec![0.0; RATE as usize * 4]), 0);
+        assert_eq!((loudness.integrated, loudness.track_gain, loudness.range), (None, None, 0.0));
+        assert_eq!((loudness.sample_peak, loudness.true_peak), (0.0, 0.0));
+        assert_eq!(loudness.scale(false), 1.0);
+
+        // Audio shorter than one block is not measured either
+        let short = Loudness::measure(&sine(1000.0, &[(0.3, -20.0)]), 0);
+        assert_eq!(short.integrated, None);
+    }
+
+    // Cases of EBU Tech 3342, whose loudness range must be within 1 LU,
+    // with sections of 10 s rather than 20
+
+    #[test]
+    fn loudness_range_of_stepped_levels() {
+        for (quiet, loud, range) in [(-30.0, -20.0, 10.0), (-20.0, -15.0, 5.0), (-40.0, -20.0, 20.0)] {
+            let loudness = Loudness::measure(&sine(1000.0, &[(10.0, quiet), (10.0, loud)]), 0);
+            assert_near(loudness.range, range, 1.0);
+        }
+    }
+
+    #[test]
+    fn true_peak_finds_peaks_between_samples() {
+        // A quarter of the sample rate, 45° out of phase with the samples:
+        // each sample is 3 dB below the peak of the wave
+        let samples: Vec<f32> = (0..RATE)
+            .flat_map(|n| {
+                let sample = (0.5 * (std::f64::consts::FRAC_PI_2 * n as f64 + std::f64::consts::FRAC_PI_4).sin()) as f32;
+                [sample, sample]
+            })
+            .collect();
+        let loudness = Loudness::measure(&DecodedAudio::new(RATE, 2, samples), 0);
+        assert_near(loudness.sample_peak, 0.5 * std::f64::consts::FRAC_1_SQRT_2, 1e-6);
+        let true_peak_db = 20.0 * (loudness.true_peak / 0.5).log10();
+        assert!((-0.4..=0.2).contains(&true_peak_db), "{true_peak_db} dB");
+    }
+
+    #[test]
+    fn scale_keeps_the_true_peak_within_full_scale() {
+        let loudness = Loudness { track_gain: Some(6.0), true_peak: 0.9, ..Loudness::measure(&sine(1000.0, &[(1.0, -23.0)]), 0) };
+        assert_near(loudness.scale(false) as f64, 1.0 / 0.9, 1e-6);
+        let loudness = Loudness { track_gain: Some(-6.0), album_gain: Some(-3.0), album_peak: Some(0.5), ..loudness };
+        assert_near(loudness.scale(false) as f64, 10f64.powf(-6.0 / 20.0), 1e-6);
+        assert_near(loudness.scale(true) as f64, 10f64.powf(-3.0 / 20.0), 1e-6);
+    }
+
+    #[test]
+    fn album_gain_gates_every_track_together() {
+        let meter = |level| {
+            let mut meter = LoudnessMeter::new(RATE, 2, 0);
+            meter.add_samples(sine(1000.0, &[(4.0, level)]).interleaved());
+            meter
+        };
+        let album = measure_album(&[meter(-20.0), meter(-30.0)]);
+        // Both tracks pass the gate, so the album is their mean energy
+        let album_loudness = 10.0 * ((10f64.powf(-2.0) + 10f64.powf(-3.0)) / 2.0).log10();
+        for (track, level) in album.iter().zip([-20.0, -30.0]) {
+            assert_near(track.integrated.unwrap(), level, 0.1);
+            assert_near(track.track_gain.unwrap(), REPLAYGAIN_REFERENCE - level, 0.1);
+            assert_near(track.album_gain.unwrap(), REPLAYGAIN_REFERENCE - album_loudness, 0.1);
+            assert_eq!(track.album_peak, Some(album[0].true_peak));
+        }
+        assert!(album[0].true_peak > album[1].true_peak);
+    }
+
+    #[test]
+    fn loud_body_round_trips() {
+        let loudness = Loudness {
+            album_gain: Some(-4.25),
+            album_peak: Some(0.98),
+            ..Loudness::measure(&sine(1000.0, &[(2.0, -20.0)]), 0)
+        };
+        let mut encoder = EuphEncoder::new();
+        encoder.add_loudness(&loudness, true).unwrap();
+        let mut file = Cursor::new(Vec::new());
+        encoder.write(&mut file).unwrap();
+
+        let container = EuphContainer::parse(&mut Cursor::new(file.into_inner())).unwrap();
+        assert_eq!(container.loudness().unwrap(), Some(loudness));
+        let silence = Loudness::measure(&DecodedAudio::new(RATE, 1, vec![0.0; 100]), 0);
+        let body = serde_json::to_vec(&silence).unwrap();
+        assert_eq!(serde_json::from_slice::<Loudness>(&body).unwrap(), silence);
+    }
+
+    #[test]
+    fn source_file_decode_is_bounded() {
+        let audio = DecodedAudio::new(8_000, 1, vec![0.25; 8_000]);
+        let wav = euph_export::wav_file(&audio, 16, 0);
+        assert!(Loudness::from_source_file(wav.clone(), u64::MAX).is_ok());
+        assert!(matches!(
+            Loudness::from_source_file(wav, 16_000),
+            Err(EuphError::ChunkTooLarge { chunk: ChunkType::Audio, limit: 16_000, .. })
+        ));
+    }
+}
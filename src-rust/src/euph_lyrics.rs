@@ -0,0 +1,312 @@
+use std::fmt::Write;
+use serde::{Serialize, Deserialize};
+
+use crate::euph_decoder::EuphError;
+
+/// Body of a LYRC chunk: time-synced lyrics, one set per language.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Lyrics {
+    pub tracks: Vec<LyricsTrack>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LyricsTrack {
+    /// BCP 47 tag such as `en` or `pt-BR`, `None` if unknown.
+    pub language: Option<String>,
+    /// Lines in time order.
+    pub lines: Vec<LyricLine>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LyricLine {
+    /// Milliseconds from the start of the audio.
+    pub time: u64,
+    pub text: String,
+    /// Word timings from enhanced LRC, empty when the line is only timed as
+    /// a whole. Word texts keep their trailing spaces, so they join up to
+    /// the line.
+    pub words: Vec<LyricWord>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LyricWord {
+    pub time: u64,
+    pub text: String,
+}
+
+impl Lyrics {
+    /// Lyrics in `language`, or the first set when `language` is `None`.
+    pub fn track(&self, language: Option<&str>) -> Option<&LyricsTrack> {
+        match language {
+            Some(language) => self.tracks.iter().find(|track| {
+                track.language.as_deref().is_some_and(|tag| tag.eq_ignore_ascii_case(language))
+            }),
+            None => self.tracks.first(),
+        }
+    }
+
+    /// Add or replace the lyrics for the language of `track`.
+    pub fn set_track(&mut self, track: LyricsTrack) {
+        let same_language = |t: &LyricsTrack| match (&t.language, &track.language) {
+            (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+            (a, b) => a == b,
+        };
+        match self.tracks.iter_mut().find(|t| same_language(t)) {
+            Some(existing) => *existing = track,
+            None => self.tracks.push(track),
+        }
+    }
+
+    /// Line being sung at `time` milliseconds in `language`, see `track`.
+    pub fn line_at(&self, language: Option<&str>, time: u64) -> Option<&LyricLine> {
+        self.track(language)?.line_at(time)
+    }
+}
+
+impl LyricsTrack {
+    /// Last line that starts at or before `time` milliseconds.
+    pub fn line_at(&self, time: u64) -> Option<&LyricLine> {
+        let index = self.line_index_at(time)?;
+        Some(&self.lines[index])
+    }
+
+    pub fn line_index_at(&self, time: u64) -> Option<usize> {
+        self.lines.partition_point(|line| line.time <= time).checked_sub(1)
+    }
+
+    /// Read an LRC or enhanced LRC file. Lines with several time tags are
+    /// repeated at each time, `[offset:]` is applied to every time, and
+    /// `[la:]` gives the language. Other ID tags and untimed lines are
+    /// skipped. Fails if no line has a time tag.
+    pub fn parse_lrc(text: &str) -> Result<Self, EuphError> {
+        let mut track = Self::default();
+        // Milliseconds to move every time by; positive offsets show lyrics sooner
+        let mut offset: i64 = 0;
+        let mut has_time_tags = false;
+
+        for line in text.lines() {
+            let mut rest = line.trim_start_matches('\u{feff}').trim();
+            let mut times = Vec::new();
+            while let Some((tag, after)) = rest.strip_prefix('[').and_then(|r| r.split_once(']')) {
+                match parse_lrc_time(tag) {
+                    Some(time) => times.push(time),
+                    None => {
+                        if let Some((key, value)) = tag.split_once(':') {
+                            let value = value.trim();
+                            match key.trim().to_ascii_lowercase().as_str() {
+                                "offset" => offset = value.trim_start_matches('+').parse().unwrap_or(0),
+                                "la" | "lang" | "language" if !value.is_empty() => track.language = Some(value.to_string()),
+                                _ => {}
+                            }
+                        }
+                    }
+                }
+                rest = after.trim_start();
+            }
+            if times.is_empty() {
+                continue;
+            }
+            has_time_tags = true;
+
+            let (text, words) = parse_words(rest);
+            for time in times {
+                track.lines.push(LyricLine { time, text: text.clone(), words: words.clone() });
+            }
+        }
+        if !has_time_tags {
+            return Err(EuphError::InvalidLyrics);
+        }
+
+        let shift = |time: u64| time.saturating_add_signed(offset.saturating_neg());
+        for line in &mut track.lines {
+            line.time = shift(line.time);
+            for word in &mut line.words {
+                word.time = shift(word.time);
+            }
+        }
+        track.lines.sort_by_key(|line| line.time);
+        Ok(track)
+    }
+
+    /// Write the lines as LRC, with word timings as enhanced LRC where there
+    /// are any. LRC times are in hundredths of a second, so milliseconds are
+    /// rounded down.
+    pub fn to_lrc(&self) -> String {
+        let mut lrc = String::new();
+        if let Some(language) = &self.language {
+            let _ = writeln!(lrc, "[la:{}]", language);
+        }
+        for line in &self.lines {
+            let _ = write!(lrc, "[{}]", lrc_time(line.time));
+            // Text before the first word tag is not timed on its own
+            let timed: String = line.words.iter().map(|word| word.text.as_str()).collect();
+            lrc.push_str(line.text.strip_suffix(timed.as_str()).unwrap_or(""));
+            for word in &line.words {
+                let _ = write!(lrc, "<{}>{}", lrc_time(word.time), word.text);
+            }
+            lrc.push('\n');
+        }
+        lrc
+    }
+}
+
+impl LyricLine {
+    /// Word being sung at `time` milliseconds, for lines with word timings.
+    pub fn word_at(&self, time: u64) -> Option<&LyricWord> {
+        let index = self.words.partition_point(|word| word.time <= time).checked_sub(1)?;
+        Some(&self.words[index])
+    }
+}
+
+/// Split the text of an enhanced LRC line into `<mm:ss.xx>` timed words.
+/// Text before the first word tag, or a line without any, is untimed and
+/// only kept in the line text.
+fn parse_words(line: &str) -> (String, Vec<LyricWord>) {
+    let mut words: Vec<LyricWord> = Vec::new();
+    let mut untimed = String::new();
+    let mut rest = line;
+    loop {
+        let Some((start, end, time)) = next_word_tag(rest) else {
+            match words.last_mut() {
+                Some(word) => word.text.push_str(rest),
+                None => untimed.push_str(rest),
+            }
+            break;
+        };
+        match words.last_mut() {
+            Some(word) => word.text.push_str(&rest[..start]),
+            None => untimed.push_str(&rest[..start]),
+        }
+        words.push(LyricWord { time, text: String::new() });
+        rest = &rest[end + 1..];
+    }
+
+    // An empty closing tag marks when the last word ends
+    words.retain(|word| !word.text.is_empty());
+    while words.last().is_some_and(|word| word.text.trim().is_empty()) {
+        words.pop();
+    }
+    if let Some(last) = words.last_mut() {
+        last.text.truncate(last.text.trim_end().len());
+    }
+    let mut text = untimed;
+    for word in &words {
+        text.push_str(&word.text);
+    }
+    (text.trim().to_string(), words)
+}
+
+/// Start, end and time of the first `<mm:ss.xx>` tag in `text`.
+fn next_word_tag(text: &str) -> Option<(usize, usize, u64)> {
+    let mut from = 0;
+    while let Some(start) = text[from..].find('<').map(|i| from + i) {
+        let end = text[start..].find('>').map(|i| start + i)?;
+        if let Some(time) = parse_lrc_time(&text[start + 1..end]) {
+            return Some((start, end, time));
+        }
+        from = start + 1;
+    }
+    None
+}
+
+/// `mm:ss`, `mm:ss.xx`, `mm:ss.xxx` or `mm:ss:xx` as milliseconds.
+fn parse_lrc_time(tag: &str) -> Option<u64> {
+    let (minutes, rest) = tag.trim().split_once(':')?;
+    let (seconds, fraction) = match rest.split_once(['.', ':']) {
+        Some((seconds, fraction)) => (seconds, fraction),
+        None => (rest, "0"),
+    };
+    let all_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    if !all_digits(minutes) || !all_digits(seconds) || !all_digits(fraction) || fraction.len() > 3 {
+        return None;
+    }
+    let seconds: u64 = seconds.parse().ok()?;
+    if seconds >= 60 {
+        return None;
+    }
+    let millis = fraction.parse::<u64>().ok()? * 10u64.pow(3 - fraction.len() as u32);
+    minutes.parse::<u64>().ok()?.checked_mul(60)?.checked_add(seconds)?.checked_mul(1000)?.checked_add(millis)
+}
+
+fn lrc_time(time: u64) -> String {
+    let centis = time / 10;
+    format!("{:02}:{:02}.{:02}", centis / 6000, centis / 100 % 60, centis % 100)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_lines_tags_and_offset() {
+        let lrc = "\u{feff}[ar:Someone]\n[la: pt-BR ]\n[offset:+500]\n[00:12.00][01:02.50]Chorus\nuntimed line\n[00:05.1]First\n[00:07:25]Second\n";
+        let track = LyricsTrack::parse_lrc(lrc).unwrap();
+        assert_eq!(track.language.as_deref(), Some("pt-BR"));
+        let lines: Vec<(u64, &str)> = track.lines.iter().map(|l| (l.time, l.text.as_str())).collect();
+        // Sorted, repeated per time tag and moved 500 ms sooner
+        assert_eq!(lines, [(4_600, "First"), (6_750, "Second"), (11_500, "Chorus"), (62_000, "Chorus")]);
+        assert_eq!(track.line_at(6_749).unwrap().text, "First");
+        assert!(track.line_at(4_599).is_none());
+    }
+
+    #[test]
+    fn negative_offset_delays_lines() {
+        let track = LyricsTrack::parse_lrc("[offset:-250]\n[00:00.00]Start\n").unwrap();
+        assert_eq!(track.lines[0].time, 250);
+    }
+
+    #[test]
+    fn parses_word_timings() {
+        let track = LyricsTrack::parse_lrc("[00:10.00]Oh <00:10.50>hello <00:11.00>there <00:11.80>\n").unwrap();
+        let line = &track.lines[0];
+        assert_eq!(line.text, "Oh hello there");
+        let words: Vec<(u64, &str)> = line.words.iter().map(|w| (w.time, w.text.as_str())).collect();
+        assert_eq!(words, [(10_500, "hello "), (11_000, "there")]);
+        assert_eq!(line.word_at(10_999).unwrap().text, "hello ");
+        assert!(line.word_at(10_000).is_none());
+    }
+
+    #[test]
+    fn export_round_trips() {
+        let lrc = "[la:en]\n[00:01.00]Plain line\n[00:10.00]Oh <00:10.50>hello <00:11.00>there\n[61:02.34]Late\n";
+        let track = LyricsTrack::parse_lrc(lrc).unwrap();
+        assert_eq!(track.to_lrc(), lrc);
+        assert_eq!(LyricsTrack::parse_lrc(&track.to_lrc()).unwrap(), track);
+    }
+
+    #[test]
+    fn export_rounds_milliseconds_down() {
+        let track = LyricsTrack { language: None, lines: vec![LyricLine { time: 1_239, text: "x".to_string(), words: Vec::new() }] };
+        assert_eq!(track.to_lrc(), "[00:01.23]x\n");
+    }
+
+    #[test]
+    fn file_without_time_tags_is_invalid() {
+        assert!(matches!(LyricsTrack::parse_lrc("[ar:Someone]\nno times here\n"), Err(EuphError::InvalidLyrics)));
+        assert!(matches!(LyricsTrack::parse_lrc(""), Err(EuphError::InvalidLyrics)));
+    }
+
+    #[test]
+    fn lrc_time_formats() {
+        assert_eq!(parse_lrc_time("01:02"), Some(62_000));
+        assert_eq!(parse_lrc_time("01:02.3"), Some(62_300));
+        assert_eq!(parse_lrc_time("01:02.345"), Some(62_345));
+        assert_eq!(parse_lrc_time("01:02:34"), Some(62_340));
+        assert_eq!(parse_lrc_time("01:60.00"), None);
+        assert_eq!(parse_lrc_time("01:02.3456"), None);
+        assert_eq!(parse_lrc_time("ar:Someone"), None);
+    }
+
+    #[test]
+    fn tracks_by_language() {
+        let mut lyrics = Lyrics::default();
+        lyrics.set_track(LyricsTrack::parse_lrc("[la:en]\n[00:01.00]Hello\n").unwrap());
+        lyrics.set_track(LyricsTrack::parse_lrc("[la:de]\n[00:01.00]Hallo\n").unwrap());
+        lyrics.set_track(LyricsTrack::parse_lrc("[la:EN]\n[00:01.00]Hi\n").unwrap());
+        assert_eq!(lyrics.tracks.len(), 2);
+        assert_eq!(lyrics.line_at(Some("en"), 1_000).unwrap().text, "Hi");
+        assert_eq!(lyrics.line_at(Some("de"), 1_000).unwrap().text, "Hallo");
+        assert_eq!(lyrics.line_at(None, 1_000).unwrap().text, "Hi");
+        assert!(lyrics.line_at(Some("fr"), 1_000).is_none());
+    }
+}
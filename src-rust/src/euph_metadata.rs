@@ -0,0 +1,605 @@
+use std::collections::BTreeMap;
+
+use serde::{Serialize, Deserialize};
+use serde_json::{Map, Value};
+
+use crate::euph_decoder::EuphError;
+
+/// Version of the METADATA schema this build writes. Files without a
+/// `schema_version` are version 1, which had every field required.
+pub const METADATA_SCHEMA_VERSION: u32 = 2;
+
+/// Tempos outside this range, in BPM, are reported by `validate`.
+pub const TEMPO_RANGE: std::ops::RangeInclusive<f32> = 20.0..=999.0;
+
+/// Body of the METADATA chunk. Every field may be left out; unknown fields,
+/// such as those of later schema versions, are kept in `extra` so they
+/// survive a rewrite.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EuphMetadata {
+    pub schema_version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artist: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub album: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub album_artist: Option<String>,
+    /// From 1.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub track_number: Option<u32>,
+    /// Tracks on the disc.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub track_total: Option<u32>,
+    /// From 1.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disc_number: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disc_total: Option<u32>,
+    /// Year of release.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub year: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub composer: Option<String>,
+    /// International Standard Recording Code, e.g. `USRC17607839`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub isrc: Option<String>,
+    #[serde(skip_serializing_if = "MusicBrainzIds::is_empty")]
+    pub musicbrainz: MusicBrainzIds,
+    /// Free-form tags, each with any number of values, e.g. `LABEL` or
+    /// `CATALOGNUMBER`. A single string is read as one value.
+    #[serde(deserialize_with = "deserialize_tags", skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub genre: Option<String>,
+    pub subgenre: Vec<String>,
+    pub mood: Vec<String>,
+    /// Beats per minute.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tempo: Option<f32>,
+    /// Musical key, e.g. `Am`, `F# major` or Camelot `8A`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// e.g. `4/4`; also read as `timesignature`, the spelling of early
+    /// versions of the spec.
+    #[serde(alias = "timesignature", skip_serializing_if = "Option::is_none")]
+    pub time_signature: Option<String>,
+    /// 0 to 1.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub energy: Option<f32>,
+    /// 0 (negative) to 1 (positive).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub valence: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spatial_profile: Option<SpatialProfile>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// MusicBrainz identifiers (UUIDs) of the recording and its release.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MusicBrainzIds {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recording_id: Option<String>,
+    /// The track on the release, as opposed to the recording on it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub track_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub release_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub release_group_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artist_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub album_artist_id: Option<String>,
+}
+
+impl MusicBrainzIds {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    fn fields(&self) -> [(&'static str, &Option<String>); 6] {
+        [
+            ("musicbrainz.recording_id", &self.recording_id),
+            ("musicbrainz.track_id", &self.track_id),
+            ("musicbrainz.release_id", &self.release_id),
+            ("musicbrainz.release_group_id", &self.release_group_id),
+            ("musicbrainz.artist_id", &self.artist_id),
+            ("musicbrainz.album_artist_id", &self.album_artist_id),
+        ]
+    }
+}
+
+/// Each dimension from 0 to 1.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SpatialProfile {
+    pub width: f32,
+    pub depth: f32,
+    pub height: f32,
+}
+
+/// Something odd about a METADATA chunk that did not stop it being read.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum MetadataWarning {
+    /// The chunk was written with an older schema and has been migrated.
+    Migrated { from: u32, to: u32 },
+    /// The chunk was written with a later schema; fields this build does
+    /// not know are kept as they are.
+    NewerSchema { version: u32 },
+    /// A field held a JSON value of the wrong type and was dropped.
+    InvalidType { field: &'static str },
+    /// A number is outside the range its field allows.
+    OutOfRange { field: &'static str, value: f32 },
+    /// A key, time signature, ISRC or MusicBrainz id in no notation this
+    /// build recognises.
+    InvalidFormat { field: &'static str, value: String },
+}
+
+/// Fields of the current schema, as named in the JSON.
+const FIELDS: [&str; 23] = [
+    "schema_version", "title", "artist", "album", "album_artist", "track_number",
+    "track_total", "disc_number", "disc_total", "year", "composer", "isrc",
+    "musicbrainz", "tags", "genre", "subgenre", "mood", "tempo", "key",
+    "time_signature", "energy", "valence", "spatial_profile",
+];
+
+/// Migrations between schema versions, the one at index `i` taking version
+/// `i + 1` to `i + 2`.
+const MIGRATIONS: [fn(&mut Map<String, Value>); 1] = [migrate_v1];
+
+impl Default for EuphMetadata {
+    fn default() -> Self {
+        Self {
+            schema_version: METADATA_SCHEMA_VERSION,
+            title: None,
+            artist: None,
+            album: None,
+            album_artist: None,
+            track_number: None,
+            track_total: None,
+            disc_number: None,
+            disc_total: None,
+            year: None,
+            composer: None,
+            isrc: None,
+            musicbrainz: MusicBrainzIds::default(),
+            tags: BTreeMap::new(),
+            genre: None,
+            subgenre: Vec::new(),
+            mood: Vec::new(),
+            tempo: None,
+            key: None,
+            time_signature: None,
+            energy: None,
+            valence: None,
+            spatial_profile: None,
+            extra: Map::new(),
+        }
+    }
+}
+
+impl EuphMetadata {
+    /// Read a METADATA chunk of any schema version, migrating older ones to
+    /// the current schema. Fields of the wrong type are dropped rather than
+    /// failing the whole chunk; only JSON that is not an object fails.
+    pub fn from_json(data: &[u8]) -> Result<(Self, Vec<MetadataWarning>), EuphError> {
+        let Value::Object(mut fields) = serde_json::from_slice(data)? else {
+            return Err(EuphError::JsonError(serde::de::Error::custom("METADATA is not a JSON object")));
+        };
+        let mut warnings = Vec::new();
+
+        let version = match fields.get("schema_version") {
+            None => 1,
+            Some(version) => match version.as_u64().and_then(|v| u32::try_from(v).ok()) {
+                Some(version) => version,
+                None => {
+                    warnings.push(MetadataWarning::InvalidType { field: "schema_version" });
+                    1
+                }
+            },
+        };
+        if version > METADATA_SCHEMA_VERSION {
+            warnings.push(MetadataWarning::NewerSchema { version });
+        } else if version < METADATA_SCHEMA_VERSION {
+            for migrate in &MIGRATIONS[version.max(1) as usize - 1..] {
+                migrate(&mut fields);
+            }
+            warnings.push(MetadataWarning::Migrated { from: version, to: METADATA_SCHEMA_VERSION });
+        }
+        fields.insert("schema_version".into(), version.max(METADATA_SCHEMA_VERSION).into());
+        // Any version may use the old spelling; the field alias would read
+        // it, but fail if both spellings are present
+        if let Some(time_signature) = fields.remove("timesignature") {
+            fields.entry("time_signature").or_insert(time_signature);
+        }
+
+        // Try each known field on its own, so one bad value only loses itself
+        for field in FIELDS {
+            let Some(value) = fields.get(field) else { continue };
+            let single = Value::Object(Map::from_iter([(field.to_string(), value.clone())]));
+            if serde_json::from_value::<Self>(single).is_err() {
+                fields.remove(field);
+                warnings.push(MetadataWarning::InvalidType { field });
+            }
+        }
+
+        let metadata: Self = serde_json::from_value(Value::Object(fields))?;
+        warnings.extend(metadata.validate());
+        Ok((metadata, warnings))
+    }
+
+    /// Values of `tag`, empty if it is not set.
+    pub fn tag(&self, name: &str) -> &[String] {
+        self.tags.get(name).map_or(&[], Vec::as_slice)
+    }
+
+    /// Give the fields `self` leaves out the values they have in `other`.
+    /// Tags and unknown fields are merged by name, keeping `self`'s values.
+    pub fn fill_missing(&mut self, other: Self) {
+        fn or<T>(field: &mut Option<T>, other: Option<T>) {
+            if field.is_none() {
+                *field = other;
+            }
+        }
+        or(&mut self.title, other.title);
+        or(&mut self.artist, other.artist);
+        or(&mut self.album, other.album);
+        or(&mut self.album_artist, other.album_artist);
+        or(&mut self.track_number, other.track_number);
+        or(&mut self.track_total, other.track_total);
+        or(&mut self.disc_number, other.disc_number);
+        or(&mut self.disc_total, other.disc_total);
+        or(&mut self.year, other.year);
+        or(&mut self.composer, other.composer);
+        or(&mut self.isrc, other.isrc);
+        let (ids, other_ids) = (&mut self.musicbrainz, other.musicbrainz);
+        or(&mut ids.recording_id, other_ids.recording_id);
+        or(&mut ids.track_id, other_ids.track_id);
+        or(&mut ids.release_id, other_ids.release_id);
+        or(&mut ids.release_group_id, other_ids.release_group_id);
+        or(&mut ids.artist_id, other_ids.artist_id);
+        or(&mut ids.album_artist_id, other_ids.album_artist_id);
+        for (name, values) in other.tags {
+            self.tags.entry(name).or_insert(values);
+        }
+        or(&mut self.genre, other.genre);
+        if self.subgenre.is_empty() {
+            self.subgenre = other.subgenre;
+        }
+        if self.mood.is_empty() {
+            self.mood = other.mood;
+        }
+        or(&mut self.tempo, other.tempo);
+        or(&mut self.key, other.key);
+        or(&mut self.time_signature, other.time_signature);
+        or(&mut self.energy, other.energy);
+        or(&mut self.valence, other.valence);
+        or(&mut self.spatial_profile, other.spatial_profile);
+        for (name, value) in other.extra {
+            self.extra.entry(name).or_insert(value);
+        }
+    }
+
+    /// Values outside their ranges, and keys, time signatures and
+    /// identifiers in no notation this build recognises.
+    pub fn validate(&self) -> Vec<MetadataWarning> {
+        let mut warnings = Vec::new();
+        let mut check_range = |field, value: Option<f32>, range: std::ops::RangeInclusive<f32>| {
+            if let Some(value) = value.filter(|value| !range.contains(value)) {
+                warnings.push(MetadataWarning::OutOfRange { field, value });
+            }
+        };
+        // Numbers count from 1 and cannot pass the total
+        for (field, number, total) in [
+            ("track_number", self.track_number, self.track_total),
+            ("disc_number", self.disc_number, self.disc_total),
+        ] {
+            let last = total.unwrap_or(u32::MAX).max(1);
+            check_range(field, number.map(|n| n as f32), 1.0..=last as f32);
+        }
+        check_range("tempo", self.tempo, TEMPO_RANGE);
+        check_range("energy", self.energy, 0.0..=1.0);
+        check_range("valence", self.valence, 0.0..=1.0);
+        if let Some(profile) = self.spatial_profile {
+            check_range("spatial_profile.width", Some(profile.width), 0.0..=1.0);
+            check_range("spatial_profile.depth", Some(profile.depth), 0.0..=1.0);
+            check_range("spatial_profile.height", Some(profile.height), 0.0..=1.0);
+        }
+
+        if let Some(key) = self.key.as_ref().filter(|key| !is_key(key)) {
+            warnings.push(MetadataWarning::InvalidFormat { field: "key", value: key.clone() });
+        }
+        if let Some(time_signature) = self.time_signature.as_ref().filter(|ts| !is_time_signature(ts)) {
+            warnings.push(MetadataWarning::InvalidFormat { field: "time_signature", value: time_signature.clone() });
+        }
+        if let Some(isrc) = self.isrc.as_ref().filter(|isrc| !is_isrc(isrc)) {
+            warnings.push(MetadataWarning::InvalidFormat { field: "isrc", value: isrc.clone() });
+        }
+        for (field, id) in self.musicbrainz.fields() {
+            if let Some(id) = id.as_ref().filter(|id| !is_uuid(id)) {
+                warnings.push(MetadataWarning::InvalidFormat { field, value: id.clone() });
+            }
+        }
+        warnings
+    }
+}
+
+/// Version 1 had every field required, so writers filled in the ones they
+/// did not know with zero or an empty string.
+fn migrate_v1(fields: &mut Map<String, Value>) {
+    for field in ["genre", "key", "time_signature", "timesignature"] {
+        if fields.get(field).and_then(Value::as_str).is_some_and(|value| value.trim().is_empty()) {
+            fields.remove(field);
+        }
+    }
+    if fields.get("tempo").and_then(Value::as_f64) == Some(0.0) {
+        fields.remove("tempo");
+    }
+}
+
+/// A note name with an optional accidental and mode (`A`, `Bb`, `F#m`,
+/// `C# minor`), or a Camelot wheel position (`1A` to `12B`).
+pub(crate) fn is_key(key: &str) -> bool {
+    let key = key.trim();
+    if let Some(number) = key.strip_suffix(['A', 'B']).filter(|n| !n.is_empty()) {
+        if let Ok(number) = number.parse::<u8>() {
+            return (1..=12).contains(&number);
+        }
+    }
+    let mut chars = key.chars();
+    if !matches!(chars.next(), Some('A'..='G')) {
+        return false;
+    }
+    let rest = chars.as_str();
+    let mode = rest.strip_prefix(['#', 'b', '♯', '♭']).unwrap_or(rest).trim_start();
+    matches!(mode, "" | "m" | "min" | "minor" | "maj" | "major")
+}
+
+/// Beats per bar over a note value that is a power of two, e.g. `7/8`.
+fn is_time_signature(time_signature: &str) -> bool {
+    let Some((beats, note)) = time_signature.trim().split_once('/') else {
+        return false;
+    };
+    let (Ok(beats), Ok(note)) = (beats.parse::<u16>(), note.parse::<u16>()) else {
+        return false;
+    };
+    beats > 0 && note.is_power_of_two() && note <= 64
+}
+
+fn deserialize_tags<'de, D>(deserializer: D) -> Result<BTreeMap<String, Vec<String>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Values {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    let tags = BTreeMap::<String, Values>::deserialize(deserializer)?;
+    Ok(tags.into_iter()
+        .map(|(name, values)| match values {
+            Values::One(value) => (name, vec![value]),
+            Values::Many(values) => (name, values),
+        })
+        .collect())
+}
+
+/// Country code, registrant code, year and designation code, e.g.
+/// `USRC17607839`, with or without the hyphens of `US-RC1-76-07839`.
+pub(crate) fn is_isrc(isrc: &str) -> bool {
+    let code: Vec<u8> = isrc.bytes().filter(|&b| b != b'-').collect();
+    code.len() == 12
+        && code[..2].iter().all(u8::is_ascii_uppercase)
+        && code[2..5].iter().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit())
+        && code[5..].iter().all(u8::is_ascii_digit)
+}
+
+/// Hyphenated UUID, as MusicBrainz writes its identifiers.
+pub(crate) fn is_uuid(id: &str) -> bool {
+    id.len() == 36 && id.bytes().enumerate().all(|(i, b)| match i {
+        8 | 13 | 18 | 23 => b == b'-',
+        _ => b.is_ascii_hexdigit(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(json: &str) -> (EuphMetadata, Vec<MetadataWarning>) {
+        EuphMetadata::from_json(json.as_bytes()).unwrap()
+    }
+
+    fn out_of_range(warnings: &[MetadataWarning]) -> Vec<&'static str> {
+        warnings
+            .iter()
+            .filter_map(|warning| match warning {
+                MetadataWarning::OutOfRange { field, .. } => Some(*field),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn v1_placeholders_become_absent() {
+        let (metadata, warnings) = read(r#"{"genre": " ", "key": "", "time_signature": "", "tempo": 0, "mood": ["calm"]}"#);
+        assert_eq!((metadata.genre, metadata.key, metadata.time_signature, metadata.tempo), (None, None, None, None));
+        assert_eq!(metadata.mood, ["calm"]);
+        assert_eq!(metadata.schema_version, METADATA_SCHEMA_VERSION);
+        assert_eq!(warnings, [MetadataWarning::Migrated { from: 1, to: METADATA_SCHEMA_VERSION }]);
+
+        // The same values are kept, and reported, in a current chunk
+        let (metadata, warnings) = read(r#"{"schema_version": 2, "key": "", "tempo": 0}"#);
+        assert_eq!((metadata.key.as_deref(), metadata.tempo), (Some(""), Some(0.0)));
+        assert_eq!(out_of_range(&warnings), ["tempo"]);
+    }
+
+    #[test]
+    fn old_time_signature_spelling_is_read() {
+        let (metadata, _) = read(r#"{"schema_version": 2, "timesignature": "3/4"}"#);
+        assert_eq!(metadata.time_signature.as_deref(), Some("3/4"));
+        assert!(metadata.extra.is_empty());
+
+        // The current spelling wins over the old one
+        let (metadata, warnings) = read(r#"{"schema_version": 2, "timesignature": "3/4", "time_signature": "6/8"}"#);
+        assert_eq!(metadata.time_signature.as_deref(), Some("6/8"));
+        assert!(warnings.is_empty());
+
+        // An empty old spelling is a v1 placeholder
+        let (metadata, _) = read(r#"{"timesignature": ""}"#);
+        assert_eq!(metadata.time_signature, None);
+    }
+
+    #[test]
+    fn fields_of_the_wrong_type_are_dropped() {
+        let (metadata, warnings) = read(r#"{"schema_version": 2, "title": "Song", "tempo": "fast", "mood": "calm", "year": -1}"#);
+        assert_eq!(metadata.title.as_deref(), Some("Song"));
+        assert_eq!((metadata.tempo, metadata.mood.len(), metadata.year), (None, 0, None));
+        assert_eq!(
+            warnings,
+            [
+                MetadataWarning::InvalidType { field: "year" },
+                MetadataWarning::InvalidType { field: "mood" },
+                MetadataWarning::InvalidType { field: "tempo" },
+            ]
+        );
+
+        let (metadata, warnings) = read(r#"{"schema_version": "two", "title": "Song"}"#);
+        assert_eq!(metadata.title.as_deref(), Some("Song"));
+        assert_eq!(warnings[0], MetadataWarning::InvalidType { field: "schema_version" });
+
+        assert!(EuphMetadata::from_json(b"[1, 2]").is_err());
+        assert!(EuphMetadata::from_json(b"{").is_err());
+    }
+
+    #[test]
+    fn newer_schemas_are_kept() {
+        let (metadata, warnings) = read(r#"{"schema_version": 7, "title": "Song", "stems": {"drums": 1}}"#);
+        assert_eq!(metadata.schema_version, 7);
+        assert_eq!(metadata.title.as_deref(), Some("Song"));
+        assert_eq!(metadata.extra["stems"], serde_json::json!({"drums": 1}));
+        assert_eq!(warnings, [MetadataWarning::NewerSchema { version: 7 }]);
+
+        // Unknown fields are written back as they were
+        let written: Value = serde_json::to_value(&metadata).unwrap();
+        assert_eq!(written["schema_version"], 7);
+        assert_eq!(written["stems"], serde_json::json!({"drums": 1}));
+    }
+
+    #[test]
+    fn tags_take_one_value_or_many() {
+        let (metadata, warnings) = read(r#"{"schema_version": 2, "tags": {"LABEL": "Warp", "CATALOGNUMBER": ["A", "B"]}}"#);
+        assert!(warnings.is_empty());
+        assert_eq!(metadata.tag("LABEL"), ["Warp"]);
+        assert_eq!(metadata.tag("CATALOGNUMBER"), ["A", "B"]);
+        assert!(metadata.tag("MISSING").is_empty());
+    }
+
+    #[test]
+    fn validate_checks_every_range() {
+        let metadata = EuphMetadata {
+            track_number: Some(0),
+            disc_number: Some(3),
+            disc_total: Some(2),
+            tempo: Some(1000.0),
+            energy: Some(-0.1),
+            valence: Some(1.5),
+            spatial_profile: Some(SpatialProfile { width: 2.0, depth: -1.0, height: 1.1 }),
+            ..Default::default()
+        };
+        assert_eq!(
+            out_of_range(&metadata.validate()),
+            [
+                "track_number",
+                "disc_number",
+                "tempo",
+                "energy",
+                "valence",
+                "spatial_profile.width",
+                "spatial_profile.depth",
+                "spatial_profile.height",
+            ]
+        );
+
+        let metadata = EuphMetadata {
+            track_number: Some(12),
+            track_total: Some(12),
+            disc_number: Some(1),
+            tempo: Some(20.0),
+            energy: Some(0.0),
+            valence: Some(1.0),
+            spatial_profile: Some(SpatialProfile { width: 1.0, depth: 0.5, height: 0.0 }),
+            ..Default::default()
+        };
+        assert!(metadata.validate().is_empty());
+        // A track number with a total of 0 is only checked against 1
+        let metadata = EuphMetadata { track_number: Some(1), track_total: Some(0), ..Default::default() };
+        assert!(metadata.validate().is_empty());
+    }
+
+    #[test]
+    fn validate_checks_notations() {
+        let metadata = EuphMetadata {
+            key: Some("H".into()),
+            time_signature: Some("4/3".into()),
+            isrc: Some("US-RC1-76".into()),
+            musicbrainz: MusicBrainzIds { release_id: Some("not-a-uuid".into()), ..Default::default() },
+            ..Default::default()
+        };
+        let fields: Vec<_> = metadata
+            .validate()
+            .into_iter()
+            .map(|warning| match warning {
+                MetadataWarning::InvalidFormat { field, .. } => field,
+                warning => panic!("{warning:?}"),
+            })
+            .collect();
+        assert_eq!(fields, ["key", "time_signature", "isrc", "musicbrainz.release_id"]);
+    }
+
+    #[test]
+    fn keys() {
+        for key in ["A", "Bb", "F#m", "C# minor", "Eb major", "G maj", "D♭", "8A", "12B", "1A", " Am "] {
+            assert!(is_key(key), "{key}");
+        }
+        for key in ["", "H", "a", "Am7", "C##", "13A", "0B", "8C", "B minorish"] {
+            assert!(!is_key(key), "{key}");
+        }
+    }
+
+    #[test]
+    fn time_signatures() {
+        for time_signature in ["4/4", "7/8", "12/16", " 3/4 "] {
+            assert!(is_time_signature(time_signature), "{time_signature}");
+        }
+        for time_signature in ["4", "0/4", "4/3", "4/128", "x/4", "4/4/4"] {
+            assert!(!is_time_signature(time_signature), "{time_signature}");
+        }
+    }
+
+    #[test]
+    fn isrcs() {
+        for isrc in ["USRC17607839", "US-RC1-76-07839", "GBAYE0000351"] {
+            assert!(is_isrc(isrc), "{isrc}");
+        }
+        for isrc in ["", "usrc17607839", "USRC1760783", "USRC176078390", "1SRC17607839", "USRC1760783X", "US_RC17607839"] {
+            assert!(!is_isrc(isrc), "{isrc}");
+        }
+    }
+
+    #[test]
+    fn uuids() {
+        assert!(is_uuid("f27ec8db-af05-4f36-916e-3d57f91ecf5e"));
+        assert!(is_uuid("F27EC8DB-AF05-4F36-916E-3D57F91ECF5E"));
+        for id in ["", "f27ec8dbaf054f36916e3d57f91ecf5e", "f27ec8db-af05-4f36-916e-3d57f91ecf5", "g27ec8db-af05-4f36-916e-3d57f91ecf5e", "f27ec8db_af05-4f36-916e-3d57f91ecf5e"] {
+            assert!(!is_uuid(id), "{id}");
+        }
+    }
+}
@@ -0,0 +1,1057 @@
+use symphonia::core::codecs::{CodecParameters, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::Packet;
+
+use crate::euph_artwork::PictureRole;
+use crate::euph_audio::{self, AudioCodec, SharedPayload};
+use crate::euph_decoder::EuphError;
+use crate::euph_export::{self, ExportTags};
+
+/// Size an Ogg page is filled to before a new one is started.
+const OGG_PAGE_SIZE: usize = 4096;
+
+/// Length of a Matroska cluster, in milliseconds.
+const CLUSTER_LENGTH: u64 = 5000;
+
+fn invalid(reason: &'static str) -> EuphError {
+    EuphError::AudioDecode(SymphoniaError::DecodeError(reason))
+}
+
+/// The packets of a payload's audio track, as its container reader splits
+/// them: whole frames of FLAC and MP3, codec packets of Vorbis and Opus,
+/// and runs of sample frames of PCM.
+pub(crate) struct Packets {
+    pub codec: AudioCodec,
+    pub params: CodecParameters,
+    pub packets: Vec<Packet>,
+}
+
+pub(crate) fn packets(payload: &SharedPayload) -> Result<Packets, EuphError> {
+    let (codec, mut format) = euph_audio::open_format(payload.clone())?;
+    let codec = codec.ok_or(EuphError::UnsupportedAudioFormat(None))?;
+    let track = format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or(EuphError::UnsupportedAudioFormat(Some(codec)))?;
+    let (track_id, params) = (track.id, track.codec_params.clone());
+
+    let mut packets = Vec::new();
+    loop {
+        match format.next_packet() {
+            Ok(packet) if packet.track_id() == track_id => packets.push(packet),
+            Ok(_) => {}
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(EuphError::AudioDecode(e)),
+        }
+    }
+    Ok(Packets { codec, params, packets })
+}
+
+impl Packets {
+    /// Timestamp in milliseconds of a packet timestamp.
+    fn milliseconds(&self, ts: u64) -> u64 {
+        match (self.params.time_base, self.params.sample_rate) {
+            (Some(base), _) => (ts as u128 * base.numer as u128 * 1000 / base.denom.max(1) as u128) as u64,
+            (None, Some(rate)) => (ts as u128 * 1000 / rate.max(1) as u128) as u64,
+            (None, None) => 0,
+        }
+    }
+
+    pub fn duration_ms(&self) -> Option<u64> {
+        let end = match self.packets.last() {
+            Some(last) => last.ts + last.dur,
+            None => self.params.n_frames?,
+        };
+        Some(self.milliseconds(end))
+    }
+}
+
+/// An Ogg page: its header fields and where it lies in the stream.
+struct OggPage<'a> {
+    serial: u32,
+    sequence: u32,
+    /// Lacing values.
+    segments: &'a [u8],
+    data: &'a [u8],
+    range: std::ops::Range<usize>,
+}
+
+fn ogg_pages(stream: &[u8]) -> impl Iterator<Item = Result<OggPage<'_>, EuphError>> + '_ {
+    let mut position = 0;
+    std::iter::from_fn(move || {
+        if position >= stream.len() {
+            return None;
+        }
+        let page = (|| {
+            let header = stream.get(position..position + 27).filter(|h| h.starts_with(b"OggS"))?;
+            let count = header[26] as usize;
+            let segments = stream.get(position + 27..position + 27 + count)?;
+            let start = position + 27 + count;
+            let length = segments.iter().map(|&s| s as usize).sum::<usize>();
+            Some(OggPage {
+                serial: u32::from_le_bytes(header[14..18].try_into().unwrap()),
+                sequence: u32::from_le_bytes(header[18..22].try_into().unwrap()),
+                segments,
+                data: stream.get(start..start + length)?,
+                range: position..start + length,
+            })
+        })();
+        match page {
+            Some(page) => {
+                position = page.range.end;
+                Some(Ok(page))
+            }
+            None => {
+                position = stream.len();
+                Some(Err(invalid("ogg: invalid page")))
+            }
+        }
+    })
+}
+
+/// Header packets of the first logical stream of an Ogg file and the pages
+/// they fill.
+pub(crate) struct OggHeaders {
+    pub packets: Vec<Vec<u8>>,
+    serial: u32,
+    /// Byte ranges of the header pages.
+    pages: Vec<std::ops::Range<usize>>,
+}
+
+/// Read the first `count` packets of the first stream of `ogg`. The page
+/// holding the last of them must end with it, as encoders write them.
+pub(crate) fn ogg_headers(ogg: &[u8], count: usize) -> Result<OggHeaders, EuphError> {
+    let mut headers = OggHeaders { packets: Vec::new(), serial: 0, pages: Vec::new() };
+    let mut packet = Vec::new();
+    for page in ogg_pages(ogg) {
+        let page = page?;
+        if headers.pages.is_empty() {
+            headers.serial = page.serial;
+        } else if page.serial != headers.serial {
+            continue;
+        }
+        headers.pages.push(page.range.clone());
+        let mut data = page.data;
+        for (i, &lacing) in page.segments.iter().enumerate() {
+            let (segment, rest) = data.split_at(lacing as usize);
+            packet.extend_from_slice(segment);
+            data = rest;
+            if lacing < 255 {
+                headers.packets.push(std::mem::take(&mut packet));
+                if headers.packets.len() == count {
+                    return match i == page.segments.len() - 1 {
+                        true => Ok(headers),
+                        false => Err(invalid("ogg: audio shares a page with the headers")),
+                    };
+                }
+            }
+        }
+    }
+    Err(invalid("ogg: missing header packets"))
+}
+
+/// `ogg` with the header packets of its first stream replaced by `packets`.
+/// Later pages of the stream are renumbered to follow the new header pages.
+pub(crate) fn replace_ogg_headers(ogg: &[u8], headers: &OggHeaders, packets: &[Vec<u8>]) -> Vec<u8> {
+    // The first packet has a page of its own and the others start a new one
+    let mut writer = OggWriter::new(headers.serial);
+    for (i, packet) in packets.iter().enumerate() {
+        writer.packet(packet, 0);
+        if i == 0 || i == packets.len() - 1 {
+            writer.flush();
+        }
+    }
+    let header_pages = writer.sequence;
+    let mut out = writer.into_pages();
+
+    let mut position = 0;
+    for page in ogg_pages(ogg) {
+        // Pages after the headers were read once already
+        let Ok(page) = page else { break };
+        position = page.range.end;
+        if headers.pages.contains(&page.range) {
+            continue;
+        }
+        let start = out.len();
+        out.extend_from_slice(&ogg[page.range.clone()]);
+        if page.serial == headers.serial {
+            let sequence = page.sequence.wrapping_sub(headers.pages.len() as u32).wrapping_add(header_pages);
+            out[start + 18..start + 22].copy_from_slice(&sequence.to_le_bytes());
+            out[start + 22..start + 26].fill(0);
+            let crc = ogg_crc(&out[start..]);
+            out[start + 22..start + 26].copy_from_slice(&crc.to_le_bytes());
+        }
+    }
+    // Bytes after the last page, such as a trailing tag, are kept as well
+    out.extend_from_slice(&ogg[position..]);
+    out
+}
+
+/// Writes packets of one logical stream into Ogg pages.
+pub(crate) struct OggWriter {
+    serial: u32,
+    sequence: u32,
+    out: Vec<u8>,
+    segments: Vec<u8>,
+    data: Vec<u8>,
+    /// Granule position of the last packet ending on the page, -1 for none.
+    granule: i64,
+    /// Whether the page starts with the rest of a packet.
+    continued: bool,
+}
+
+impl OggWriter {
+    pub fn new(serial: u32) -> Self {
+        Self { serial, sequence: 0, out: Vec::new(), segments: Vec::new(), data: Vec::new(), granule: -1, continued: false }
+    }
+
+    /// Add a packet ending at `granule`. Pages are flushed when full.
+    pub fn packet(&mut self, packet: &[u8], granule: u64) {
+        if self.data.len() >= OGG_PAGE_SIZE {
+            self.flush();
+        }
+        // A packet is split in 255-byte segments, ended by a shorter one
+        let mut rest = packet;
+        loop {
+            if self.segments.len() == 255 {
+                // The next page continues a packet only if this one ends mid-packet
+                let continued = self.segments.last() == Some(&255);
+                self.write_page(false);
+                self.continued = continued;
+            }
+            let length = rest.len().min(255);
+            self.segments.push(length as u8);
+            self.data.extend_from_slice(&rest[..length]);
+            rest = &rest[length..];
+            if length < 255 {
+                break;
+            }
+        }
+        self.granule = granule as i64;
+    }
+
+    /// Write the packets added so far, so the next one starts a page.
+    pub fn flush(&mut self) {
+        if !self.segments.is_empty() {
+            self.write_page(false);
+        }
+    }
+
+    /// The pages written, after the last was flushed.
+    pub fn into_pages(self) -> Vec<u8> {
+        self.out
+    }
+
+    /// The pages, the last marked as the end of the stream.
+    pub fn finish(mut self) -> Vec<u8> {
+        self.write_page(true);
+        self.out
+    }
+
+    fn write_page(&mut self, last: bool) {
+        let mut header_type = 0;
+        if self.continued {
+            header_type |= 0x01;
+        }
+        if self.sequence == 0 {
+            header_type |= 0x02;
+        }
+        if last {
+            header_type |= 0x04;
+        }
+        let start = self.out.len();
+        self.out.extend_from_slice(b"OggS\0");
+        self.out.push(header_type);
+        self.out.extend_from_slice(&self.granule.to_le_bytes());
+        self.out.extend_from_slice(&self.serial.to_le_bytes());
+        self.out.extend_from_slice(&self.sequence.to_le_bytes());
+        self.out.extend_from_slice(&[0; 4]);
+        self.out.push(self.segments.len() as u8);
+        self.out.append(&mut self.segments);
+        self.out.append(&mut self.data);
+        let crc = ogg_crc(&self.out[start..]);
+        self.out[start + 22..start + 26].copy_from_slice(&crc.to_le_bytes());
+        self.sequence += 1;
+        self.granule = -1;
+        self.continued = false;
+    }
+}
+
+/// CRC-32 of Ogg pages: polynomial 0x04C11DB7, not reflected, no final XOR.
+fn ogg_crc(page: &[u8]) -> u32 {
+    static TABLE: std::sync::OnceLock<[u32; 256]> = std::sync::OnceLock::new();
+    let table = TABLE.get_or_init(|| {
+        std::array::from_fn(|i| {
+            (0..8).fold((i as u32) << 24, |crc, _| if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04C1_1DB7 } else { crc << 1 })
+        })
+    });
+    page.iter().fold(0, |crc, &byte| (crc << 8) ^ table[((crc >> 24) as u8 ^ byte) as usize])
+}
+
+/// Ogg FLAC, in the mapping of the FLAC project: a first packet holding
+/// STREAMINFO, the other metadata blocks as packets with the Vorbis comment
+/// first, then a packet per frame. The seek table is dropped, as Ogg pages
+/// carry positions of their own.
+pub(crate) fn ogg_flac(flac: Vec<u8>) -> Result<Vec<u8>, EuphError> {
+    const SEEKTABLE: u8 = 3;
+    const VORBIS_COMMENT: u8 = 4;
+
+    let shared = SharedPayload::from(flac);
+    let flac = shared.as_ref();
+    let (blocks, _) = euph_export::flac_blocks(flac)?;
+    let (streaminfo, others) = blocks.split_first().ok_or_else(|| invalid("flac: missing STREAMINFO"))?;
+    let mut others: Vec<_> = others.iter().filter(|(block_type, _)| *block_type != SEEKTABLE).collect();
+    others.sort_by_key(|(block_type, _)| *block_type != VORBIS_COMMENT);
+
+    let mut first = b"\x7fFLAC\x01\x00".to_vec();
+    first.extend_from_slice(&(others.len() as u16).to_be_bytes());
+    first.extend_from_slice(b"fLaC");
+    first.extend_from_slice(&euph_export::flac_block_header(streaminfo.0, streaminfo.1.len(), others.is_empty()));
+    first.extend_from_slice(streaminfo.1);
+
+    let mut writer = OggWriter::new(ogg_serial(flac));
+    writer.packet(&first, 0);
+    writer.flush();
+    for (i, (block_type, body)) in others.iter().enumerate() {
+        let mut packet = euph_export::flac_block_header(*block_type, body.len(), i == others.len() - 1).to_vec();
+        packet.extend_from_slice(body);
+        writer.packet(&packet, 0);
+    }
+    writer.flush();
+    for packet in packets(&shared)?.packets {
+        writer.packet(&packet.data, packet.ts + packet.dur);
+    }
+    Ok(writer.finish())
+}
+
+/// The FLAC file an Ogg FLAC stream holds: the STREAMINFO of its first
+/// packet, the metadata blocks of the header packets after it and the
+/// frames of the others.
+pub(crate) fn flac_from_ogg(ogg: &[u8]) -> Result<Vec<u8>, EuphError> {
+    let packets = ogg_stream_packets(ogg)?;
+    let (first, rest) = packets.split_first().ok_or_else(|| invalid("ogg: missing header packets"))?;
+    let streaminfo = first
+        .strip_prefix(b"\x7fFLAC\x01")
+        .and_then(|first| first.get(3..))
+        .filter(|flac| flac.starts_with(b"fLaC") && flac.len() == 42)
+        .ok_or_else(|| invalid("ogg: not an Ogg FLAC stream"))?;
+    // Frames start with a sync code no metadata block header can
+    let metadata = rest.iter().take_while(|packet| packet.first().is_some_and(|&b| b != 0xFF)).count();
+
+    let mut flac = streaminfo.to_vec();
+    flac[4] = if metadata == 0 { 0x80 } else { 0 };
+    for (i, block) in rest[..metadata].iter().enumerate() {
+        let last = if i == metadata - 1 { 0x80 } else { 0 };
+        flac.push(block[0] & 0x7F | last);
+        flac.extend_from_slice(&block[1..]);
+    }
+    for frame in &rest[metadata..] {
+        flac.extend_from_slice(frame);
+    }
+    Ok(flac)
+}
+
+/// The packets of the first logical stream of `ogg`. Bytes after the last
+/// page are ignored.
+fn ogg_stream_packets(ogg: &[u8]) -> Result<Vec<Vec<u8>>, EuphError> {
+    let mut packets = Vec::new();
+    let mut packet = Vec::new();
+    let mut serial = None;
+    for page in ogg_pages(ogg) {
+        let Ok(page) = page else { break };
+        if *serial.get_or_insert(page.serial) != page.serial {
+            continue;
+        }
+        let mut data = page.data;
+        for &lacing in page.segments {
+            let (segment, rest) = data.split_at(lacing as usize);
+            packet.extend_from_slice(segment);
+            data = rest;
+            if lacing < 255 {
+                packets.push(std::mem::take(&mut packet));
+            }
+        }
+    }
+    match serial {
+        Some(_) => Ok(packets),
+        None => Err(invalid("ogg: invalid page")),
+    }
+}
+
+/// Serial number of a new Ogg stream, taken from its content so exports
+/// are reproducible.
+fn ogg_serial(data: &[u8]) -> u32 {
+    crc32fast::hash(&data[..data.len().min(4096)])
+}
+
+/// Writes EBML elements.
+#[derive(Default)]
+struct Ebml(Vec<u8>);
+
+impl Ebml {
+    fn element(&mut self, id: u32, body: &[u8]) -> &mut Self {
+        let id_bytes = id.to_be_bytes();
+        let skip = (id.leading_zeros() / 8) as usize;
+        self.0.extend_from_slice(&id_bytes[skip.min(3)..]);
+        self.0.extend(ebml_size(body.len() as u64));
+        self.0.extend_from_slice(body);
+        self
+    }
+
+    fn uint(&mut self, id: u32, value: u64) -> &mut Self {
+        let bytes = value.to_be_bytes();
+        let skip = ((value.leading_zeros() / 8) as usize).min(7);
+        self.element(id, &bytes[skip..])
+    }
+
+    fn float(&mut self, id: u32, value: f64) -> &mut Self {
+        self.element(id, &value.to_be_bytes())
+    }
+
+    fn string(&mut self, id: u32, value: &str) -> &mut Self {
+        self.element(id, value.as_bytes())
+    }
+
+    fn master(&mut self, id: u32, build: impl FnOnce(&mut Ebml)) -> &mut Self {
+        let mut body = Ebml::default();
+        build(&mut body);
+        self.element(id, &body.0)
+    }
+}
+
+/// An EBML size: the shortest variable-length integer whose value bits are
+/// not all ones, which would mean an unknown size.
+fn ebml_size(size: u64) -> Vec<u8> {
+    let length = (1..=8u32).find(|&length| size < (1u64 << (7 * length)) - 1).unwrap_or(8);
+    let marked = size | 1u64 << (7 * length);
+    marked.to_be_bytes()[8 - length as usize..].to_vec()
+}
+
+/// Matroska audio of `payload` with its packets stored as they are. Tags
+/// go in Matroska tags at album, disc and track level, chapters in an
+/// edition of their own and pictures in attachments named after their role,
+/// such as `cover.jpg`.
+pub(crate) fn matroska(payload: &SharedPayload, tags: &ExportTags) -> Result<Vec<u8>, EuphError> {
+    let stream = packets(payload)?;
+    let CodecMapping { id: codec_id, private, bit_depth } = codec_mapping(payload.as_ref(), &stream)?;
+    let sample_rate = stream.params.sample_rate.unwrap_or(0);
+    let channels = stream.params.channels.map_or(0, |c| c.count());
+    let duration = stream.duration_ms();
+
+    let mut segment = Ebml::default();
+    segment.master(0x1549A966, |info| {
+        info.uint(0x2AD7B1, 1_000_000);
+        if let Some(duration) = duration {
+            info.float(0x4489, duration as f64);
+        }
+        info.string(0x4D80, concat!("ravr-wasm ", env!("CARGO_PKG_VERSION")));
+        info.string(0x5741, concat!("ravr-wasm ", env!("CARGO_PKG_VERSION")));
+    });
+    segment.master(0x1654AE6B, |tracks| {
+        tracks.master(0xAE, |track| {
+            track.uint(0xD7, 1).uint(0x73C5, 1).uint(0x83, 2).string(0x86, codec_id);
+            if let Some(private) = &private {
+                track.element(0x63A2, private);
+            }
+            if stream.codec == AudioCodec::Opus {
+                // Pre-skip at 48 kHz, and 80 ms to converge after a seek
+                let pre_skip = private.as_ref().and_then(|head| head.get(10..12)).map_or(0, |b| u16::from_le_bytes([b[0], b[1]]));
+                track.uint(0x56AA, pre_skip as u64 * 1_000_000_000 / 48_000).uint(0x56BB, 80_000_000);
+            }
+            track.master(0xE1, |audio| {
+                audio.float(0xB5, sample_rate as f64).uint(0x9F, channels as u64);
+                if let Some(bits) = bit_depth {
+                    audio.uint(0x6264, bits as u64);
+                }
+            });
+        });
+    });
+
+    let chapters = tags.chapters(duration);
+    if !chapters.is_empty() {
+        segment.master(0x1043A770, |edition| {
+            edition.master(0x45B9, |edition| {
+                for (i, chapter) in chapters.iter().enumerate() {
+                    edition.master(0xB6, |atom| {
+                        atom.uint(0x73C4, i as u64 + 1).uint(0x91, chapter.start * 1_000_000);
+                        if let Some(end) = chapter.end {
+                            atom.uint(0x92, end * 1_000_000);
+                        }
+                        atom.master(0x80, |display| {
+                            display.string(0x85, chapter.title).string(0x437C, "und");
+                        });
+                    });
+                }
+            });
+        });
+    }
+
+    let matroska_tags = matroska_tags(tags);
+    if !matroska_tags.is_empty() {
+        segment.master(0x1254C367, |out| {
+            for (level, simple_tags) in &matroska_tags {
+                out.master(0x7373, |tag| {
+                    tag.master(0x63C0, |targets| {
+                        targets.uint(0x68CA, *level);
+                    });
+                    for (name, value, language) in simple_tags {
+                        tag.master(0x67C8, |simple| {
+                            simple.string(0x45A3, name).string(0x447A, language.unwrap_or("und")).string(0x4487, value);
+                        });
+                    }
+                });
+            }
+        });
+    }
+
+    if !tags.artwork.pictures.is_empty() {
+        segment.master(0x1941A469, |attachments| {
+            let mut names: Vec<String> = Vec::new();
+            for (i, picture) in tags.artwork.pictures.iter().enumerate() {
+                let stem = match picture.role {
+                    PictureRole::FrontCover => "cover",
+                    PictureRole::BackCover => "back",
+                    PictureRole::Artist => "artist",
+                    PictureRole::Other => "picture",
+                };
+                let extension = match picture.mime_type.as_str() {
+                    "image/jpeg" => "jpg",
+                    "image/png" => "png",
+                    "image/webp" => "webp",
+                    "image/gif" => "gif",
+                    _ => "bin",
+                };
+                let mut name = format!("{stem}.{extension}");
+                if names.contains(&name) {
+                    name = format!("{stem}{}.{extension}", i + 1);
+                }
+                attachments.master(0x61A7, |file| {
+                    if !picture.description.is_empty() {
+                        file.string(0x467E, &picture.description);
+                    }
+                    file.string(0x466E, &name).string(0x4660, &picture.mime_type);
+                    file.element(0x465C, &picture.data).uint(0x46AE, i as u64 + 1);
+                });
+                names.push(name);
+            }
+        });
+    }
+
+    // Block timestamps are 16-bit offsets from their cluster's
+    let mut cluster: Option<(u64, Ebml)> = None;
+    for packet in &stream.packets {
+        let time = stream.milliseconds(packet.ts);
+        if cluster.as_ref().is_none_or(|(start, _)| time < *start || time - start >= CLUSTER_LENGTH) {
+            if let Some((start, blocks)) = cluster.take() {
+                write_cluster(&mut segment, start, blocks);
+            }
+            cluster = Some((time, Ebml::default()));
+        }
+        let (start, blocks) = cluster.as_mut().unwrap();
+        let mut block = vec![0x81];
+        block.extend_from_slice(&((time - *start) as i16).to_be_bytes());
+        // Every audio frame is a keyframe
+        block.push(0x80);
+        block.extend_from_slice(&packet.data);
+        blocks.element(0xA3, &block);
+    }
+    if let Some((start, blocks)) = cluster {
+        write_cluster(&mut segment, start, blocks);
+    }
+
+    let mut mka = Ebml::default();
+    mka.master(0x1A45DFA3, |header| {
+        header.uint(0x4286, 1).uint(0x42F7, 1).uint(0x42F2, 4).uint(0x42F3, 8);
+        header.string(0x4282, "matroska").uint(0x4287, 4).uint(0x4285, 2);
+    });
+    mka.element(0x18538067, &segment.0);
+    Ok(mka.0)
+}
+
+fn write_cluster(segment: &mut Ebml, start: u64, blocks: Ebml) {
+    let mut cluster = Ebml::default();
+    cluster.uint(0xE7, start);
+    cluster.0.extend(blocks.0);
+    segment.element(0x1F43B675, &cluster.0);
+}
+
+/// How a payload's track is described in a Matroska track entry.
+struct CodecMapping {
+    id: &'static str,
+    private: Option<Vec<u8>>,
+    bit_depth: Option<u32>,
+}
+
+fn codec_mapping(payload: &[u8], stream: &Packets) -> Result<CodecMapping, EuphError> {
+    let bits = stream.params.bits_per_sample;
+    let (id, private, bit_depth) = match stream.codec {
+        AudioCodec::Flac => {
+            // fLaC and STREAMINFO, as the last block
+            let (blocks, _) = euph_export::flac_blocks(payload)?;
+            let mut private = b"fLaC".to_vec();
+            private.extend_from_slice(&euph_export::flac_block_header(0, blocks[0].1.len(), true));
+            private.extend_from_slice(blocks[0].1);
+            ("A_FLAC", Some(private), bits)
+        }
+        AudioCodec::Mp3 => ("A_MPEG/L3", None, None),
+        AudioCodec::Opus => {
+            let headers = ogg_headers(payload, 2)?.packets;
+            ("A_OPUS", Some(headers.into_iter().next().unwrap()), None)
+        }
+        AudioCodec::Vorbis => {
+            // The three headers, Xiph-laced
+            let headers = ogg_headers(payload, 3)?.packets;
+            let mut private = vec![2];
+            for header in &headers[..2] {
+                private.extend(std::iter::repeat_n(255, header.len() / 255));
+                private.push((header.len() % 255) as u8);
+            }
+            for header in headers {
+                private.extend(header);
+            }
+            ("A_VORBIS", Some(private), None)
+        }
+        AudioCodec::Wav => match wav_format_tag(payload) {
+            Some(1) => ("A_PCM/INT/LIT", None, bits),
+            Some(3) => ("A_PCM/FLOAT/IEEE", None, bits),
+            _ => return Err(EuphError::UnsupportedAudioFormat(Some(AudioCodec::Wav))),
+        },
+    };
+    Ok(CodecMapping { id, private, bit_depth })
+}
+
+/// Format tag of a WAV's `fmt ` chunk, the sub-format's for
+/// `WAVE_FORMAT_EXTENSIBLE`.
+fn wav_format_tag(wav: &[u8]) -> Option<u16> {
+    let (_, (start, size)) = euph_audio::riff_chunks(wav).find(|(id, _)| id == b"fmt ")?;
+    let fmt = wav.get(start..start + size)?;
+    match u16::from_le_bytes([*fmt.first()?, *fmt.get(1)?]) {
+        0xFFFE => fmt.get(24..26).map(|tag| u16::from_le_bytes([tag[0], tag[1]])),
+        tag => Some(tag),
+    }
+}
+
+/// Name, value and language of a Matroska simple tag.
+type SimpleTag<'a> = (String, String, Option<&'a str>);
+
+/// Simple tags of each target level: 50 for the album, 60 for the disc and
+/// 30 for the track.
+fn matroska_tags(tags: &ExportTags) -> Vec<(u64, Vec<SimpleTag<'_>>)> {
+    let fields = euph_export::text_fields(&tags.metadata);
+    let mut album = Vec::new();
+    let mut disc = Vec::new();
+    let mut track = Vec::new();
+    for (name, values) in fields {
+        let (level, name) = match name.as_str() {
+            "ALBUM" => (&mut album, "TITLE"),
+            "ALBUMARTIST" => (&mut album, "ARTIST"),
+            "TRACKTOTAL" => (&mut album, "TOTAL_PARTS"),
+            "DATE" => (&mut album, "DATE_RELEASED"),
+            "DISCNUMBER" => (&mut disc, "PART_NUMBER"),
+            "DISCTOTAL" => (&mut disc, "TOTAL_PARTS"),
+            "TRACKNUMBER" => (&mut track, "PART_NUMBER"),
+            "INITIALKEY" => (&mut track, "INITIAL_KEY"),
+            name => (&mut track, name),
+        };
+        let name = name.to_string();
+        level.extend(values.into_iter().map(|value| (name.clone(), value, None)));
+    }
+    for lyrics in &tags.lyrics.tracks {
+        track.push(("LYRICS".to_string(), lyrics.to_lrc(), lyrics.language.as_deref()));
+    }
+    [(50, album), (60, disc), (30, track)].into_iter().filter(|(_, tags)| !tags.is_empty()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::euph_audio::DecodedAudio;
+    use crate::euph_artwork::{Artwork, Picture};
+    use crate::euph_cues::{Chapter, CueSheet};
+    use crate::euph_export::ExportFormat;
+    use crate::euph_flac;
+    use crate::euph_metadata::EuphMetadata;
+
+    /// Header type and lacing values of each page.
+    fn page_layout(ogg: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        ogg_pages(ogg).map(|page| page.unwrap()).map(|page| (ogg[page.range.start + 5], page.segments.to_vec())).collect()
+    }
+
+    /// The packets of stream `serial` with the granule position of the page
+    /// each ends on, checking page numbers and checksums on the way. Bytes
+    /// after the last page are skipped.
+    fn ogg_packets(ogg: &[u8], serial: u32) -> Vec<(Vec<u8>, i64)> {
+        let mut packets = Vec::new();
+        let mut packet = Vec::new();
+        let mut sequence = 0;
+        for page in ogg_pages(ogg) {
+            let Ok(page) = page else { break };
+            let mut bytes = ogg[page.range.clone()].to_vec();
+            let stored = u32::from_le_bytes(bytes[22..26].try_into().unwrap());
+            bytes[22..26].fill(0);
+            assert_eq!(ogg_crc(&bytes), stored, "page at {}", page.range.start);
+            if page.serial != serial {
+                continue;
+            }
+            assert_eq!(page.sequence, sequence);
+            sequence += 1;
+            let granule = i64::from_le_bytes(bytes[6..14].try_into().unwrap());
+            let mut data = page.data;
+            for &lacing in page.segments {
+                let (segment, rest) = data.split_at(lacing as usize);
+                packet.extend_from_slice(segment);
+                data = rest;
+                if lacing < 255 {
+                    packets.push((std::mem::take(&mut packet), granule));
+                }
+            }
+        }
+        packets
+    }
+
+    fn noise_flac(frames: usize) -> Vec<u8> {
+        let mut state = 1u32;
+        let samples = (0..frames * 2)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state >> 16) as f32 / 65_536.0 - 0.5
+            })
+            .collect();
+        euph_flac::encode(&DecodedAudio::new(44_100, 2, samples), 16)
+    }
+
+    /// `flac` with a seek table, an application block and a Vorbis comment
+    /// after STREAMINFO, in that order.
+    fn with_metadata_blocks(flac: &[u8]) -> Vec<u8> {
+        let mut out = flac[..42].to_vec();
+        out[4] = 0;
+        out.extend_from_slice(&[3, 0, 0, 18]);
+        out.extend_from_slice(&[0; 18]);
+        out.extend_from_slice(&[2, 0, 0, 8]);
+        out.extend_from_slice(b"testdata");
+        out.extend_from_slice(&[0x84, 0, 0, 8]);
+        out.extend_from_slice(&[0; 8]);
+        out.extend_from_slice(&flac[42..]);
+        out
+    }
+
+    /// Children of an EBML master element, as IDs with their bodies.
+    fn ebml_children(mut data: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut children = Vec::new();
+        while !data.is_empty() {
+            let id_length = data[0].leading_zeros() as usize + 1;
+            let id = data[..id_length].iter().fold(0, |id, &b| id << 8 | b as u32);
+            let size_length = data[id_length].leading_zeros() as usize + 1;
+            let size = data[id_length..id_length + size_length]
+                .iter()
+                .fold(0u64, |size, &b| size << 8 | b as u64)
+                & ((1u64 << (7 * size_length)) - 1);
+            let start = id_length + size_length;
+            children.push((id, &data[start..start + size as usize]));
+            data = &data[start + size as usize..];
+        }
+        children
+    }
+
+    fn ebml_child(data: &[u8], id: u32) -> &[u8] {
+        ebml_children(data).into_iter().find(|(child, _)| *child == id).unwrap_or_else(|| panic!("no {id:X}")).1
+    }
+
+    fn ebml_uint(body: &[u8]) -> u64 {
+        body.iter().fold(0, |value, &b| value << 8 | b as u64)
+    }
+
+    #[test]
+    fn pages_full_of_whole_packets_are_not_continued() {
+        let mut writer = OggWriter::new(1);
+        for i in 0..1000u64 {
+            writer.packet(&[i as u8], i);
+        }
+        let pages = page_layout(&writer.finish());
+        assert_eq!(pages.len(), 4);
+        for (i, (header_type, segments)) in pages.iter().enumerate() {
+            assert_eq!(header_type & 0x01, 0, "page {i}");
+            assert!(segments.iter().all(|&lacing| lacing == 1));
+        }
+    }
+
+    #[test]
+    fn pages_split_mid_packet_are_continued() {
+        // 254 small packets, then one spanning three pages and a small one
+        // starting a page of its own, as the third is full
+        let mut writer = OggWriter::new(1);
+        for _ in 0..254 {
+            writer.packet(&[0], 0);
+        }
+        writer.packet(&[0; 255 * 300], 1);
+        writer.packet(&[0], 2);
+        let ogg = writer.finish();
+        let pages = page_layout(&ogg);
+        assert_eq!(pages.len(), 4);
+        let mut previous_last = None;
+        for (header_type, segments) in &pages {
+            assert_eq!(header_type & 0x01 != 0, previous_last == Some(255));
+            previous_last = segments.last().copied();
+        }
+        assert_eq!(pages[0].0 & 0x02, 0x02);
+        assert_eq!(pages.iter().map(|(header_type, _)| header_type & 0x01).collect::<Vec<_>>(), [0, 1, 1, 0]);
+        assert_eq!(pages[3].0 & 0x04, 0x04);
+    }
+
+    #[test]
+    fn written_pages_have_valid_checksums() {
+        let mut writer = OggWriter::new(7);
+        writer.packet(&[1; 1000], 0);
+        let mut ogg = writer.finish();
+        let stored = u32::from_le_bytes(ogg[22..26].try_into().unwrap());
+        ogg[22..26].fill(0);
+        assert_eq!(ogg_crc(&ogg), stored);
+    }
+
+    #[test]
+    fn ogg_flac_maps_metadata_and_frames_to_packets() {
+        let flac = with_metadata_blocks(&noise_flac(20_000));
+        let ogg = ogg_flac(flac.clone()).unwrap();
+        let pages = page_layout(&ogg);
+        assert_eq!(pages[0], (0x02, vec![51]));
+        assert_eq!(pages.last().unwrap().0 & 0x04, 0x04);
+
+        let packets = ogg_packets(&ogg, ogg_serial(&flac));
+        let (first, _) = &packets[0];
+        // Two header packets follow: the seek table is dropped
+        assert_eq!(&first[..13], b"\x7fFLAC\x01\x00\x00\x02fLaC");
+        assert_eq!(first[13], 0x00);
+        assert_eq!(&first[17..], &flac[8..42]);
+        assert_eq!(packets[1].0, [&[0x04, 0, 0, 8][..], &[0; 8]].concat());
+        assert_eq!(packets[2].0, [&[0x82, 0, 0, 8][..], b"testdata"].concat());
+        assert!(packets[..3].iter().all(|(_, granule)| *granule == 0));
+
+        let (_, frames_start) = euph_export::flac_blocks(&flac).unwrap();
+        let frames: Vec<u8> = packets[3..].iter().flat_map(|(packet, _)| packet.clone()).collect();
+        assert_eq!(frames, &flac[frames_start..]);
+        assert_eq!(packets.last().unwrap().1, 20_000);
+    }
+
+    #[test]
+    fn ogg_flac_is_detected_and_decoded() {
+        let flac = noise_flac(10_000);
+        let ogg = ogg_flac(flac.clone()).unwrap();
+        assert_eq!(AudioCodec::detect(&ogg), Some(AudioCodec::Flac));
+        let decoded = euph_audio::decode_audio(ogg, u64::MAX).unwrap();
+        let expected = euph_audio::decode_audio(flac, u64::MAX).unwrap();
+        assert_eq!((decoded.sample_rate, decoded.channels), (44_100, 2));
+        assert_eq!(decoded.interleaved(), expected.interleaved());
+    }
+
+    #[test]
+    fn ogg_flac_unwraps_to_the_flac_file() {
+        let flac = noise_flac(10_000);
+        assert_eq!(flac_from_ogg(&ogg_flac(flac.clone()).unwrap()).unwrap(), flac);
+
+        // Blocks come back in Ogg order, the seek table left out
+        let with_blocks = with_metadata_blocks(&flac);
+        let mut expected = flac[..42].to_vec();
+        expected[4] = 0;
+        expected.extend_from_slice(&[4, 0, 0, 8]);
+        expected.extend_from_slice(&[0; 8]);
+        expected.extend_from_slice(&[0x82, 0, 0, 8]);
+        expected.extend_from_slice(b"testdata");
+        expected.extend_from_slice(&flac[42..]);
+        assert_eq!(flac_from_ogg(&ogg_flac(with_blocks.clone()).unwrap()).unwrap(), expected);
+
+        let mut writer = OggWriter::new(1);
+        writer.packet(b"\x01vorbis", 0);
+        assert!(flac_from_ogg(&writer.finish()).is_err());
+    }
+
+    #[test]
+    fn ogg_flac_payloads_export_to_each_format() {
+        let flac = noise_flac(10_000);
+        let ogg = ogg_flac(flac.clone()).unwrap();
+        let tags = ExportTags { metadata: EuphMetadata { title: Some("Song".into()), ..Default::default() }, ..Default::default() };
+        let native = euph_export::export_audio(ogg.clone(), &tags, ExportFormat::Native, u64::MAX).unwrap();
+        assert_eq!(native, euph_export::export_audio(flac.clone(), &tags, ExportFormat::Flac, u64::MAX).unwrap());
+        assert_eq!(euph_export::export_audio(ogg.clone(), &tags, ExportFormat::Ogg, u64::MAX).unwrap(), ogg_flac(native.clone()).unwrap());
+        assert!(euph_export::export_audio(ogg.clone(), &tags, ExportFormat::Wav, u64::MAX).unwrap().starts_with(b"RIFF"));
+        let mka = euph_export::export_audio(ogg.clone(), &tags, ExportFormat::Matroska, u64::MAX).unwrap();
+        assert_eq!(mka, matroska(&flac.clone().into(), &tags).unwrap());
+    }
+
+    #[test]
+    fn ogg_flac_header_pages_hold_no_audio() {
+        let flac = with_metadata_blocks(&noise_flac(5000));
+        let headers = ogg_headers(&ogg_flac(flac.clone()).unwrap(), 3).unwrap();
+        assert_eq!(headers.pages.len(), 2);
+        assert_eq!(headers.serial, ogg_serial(&flac));
+    }
+
+    /// A stream of three header packets, the first on a page of its own,
+    /// then audio, with a page of another stream between and bytes after
+    /// the last page.
+    fn ogg_with_headers() -> (Vec<u8>, Vec<Vec<u8>>) {
+        let audio: Vec<Vec<u8>> = (0..40u8).map(|i| vec![i; 300]).collect();
+        let mut writer = OggWriter::new(5);
+        writer.packet(b"first header", 0);
+        writer.flush();
+        writer.packet(b"second header", 0);
+        writer.packet(b"third header", 0);
+        writer.flush();
+        for (i, packet) in audio[..20].iter().enumerate() {
+            writer.packet(packet, i as u64);
+        }
+        writer.flush();
+        let sequence = writer.sequence;
+        let mut ogg = writer.into_pages();
+        let mut other = OggWriter::new(9);
+        other.packet(b"another stream", 0);
+        ogg.extend(other.finish());
+
+        let mut rest = OggWriter::new(5);
+        rest.sequence = sequence;
+        for (i, packet) in audio[20..].iter().enumerate() {
+            rest.packet(packet, 20 + i as u64);
+        }
+        ogg.extend(rest.finish());
+        ogg.extend_from_slice(b"TAG trailing");
+        (ogg, audio)
+    }
+
+    #[test]
+    fn ogg_headers_are_replaced_and_pages_renumbered() {
+        let (ogg, audio) = ogg_with_headers();
+        let headers = ogg_headers(&ogg, 3).unwrap();
+        assert_eq!(headers.packets, [b"first header".to_vec(), b"second header".to_vec(), b"third header".to_vec()]);
+        assert_eq!(headers.pages.len(), 2);
+
+        // A comment header long enough to span pages
+        let replacement = vec![b"new first".to_vec(), vec![7; 100_000], b"new third".to_vec()];
+        let replaced = replace_ogg_headers(&ogg, &headers, &replacement);
+        assert_eq!(ogg_headers(&replaced, 3).unwrap().packets, replacement);
+
+        let packets = ogg_packets(&replaced, 5);
+        assert_eq!(packets.len(), 3 + audio.len());
+        for (i, ((packet, _), expected)) in packets[3..].iter().zip(&audio).enumerate() {
+            assert_eq!(packet, expected, "audio packet {i}");
+        }
+        assert_eq!(ogg_packets(&replaced, 9), [(b"another stream".to_vec(), 0)]);
+        assert!(replaced.ends_with(b"TAG trailing"));
+    }
+
+    #[test]
+    fn ogg_headers_sharing_a_page_with_audio_are_rejected() {
+        let mut writer = OggWriter::new(1);
+        writer.packet(b"header", 0);
+        writer.packet(b"audio", 1);
+        assert!(ogg_headers(&writer.finish(), 1).is_err());
+        assert!(ogg_headers(b"OggS truncated", 1).is_err());
+    }
+
+    #[test]
+    fn ebml_sizes_avoid_the_unknown_size_marker() {
+        assert_eq!(ebml_size(0), [0x80]);
+        assert_eq!(ebml_size(126), [0xFE]);
+        assert_eq!(ebml_size(127), [0x40, 0x7F]);
+        assert_eq!(ebml_size(16_382), [0x7F, 0xFE]);
+        assert_eq!(ebml_size(16_383), [0x20, 0x3F, 0xFF]);
+    }
+
+    #[test]
+    fn matroska_carries_chapters_tags_and_attachments() {
+        let flac = noise_flac(88_200);
+        let tags = ExportTags {
+            metadata: EuphMetadata {
+                title: Some("Song".into()),
+                album: Some("Album".into()),
+                album_artist: Some("Band".into()),
+                track_number: Some(3),
+                disc_number: Some(1),
+                ..Default::default()
+            },
+            artwork: Artwork {
+                pictures: vec![Picture {
+                    role: PictureRole::FrontCover,
+                    mime_type: "image/jpeg".into(),
+                    width: 0,
+                    height: 0,
+                    description: String::new(),
+                    data: b"jpeg".to_vec(),
+                }],
+            },
+            cues: Some(CueSheet {
+                sample_rate: 44_100,
+                title: None,
+                performer: None,
+                chapters: vec![
+                    Chapter { start: 0, title: "Intro".into(), performer: None },
+                    Chapter { start: 44_100, title: "Outro".into(), performer: None },
+                ],
+                loops: Vec::new(),
+                hot_cues: Vec::new(),
+            }),
+            ..Default::default()
+        };
+        let mka = matroska(&flac.clone().into(), &tags).unwrap();
+
+        let top = ebml_children(&mka);
+        assert_eq!(ebml_child(top[0].1, 0x4282), b"matroska");
+        let segment = ebml_child(&mka, 0x18538067);
+
+        let track = ebml_child(ebml_child(segment, 0x1654AE6B), 0xAE);
+        assert_eq!(ebml_child(track, 0x86), b"A_FLAC");
+        assert_eq!(&ebml_child(track, 0x63A2)[..8], b"fLaC\x80\0\0\x22");
+        assert_eq!(ebml_uint(ebml_child(ebml_child(track, 0xE1), 0x9F)), 2);
+
+        let edition = ebml_child(ebml_child(segment, 0x1043A770), 0x45B9);
+        let chapters: Vec<_> = ebml_children(edition)
+            .into_iter()
+            .map(|(_, atom)| {
+                let start = ebml_uint(ebml_child(atom, 0x91));
+                let end = ebml_uint(ebml_child(atom, 0x92));
+                let title = ebml_child(ebml_child(atom, 0x80), 0x85);
+                (start, end, String::from_utf8(title.to_vec()).unwrap())
+            })
+            .collect();
+        assert_eq!(
+            chapters,
+            [(0, 1_000_000_000, "Intro".to_string()), (1_000_000_000, 2_000_000_000, "Outro".to_string())]
+        );
+
+        let levels: Vec<(u64, Vec<(String, String)>)> = ebml_children(ebml_child(segment, 0x1254C367))
+            .into_iter()
+            .map(|(_, tag)| {
+                let level = ebml_uint(ebml_child(ebml_child(tag, 0x63C0), 0x68CA));
+                let simple = ebml_children(tag)
+                    .into_iter()
+                    .filter(|(id, _)| *id == 0x67C8)
+                    .map(|(_, simple)| {
+                        let text = |id| String::from_utf8(ebml_child(simple, id).to_vec()).unwrap();
+                        (text(0x45A3), text(0x4487))
+                    })
+                    .collect();
+                (level, simple)
+            })
+            .collect();
+        let pair = |name: &str, value: &str| (name.to_string(), value.to_string());
+        assert_eq!(levels[0], (50, vec![pair("TITLE", "Album"), pair("ARTIST", "Band")]));
+        assert_eq!(levels[1], (60, vec![pair("PART_NUMBER", "1")]));
+        assert_eq!(levels[2].0, 30);
+        assert!(levels[2].1.contains(&pair("TITLE", "Song")));
+        assert!(levels[2].1.contains(&pair("PART_NUMBER", "3")));
+
+        let attachment = ebml_child(ebml_child(segment, 0x1941A469), 0x61A7);
+        assert_eq!(ebml_child(attachment, 0x466E), b"cover.jpg");
+        assert_eq!(ebml_child(attachment, 0x465C), b"jpeg");
+
+        // Blocks hold the FLAC frames in order, in clusters of at most five seconds
+        let (_, frames_start) = euph_export::flac_blocks(&flac).unwrap();
+        let mut frames = Vec::new();
+        for (_, cluster) in ebml_children(segment).into_iter().filter(|(id, _)| *id == 0x1F43B675) {
+            for (_, block) in ebml_children(cluster).into_iter().filter(|(id, _)| *id == 0xA3) {
+                assert_eq!((block[0], block[3]), (0x81, 0x80));
+                frames.extend_from_slice(&block[4..]);
+            }
+        }
+        assert_eq!(frames, &flac[frames_start..]);
+    }
+}
@@ -0,0 +1,191 @@
+use crate::euph_audio::DecodedAudio;
+use crate::euph_decoder::EuphError;
+
+/// Bucket sizes the encoder stores, in samples per channel: about 5 ms,
+/// 20 ms and 90 ms at 44.1 kHz.
+pub const DEFAULT_RESOLUTIONS: [u32; 3] = [256, 1024, 4096];
+
+/// Body of a PEAK chunk: waveform overviews of the first AUDIO chunk at
+/// several resolutions, for drawing it without decoding the audio.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Peaks {
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Finest resolution first.
+    pub levels: Vec<PeakLevel>,
+}
+
+/// Overview at one resolution.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeakLevel {
+    pub samples_per_bucket: u32,
+    /// Minimum, maximum and RMS of each channel in each bucket, interleaved
+    /// like the audio and scaled so 32767 is full scale. The last bucket
+    /// may be shorter than the others.
+    pub values: Vec<i16>,
+}
+
+impl Peaks {
+    /// Overviews of `audio` with the given bucket sizes; zero sizes are
+    /// skipped.
+    pub fn measure(audio: &DecodedAudio, resolutions: &[u32]) -> Self {
+        let mut resolutions: Vec<u32> = resolutions.iter().copied().filter(|&r| r > 0).collect();
+        resolutions.sort_unstable();
+        resolutions.dedup();
+
+        let channels = audio.channels as usize;
+        let levels = resolutions.into_iter()
+            .map(|samples_per_bucket| {
+                let bucket_length = samples_per_bucket as usize * channels.max(1);
+                let mut values = Vec::new();
+                for bucket in audio.interleaved().chunks(bucket_length) {
+                    let frames = (bucket.len() / channels.max(1)).max(1) as f64;
+                    for channel in 0..channels {
+                        let samples = bucket.iter().skip(channel).step_by(channels).map(|&s| s as f64);
+                        let (min, max, energy) = samples.fold((f64::MAX, f64::MIN, 0.0), |(min, max, energy), s| {
+                            (min.min(s), max.max(s), energy + s * s)
+                        });
+                        // A trailing partial frame leaves later channels without samples
+                        let (min, max) = if min <= max { (min, max) } else { (0.0, 0.0) };
+                        values.extend([quantize(min), quantize(max), quantize((energy / frames).sqrt())]);
+                    }
+                }
+                PeakLevel { samples_per_bucket, values }
+            })
+            .collect();
+        Self { sample_rate: audio.sample_rate, channels: audio.channels, levels }
+    }
+
+    /// Level with exactly `samples_per_bucket`.
+    pub fn level(&self, samples_per_bucket: u32) -> Option<&PeakLevel> {
+        self.levels.iter().find(|level| level.samples_per_bucket == samples_per_bucket)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.sample_rate.to_le_bytes());
+        bytes.extend_from_slice(&self.channels.to_le_bytes());
+        bytes.extend_from_slice(&(self.levels.len() as u16).to_le_bytes());
+        for level in &self.levels {
+            let buckets = level.values.len() / (self.channels as usize * 3).max(1);
+            bytes.extend_from_slice(&level.samples_per_bucket.to_le_bytes());
+            bytes.extend_from_slice(&(buckets as u32).to_le_bytes());
+            for value in &level.values {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    pub fn parse(body: &[u8]) -> Result<Self, EuphError> {
+        let field = |at: usize, length: usize| body.get(at..at + length).ok_or(EuphError::InvalidPeaks);
+        let sample_rate = u32::from_le_bytes(field(0, 4)?.try_into().unwrap());
+        let channels = u16::from_le_bytes(field(4, 2)?.try_into().unwrap());
+        let level_count = u16::from_le_bytes(field(6, 2)?.try_into().unwrap());
+
+        let mut position = 8;
+        let mut levels = Vec::new();
+        for _ in 0..level_count {
+            let samples_per_bucket = u32::from_le_bytes(field(position, 4)?.try_into().unwrap());
+            let buckets = u32::from_le_bytes(field(position + 4, 4)?.try_into().unwrap());
+            position += 8;
+            // Checked against the body before anything is allocated for it
+            let length = (buckets as usize)
+                .checked_mul(channels as usize * 6)
+                .filter(|&length| length <= body.len() - position)
+                .ok_or(EuphError::InvalidPeaks)?;
+            let values = body[position..position + length]
+                .chunks_exact(2)
+                .map(|value| i16::from_le_bytes([value[0], value[1]]))
+                .collect();
+            position += length;
+            levels.push(PeakLevel { samples_per_bucket, values });
+        }
+        if position != body.len() {
+            return Err(EuphError::InvalidPeaks);
+        }
+        Ok(Self { sample_rate, channels, levels })
+    }
+}
+
+fn quantize(value: f64) -> i16 {
+    (value * i16::MAX as f64).round().clamp(-(i16::MAX as f64), i16::MAX as f64) as i16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stereo: a square wave of ±0.5 on the left, silence then full scale
+    /// on the right.
+    fn audio() -> DecodedAudio {
+        let samples = (0..10).flat_map(|i| [if i % 2 == 0 { 0.5 } else { -0.5 }, if i < 4 { 0.0 } else { 1.0 }]).collect();
+        DecodedAudio::new(8_000, 2, samples)
+    }
+
+    #[test]
+    fn buckets_hold_min_max_and_rms() {
+        let peaks = Peaks::measure(&audio(), &[4]);
+        assert_eq!((peaks.sample_rate, peaks.channels), (8_000, 2));
+        assert_eq!(peaks.levels.len(), 1);
+        let half = quantize(0.5);
+        assert_eq!(half, 16_384);
+        let values = &peaks.levels[0].values;
+        assert_eq!(values[..6], [-half, half, half, 0, 0, 0]);
+        assert_eq!(values[6..12], [-half, half, half, 32_767, 32_767, 32_767]);
+        // The last bucket holds two frames
+        assert_eq!(values[12..], [-half, half, half, 32_767, 32_767, 32_767]);
+    }
+
+    #[test]
+    fn rms_of_a_sine_is_its_peak_over_root_two() {
+        let samples = (0..4800).map(|n| (2.0 * std::f32::consts::PI * n as f32 / 48.0).sin() * 0.8).collect();
+        let peaks = Peaks::measure(&DecodedAudio::new(48_000, 1, samples), &[4800]);
+        let [min, max, rms] = peaks.levels[0].values[..] else { panic!() };
+        assert_eq!((min, max), (quantize(-0.8), quantize(0.8)));
+        assert!((rms - quantize(0.8 / 2f64.sqrt())).abs() <= 2, "{rms}");
+    }
+
+    #[test]
+    fn resolutions_are_sorted_and_deduplicated() {
+        let peaks = Peaks::measure(&audio(), &[8, 0, 2, 8]);
+        let resolutions: Vec<u32> = peaks.levels.iter().map(|level| level.samples_per_bucket).collect();
+        assert_eq!(resolutions, [2, 8]);
+        assert_eq!(peaks.level(8).unwrap().values.len(), 2 * 2 * 3);
+        assert!(peaks.level(4).is_none());
+    }
+
+    #[test]
+    fn a_partial_frame_leaves_later_channels_empty() {
+        let peaks = Peaks::measure(&DecodedAudio::new(8_000, 2, vec![0.5, 0.25, 1.0]), &[1]);
+        assert_eq!(peaks.levels[0].values[6..], [32_767, 32_767, 32_767, 0, 0, 0]);
+    }
+
+    #[test]
+    fn body_round_trips() {
+        let peaks = Peaks::measure(&audio(), &DEFAULT_RESOLUTIONS);
+        let body = peaks.to_bytes();
+        assert_eq!(body.len(), 8 + 3 * (8 + 2 * 3 * 2));
+        assert_eq!(Peaks::parse(&body).unwrap(), peaks);
+        assert_eq!(Peaks::parse(&Peaks::default().to_bytes()).unwrap(), Peaks::default());
+    }
+
+    #[test]
+    fn malformed_bodies_are_rejected() {
+        let body = Peaks::measure(&audio(), &[2, 4]).to_bytes();
+        for length in [0, 7, 8, 12, body.len() - 1] {
+            assert!(matches!(Peaks::parse(&body[..length]), Err(EuphError::InvalidPeaks)), "{length} bytes");
+        }
+        let mut trailing = body.clone();
+        trailing.push(0);
+        assert!(matches!(Peaks::parse(&trailing), Err(EuphError::InvalidPeaks)));
+
+        // Bucket counts no body could hold are rejected without allocating
+        let mut oversized = body.clone();
+        oversized[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(Peaks::parse(&oversized), Err(EuphError::InvalidPeaks)));
+        let mut levels = body;
+        levels[6..8].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(matches!(Peaks::parse(&levels), Err(EuphError::InvalidPeaks)));
+    }
+}
@@ -0,0 +1,170 @@
+use std::ops::Range;
+use serde::{Serialize, Deserialize};
+
+use crate::euph_audio::{self, AudioFormat, SharedPayload};
+use crate::euph_codec::ChunkCodec;
+use crate::euph_decoder::EuphError;
+
+/// Decoded size the encoder aims for in each block of an AUDIO chunk.
+pub const AUDIO_BLOCK_SIZE: usize = 256 * 1024;
+
+/// Body of a SEEK chunk: the independently compressed blocks of the AUDIO
+/// chunk with the same role, with the first sample each one decodes to.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SeekTable {
+    pub sample_rate: u32,
+    /// Blocks in stored order. The first holds the format descriptor and the
+    /// payload headers, and is needed to decode any of the others.
+    pub blocks: Vec<SeekBlock>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SeekBlock {
+    /// First sample per channel decoded from the block.
+    pub frame: u64,
+    /// Position and length in the decompressed AUDIO body.
+    pub offset: u64,
+    pub size: u64,
+    /// Position and length in the AUDIO body as stored, after decryption.
+    pub stored_offset: u64,
+    pub stored_size: u64,
+}
+
+impl SeekTable {
+    /// Blocks after the header block that hold samples `start..end`.
+    pub fn block_range(&self, start: u64, end: u64) -> Range<usize> {
+        if self.blocks.len() < 2 {
+            return 1..1;
+        }
+        let audio_blocks = &self.blocks[1..];
+        let first = audio_blocks.partition_point(|b| b.frame <= start).saturating_sub(1) + 1;
+        let last = audio_blocks.partition_point(|b| b.frame < end) + 1;
+        first..last.max(first + 1)
+    }
+}
+
+/// Prefix an AUDIO payload with its format descriptor and compress it. PCM
+/// WAV and FLAC payloads are compressed in blocks of about
+/// `AUDIO_BLOCK_SIZE` that each start on a frame, described by the returned
+/// seek table; other payloads are compressed as a single stream.
+pub(crate) fn compress_audio(payload: Vec<u8>, codec: ChunkCodec) -> Result<(Vec<u8>, Option<SeekTable>), EuphError> {
+    let body = SharedPayload::from(euph_audio::with_audio_format(payload));
+    let descriptor = match AudioFormat::parse(body.as_ref()) {
+        Ok(Some((_, size))) => size,
+        _ => 0,
+    };
+    let split = euph_audio::split_points(&body.skip(descriptor), AUDIO_BLOCK_SIZE);
+    // The split reader has let go of its share by now
+    let body = body.into_vec();
+    let Some(split) = split else {
+        return Ok((codec.compress(body)?, None));
+    };
+
+    // Header block, audio blocks, then whatever follows the audio, such as
+    // trailing RIFF chunks, as a block the table does not list
+    let mut starts = vec![(0, 0)];
+    starts.extend(split.points.iter().map(|&(offset, frame)| (descriptor + offset, frame)));
+    let audio_end = descriptor + split.end;
+
+    let mut stored = Vec::new();
+    let mut blocks = Vec::with_capacity(starts.len());
+    for (i, &(offset, frame)) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).map_or(audio_end, |&(next, _)| next);
+        let block = codec.compress(body[offset..end].to_vec())?;
+        blocks.push(SeekBlock {
+            frame,
+            offset: offset as u64,
+            size: (end - offset) as u64,
+            stored_offset: stored.len() as u64,
+            stored_size: block.len() as u64,
+        });
+        stored.extend_from_slice(&block);
+    }
+    if audio_end < body.len() {
+        stored.extend_from_slice(&codec.compress(body[audio_end..].to_vec())?);
+    }
+
+    Ok((stored, Some(SeekTable { sample_rate: split.sample_rate, blocks })))
+}
+
+/// Samples per channel from `start_time` for `duration` seconds.
+pub(crate) fn frame_range(start_time: f64, duration: f64, sample_rate: u32) -> Range<u64> {
+    // Float to integer casts saturate, and NaN becomes 0
+    let start = (start_time * sample_rate as f64).floor() as u64;
+    let end = ((start_time + duration) * sample_rate as f64).ceil() as u64;
+    start..end.max(start)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+    use crate::euph_audio::DecodedAudio;
+    use crate::euph_decoder::{ChunkType, EuphContainer};
+    use crate::euph_encoder::EuphEncoder;
+    use crate::{euph_export, euph_flac};
+
+    /// Three seconds of stereo noise, which not even FLAC fits in one block.
+    fn noise() -> DecodedAudio {
+        let mut state = 1u32;
+        let samples = (0..3 * 44_100 * 2)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state >> 16) as f32 / 65_536.0 - 0.5
+            })
+            .collect();
+        DecodedAudio::new(44_100, 2, samples)
+    }
+
+    fn container(payload: Vec<u8>, codec: ChunkCodec) -> EuphContainer {
+        let mut encoder = EuphEncoder::new().with_codec(codec);
+        encoder.add_chunk(ChunkType::Audio, payload, codec).unwrap();
+        let mut file = Cursor::new(Vec::new());
+        encoder.write(&mut file).unwrap();
+        EuphContainer::parse(&mut Cursor::new(file.into_inner())).unwrap()
+    }
+
+    fn assert_ranges_match_full_decode(mut container: EuphContainer) {
+        let table: SeekTable = serde_json::from_slice(&container.read_chunk(ChunkType::Seek).unwrap()).unwrap();
+        assert!(table.blocks.len() > 2, "only {} blocks", table.blocks.len());
+
+        let full = container.decode_audio().unwrap();
+        // Within a block, across block boundaries, from the start and past the end
+        for (start, duration) in [(0.0, 0.25), (1.3, 0.5), (0.5, 2.0), (2.9, 1.0), (5.0, 1.0)] {
+            let range = container.read_audio_range(start, duration).unwrap();
+            let frames = frame_range(start, duration, full.sample_rate);
+            let expected = full.clone().slice_frames(frames.start, frames.end - frames.start);
+            assert_eq!((range.sample_rate, range.channels), (expected.sample_rate, expected.channels));
+            assert_eq!(range.interleaved(), expected.interleaved(), "{start}s + {duration}s");
+        }
+    }
+
+    #[test]
+    fn wav_range_matches_full_decode() {
+        let wav = euph_export::wav_file(&noise(), 16, 0x3);
+        assert_ranges_match_full_decode(container(wav, ChunkCodec::Gzip { level: 1 }));
+    }
+
+    #[test]
+    fn flac_range_matches_full_decode() {
+        let flac = euph_flac::encode(&noise(), 16);
+        assert_ranges_match_full_decode(container(flac, ChunkCodec::None));
+    }
+
+    #[test]
+    fn block_range_covers_the_requested_frames() {
+        let block = |frame| SeekBlock { frame, offset: 0, size: 0, stored_offset: 0, stored_size: 0 };
+        let table = SeekTable { sample_rate: 1000, blocks: vec![block(0), block(0), block(100), block(200)] };
+        assert_eq!(table.block_range(0, 50), 1..2);
+        assert_eq!(table.block_range(150, 250), 2..4);
+        assert_eq!(table.block_range(500, 600), 3..4);
+        assert_eq!(SeekTable::default().block_range(0, 10), 1..1);
+    }
+
+    #[test]
+    fn frame_range_rounds_outwards() {
+        assert_eq!(frame_range(0.5, 0.25, 1000), 500..750);
+        assert_eq!(frame_range(0.0004, 0.0002, 1000), 0..1);
+        assert_eq!(frame_range(f64::NAN, 1.0, 1000), 0..0);
+    }
+}
@@ -0,0 +1,171 @@
+use std::io::Write;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha2::{Digest, Sha256};
+
+use crate::euph_decoder::{ChunkType, CHUNK_FLAG_CRC};
+use crate::euph_encoder::SignatureData;
+
+/// Outcome of checking the SIGNATURE chunk of a file against a public key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureStatus {
+    /// The chunks match the integrity hash and the hash is signed by the key.
+    Valid,
+    /// The chunks no longer match the integrity hash, or the signature no
+    /// longer matches the hash.
+    Tampered,
+    /// The file has no SIGNATURE chunk, or one without a digital signature.
+    Unsigned,
+    /// The file is signed, but not with the given key.
+    UnknownKey,
+}
+
+/// SHA-256 over every chunk except SIGNATURE chunks, in chunk table order.
+/// Each chunk contributes its type, its flags without `CHUNK_FLAG_CRC` (which
+/// only concerns storage), its size and its body as stored.
+#[derive(Default)]
+pub(crate) struct IntegrityHasher {
+    sha: Sha256,
+}
+
+impl IntegrityHasher {
+    /// Start a chunk, whose `size` bytes of body are then written to the hasher.
+    pub fn start_chunk(&mut self, chunk_type: ChunkType, flags: u32, size: u64) {
+        self.sha.update(chunk_type.id().to_le_bytes());
+        self.sha.update((flags & !CHUNK_FLAG_CRC).to_le_bytes());
+        self.sha.update(size.to_le_bytes());
+    }
+
+    pub fn finalize(self) -> [u8; 32] {
+        self.sha.finalize().into()
+    }
+}
+
+impl Write for IntegrityHasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.sha.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Fill in the integrity hash and, given a key, the signature over it and
+/// the public key to check it with.
+pub(crate) fn sign(signature: &mut SignatureData, hash: [u8; 32], key: Option<&SigningKey>) {
+    signature.integrity_hash = hex::encode(hash);
+    if let Some(key) = key {
+        signature.digital_signature = Some(hex::encode(key.sign(&hash).to_bytes()));
+        signature.certificate = Some(hex::encode(key.verifying_key().to_bytes()));
+    }
+}
+
+pub(crate) fn verify(signature: &SignatureData, hash: [u8; 32], public_key: &VerifyingKey) -> SignatureStatus {
+    let Some(digital_signature) = &signature.digital_signature else {
+        return SignatureStatus::Unsigned;
+    };
+    if !signature.integrity_hash.eq_ignore_ascii_case(&hex::encode(hash)) {
+        return SignatureStatus::Tampered;
+    }
+
+    // The certificate names the key the file was signed with; without one a
+    // failed check cannot tell a foreign key from a damaged signature
+    let signed_by_key = match &signature.certificate {
+        Some(certificate) => {
+            if !certificate.eq_ignore_ascii_case(&hex::encode(public_key.to_bytes())) {
+                return SignatureStatus::UnknownKey;
+            }
+            true
+        }
+        None => false,
+    };
+
+    let verified = hex::decode(digital_signature)
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .is_some_and(|sig| public_key.verify(&hash, &sig).is_ok());
+    match (verified, signed_by_key) {
+        (true, _) => SignatureStatus::Valid,
+        (false, true) => SignatureStatus::Tampered,
+        (false, false) => SignatureStatus::UnknownKey,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+    use crate::euph_codec::ChunkCodec;
+    use crate::euph_decoder::EuphContainer;
+    use crate::euph_encoder::EuphEncoder;
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    fn file(configure: impl FnOnce(EuphEncoder) -> EuphEncoder) -> EuphContainer {
+        let mut encoder = configure(EuphEncoder::new());
+        encoder.add_chunk(ChunkType::Custom(*b"ANLY"), b"analysis".to_vec(), ChunkCodec::None).unwrap();
+        let mut file = Cursor::new(Vec::new());
+        encoder.write(&mut file).unwrap();
+        EuphContainer::parse(&mut Cursor::new(file.into_inner())).unwrap()
+    }
+
+    #[test]
+    fn signed_file_is_valid() {
+        let mut container = file(|encoder| encoder.with_signing_key(key(1)));
+        assert_eq!(container.verify_signature(&key(1).verifying_key()).unwrap(), SignatureStatus::Valid);
+        assert_eq!(container.verify_integrity().unwrap(), Some(true));
+    }
+
+    #[test]
+    fn edited_chunk_is_tampered() {
+        let mut container = file(|encoder| encoder.with_signing_key(key(1)));
+        container.replace_chunk(ChunkType::Custom(*b"ANLY"), b"analyses".to_vec(), ChunkCodec::None).unwrap();
+        assert_eq!(container.verify_signature(&key(1).verifying_key()).unwrap(), SignatureStatus::Tampered);
+        assert_eq!(container.verify_integrity().unwrap(), Some(false));
+    }
+
+    #[test]
+    fn forged_signature_is_tampered() {
+        let hash = [3; 32];
+        let mut signature = SignatureData::default();
+        sign(&mut signature, hash, Some(&key(1)));
+        signature.digital_signature = Some(hex::encode([0u8; 64]));
+        assert_eq!(verify(&signature, hash, &key(1).verifying_key()), SignatureStatus::Tampered);
+    }
+
+    #[test]
+    fn file_without_a_signature_is_unsigned() {
+        let mut container = file(|encoder| encoder);
+        assert_eq!(container.verify_signature(&key(1).verifying_key()).unwrap(), SignatureStatus::Unsigned);
+        assert_eq!(container.verify_integrity().unwrap(), None);
+    }
+
+    #[test]
+    fn integrity_hash_alone_is_unsigned() {
+        let mut container = file(|mut encoder| {
+            encoder.add_signature(&SignatureData::default()).unwrap();
+            encoder
+        });
+        assert_eq!(container.verify_signature(&key(1).verifying_key()).unwrap(), SignatureStatus::Unsigned);
+        assert_eq!(container.verify_integrity().unwrap(), Some(true));
+    }
+
+    #[test]
+    fn other_key_is_unknown() {
+        let mut container = file(|encoder| encoder.with_signing_key(key(1)));
+        assert_eq!(container.verify_signature(&key(2).verifying_key()).unwrap(), SignatureStatus::UnknownKey);
+    }
+
+    #[test]
+    fn other_key_without_a_certificate_is_unknown() {
+        let hash = [3; 32];
+        let mut signature = SignatureData::default();
+        sign(&mut signature, hash, Some(&key(1)));
+        signature.certificate = None;
+        assert_eq!(verify(&signature, hash, &key(1).verifying_key()), SignatureStatus::Valid);
+        assert_eq!(verify(&signature, hash, &key(2).verifying_key()), SignatureStatus::UnknownKey);
+    }
+}
@@ -0,0 +1,604 @@
+use serde::Serialize;
+use symphonia::core::meta::{StandardTagKey, Tag, Value};
+
+use crate::euph_audio;
+use crate::euph_lyrics::{Lyrics, LyricsTrack};
+use crate::euph_metadata::{self, EuphMetadata, TEMPO_RANGE};
+
+/// Metadata and lyrics read from the tags of a source audio file: ID3v2
+/// and ID3v1 tags, Vorbis comments, RIFF `INFO` chunks and MP4 `ilst`
+/// atoms. Pictures are read by `Artwork::from_source_file`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImportedTags {
+    pub metadata: EuphMetadata,
+    /// Lyrics tags holding LRC. Plain lyrics go to the `LYRICS` tag.
+    pub lyrics: Lyrics,
+    /// Tags that were read but not carried over, in the order they were
+    /// read.
+    pub unconverted: Vec<UnconvertedTag>,
+}
+
+/// A source tag `ImportedTags` did not carry over.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UnconvertedTag {
+    /// Key as the tag format writes it, e.g. `TXXX:Rating`,
+    /// `REPLAYGAIN_TRACK_GAIN` or `----:com.apple.iTunes:Rating`.
+    pub key: String,
+    /// Text of the value, or its size when it is binary.
+    pub value: String,
+    pub reason: UnconvertedReason,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnconvertedReason {
+    /// Binary data other than a picture.
+    Binary,
+    /// Not a number, date, key or identifier the field can hold.
+    InvalidValue,
+    /// A different value for a field already set by an earlier tag, which
+    /// is kept.
+    Conflict,
+    /// ReplayGain, which the LOUD chunk replaces.
+    ReplayGain,
+}
+
+/// Where the value of a tag goes.
+enum Target {
+    Text(fn(&mut EuphMetadata) -> &mut Option<String>),
+    /// Text in a notation the field checks, such as an ISRC.
+    Checked(fn(&mut EuphMetadata) -> &mut Option<String>, fn(&str) -> bool),
+    /// `n` or `n/total`.
+    Number(fn(&mut EuphMetadata) -> &mut Option<u32>, fn(&mut EuphMetadata) -> &mut Option<u32>),
+    Total(fn(&mut EuphMetadata) -> &mut Option<u32>),
+    Year,
+    Genre,
+    Mood,
+    Tempo,
+    Lyrics,
+    ReplayGain,
+    Tag(String),
+}
+
+impl ImportedTags {
+    /// Tags of every format `payload` carries. Where formats disagree on a
+    /// field, ID3v2 wins over the container's own tags, which win over
+    /// ID3v1.
+    pub fn from_source_file<T>(payload: T) -> Self
+    where
+        T: AsRef<[u8]> + Send + Sync + 'static,
+    {
+        let mut imported = Self::default();
+        for revision in euph_audio::source_metadata(payload) {
+            for tag in revision.tags() {
+                imported.import(tag);
+            }
+        }
+        imported
+    }
+
+    fn import(&mut self, tag: &Tag) {
+        let value = match &tag.value {
+            Value::Binary(data) => {
+                return self.report(tag, format!("{} bytes", data.len()), UnconvertedReason::Binary);
+            }
+            Value::Flag => "1".to_string(),
+            Value::Boolean(flag) => u8::from(*flag).to_string(),
+            // RIFF INFO values are often NUL-terminated
+            value => value.to_string().trim_matches(|c: char| c.is_whitespace() || c == '\0').to_string(),
+        };
+        if value.is_empty() {
+            return;
+        }
+        if let Err(reason) = self.store(tag, &value) {
+            self.report(tag, value, reason);
+        }
+    }
+
+    fn store(&mut self, tag: &Tag, value: &str) -> Result<(), UnconvertedReason> {
+        let metadata = &mut self.metadata;
+        match target(tag) {
+            Target::Text(field) => set(field(metadata), value.to_string()),
+            Target::Checked(field, check) => match check(value) {
+                true => set(field(metadata), value.to_string()),
+                false => Err(UnconvertedReason::InvalidValue),
+            },
+            Target::Number(number, total) => {
+                let (n, of) = match value.split_once('/') {
+                    Some((n, of)) => (n, Some(of)),
+                    None => (value, None),
+                };
+                let n = parse_count(n)?;
+                let of = of.map(parse_count).transpose()?.flatten();
+                if let Some(n) = n {
+                    set(number(metadata), n)?;
+                }
+                match of {
+                    Some(of) => set(total(metadata), of),
+                    None => Ok(()),
+                }
+            }
+            Target::Total(total) => match parse_count(value)? {
+                Some(of) => set(total(metadata), of),
+                None => Ok(()),
+            },
+            Target::Year => {
+                let year = value.get(..4)
+                    .filter(|year| year.bytes().all(|b| b.is_ascii_digit()))
+                    .and_then(|year| year.parse().ok())
+                    .ok_or(UnconvertedReason::InvalidValue)?;
+                set(&mut metadata.year, year)
+            }
+            Target::Genre => {
+                let genre = genre_name(value);
+                match &metadata.genre {
+                    None => metadata.genre = Some(genre),
+                    Some(first) if *first == genre => {}
+                    Some(_) => push_new(&mut metadata.subgenre, genre),
+                }
+                Ok(())
+            }
+            Target::Mood => {
+                push_new(&mut metadata.mood, value.to_string());
+                Ok(())
+            }
+            Target::Tempo => {
+                let tempo = value.parse::<f32>()
+                    .ok()
+                    .filter(|tempo| TEMPO_RANGE.contains(tempo))
+                    .ok_or(UnconvertedReason::InvalidValue)?;
+                set(&mut metadata.tempo, tempo)
+            }
+            Target::Lyrics => match LyricsTrack::parse_lrc(value) {
+                Ok(mut track) => {
+                    if track.language.is_none() {
+                        track.language = uslt_language(&tag.key);
+                    }
+                    if self.lyrics.tracks.iter().any(|t| t.language == track.language) {
+                        return Err(UnconvertedReason::Conflict);
+                    }
+                    self.lyrics.set_track(track);
+                    Ok(())
+                }
+                Err(_) => {
+                    push_new(metadata.tags.entry("LYRICS".into()).or_default(), value.to_string());
+                    Ok(())
+                }
+            },
+            Target::ReplayGain => Err(UnconvertedReason::ReplayGain),
+            Target::Tag(name) => {
+                push_new(metadata.tags.entry(name).or_default(), value.to_string());
+                Ok(())
+            }
+        }
+    }
+
+    fn report(&mut self, tag: &Tag, value: String, reason: UnconvertedReason) {
+        self.unconverted.push(UnconvertedTag { key: tag.key.clone(), value, reason });
+    }
+}
+
+fn target(tag: &Tag) -> Target {
+    use StandardTagKey::*;
+
+    match tag.std_key {
+        Some(TrackTitle) => return Target::Text(|m| &mut m.title),
+        Some(Artist) => return Target::Text(|m| &mut m.artist),
+        Some(Album) => return Target::Text(|m| &mut m.album),
+        Some(AlbumArtist) => return Target::Text(|m| &mut m.album_artist),
+        Some(Composer) => return Target::Text(|m| &mut m.composer),
+        Some(IdentIsrc) => return Target::Checked(|m| &mut m.isrc, euph_metadata::is_isrc),
+        Some(TrackNumber) => return Target::Number(|m| &mut m.track_number, |m| &mut m.track_total),
+        Some(TrackTotal) => return Target::Total(|m| &mut m.track_total),
+        Some(DiscNumber) => return Target::Number(|m| &mut m.disc_number, |m| &mut m.disc_total),
+        Some(DiscTotal) => return Target::Total(|m| &mut m.disc_total),
+        Some(Date) => return Target::Year,
+        Some(Genre) => return Target::Genre,
+        Some(Mood) => return Target::Mood,
+        Some(Bpm) => return Target::Tempo,
+        Some(Lyrics) => return Target::Lyrics,
+        Some(ReplayGainAlbumGain | ReplayGainAlbumPeak | ReplayGainTrackGain | ReplayGainTrackPeak) => {
+            return Target::ReplayGain;
+        }
+        _ => {}
+    }
+
+    // Identifiers symphonia only maps for some spellings, and fields it has
+    // no standard key for, compared without case, spaces or underscores.
+    // MusicBrainz calls a recording a track in Vorbis comments and MP4.
+    let freeform = || tag.key.strip_prefix("----:")?.split_once(':').map(|(_, name)| name);
+    let name = tag.key.strip_prefix("TXXX:").or_else(freeform).unwrap_or(&tag.key);
+    let normalized: String = name.chars().filter(|c| !matches!(c, ' ' | '_')).collect::<String>().to_ascii_uppercase();
+    let id = |field| Target::Checked(field, euph_metadata::is_uuid);
+    match (tag.std_key, normalized.as_str()) {
+        (Some(MusicBrainzRecordingId | MusicBrainzTrackId), _) | (_, "MUSICBRAINZTRACKID" | "MUSICBRAINZRECORDINGID") => {
+            id(|m| &mut m.musicbrainz.recording_id)
+        }
+        (Some(MusicBrainzReleaseTrackId), _) | (_, "MUSICBRAINZRELEASETRACKID") => id(|m| &mut m.musicbrainz.track_id),
+        (Some(MusicBrainzAlbumId), _) | (_, "MUSICBRAINZALBUMID") => id(|m| &mut m.musicbrainz.release_id),
+        (Some(MusicBrainzReleaseGroupId), _) | (_, "MUSICBRAINZRELEASEGROUPID") => {
+            id(|m| &mut m.musicbrainz.release_group_id)
+        }
+        (Some(MusicBrainzArtistId), _) | (_, "MUSICBRAINZARTISTID") => id(|m| &mut m.musicbrainz.artist_id),
+        (Some(MusicBrainzAlbumArtistId), _) | (_, "MUSICBRAINZALBUMARTISTID") => {
+            id(|m| &mut m.musicbrainz.album_artist_id)
+        }
+        (_, "TKEY" | "KEY" | "INITIALKEY") => Target::Checked(|m| &mut m.key, euph_metadata::is_key),
+        (_, normalized) if normalized.starts_with("REPLAYGAIN") => Target::ReplayGain,
+        (Some(key), _) => Target::Tag(tag_name(key)),
+        (None, _) => Target::Tag(name.to_uppercase()),
+    }
+}
+
+/// Store `value` in a field that holds one, unless it holds a different
+/// value already.
+fn set<T: PartialEq>(field: &mut Option<T>, value: T) -> Result<(), UnconvertedReason> {
+    match field {
+        Some(existing) if *existing != value => Err(UnconvertedReason::Conflict),
+        _ => {
+            *field = Some(value);
+            Ok(())
+        }
+    }
+}
+
+fn push_new(values: &mut Vec<String>, value: String) {
+    if !values.contains(&value) {
+        values.push(value);
+    }
+}
+
+/// A track or disc number or total. ID3v1 and some taggers write zero for
+/// one they do not know, which is read as no number.
+fn parse_count(count: &str) -> Result<Option<u32>, UnconvertedReason> {
+    match count.trim().parse::<u32>() {
+        Ok(0) => Ok(None),
+        Ok(count) => Ok(Some(count)),
+        Err(_) => Err(UnconvertedReason::InvalidValue),
+    }
+}
+
+/// ID3v2 genres may refer to an ID3v1 genre by number, as `17`, `(17)` or
+/// `(17)Rock`.
+fn genre_name(genre: &str) -> String {
+    let id3v1_genre = |number: &str| {
+        number.parse::<u8>().ok().and_then(symphonia_metadata::id3v1::util::genre_name).map(|name| name.to_string())
+    };
+    match genre.strip_prefix('(').and_then(|g| g.split_once(')')) {
+        Some((number, refined)) if number.parse::<u8>().is_ok() => match refined.trim() {
+            "" => id3v1_genre(number),
+            refined => Some(refined.to_string()),
+        },
+        Some(_) => None,
+        None => id3v1_genre(genre),
+    }
+    .unwrap_or_else(|| genre.to_string())
+}
+
+/// Language of an ID3v2 `USLT` frame, whose key symphonia writes as
+/// `USLT!eng`. `xxx` and `und` mean unknown.
+fn uslt_language(key: &str) -> Option<String> {
+    let (_, language) = key.split_once('!')?;
+    let known = language.len() == 3 && language.bytes().all(|b| b.is_ascii_lowercase());
+    (known && !matches!(language, "xxx" | "und")).then(|| language.to_string())
+}
+
+/// Tag name for a field METADATA has no field for: the standard key
+/// upper-cased, which for most keys is their Vorbis comment name, e.g.
+/// `LABEL`, `CATALOGNUMBER` or `ORIGINALDATE`.
+fn tag_name(key: StandardTagKey) -> String {
+    let name = format!("{key:?}");
+    name.strip_prefix("Ident").unwrap_or(&name).to_ascii_uppercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::euph_audio::DecodedAudio;
+    use crate::euph_export::{self, ExportFormat, ExportTags};
+    use crate::euph_flac;
+
+    const RECORDING_ID: &str = "f1a2b3c4-d5e6-4f70-8192-a3b4c5d6e7f8";
+
+    fn tag(std_key: Option<StandardTagKey>, key: &str, value: &str) -> Tag {
+        Tag::new(std_key, key, Value::from(value))
+    }
+
+    fn imported(tags: &[Tag]) -> ImportedTags {
+        let mut imported = ImportedTags::default();
+        for tag in tags {
+            imported.import(tag);
+        }
+        imported
+    }
+
+    fn reasons(imported: &ImportedTags) -> Vec<(&str, UnconvertedReason)> {
+        imported.unconverted.iter().map(|tag| (tag.key.as_str(), tag.reason)).collect()
+    }
+
+    fn titled(title: &str, artist: &str) -> ExportTags {
+        let metadata = EuphMetadata {
+            title: Some(title.into()),
+            artist: Some(artist.into()),
+            track_number: Some(3),
+            track_total: Some(12),
+            ..Default::default()
+        };
+        ExportTags { metadata, ..Default::default() }
+    }
+
+    fn silence() -> DecodedAudio {
+        DecodedAudio::new(8000, 1, vec![0.0; 800])
+    }
+
+    fn riff_chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(body);
+        if body.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn atom(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut atom = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        atom.extend_from_slice(id);
+        atom.extend_from_slice(body);
+        atom
+    }
+
+    /// `data` atom of an `ilst` item with its well-known type.
+    fn data(kind: u32, value: &[u8]) -> Vec<u8> {
+        let mut body = kind.to_be_bytes().to_vec();
+        body.extend_from_slice(&[0; 4]);
+        body.extend_from_slice(value);
+        atom(b"data", &body)
+    }
+
+    fn freeform(name: &str, value: &str) -> Vec<u8> {
+        let mut item = atom(b"mean", b"\0\0\0\0com.apple.iTunes");
+        item.extend(atom(b"name", &[&[0; 4], name.as_bytes()].concat()));
+        item.extend(data(1, value.as_bytes()));
+        atom(b"----", &item)
+    }
+
+    fn m4a(ilst: &[u8], meta_version: bool) -> Vec<u8> {
+        let mut meta = if meta_version { vec![0; 4] } else { Vec::new() };
+        meta.extend(atom(b"hdlr", &[&[0; 8], &b"mdirappl"[..], &[0; 9]].concat()));
+        meta.extend(atom(b"ilst", ilst));
+        let moov = atom(b"moov", &atom(b"udta", &atom(b"meta", &meta)));
+        let mut file = atom(b"ftyp", b"M4A \0\0\x02\0isomM4A ");
+        file.extend(atom(b"mdat", &[0; 16]));
+        file.extend(moov);
+        file
+    }
+
+    #[test]
+    fn numbers_and_totals() {
+        use StandardTagKey::*;
+
+        let imported = imported(&[
+            tag(Some(TrackNumber), "TRACKNUMBER", "3/12"),
+            tag(Some(DiscNumber), "TPOS", "0/2"),
+            tag(Some(TrackTotal), "TRACKTOTAL", "12"),
+            tag(Some(DiscTotal), "DISCTOTAL", "0"),
+            tag(Some(DiscNumber), "DISCNUMBER", "one"),
+        ]);
+        let metadata = &imported.metadata;
+        assert_eq!((metadata.track_number, metadata.track_total), (Some(3), Some(12)));
+        // Zero is how ID3v1 and some taggers say they do not know
+        assert_eq!((metadata.disc_number, metadata.disc_total), (None, Some(2)));
+        assert_eq!(reasons(&imported), [("DISCNUMBER", UnconvertedReason::InvalidValue)]);
+    }
+
+    #[test]
+    fn genres_by_id3v1_number() {
+        assert_eq!(genre_name("(17)Rock"), "Rock");
+        assert_eq!(genre_name("(17)"), "Rock");
+        assert_eq!(genre_name("17"), "Rock");
+        assert_eq!(genre_name("(9)Black Metal"), "Black Metal");
+        assert_eq!(genre_name("(255)"), "(255)");
+        assert_eq!(genre_name("(Live)"), "(Live)");
+        assert_eq!(genre_name("Synthwave"), "Synthwave");
+
+        let genre = |value| tag(Some(StandardTagKey::Genre), "TCON", value);
+        let imported = imported(&[genre("(17)"), genre("Rock"), genre("(9)"), genre("Metal")]);
+        assert_eq!(imported.metadata.genre.as_deref(), Some("Rock"));
+        assert_eq!(imported.metadata.subgenre, ["Metal"]);
+    }
+
+    #[test]
+    fn reports_what_is_not_carried_over() {
+        use StandardTagKey::*;
+
+        let imported = imported(&[
+            tag(Some(TrackTitle), "TIT2", "Title"),
+            tag(Some(TrackTitle), "TITLE", "Other title"),
+            tag(Some(TrackTitle), "INAM", "Title\0"),
+            tag(Some(ReplayGainTrackGain), "REPLAYGAIN_TRACK_GAIN", "-6.50 dB"),
+            tag(None, "TXXX:replaygain_album_peak", "0.98"),
+            Tag::new(None, "PRIV", Value::Binary(vec![1, 2, 3].into())),
+            tag(Some(IdentIsrc), "TSRC", "not an isrc"),
+            tag(Some(Bpm), "TBPM", "9000"),
+            tag(Some(Date), "TDRC", "20xx"),
+            tag(None, "COMMENT", "  "),
+        ]);
+        assert_eq!(imported.metadata.title.as_deref(), Some("Title"));
+        assert_eq!(
+            reasons(&imported),
+            [
+                ("TITLE", UnconvertedReason::Conflict),
+                ("REPLAYGAIN_TRACK_GAIN", UnconvertedReason::ReplayGain),
+                ("TXXX:replaygain_album_peak", UnconvertedReason::ReplayGain),
+                ("PRIV", UnconvertedReason::Binary),
+                ("TSRC", UnconvertedReason::InvalidValue),
+                ("TBPM", UnconvertedReason::InvalidValue),
+                ("TDRC", UnconvertedReason::InvalidValue),
+            ]
+        );
+        assert_eq!(imported.unconverted[0].value, "Other title");
+        assert_eq!(imported.unconverted[3].value, "3 bytes");
+    }
+
+    #[test]
+    fn lyrics_languages() {
+        assert_eq!(uslt_language("USLT!eng").as_deref(), Some("eng"));
+        assert_eq!(uslt_language("USLT!xxx"), None);
+        assert_eq!(uslt_language("USLT!und"), None);
+        assert_eq!(uslt_language("USLT!EN"), None);
+        assert_eq!(uslt_language("LYRICS"), None);
+
+        let lyrics = |key, value| tag(Some(StandardTagKey::Lyrics), key, value);
+        let imported = imported(&[
+            lyrics("USLT!eng", "[00:01.00]Hello"),
+            lyrics("USLT!deu", "[00:01.00]Hallo"),
+            lyrics("LYRICS", "[la:eng]\n[00:02.00]Hello again"),
+            lyrics("USLT!xxx", "Words without times"),
+        ]);
+        let languages: Vec<_> = imported.lyrics.tracks.iter().map(|t| t.language.as_deref()).collect();
+        assert_eq!(languages, [Some("eng"), Some("deu")]);
+        assert_eq!(reasons(&imported), [("LYRICS", UnconvertedReason::Conflict)]);
+        assert_eq!(imported.metadata.tags["LYRICS"], ["Words without times"]);
+    }
+
+    #[test]
+    fn identifiers_by_any_spelling() {
+        let imported = imported(&[
+            tag(None, "TXXX:MusicBrainz Release Track Id", "0a1b2c3d-4e5f-4061-8728-394a5b6c7d8e"),
+            tag(None, "MUSICBRAINZ_ALBUMID", "11111111-2222-4333-8444-555555555555"),
+            tag(None, "----:com.apple.iTunes:MusicBrainz Track Id", RECORDING_ID),
+            tag(None, "TXXX:MusicBrainz Artist Id", "not a uuid"),
+            tag(None, "TXXX:initialkey", "Am"),
+            tag(None, "TXXX:Rating", "5"),
+            tag(Some(StandardTagKey::Label), "TPUB", "Label"),
+        ]);
+        let ids = &imported.metadata.musicbrainz;
+        assert_eq!(ids.track_id.as_deref(), Some("0a1b2c3d-4e5f-4061-8728-394a5b6c7d8e"));
+        assert_eq!(ids.release_id.as_deref(), Some("11111111-2222-4333-8444-555555555555"));
+        assert_eq!(ids.recording_id.as_deref(), Some(RECORDING_ID));
+        assert_eq!(imported.metadata.key.as_deref(), Some("Am"));
+        assert_eq!(imported.metadata.tags["RATING"], ["5"]);
+        assert_eq!(imported.metadata.tags["LABEL"], ["Label"]);
+        assert_eq!(reasons(&imported), [("TXXX:MusicBrainz Artist Id", UnconvertedReason::InvalidValue)]);
+    }
+
+    #[test]
+    fn wav_id3v2_wins_over_info() {
+        let mut wav = euph_export::wav_file(&silence(), 16, 0);
+        let info = [riff_chunk(b"INAM", b"Info title\0"), riff_chunk(b"IART", b"Info artist\0")].concat();
+        wav.extend(riff_chunk(b"LIST", &[&b"INFO"[..], &info].concat()));
+        let mut tags = titled("ID3 title", "ID3 artist");
+        tags.metadata.artist = None;
+        wav.extend(riff_chunk(b"id3 ", &euph_export::id3v2_tag(&tags, None)));
+
+        let imported = ImportedTags::from_source_file(wav.clone());
+        assert_eq!(imported.metadata.title.as_deref(), Some("ID3 title"));
+        assert_eq!(imported.metadata.artist.as_deref(), Some("Info artist"));
+        assert_eq!(imported.metadata.track_number, Some(3));
+        assert_eq!(reasons(&imported), [("INAM", UnconvertedReason::Conflict)]);
+    }
+
+    #[test]
+    fn mp3_id3v2_wins_over_id3v1() {
+        let mut mp3 = euph_export::id3v2_tag(&titled("Long title of the ID3v2 tag", "Artist"), None);
+        let mut id3v1 = [0u8; 128];
+        id3v1[..3].copy_from_slice(b"TAG");
+        id3v1[3..18].copy_from_slice(b"Long title of t");
+        id3v1[33..39].copy_from_slice(b"Artist");
+        id3v1[63..68].copy_from_slice(b"Album");
+        id3v1[93..97].copy_from_slice(b"1999");
+        id3v1[127] = 17;
+        mp3.extend_from_slice(&id3v1);
+
+        let imported = ImportedTags::from_source_file(mp3.clone());
+        let metadata = &imported.metadata;
+        assert_eq!(metadata.title.as_deref(), Some("Long title of the ID3v2 tag"));
+        assert_eq!(metadata.album.as_deref(), Some("Album"));
+        assert_eq!((metadata.year, metadata.genre.as_deref()), (Some(1999), Some("Rock")));
+        assert_eq!(reasons(&imported), [("TITLE", UnconvertedReason::Conflict)]);
+    }
+
+    #[test]
+    fn flac_vorbis_comments() {
+        let flac = euph_flac::encode(&silence(), 16);
+        let mut tags = titled("Title", "Artist");
+        tags.metadata.musicbrainz.recording_id = Some(RECORDING_ID.into());
+        tags.metadata.tags.insert("LABEL".into(), vec!["Label".into()]);
+        tags.lyrics.set_track(LyricsTrack::parse_lrc("[la:eng]\n[00:00.50]Hello").unwrap());
+        let flac = euph_export::export_audio(flac.clone(), &tags, ExportFormat::Native, u64::MAX).unwrap();
+
+        let imported = ImportedTags::from_source_file(flac.clone());
+        let metadata = &imported.metadata;
+        assert_eq!((metadata.title.as_deref(), metadata.artist.as_deref()), (Some("Title"), Some("Artist")));
+        assert_eq!((metadata.track_number, metadata.track_total), (Some(3), Some(12)));
+        assert_eq!(metadata.musicbrainz.recording_id.as_deref(), Some(RECORDING_ID));
+        assert_eq!(metadata.tags["LABEL"], ["Label"]);
+        assert_eq!(imported.lyrics, tags.lyrics);
+    }
+
+    #[test]
+    fn mp4_ilst_atoms() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR\0\0\0\x02\0\0\0\x03\x08\x06\0\0\0";
+        let ilst = [
+            atom(b"\xa9nam", &data(1, b"MP4 title")),
+            atom(b"\xa9ART", &data(1, b"MP4 artist")),
+            atom(b"trkn", &data(0, &[0, 0, 0, 3, 0, 12, 0, 0])),
+            atom(b"disk", &data(0, &[0, 0, 0, 1, 0, 0])),
+            atom(b"gnre", &data(0, &[0, 18])),
+            atom(b"tmpo", &data(21, &[0, 120])),
+            atom(b"\xa9day", &data(1, b"2024-05-01T00:00:00Z")),
+            freeform("MusicBrainz Track Id", RECORDING_ID),
+            freeform("ISRC", "USRC17607839"),
+            freeform("replaygain_track_gain", "-6.50 dB"),
+            atom(b"covr", &data(14, png)),
+            atom(b"\xa9xyz", &data(1, b"Other")),
+        ]
+        .concat();
+
+        for meta_version in [true, false] {
+            let m4a = m4a(&ilst, meta_version);
+            let imported = ImportedTags::from_source_file(m4a.clone());
+            let metadata = &imported.metadata;
+            assert_eq!((metadata.title.as_deref(), metadata.artist.as_deref()), (Some("MP4 title"), Some("MP4 artist")));
+            assert_eq!((metadata.track_number, metadata.track_total), (Some(3), Some(12)));
+            assert_eq!((metadata.disc_number, metadata.disc_total), (Some(1), None));
+            assert_eq!((metadata.genre.as_deref(), metadata.tempo, metadata.year), (Some("Rock"), Some(120.0), Some(2024)));
+            assert_eq!(metadata.musicbrainz.recording_id.as_deref(), Some(RECORDING_ID));
+            assert_eq!(metadata.isrc.as_deref(), Some("USRC17607839"));
+            assert_eq!(metadata.tags["\u{a9}XYZ"], ["Other"]);
+            assert_eq!(
+                reasons(&imported),
+                [("----:com.apple.iTunes:replaygain_track_gain", UnconvertedReason::ReplayGain)]
+            );
+
+            let artwork = crate::euph_artwork::Artwork::from_source_file(m4a.clone());
+            assert_eq!(artwork.pictures.len(), 1);
+            let cover = &artwork.pictures[0];
+            assert_eq!(cover.role, crate::euph_artwork::PictureRole::FrontCover);
+            assert_eq!((cover.mime_type.as_str(), cover.width, cover.height), ("image/png", 2, 3));
+        }
+    }
+
+    #[test]
+    fn mp4_atom_sizes() {
+        // A 64-bit size, and a last atom that runs to the end of the file
+        let title = data(1, b"Title");
+        let mut item = 1u32.to_be_bytes().to_vec();
+        item.extend_from_slice(b"\xa9nam");
+        item.extend_from_slice(&(title.len() as u64 + 16).to_be_bytes());
+        item.extend(title);
+        let mut m4a = m4a(&item, true);
+        let moov = m4a.windows(4).position(|w| w == b"moov").unwrap() - 4;
+        m4a[moov..moov + 4].copy_from_slice(&[0; 4]);
+        assert_eq!(ImportedTags::from_source_file(m4a.clone()).metadata.title.as_deref(), Some("Title"));
+
+        // Atoms that run past their parent, or are smaller than their header
+        let mut overrun = m4a.clone();
+        overrun.truncate(overrun.len() - 1);
+        m4a[moov..moov + 4].copy_from_slice(&4u32.to_be_bytes());
+        for m4a in [overrun, m4a] {
+            assert_eq!(ImportedTags::from_source_file(m4a.clone()), ImportedTags::default());
+        }
+    }
+}
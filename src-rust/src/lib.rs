@@ -1,10 +1,37 @@
 use wasm_bindgen::prelude::*;
 use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::io::Cursor;
 
 // DSP Engine module
 pub mod dsp_engine;
 pub use dsp_engine::*;
 
+// EUPH container format
+pub mod euph_artwork;
+pub mod euph_audio;
+pub mod euph_codec;
+pub mod euph_crypto;
+pub mod euph_cues;
+pub mod euph_decoder;
+pub mod euph_encoder;
+pub mod euph_export;
+pub mod euph_flac;
+pub mod euph_loudness;
+pub mod euph_lyrics;
+pub mod euph_metadata;
+pub mod euph_mux;
+pub mod euph_peaks;
+pub mod euph_seek;
+pub mod euph_signature;
+pub mod euph_tags;
+
+use euph_decoder::{ChunkType, EuphContainer, EuphLayout};
+use euph_export::ExportFormat;
+use euph_lyrics::Lyrics;
+use euph_metadata::EuphMetadata;
+use euph_signature::SignatureStatus;
+
 // Simple EUPH encoder/decoder for WASM
 #[wasm_bindgen]
 pub struct EuphEncoder {
@@ -13,7 +40,13 @@ pub struct EuphEncoder {
 
 #[wasm_bindgen]
 pub struct EuphDecoder {
-    chunks: Vec<EuphChunk>,
+    container: Option<EuphContainer>,
+    keys: HashMap<u8, [u8; 32]>,
+    /// Parsed LYRC chunk, kept for players asking for the current line on
+    /// every frame.
+    lyrics: Option<Lyrics>,
+    /// Parsed METADATA chunk, for the tag getters.
+    metadata: Option<EuphMetadata>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -22,6 +55,12 @@ struct EuphChunk {
     data: Vec<u8>,
 }
 
+impl Default for EuphEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[wasm_bindgen]
 impl EuphEncoder {
     #[wasm_bindgen(constructor)]
@@ -48,6 +87,87 @@ impl EuphEncoder {
         Ok(())
     }
 
+    #[wasm_bindgen(js_name = "setTitle")]
+    pub fn set_title(&mut self, title: Option<String>) -> Result<(), JsValue> {
+        self.update_metadata(|metadata| metadata.title = title)
+    }
+
+    #[wasm_bindgen(js_name = "setArtist")]
+    pub fn set_artist(&mut self, artist: Option<String>) -> Result<(), JsValue> {
+        self.update_metadata(|metadata| metadata.artist = artist)
+    }
+
+    #[wasm_bindgen(js_name = "setAlbum")]
+    pub fn set_album(&mut self, album: Option<String>) -> Result<(), JsValue> {
+        self.update_metadata(|metadata| metadata.album = album)
+    }
+
+    #[wasm_bindgen(js_name = "setAlbumArtist")]
+    pub fn set_album_artist(&mut self, album_artist: Option<String>) -> Result<(), JsValue> {
+        self.update_metadata(|metadata| metadata.album_artist = album_artist)
+    }
+
+    /// Track number on the disc and number of tracks on it.
+    #[wasm_bindgen(js_name = "setTrack")]
+    pub fn set_track(&mut self, number: Option<u32>, total: Option<u32>) -> Result<(), JsValue> {
+        self.update_metadata(|metadata| (metadata.track_number, metadata.track_total) = (number, total))
+    }
+
+    /// Disc number and number of discs of the release.
+    #[wasm_bindgen(js_name = "setDisc")]
+    pub fn set_disc(&mut self, number: Option<u32>, total: Option<u32>) -> Result<(), JsValue> {
+        self.update_metadata(|metadata| (metadata.disc_number, metadata.disc_total) = (number, total))
+    }
+
+    #[wasm_bindgen(js_name = "setYear")]
+    pub fn set_year(&mut self, year: Option<u16>) -> Result<(), JsValue> {
+        self.update_metadata(|metadata| metadata.year = year)
+    }
+
+    #[wasm_bindgen(js_name = "setComposer")]
+    pub fn set_composer(&mut self, composer: Option<String>) -> Result<(), JsValue> {
+        self.update_metadata(|metadata| metadata.composer = composer)
+    }
+
+    #[wasm_bindgen(js_name = "setIsrc")]
+    pub fn set_isrc(&mut self, isrc: Option<String>) -> Result<(), JsValue> {
+        self.update_metadata(|metadata| metadata.isrc = isrc)
+    }
+
+    /// MusicBrainz id of `kind`: `recording`, `track`, `release`,
+    /// `release_group`, `artist` or `album_artist`.
+    #[wasm_bindgen(js_name = "setMusicBrainzId")]
+    pub fn set_musicbrainz_id(&mut self, kind: &str, id: Option<String>) -> Result<(), JsValue> {
+        let mut metadata = self.pending_metadata()?;
+        let ids = &mut metadata.musicbrainz;
+        let field = match kind {
+            "recording" => &mut ids.recording_id,
+            "track" => &mut ids.track_id,
+            "release" => &mut ids.release_id,
+            "release_group" => &mut ids.release_group_id,
+            "artist" => &mut ids.artist_id,
+            "album_artist" => &mut ids.album_artist_id,
+            _ => return Err(JsValue::from_str(&format!("Unknown MusicBrainz id kind: {}", kind))),
+        };
+        *field = id;
+        self.store_metadata(&metadata)
+    }
+
+    /// Set a free-form tag to a single value, or remove it.
+    #[wasm_bindgen(js_name = "setTag")]
+    pub fn set_tag(&mut self, name: &str, value: Option<String>) -> Result<(), JsValue> {
+        self.update_metadata(|metadata| match value {
+            Some(value) => { metadata.tags.insert(name.to_string(), vec![value]); }
+            None => { metadata.tags.remove(name); }
+        })
+    }
+
+    /// Add a value to a free-form tag, keeping those it has.
+    #[wasm_bindgen(js_name = "addTag")]
+    pub fn add_tag(&mut self, name: &str, value: String) -> Result<(), JsValue> {
+        self.update_metadata(|metadata| metadata.tags.entry(name.to_string()).or_default().push(value))
+    }
+
     #[wasm_bindgen(js_name = "encode")]
     pub fn encode(&self) -> Result<Vec<u8>, JsValue> {
         let mut result = Vec::new();
@@ -85,92 +205,391 @@ impl EuphEncoder {
     }
 }
 
+impl EuphEncoder {
+    /// Apply `update` to the metadata of the last METADATA chunk, adding one
+    /// if there is none.
+    fn update_metadata(&mut self, update: impl FnOnce(&mut EuphMetadata)) -> Result<(), JsValue> {
+        let mut metadata = self.pending_metadata()?;
+        update(&mut metadata);
+        self.store_metadata(&metadata)
+    }
+
+    fn pending_metadata(&self) -> Result<EuphMetadata, JsValue> {
+        match self.chunks.iter().rfind(|chunk| chunk.chunk_type == "METADATA") {
+            Some(chunk) => EuphMetadata::from_json(&chunk.data)
+                .map(|(metadata, _)| metadata)
+                .map_err(|e| JsValue::from_str(&format!("Metadata is not a JSON object: {}", e))),
+            None => Ok(EuphMetadata::default()),
+        }
+    }
+
+    fn store_metadata(&mut self, metadata: &EuphMetadata) -> Result<(), JsValue> {
+        let data = serde_json::to_vec(metadata).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        match self.chunks.iter_mut().rfind(|chunk| chunk.chunk_type == "METADATA") {
+            Some(chunk) => chunk.data = data,
+            None => self.chunks.push(EuphChunk { chunk_type: "METADATA".to_string(), data }),
+        }
+        Ok(())
+    }
+}
+
+impl EuphDecoder {
+    /// `decode` without the conversion to a JS error, for native callers such
+    /// as the fuzz targets.
+    pub fn decode_bytes(&mut self, data: &[u8]) -> Result<(), euph_decoder::EuphError> {
+        self.container = None;
+        let mut container = EuphContainer::parse(&mut Cursor::new(data))?;
+        container.set_key_provider(self.keys.clone());
+        self.container = Some(container);
+        self.load_cached_chunks();
+        Ok(())
+    }
+
+    fn load_cached_chunks(&mut self) {
+        let Some(container) = &self.container else { return };
+        self.lyrics = container.lyrics().ok().flatten();
+        self.metadata = container.chunk_data(ChunkType::Metadata).ok()
+            .and_then(|data| EuphMetadata::from_json(&data).ok())
+            .map(|(metadata, _)| metadata);
+    }
+}
+
+impl Default for EuphDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[wasm_bindgen]
 impl EuphDecoder {
     #[wasm_bindgen(constructor)]
     pub fn new() -> Self {
         Self {
-            chunks: Vec::new(),
+            container: None,
+            keys: HashMap::new(),
+            lyrics: None,
+            metadata: None,
         }
     }
 
+    /// Decode an EUPH file in either the legacy or the spec layout.
     #[wasm_bindgen(js_name = "decode")]
     pub fn decode(&mut self, data: &[u8]) -> Result<(), JsValue> {
-        if data.len() < 8 {
-            return Err(JsValue::from_str("Invalid EUPH file: too short"));
-        }
-
-        // Check magic
-        if &data[0..4] != b"EUPH" {
-            return Err(JsValue::from_str("Invalid EUPH file: wrong magic"));
-        }
+        self.decode_bytes(data)
+            .map_err(|e| JsValue::from_str(&format!("Invalid EUPH file: {}", e)))
+    }
 
-        // Read version
-        let _version_minor = data[5];
-        
-        // Read chunk count
-        let chunk_count = u32::from_le_bytes([data[6], data[7], data[8], data[9]]) as usize;
-        
-        let mut offset = 10;
-        self.chunks.clear();
-        
-        for _ in 0..chunk_count {
-            if offset + 8 > data.len() {
-                return Err(JsValue::from_str("Invalid EUPH file: chunk extends beyond file"));
-            }
-            
-            // Read chunk type
-            let chunk_type_bytes = &data[offset..offset + 4];
-            let chunk_type = String::from_utf8_lossy(chunk_type_bytes).trim_end_matches('\0').to_string();
-            offset += 4;
-            
-            // Read chunk size
-            let chunk_size = u32::from_le_bytes([
-                data[offset], data[offset + 1], 
-                data[offset + 2], data[offset + 3]
-            ]) as usize;
-            offset += 4;
-            
-            if offset + chunk_size > data.len() {
-                return Err(JsValue::from_str("Invalid EUPH file: chunk data extends beyond file"));
-            }
-            
-            // Read chunk data
-            let chunk_data = data[offset..offset + chunk_size].to_vec();
-            offset += chunk_size;
-            
-            self.chunks.push(EuphChunk {
-                chunk_type,
-                data: chunk_data,
-            });
+    /// Register the 32-byte key for encrypted chunks with `key_id`.
+    #[wasm_bindgen(js_name = "addKey")]
+    pub fn add_key(&mut self, key_id: u8, key: &[u8]) -> Result<(), JsValue> {
+        let key = <[u8; 32]>::try_from(key).map_err(|_| JsValue::from_str("Keys must be 32 bytes"))?;
+        self.keys.insert(key_id, key);
+        if let Some(container) = &mut self.container {
+            container.set_key_provider(self.keys.clone());
+            // The LYRC and METADATA chunks may only have become readable
+            // with this key
+            self.load_cached_chunks();
         }
-        
         Ok(())
     }
 
     #[wasm_bindgen(js_name = "getAudioData")]
     pub fn get_audio_data(&self) -> Option<Vec<u8>> {
-        for chunk in &self.chunks {
-            if chunk.chunk_type == "AUDIO" {
-                return Some(chunk.data.clone());
-            }
-        }
-        None
+        let data = self.container.as_ref()?.get_audio_data().ok()?;
+        Some(data.into_owned())
+    }
+
+    /// Codec, sample rate, channels, channel layout, bits per sample, frames
+    /// and duration of the audio as JSON, if the file records them.
+    #[wasm_bindgen(js_name = "getAudioFormat")]
+    pub fn get_audio_format(&self) -> Option<String> {
+        let format = self.container.as_ref()?.audio_format().ok()??;
+        let mut value = serde_json::to_value(format).ok()?;
+        value["duration"] = serde_json::json!(format.duration_secs());
+        Some(value.to_string())
     }
 
+    /// Interleaved samples of `duration` seconds of audio from `start_time`,
+    /// decompressing only the blocks that hold them. Call `reset` on the DSP
+    /// processor before feeding it audio from a new position.
+    #[wasm_bindgen(js_name = "readAudioRange")]
+    pub fn read_audio_range(&mut self, start_time: f64, duration: f64) -> Option<Vec<f32>> {
+        let audio = self.container.as_mut()?.read_audio_range(start_time, duration).ok()?;
+        Some(audio.into_interleaved())
+    }
+
+    /// The embedded audio as a standalone file tagged with this file's
+    /// metadata, artwork, lyrics and chapters. `format` is "native", "wav",
+    /// "flac", "matroska" or "ogg".
+    #[wasm_bindgen(js_name = "exportAudio")]
+    pub fn export_audio(&self, format: &str) -> Result<Vec<u8>, JsValue> {
+        let format = ExportFormat::from_name(format)
+            .ok_or_else(|| JsValue::from_str(&format!("Unknown export format: {}", format)))?;
+        let container = self.container.as_ref().ok_or_else(|| JsValue::from_str("No EUPH file decoded"))?;
+        container.export(format).map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    /// Audio labelled with `role`, e.g. "original" or "enhanced".
+    #[wasm_bindgen(js_name = "getAudioDataWithRole")]
+    pub fn get_audio_data_with_role(&self, role: &str) -> Option<Vec<u8>> {
+        let container = self.container.as_ref()?;
+        let chunk = container.chunks_with_role(ChunkType::Audio, role).next()?;
+        let data = container.get_audio_data_at(chunk.index()).ok()?;
+        Some(data.into_owned())
+    }
+
+    /// Metadata as JSON, migrated to the current schema, or the chunk as
+    /// stored when it is not a JSON object.
     #[wasm_bindgen(js_name = "getMetadata")]
     pub fn get_metadata(&self) -> Option<String> {
-        for chunk in &self.chunks {
-            if chunk.chunk_type == "METADATA" {
-                return String::from_utf8(chunk.data.clone()).ok();
-            }
+        let data = self.chunk_data(ChunkType::Metadata)?;
+        match EuphMetadata::from_json(&data) {
+            Ok((metadata, _)) => serde_json::to_string(&metadata).ok(),
+            Err(_) => String::from_utf8(data).ok(),
         }
-        None
+    }
+
+    /// What was migrated, dropped or out of range in the metadata, as a JSON
+    /// array of objects with a `kind` such as `migrated` or `out_of_range`.
+    #[wasm_bindgen(js_name = "getMetadataWarnings")]
+    pub fn get_metadata_warnings(&self) -> Option<String> {
+        let data = self.chunk_data(ChunkType::Metadata)?;
+        let (_, warnings) = EuphMetadata::from_json(&data).ok()?;
+        serde_json::to_string(&warnings).ok()
+    }
+
+    #[wasm_bindgen(js_name = "getTitle")]
+    pub fn get_title(&self) -> Option<String> {
+        self.metadata.as_ref()?.title.clone()
+    }
+
+    #[wasm_bindgen(js_name = "getArtist")]
+    pub fn get_artist(&self) -> Option<String> {
+        self.metadata.as_ref()?.artist.clone()
+    }
+
+    #[wasm_bindgen(js_name = "getAlbum")]
+    pub fn get_album(&self) -> Option<String> {
+        self.metadata.as_ref()?.album.clone()
+    }
+
+    #[wasm_bindgen(js_name = "getAlbumArtist")]
+    pub fn get_album_artist(&self) -> Option<String> {
+        self.metadata.as_ref()?.album_artist.clone()
+    }
+
+    #[wasm_bindgen(js_name = "getTrackNumber")]
+    pub fn get_track_number(&self) -> Option<u32> {
+        self.metadata.as_ref()?.track_number
+    }
+
+    #[wasm_bindgen(js_name = "getTrackTotal")]
+    pub fn get_track_total(&self) -> Option<u32> {
+        self.metadata.as_ref()?.track_total
+    }
+
+    #[wasm_bindgen(js_name = "getDiscNumber")]
+    pub fn get_disc_number(&self) -> Option<u32> {
+        self.metadata.as_ref()?.disc_number
+    }
+
+    #[wasm_bindgen(js_name = "getDiscTotal")]
+    pub fn get_disc_total(&self) -> Option<u32> {
+        self.metadata.as_ref()?.disc_total
+    }
+
+    #[wasm_bindgen(js_name = "getYear")]
+    pub fn get_year(&self) -> Option<u16> {
+        self.metadata.as_ref()?.year
+    }
+
+    #[wasm_bindgen(js_name = "getComposer")]
+    pub fn get_composer(&self) -> Option<String> {
+        self.metadata.as_ref()?.composer.clone()
+    }
+
+    #[wasm_bindgen(js_name = "getIsrc")]
+    pub fn get_isrc(&self) -> Option<String> {
+        self.metadata.as_ref()?.isrc.clone()
+    }
+
+    /// MusicBrainz recording, track, release, release group, artist and
+    /// album artist ids as a JSON object, leaving out unknown ones.
+    #[wasm_bindgen(js_name = "getMusicBrainzIds")]
+    pub fn get_musicbrainz_ids(&self) -> Option<String> {
+        serde_json::to_string(&self.metadata.as_ref()?.musicbrainz).ok()
+    }
+
+    /// Values of a free-form tag as a JSON array, empty if it is not set.
+    #[wasm_bindgen(js_name = "getTag")]
+    pub fn get_tag(&self, name: &str) -> Option<String> {
+        serde_json::to_string(self.metadata.as_ref()?.tag(name)).ok()
+    }
+
+    /// Every free-form tag as a JSON object of arrays of values.
+    #[wasm_bindgen(js_name = "getTags")]
+    pub fn get_tags(&self) -> Option<String> {
+        serde_json::to_string(&self.metadata.as_ref()?.tags).ok()
+    }
+
+    /// Chapters as a JSON array of `{title, performer, start}`, with `start`
+    /// in seconds, for a chapter list.
+    #[wasm_bindgen(js_name = "getChapters")]
+    pub fn get_chapters(&self) -> Option<String> {
+        let cues = self.container.as_ref()?.cues().ok()??;
+        let chapters: Vec<serde_json::Value> = cues.chapters.iter()
+            .map(|chapter| serde_json::json!({
+                "title": chapter.title,
+                "performer": chapter.performer,
+                "start": cues.seconds(chapter.start),
+            }))
+            .collect();
+        serde_json::to_string(&chapters).ok()
+    }
+
+    /// Chapters, loops and hot cues as JSON, with positions in samples at
+    /// the recorded `sample_rate`.
+    #[wasm_bindgen(js_name = "getCues")]
+    pub fn get_cues(&self) -> Option<String> {
+        String::from_utf8(self.chunk_data(ChunkType::Cues)?).ok()
+    }
+
+    /// The chapters as a `.cue` sheet for the audio saved as `file_name`.
+    #[wasm_bindgen(js_name = "exportCueSheet")]
+    pub fn export_cue_sheet(&self, file_name: &str) -> Option<String> {
+        let cues = self.container.as_ref()?.cues().ok()??;
+        Some(cues.to_cue(file_name))
+    }
+
+    /// Loudness measured by the encoder as JSON: integrated loudness in
+    /// LUFS, loudness range in LU, sample and true peak at full scale 1.0,
+    /// and ReplayGain track and album gains in dB.
+    #[wasm_bindgen(js_name = "getLoudness")]
+    pub fn get_loudness(&self) -> Option<String> {
+        let loudness = self.container.as_ref()?.loudness().ok()??;
+        serde_json::to_string(&loudness).ok()
+    }
+
+    /// Factor for a `GainNode` that normalizes playback to the ReplayGain
+    /// reference, by the album gain when `album` is set, without clipping.
+    /// `None` for files without loudness values.
+    #[wasm_bindgen(js_name = "getNormalizationGain")]
+    pub fn get_normalization_gain(&self, album: bool) -> Option<f32> {
+        Some(self.container.as_ref()?.loudness().ok()??.scale(album))
+    }
+
+    /// Sample rate, channels and the bucket sizes `getPeaks` takes, as JSON.
+    #[wasm_bindgen(js_name = "getPeakResolutions")]
+    pub fn get_peak_resolutions(&self) -> Option<String> {
+        let peaks = self.container.as_ref()?.peaks().ok()??;
+        let resolutions: Vec<u32> = peaks.levels.iter().map(|level| level.samples_per_bucket).collect();
+        serde_json::to_string(&serde_json::json!({
+            "sample_rate": peaks.sample_rate,
+            "channels": peaks.channels,
+            "resolutions": resolutions,
+        })).ok()
+    }
+
+    /// Waveform overview with `resolution` samples per bucket, as an
+    /// `Int16Array` of the minimum, maximum and RMS of each channel in each
+    /// bucket, with 32767 as full scale. `None` for resolutions the file
+    /// does not store.
+    #[wasm_bindgen(js_name = "getPeaks")]
+    pub fn get_peaks(&self, resolution: u32) -> Option<Vec<i16>> {
+        let peaks = self.container.as_ref()?.peaks().ok()??;
+        Some(peaks.level(resolution)?.values.clone())
+    }
+
+    /// Pictures in the file as a JSON array of their role (`front`, `back`,
+    /// `artist` or `other`), MIME type, size and description, in the order
+    /// `getArtworkImage` takes.
+    #[wasm_bindgen(js_name = "getArtwork")]
+    pub fn get_artwork(&self) -> Option<String> {
+        let artwork = self.container.as_ref()?.artwork().ok()??;
+        let pictures: Vec<serde_json::Value> = artwork.pictures.iter()
+            .map(|picture| serde_json::json!({
+                "role": picture.role.name(),
+                "mime_type": picture.mime_type,
+                "width": picture.width,
+                "height": picture.height,
+                "description": picture.description,
+            }))
+            .collect();
+        serde_json::to_string(&pictures).ok()
+    }
+
+    /// Image file of the picture at `index` in `getArtwork`, ready for a
+    /// `Blob` of its MIME type.
+    #[wasm_bindgen(js_name = "getArtworkImage")]
+    pub fn get_artwork_image(&self, index: usize) -> Option<Vec<u8>> {
+        let artwork = self.container.as_ref()?.artwork().ok()??;
+        artwork.pictures.into_iter().nth(index).map(|picture| picture.data)
+    }
+
+    /// Languages the lyrics are in, as a JSON array; `null` stands for
+    /// lyrics of unknown language.
+    #[wasm_bindgen(js_name = "getLyricsLanguages")]
+    pub fn get_lyrics_languages(&self) -> Option<String> {
+        let languages: Vec<Option<&str>> = self.lyrics.as_ref()?.tracks.iter()
+            .map(|track| track.language.as_deref())
+            .collect();
+        serde_json::to_string(&languages).ok()
+    }
+
+    /// Lyric line being sung `time` seconds into playback as JSON, with its
+    /// `index` in the lyrics and the index of the current `word` for lines
+    /// with word timings. Pass no language for the first set of lyrics.
+    #[wasm_bindgen(js_name = "getLyricLine")]
+    pub fn get_lyric_line(&self, time: f64, language: Option<String>) -> Option<String> {
+        let track = self.lyrics.as_ref()?.track(language.as_deref())?;
+        let time = (time * 1000.0) as u64;
+        let index = track.line_index_at(time)?;
+        let line = &track.lines[index];
+        let mut value = serde_json::to_value(line).ok()?;
+        value["index"] = serde_json::json!(index);
+        value["word"] = serde_json::json!(line.words.partition_point(|word| word.time <= time).checked_sub(1));
+        Some(value.to_string())
+    }
+
+    /// Lyrics in `language`, or the first set, as (enhanced) LRC.
+    #[wasm_bindgen(js_name = "exportLrc")]
+    pub fn export_lrc(&self, language: Option<String>) -> Option<String> {
+        Some(self.lyrics.as_ref()?.track(language.as_deref())?.to_lrc())
+    }
+
+    /// Check the file's signature against a 32-byte Ed25519 public key:
+    /// "valid", "tampered", "unsigned" or "unknown_key".
+    #[wasm_bindgen(js_name = "verifySignature")]
+    pub fn verify_signature(&mut self, public_key: &[u8]) -> Result<String, JsValue> {
+        let public_key = <[u8; 32]>::try_from(public_key)
+            .ok()
+            .and_then(|bytes| ed25519_dalek::VerifyingKey::from_bytes(&bytes).ok())
+            .ok_or_else(|| JsValue::from_str("Invalid Ed25519 public key"))?;
+        let container = self.container.as_mut().ok_or_else(|| JsValue::from_str("No EUPH file decoded"))?;
+        let status = container.verify_signature(&public_key)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(match status {
+            SignatureStatus::Valid => "valid",
+            SignatureStatus::Tampered => "tampered",
+            SignatureStatus::Unsigned => "unsigned",
+            SignatureStatus::UnknownKey => "unknown_key",
+        }.to_string())
     }
 
     #[wasm_bindgen(js_name = "getChunkCount")]
     pub fn get_chunk_count(&self) -> usize {
-        self.chunks.len()
+        self.container.as_ref().map_or(0, EuphContainer::chunk_count)
+    }
+
+    /// Whether the decoded file uses the legacy layout and should be upgraded.
+    #[wasm_bindgen(js_name = "isLegacyLayout")]
+    pub fn is_legacy_layout(&self) -> bool {
+        self.container.as_ref().is_some_and(|c| c.layout() == EuphLayout::Legacy)
+    }
+
+    fn chunk_data(&self, chunk_type: ChunkType) -> Option<Vec<u8>> {
+        let data = self.container.as_ref()?.chunk_data(chunk_type).ok()?;
+        Some(data.into_owned())
     }
 }
 
@@ -190,3 +609,12 @@ pub fn validate_euph_file(data: &[u8]) -> bool {
     }
     &data[0..4] == b"EUPH"
 }
+
+/// Rewrite an EUPH file of either layout in the spec layout.
+#[wasm_bindgen]
+pub fn upgrade_euph_file(data: &[u8]) -> Result<Vec<u8>, JsValue> {
+    let mut output = Cursor::new(Vec::new());
+    euph_encoder::EuphEncoder::upgrade(&mut Cursor::new(data), &mut output)
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+    Ok(output.into_inner())
+}
@@ -0,0 +1,152 @@
+//! `euph` run as scripts run it: JSON reports and the exit codes of its
+//! `--help` table.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use serde_json::Value;
+
+/// A scratch directory for one test, removed when the test ends.
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("euph-cli-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Exit code and stdout of `euph` run with `args`.
+fn euph(args: &[&str]) -> (i32, Vec<u8>) {
+    let output = Command::new(env!("CARGO_BIN_EXE_euph")).args(args).output().unwrap();
+    (output.status.code().unwrap(), output.stdout)
+}
+
+/// Exit code and JSON report of `euph --json` run with `args`.
+fn euph_json(args: &[&str]) -> (i32, Value) {
+    let (code, stdout) = euph(&[&["--json"], args].concat());
+    (code, serde_json::from_slice(&stdout).unwrap())
+}
+
+fn arg(path: &Path) -> &str {
+    path.to_str().unwrap()
+}
+
+/// One second of a 16-bit mono 8 kHz sawtooth as a WAV file.
+fn wav() -> Vec<u8> {
+    let data: Vec<u8> = (0..8000i32).flat_map(|i| ((i * 37 % 2000 - 1000) as i16).to_le_bytes()).collect();
+    let mut wav = b"RIFF".to_vec();
+    wav.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt \x10\0\0\0\x01\0\x01\0");
+    wav.extend_from_slice(&8000u32.to_le_bytes());
+    wav.extend_from_slice(&16000u32.to_le_bytes());
+    wav.extend_from_slice(b"\x02\0\x10\0data");
+    wav.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    wav.extend_from_slice(&data);
+    wav
+}
+
+/// Pack `audio` with a title into `name`, returning the EUPH file's path.
+fn pack(scratch: &Scratch, audio: &[u8], name: &str) -> PathBuf {
+    let (audio_path, meta, output) = (scratch.path("audio"), scratch.path("meta.json"), scratch.path(name));
+    std::fs::write(&audio_path, audio).unwrap();
+    std::fs::write(&meta, r#"{"title":"CLI","artist":"Tester"}"#).unwrap();
+    let (code, report) = euph_json(&["pack", "-o", arg(&output), "--audio", arg(&audio_path), "--meta", arg(&meta), "--timestamp", "1700000000"]);
+    assert_eq!(code, 0, "{report}");
+    output
+}
+
+#[test]
+fn info_reports_the_file_as_json() {
+    let scratch = Scratch::new("info");
+    let file = pack(&scratch, &wav(), "a.euph");
+
+    let (code, report) = euph_json(&["info", arg(&file)]);
+    assert_eq!(code, 0, "{report}");
+    assert_eq!(report["layout"], "spec");
+    assert_eq!(report["created"], 1_700_000_000);
+    assert_eq!(report["length"], std::fs::metadata(&file).unwrap().len());
+    assert_eq!(report["metadata"]["title"], "CLI");
+    assert_eq!(report["metadata"]["artist"], "Tester");
+    let format = &report["audio_format"];
+    assert_eq!((&format["codec"], &format["sample_rate"], &format["frames"]), (&"wav".into(), &8000.into(), &8000.into()));
+    let types: Vec<_> = report["chunks"].as_array().unwrap().iter().map(|chunk| chunk["type"].as_str().unwrap()).collect();
+    assert!(types.contains(&"AUDI") && types.contains(&"META"));
+    assert_eq!(report["problems"], Value::Array(Vec::new()));
+
+    // Not an EUPH file
+    let not_euph = scratch.path("a.wav");
+    std::fs::write(&not_euph, wav()).unwrap();
+    let (code, report) = euph_json(&["info", arg(&not_euph)]);
+    assert_eq!((code, &report["error"]["kind"]), (3, &"invalid_magic".into()));
+    assert_eq!(report["error"]["exit_code"], 3);
+}
+
+#[test]
+fn verify_fails_on_a_tampered_file() {
+    let scratch = Scratch::new("verify");
+    let file = pack(&scratch, &wav(), "a.euph");
+    let (code, report) = euph_json(&["verify", arg(&file)]);
+    assert_eq!(code, 0, "{report}");
+    assert_eq!(report["problems"], Value::Array(Vec::new()));
+
+    let (_, info) = euph_json(&["info", arg(&file)]);
+    let offset = info["chunks"][0]["offset"].as_u64().unwrap() as usize;
+    let mut data = std::fs::read(&file).unwrap();
+    data[offset] ^= 0xFF;
+    std::fs::write(&file, data).unwrap();
+
+    let (code, report) = euph_json(&["verify", arg(&file)]);
+    assert_eq!(code, 5, "{report}");
+    assert_eq!(report["error"]["kind"], "checksum_mismatch");
+    assert!(!report["problems"].as_array().unwrap().is_empty());
+
+    // An unparseable key is a usage error, before the file is read
+    let (code, report) = euph_json(&["verify", arg(&file), "--public-key", "00"]);
+    assert_eq!((code, &report["error"]["kind"]), (2, &"usage".into()));
+}
+
+#[test]
+fn extract_writes_chunk_bodies() {
+    let scratch = Scratch::new("extract");
+    let file = pack(&scratch, &wav(), "a.euph");
+
+    // The audio file as it went in, without the format descriptor
+    let output = scratch.path("out.wav");
+    let (code, report) = euph_json(&["extract", arg(&file), "audio", "-o", arg(&output)]);
+    assert_eq!(code, 0, "{report}");
+    assert_eq!(report["chunk"], "AUDI");
+    assert_eq!(std::fs::read(&output).unwrap(), wav());
+    assert_eq!(report["bytes"], wav().len());
+
+    let (code, stdout) = euph(&["extract", arg(&file), "metadata"]);
+    assert_eq!(code, 0);
+    let metadata: Value = serde_json::from_slice(&stdout).unwrap();
+    assert_eq!(metadata["title"], "CLI");
+
+    let (code, report) = euph_json(&["extract", arg(&file), "lyrics"]);
+    assert_eq!((code, &report["error"]["kind"]), (7, &"missing_chunk".into()));
+    let (code, report) = euph_json(&["extract", arg(&file), "nonsense"]);
+    assert_eq!((code, &report["error"]["kind"]), (2, &"usage".into()));
+}
+
+#[test]
+fn export_of_unrecognised_audio_fails() {
+    let scratch = Scratch::new("export");
+    let file = pack(&scratch, b"not audio at all", "a.euph");
+    let (code, report) = euph_json(&["export", arg(&file), "-o", arg(&scratch.path("a.wav"))]);
+    assert_eq!(code, 15, "{report}");
+    assert_eq!(report["error"]["kind"], "unsupported_audio_format");
+    assert!(!scratch.path("a.wav").exists());
+}